@@ -0,0 +1,248 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Following bound Programs on chain
+use crate::contracts::{open_tx_index, CommandReturn, Response};
+use bitcoin::hash_types::Txid;
+use bitcoincore_rpc_async as rpc;
+use rpc::RpcApi;
+use sapio::contract::abi::studio::Program;
+use sapio_psbt::SigningKey;
+use sapio_tools::broadcaster::{Broadcaster, Confirmation, Preferred};
+use sapio_tools::monitor::{BlockSource, Monitor, RawBlockFile, RpcBlockSource};
+use sapio_tools::BitcoinNodeIndex;
+use std::error::Error;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// The node to read blocks from and broadcast to
+pub(crate) struct Node {
+    pub url: String,
+    pub auth: rpc::Auth,
+}
+
+impl Node {
+    fn client(&self, runtime: &tokio::runtime::Runtime) -> Result<rpc::Client, Box<dyn Error>> {
+        Ok(runtime.block_on(rpc::Client::new(self.url.clone(), self.auth.clone()))?)
+    }
+}
+
+/// Where blocks are read from
+pub(crate) enum Blocks {
+    /// hex encoded blocks, one per line, the first at the given height
+    File(PathBuf, u32),
+    /// the node, from the given height or else its next block, polling for
+    /// new blocks every `follow` if set
+    Node {
+        start: Option<u32>,
+        follow: Option<Duration>,
+    },
+}
+
+/// parses a Program from either the Program or the response to the bind
+/// which created it
+pub(crate) fn parse_program(bytes: &[u8]) -> Result<Program, Box<dyn Error>> {
+    if let Ok(program) = serde_json::from_slice(bytes) {
+        return Ok(program);
+    }
+    match serde_json::from_slice::<Response>(bytes)?.result {
+        Ok(CommandReturn::Bind(program)) => Ok(program),
+        _ => Err("Not a bound program".into()),
+    }
+}
+
+/// reads a Program from a file, as in `parse_program`
+pub(crate) fn read_program(path: &PathBuf) -> Result<Program, Box<dyn Error>> {
+    parse_program(&std::fs::read(path)?).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// opens `blocks`, returning the source and how often to poll it
+fn open_blocks(
+    blocks: Blocks,
+    node: &Node,
+    runtime: &Arc<tokio::runtime::Runtime>,
+) -> Result<(Box<dyn BlockSource>, Option<Duration>), Box<dyn Error>> {
+    match blocks {
+        Blocks::File(path, start) => {
+            let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+            Ok((Box::new(RawBlockFile::new(reader, start)), None))
+        }
+        Blocks::Node { start, follow } => {
+            let client = node.client(runtime)?;
+            let next_height = match start {
+                Some(start) => start,
+                None => runtime.block_on(client.get_block_count())? as u32 + 1,
+            };
+            let source = RpcBlockSource {
+                client,
+                runtime: runtime.clone(),
+                next_height,
+            };
+            Ok((Box::new(source), follow))
+        }
+    }
+}
+
+/// asks the node when each of `txids` confirmed, skipping any it does not
+/// know to be confirmed
+fn node_confirmations(
+    client: &rpc::Client,
+    runtime: &tokio::runtime::Runtime,
+    txids: Vec<Txid>,
+) -> Vec<(Txid, Confirmation)> {
+    runtime.block_on(async {
+        let mut confirmations = vec![];
+        for txid in txids {
+            let conf = async {
+                let block = client
+                    .get_raw_transaction_info(&txid, None)
+                    .await
+                    .ok()?
+                    .blockhash?;
+                let header = client.get_block_header_info(&block).await.ok()?;
+                let prev = client
+                    .get_block_header_info(&header.previous_block_hash?)
+                    .await
+                    .ok()?;
+                Some(Confirmation {
+                    height: header.height as u32,
+                    mtp: prev.median_time? as u32,
+                })
+            };
+            if let Some(conf) = conf.await {
+                confirmations.push((txid, conf));
+            }
+        }
+        confirmations
+    })
+}
+
+/// runs `f` on a thread of its own, as the monitor and its block sources
+/// block and are not `Send`.
+async fn on_thread<F>(f: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce() -> Result<(), Box<dyn Error>> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(f().map_err(|e| e.to_string()));
+    });
+    Ok(rx.await??)
+}
+
+/// Watches bound programs, printing each change to them and then what may be
+/// done next with each as JSON lines.
+pub(crate) async fn monitor(
+    programs: Vec<PathBuf>,
+    node: Node,
+    blocks: Blocks,
+    tx_index: PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    on_thread(move || {
+        let runtime = Arc::new(tokio::runtime::Runtime::new()?);
+        let mut monitor = Monitor::new(Rc::new(open_tx_index(tx_index)?));
+        let mut names = vec![];
+        for path in programs {
+            let name = path.display().to_string();
+            monitor.watch(name.clone(), &read_program(&path)?)?;
+            names.push(name);
+        }
+        // outputs confirmed before the blocks to be read, and not yet in the
+        // index, e.g. on the first run
+        if let Blocks::Node { .. } = blocks {
+            let txids = monitor.unconfirmed_txids().into_iter().collect();
+            for (txid, conf) in node_confirmations(&node.client(&runtime)?, &runtime, txids) {
+                monitor.set_confirmed(txid, conf);
+            }
+        }
+        let (mut source, follow) = open_blocks(blocks, &node, &runtime)?;
+        let mut first = true;
+        loop {
+            let events = monitor.run(source.as_mut())?;
+            for event in &events {
+                println!("{}", serde_json::json!({ "event": event }));
+            }
+            if first || !events.is_empty() {
+                for name in &names {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "program": name,
+                            "tip": monitor.tip().map(|t| (t.height, t.mtp)),
+                            "phase": monitor.state(name).map(|s| s.phase()),
+                            "live": monitor.live(name)?,
+                        })
+                    );
+                }
+            }
+            first = false;
+            match follow {
+                Some(every) => std::thread::sleep(every),
+                None => return Ok(()),
+            }
+        }
+    })
+    .await
+}
+
+/// Signs and broadcasts the transactions of bound programs to the node as
+/// they become valid, printing what was done as JSON lines.
+///
+/// Where alternative branches are valid at once, the first of `prefer`
+/// among them is broadcast, and otherwise none are.
+pub(crate) async fn broadcast(
+    programs: Vec<PathBuf>,
+    key: SigningKey,
+    prefer: Vec<Txid>,
+    node: Node,
+    blocks: Blocks,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    on_thread(move || {
+        let runtime = Arc::new(tokio::runtime::Runtime::new()?);
+        let index = BitcoinNodeIndex {
+            client: node.client(&runtime)?,
+            runtime: runtime.clone(),
+            can_add: !dry_run,
+        };
+        let mut broadcaster = Broadcaster::new(key, Rc::new(index), Box::new(Preferred(prefer)));
+        for path in &programs {
+            broadcaster.schedule(&read_program(path)?)?;
+        }
+        // e.g., the programs' funding transactions
+        let parents = broadcaster.unconfirmed_parents().cloned().collect();
+        for (txid, conf) in node_confirmations(&node.client(&runtime)?, &runtime, parents) {
+            broadcaster.set_confirmed(txid, conf);
+        }
+        let (mut source, follow) = open_blocks(blocks, &node, &runtime)?;
+        loop {
+            let report = broadcaster.run(source.as_mut())?;
+            for txid in &report.broadcast {
+                println!("{}", serde_json::json!({ "broadcast": txid }));
+            }
+            for txid in &report.rebroadcast {
+                println!("{}", serde_json::json!({ "rebroadcast": txid }));
+            }
+            for (txid, errors) in &report.failed {
+                println!(
+                    "{}",
+                    serde_json::json!({ "failed": txid, "errors": errors })
+                );
+            }
+            for branches in &report.undecided {
+                println!("{}", serde_json::json!({ "undecided": branches }));
+            }
+            match follow {
+                Some(every) => std::thread::sleep(every),
+                None => return Ok(()),
+            }
+        }
+    })
+    .await
+}
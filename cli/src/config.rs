@@ -4,13 +4,18 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use bitcoin::secp256k1::Keypair;
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoincore_rpc_async as rpc;
 
 use directories::BaseDirs;
-use emulator_connect::connections::federated::FederatedEmulatorConnection;
+use emulator_connect::connections::federated::{
+    FederatedEmulatorConnection, FederationMember, DEFAULT_MEMBER_TIMEOUT,
+};
 use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::transport::client_key_from_seed;
 use emulator_connect::CTVEmulator;
+use sapio_wasm_plugin::host::{limits::PluginLimits, manifest::PublisherTrust};
 use schemars::JsonSchema;
 use serde::*;
 use std::collections::BTreeMap;
@@ -33,6 +38,14 @@ pub struct EmulatorConfig {
     pub emulators: Vec<(ExtendedPubKey, String)>,
     /// threshold could be larger than u8, but that seems very unlikely/an error.
     pub threshold: u8,
+    /// how long, in milliseconds, each emulator in a federation has to sign
+    #[serde(default)]
+    pub member_timeout_ms: Option<u64>,
+    /// a file whose contents seed the key this client authenticates to
+    /// emulators with. If not set, a new key is made every run, which only
+    /// servers accepting any client will sign for.
+    #[serde(default)]
+    pub client_seed: Option<PathBuf>,
 }
 
 impl EmulatorConfig {
@@ -40,16 +53,17 @@ impl EmulatorConfig {
     /// are using a Federated Emulator Connection if emulators.len() > 1, or a
     /// bare HDOracleEmulatorConnection if emulators.len() == 1
     pub fn get_emulator(&self) -> Result<Arc<dyn CTVEmulator>, Box<dyn std::error::Error>> {
-        if self.emulators.len() < self.threshold as usize {
-            Err(String::from("Too High Thresh"))?;
-        } else if self.emulators.len() == 0 {
-            Err(String::from("Too High Thresh"))?;
-        }
+        FederatedEmulatorConnection::check_threshold(self.emulators.len(), self.threshold)?;
+        let client_key = self.get_client_key()?;
         let _n_emulators = self.emulators.len();
         let rt = Handle::try_current()
             .err()
             .map(|_e| Arc::new(tokio::runtime::Runtime::new().unwrap()));
         let secp = Arc::new(bitcoin::secp256k1::Secp256k1::new());
+        let timeout = self
+            .member_timeout_ms
+            .map(std::time::Duration::from_millis)
+            .unwrap_or(DEFAULT_MEMBER_TIMEOUT);
         let mut it =
             self.emulators
                 .iter()
@@ -63,20 +77,35 @@ impl EmulatorConfig {
                         connection: Mutex::new(None),
                         reconnect: host.to_socket_addrs()?.next().unwrap(),
                         root: *epk,
+                        client_key,
                         secp: secp.clone(),
+                        timeout,
                     })
                 });
         Ok(if self.emulators.len() == 1 {
             Arc::new(it.next().unwrap()?)
         } else {
-            Arc::new(FederatedEmulatorConnection::new(
+            let federation = FederatedEmulatorConnection::new(
                 it.map(|n| -> Result<_, Box<dyn std::error::Error>> {
-                    let b: Arc<dyn CTVEmulator> = Arc::new(n?);
+                    let b: Arc<dyn FederationMember> = Arc::new(n?);
                     Ok(b)
                 })
                 .collect::<Result<Vec<_>, _>>()?,
                 self.threshold,
-            ))
+                rt.clone(),
+            )?;
+            Arc::new(federation.with_timeout(timeout))
+        })
+    }
+
+    /// The key this client authenticates to emulators with
+    pub fn get_client_key(&self) -> Result<Keypair, Box<dyn std::error::Error>> {
+        Ok(match &self.client_seed {
+            Some(seed) => client_key_from_seed(&std::fs::read(seed)?)?,
+            None => Keypair::new(
+                &bitcoin::secp256k1::Secp256k1::new(),
+                &mut bitcoin::secp256k1::rand::thread_rng(),
+            ),
         })
     }
 }
@@ -145,6 +174,12 @@ pub struct NetworkConfig {
     pub emulator_nodes: Option<EmulatorConfig>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub plugin_map: Option<BTreeMap<String, WasmerCacheHash>>,
+    /// limits for each call into a plugin, defaults are used if not set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub plugin_limits: Option<PluginLimits>,
+    /// which plugin publishers to trust, and whether to only run their plugins
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub trusted_publishers: Option<PublisherTrust>,
 }
 
 impl From<WasmerCacheHash> for [u8; 32] {
@@ -423,10 +458,14 @@ impl ConfigVerifier {
             emulator_nodes: Some(EmulatorConfig{
                 enabled: false,
                 threshold: 1u8,
+                member_timeout_ms: None,
+                client_seed: None,
                 emulators: vec![(ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4Wf398td3H8YhWBsXx9Sxa4W3cQWkNW3N3DHSNB2qtPoUMXrA6JNaPxodQfRpoZNE5tGM9iZ4xfUEFRJEJvfs8W5paUagYCE").unwrap(),
                     "example.please.change.this.before.using:8367".into())],
             }),
             plugin_map: None,
+            plugin_limits: None,
+            trusted_publishers: None,
         };
         let cv: ConfigVerifier = Config { network, active }.into();
         println!(
@@ -467,10 +506,14 @@ impl std::default::Default for ConfigVerifier {
             emulator_nodes: Some(EmulatorConfig{
                 enabled: true,
                 threshold: 1u8,
+                member_timeout_ms: None,
+                client_seed: None,
                 emulators: vec![(ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4Wf398td3H8YhWBsXx9Sxa4W3cQWkNW3N3DHSNB2qtPoUMXrA6JNaPxodQfRpoZNE5tGM9iZ4xfUEFRJEJvfs8W5paUagYCE").unwrap(),
                     "ctv.d31373.org:8367".into())],
             }),
             plugin_map: None,
+            plugin_limits: None,
+            trusted_publishers: None,
         };
         ConfigVerifier {
             main: None,
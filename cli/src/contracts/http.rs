@@ -0,0 +1,754 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal HTTP/1.1 and WebSocket transport for the JSON-RPC studio server.
+//!
+//! `POST` requests carry a JSON-RPC message (or batch) as their body, `GET
+//! /openrpc.json` returns the OpenRPC document, and a `GET` with a WebSocket
+//! upgrade opens a session where each text message is a JSON-RPC message and
+//! `progress` notifications are sent for running requests.
+//!
+//! Request bodies may be sent with a `Content-Length` or chunked, and
+//! WebSocket messages may be fragmented. Closing a WebSocket (or losing it)
+//! cancels the requests made on it which are still running.
+use super::rpc::{openrpc, RpcHandler, Session};
+use bitcoin::hashes::{sha1, Hash};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::task::JoinSet;
+
+type HttpResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const MAX_HEADERS: usize = 64 * 1024;
+const MAX_BODY: usize = 64 * 1024 * 1024;
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Who may call the server from a browser
+#[derive(Clone, Default)]
+pub struct Origins(pub Vec<String>);
+impl Origins {
+    /// requests without an `Origin` (i.e., not from a browser) are allowed
+    fn allows(&self, origin: Option<&String>) -> bool {
+        origin.map_or(true, |o| self.0.iter().any(|a| a == "*" || a == o))
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// read a line ending in CRLF, without the CRLF
+async fn read_line<R: AsyncRead + Unpin>(r: &mut BufReader<R>) -> HttpResult<String> {
+    let mut line = vec![];
+    while !line.ends_with(b"\r\n") {
+        if line.len() > MAX_HEADERS {
+            return Err("Line Too Long".into());
+        }
+        line.push(r.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8(line)?)
+}
+
+/// read a body sent with `Transfer-Encoding: chunked`
+async fn read_chunked<R: AsyncRead + Unpin>(r: &mut BufReader<R>) -> HttpResult<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(r).await?;
+        // chunk extensions are ignored
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        if size == 0 {
+            // skip any trailers
+            while !read_line(r).await?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > MAX_BODY {
+            return Err("Body Too Large".into());
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        r.read_exact(&mut body[start..]).await?;
+        if !read_line(r).await?.is_empty() {
+            return Err("Bad Chunk".into());
+        }
+    }
+}
+
+async fn read_request<R: AsyncRead + Unpin>(
+    r: &mut BufReader<R>,
+) -> HttpResult<Option<HttpRequest>> {
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEADERS {
+            return Err("Headers Too Large".into());
+        }
+        match r.read_u8().await {
+            Ok(b) => head.push(b),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && head.is_empty() => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let head = String::from_utf8(head)?;
+    let mut lines = head.split("\r\n");
+    let mut start = lines.next().unwrap_or_default().split(' ');
+    let method = start.next().unwrap_or_default().to_string();
+    let path = start.next().ok_or("Bad Request Line")?.to_string();
+    let headers: BTreeMap<String, String> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    // a transfer encoding overrides any content length
+    let body = match headers.get("transfer-encoding") {
+        Some(te) if te.eq_ignore_ascii_case("chunked") => read_chunked(r).await?,
+        Some(_) => return Err("Unsupported Transfer-Encoding".into()),
+        None => {
+            let len: usize = headers
+                .get("content-length")
+                .map(|l| l.parse())
+                .transpose()?
+                .unwrap_or(0);
+            if len > MAX_BODY {
+                return Err("Body Too Large".into());
+            }
+            let mut body = vec![0; len];
+            r.read_exact(&mut body).await?;
+            body
+        }
+    };
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> std::io::Result<()> {
+    let mut out = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n", status, body.len());
+    for (k, v) in headers {
+        out.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    out.push_str("\r\n");
+    w.write_all(out.as_bytes()).await?;
+    w.write_all(body).await?;
+    w.flush().await
+}
+
+/// Serve JSON-RPC over HTTP and WebSocket on `interface` until an error.
+pub async fn serve(
+    interface: &str,
+    handler: Arc<RpcHandler>,
+    origins: Origins,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(interface).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handler = handler.clone();
+        let origins = origins.clone();
+        tokio::spawn(async move {
+            // errors only end this connection
+            let _ = connection(stream, handler, origins).await;
+        });
+    }
+}
+
+async fn connection(
+    stream: TcpStream,
+    handler: Arc<RpcHandler>,
+    origins: Origins,
+) -> HttpResult<()> {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let req = match read_request(&mut stream).await {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            Err(e) => {
+                // the rest of the stream can't be understood, so close it
+                let body = e.to_string();
+                let close = ("Connection", "close".to_string());
+                write_response(
+                    stream.get_mut(),
+                    "400 Bad Request",
+                    &[close],
+                    body.as_bytes(),
+                )
+                .await?;
+                break;
+            }
+        };
+        let origin = req.headers.get("origin");
+        let mut cors = vec![];
+        if let Some(o) = origin {
+            if !origins.allows(Some(o)) {
+                write_response(stream.get_mut(), "403 Forbidden", &[], b"").await?;
+                return Ok(());
+            }
+            cors.push(("Access-Control-Allow-Origin", o.clone()));
+            cors.push(("Vary", "Origin".into()));
+        }
+        let json = ("Content-Type", "application/json".to_string());
+        match (req.method.as_str(), req.path.as_str()) {
+            ("OPTIONS", _) => {
+                cors.push(("Access-Control-Allow-Methods", "GET, POST, OPTIONS".into()));
+                cors.push(("Access-Control-Allow-Headers", "Content-Type".into()));
+                write_response(stream.get_mut(), "204 No Content", &cors, b"").await?;
+            }
+            ("GET", _)
+                if req
+                    .headers
+                    .get("upgrade")
+                    .map_or(false, |u| u.eq_ignore_ascii_case("websocket")) =>
+            {
+                let key = req
+                    .headers
+                    .get("sec-websocket-key")
+                    .ok_or("Missing Sec-WebSocket-Key")?;
+                let accept =
+                    base64::encode(&sha1::Hash::hash(format!("{}{}", key, WS_GUID).as_bytes())[..]);
+                write_response(
+                    stream.get_mut(),
+                    "101 Switching Protocols",
+                    &[
+                        ("Upgrade", "websocket".into()),
+                        ("Connection", "Upgrade".into()),
+                        ("Sec-WebSocket-Accept", accept),
+                    ],
+                    b"",
+                )
+                .await?;
+                return websocket(stream, handler).await;
+            }
+            ("GET", "/openrpc.json") => {
+                cors.push(json);
+                let body = serde_json::to_vec(&openrpc())?;
+                write_response(stream.get_mut(), "200 OK", &cors, &body).await?;
+            }
+            ("POST", _) => {
+                cors.push(json);
+                match handler.handle(&req.body, None).await {
+                    Some(v) => {
+                        let body = serde_json::to_vec(&v)?;
+                        write_response(stream.get_mut(), "200 OK", &cors, &body).await?
+                    }
+                    None => write_response(stream.get_mut(), "204 No Content", &cors, b"").await?,
+                }
+            }
+            _ => write_response(stream.get_mut(), "404 Not Found", &cors, b"").await?,
+        }
+        if req
+            .headers
+            .get("connection")
+            .map_or(false, |c| c.eq_ignore_ascii_case("close"))
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// WebSocket opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// close codes
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+/// how long to wait for the client to answer a close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// read one frame, returning (fin, opcode, payload). Frames which the server
+/// must reject are an `InvalidData` error: unmasked ones, those with reserved
+/// bits set, and those which are too large.
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<(bool, u8, Vec<u8>)> {
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let b0 = r.read_u8().await?;
+    let b1 = r.read_u8().await?;
+    if b0 & 0x70 != 0 {
+        return Err(invalid("Reserved Bits Set"));
+    }
+    if b1 & 0x80 == 0 {
+        return Err(invalid("Unmasked Frame"));
+    }
+    let len = match b1 & 0x7f {
+        126 => r.read_u16().await? as u64,
+        127 => r.read_u64().await?,
+        n => n as u64,
+    };
+    if len > MAX_BODY as u64 {
+        return Err(invalid("Frame Too Large"));
+    }
+    let mut mask = [0u8; 4];
+    r.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    r.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((b0 & 0x80 != 0, b0 & 0x0f, payload))
+}
+
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x80 | opcode];
+    match payload.len() {
+        n if n < 126 => out.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            out.push(126);
+            out.extend((n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(127);
+            out.extend((n as u64).to_be_bytes());
+        }
+    }
+    out.extend(payload);
+    out
+}
+
+/// the payload of a close frame answering `payload`: its status code, if any
+fn close_reply(payload: &[u8]) -> Result<Vec<u8>, u16> {
+    match payload.len() {
+        0 => Ok(vec![]),
+        1 => Err(PROTOCOL_ERROR),
+        _ if std::str::from_utf8(&payload[2..]).is_err() => Err(PROTOCOL_ERROR),
+        _ => Ok(payload[..2].to_vec()),
+    }
+}
+
+/// Why the read loop of a WebSocket stopped
+enum Closing {
+    /// the client sent a close, which was answered
+    ByClient,
+    /// the server sent a close with this code, and waits for the client's
+    ByServer(u16),
+    /// the connection failed
+    Failed(std::io::Error),
+}
+
+async fn websocket(stream: BufReader<TcpStream>, handler: Arc<RpcHandler>) -> HttpResult<()> {
+    let (read, mut write) = stream.into_inner().into_split();
+    let mut read = BufReader::new(read);
+    let (out, mut out_rx) = unbounded_channel::<Vec<u8>>();
+    let (progress, mut progress_rx) = unbounded_channel();
+    let session = Session::new(progress);
+    let notifications = tokio::spawn({
+        let out = out.clone();
+        async move {
+            while let Some((id, progress)) = progress_rx.recv().await {
+                let n = json!({
+                    "jsonrpc": "2.0",
+                    "method": "progress",
+                    "params": {"id": id, "progress": progress},
+                });
+                if out.send(frame(TEXT, n.to_string().as_bytes())).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    // runs until every sender is gone or a close is sent, so queued frames
+    // are still written after the reader stops
+    let writer = tokio::spawn(async move {
+        while let Some(f) = out_rx.recv().await {
+            let close = f.first() == Some(&(0x80 | CLOSE));
+            write.write_all(&f).await?;
+            if close {
+                break;
+            }
+        }
+        write.shutdown().await
+    });
+    // the opcode and data of a fragmented message being received
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    let mut tasks = JoinSet::new();
+    let closing = loop {
+        // forget handled messages, so that a long session doesn't grow
+        while tasks.try_join_next().is_some() {}
+        let (fin, opcode, payload) = match read_frame(&mut read).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                let code = if e.to_string() == "Frame Too Large" {
+                    TOO_BIG
+                } else {
+                    PROTOCOL_ERROR
+                };
+                break Closing::ByServer(code);
+            }
+            Err(e) => break Closing::Failed(e),
+        };
+        if opcode >= CLOSE && (!fin || payload.len() > 125) {
+            // control frames can't be fragmented or large
+            break Closing::ByServer(PROTOCOL_ERROR);
+        }
+        let data = match (opcode, message.take()) {
+            (TEXT | BINARY, None) => (opcode, payload),
+            (CONTINUATION, Some((op, mut data))) => {
+                data.extend(payload);
+                (op, data)
+            }
+            (TEXT | BINARY | CONTINUATION, _) => break Closing::ByServer(PROTOCOL_ERROR),
+            (PING, m) => {
+                message = m;
+                let _ = out.send(frame(PONG, &payload));
+                continue;
+            }
+            (PONG, m) => {
+                message = m;
+                continue;
+            }
+            (CLOSE, _) => match close_reply(&payload) {
+                Ok(reply) => {
+                    let _ = out.send(frame(CLOSE, &reply));
+                    break Closing::ByClient;
+                }
+                Err(code) => break Closing::ByServer(code),
+            },
+            _ => break Closing::ByServer(PROTOCOL_ERROR),
+        };
+        if data.1.len() > MAX_BODY {
+            break Closing::ByServer(TOO_BIG);
+        }
+        if !fin {
+            message = Some(data);
+            continue;
+        }
+        let (opcode, msg) = data;
+        if opcode == TEXT && std::str::from_utf8(&msg).is_err() {
+            break Closing::ByServer(INVALID_DATA);
+        }
+        let (handler, session, out) = (handler.clone(), session.clone(), out.clone());
+        // handle messages concurrently so a long compile doesn't block
+        // cancelling it
+        tasks.spawn(async move {
+            if let Some(v) = handler.handle(&msg, Some(&session)).await {
+                let _ = out.send(frame(TEXT, v.to_string().as_bytes()));
+            }
+        });
+    };
+    // the client is gone or going, so stop its requests on the server too
+    handler.cancel_session(&session);
+    tasks.abort_all();
+    notifications.abort();
+    if let Closing::ByServer(code) = closing {
+        let _ = out.send(frame(CLOSE, &code.to_be_bytes()));
+        // wait for the client's close, ignoring anything else it sends
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while let Ok((_, opcode, _)) = read_frame(&mut read).await {
+                if opcode == CLOSE {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+    drop(out);
+    drop(session);
+    writer.await??;
+    match closing {
+        Closing::Failed(e) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contracts::rpc::INVALID_REQUEST;
+    use serde_json::Value;
+    use tokio::io::AsyncBufReadExt;
+
+    async fn parse(raw: &[u8]) -> HttpResult<Option<HttpRequest>> {
+        read_request(&mut BufReader::new(raw)).await
+    }
+
+    #[tokio::test]
+    async fn test_request_bodies() {
+        let req = parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((req.method.as_str(), req.path.as_str()), ("POST", "/"));
+        assert_eq!(req.body, b"hello");
+        // chunk extensions and trailers are skipped, and the encoding wins
+        // over a content length
+        let req = parse(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n",
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(req.body, b"hello world");
+        assert!(parse(b"").await.unwrap().is_none());
+
+        let too_large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert!(parse(too_large.as_bytes()).await.is_err());
+        let too_large = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            MAX_BODY + 1
+        );
+        assert!(parse(too_large.as_bytes()).await.is_err());
+        let headers = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEADERS));
+        assert!(parse(headers.as_bytes()).await.is_err());
+        // a chunk must be followed by a CRLF
+        assert!(parse(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n0\r\n\r\n"
+        )
+        .await
+        .is_err());
+        assert!(parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n")
+            .await
+            .is_err());
+    }
+
+    /// a server with no studio `Server` behind it, which can still answer
+    /// `rpc.discover`
+    async fn listen(origins: Origins) -> std::net::SocketAddr {
+        let (tx, _) = unbounded_channel();
+        let handler = Arc::new(RpcHandler::new(tx));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (handler, origins) = (handler.clone(), origins.clone());
+                tokio::spawn(connection(stream, handler, origins));
+            }
+        });
+        addr
+    }
+
+    /// send a request and read the response's status, headers and body
+    async fn exchange(
+        addr: std::net::SocketAddr,
+        head: &str,
+        body: &str,
+    ) -> (String, BTreeMap<String, String>, Vec<u8>) {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let req = format!(
+            "{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            head,
+            body.len(),
+            body
+        );
+        stream.get_mut().write_all(req.as_bytes()).await.unwrap();
+        let mut status = String::new();
+        stream.read_line(&mut status).await.unwrap();
+        let status = status.trim_end().splitn(2, ' ').nth(1).unwrap().to_string();
+        let mut headers = BTreeMap::new();
+        loop {
+            let line = read_line(&mut stream).await.unwrap();
+            match line.split_once(':') {
+                Some((k, v)) => headers.insert(k.to_ascii_lowercase(), v.trim().to_string()),
+                None => break,
+            };
+        }
+        let mut body = vec![];
+        stream.read_to_end(&mut body).await.unwrap();
+        (status, headers, body)
+    }
+
+    #[tokio::test]
+    async fn test_http() {
+        let addr = listen(Origins(vec!["https://studio".into()])).await;
+        let discover = r#"{"jsonrpc":"2.0","id":"abc","method":"rpc.discover"}"#;
+        let (status, headers, body) = exchange(addr, "POST / HTTP/1.1", discover).await;
+        assert_eq!(status, "200 OK");
+        assert!(!headers.contains_key("access-control-allow-origin"));
+        let v: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["id"], "abc");
+        assert_eq!(v["result"]["openrpc"], "1.2.6");
+
+        let (status, headers, _) =
+            exchange(addr, "POST / HTTP/1.1\r\nOrigin: https://studio", discover).await;
+        assert_eq!(status, "200 OK");
+        assert_eq!(headers["access-control-allow-origin"], "https://studio");
+        let (status, _, body) =
+            exchange(addr, "POST / HTTP/1.1\r\nOrigin: https://evil", discover).await;
+        assert_eq!(status, "403 Forbidden");
+        assert!(body.is_empty());
+
+        // a batch of notifications has nothing to answer
+        let notifications = r#"[{"jsonrpc":"2.0","method":"rpc.discover"},
+            {"jsonrpc":"2.0","method":"rpc.discover"}]"#;
+        let (status, _, body) = exchange(addr, "POST / HTTP/1.1", notifications).await;
+        assert_eq!(status, "204 No Content");
+        assert!(body.is_empty());
+        let (status, _, body) = exchange(addr, "POST / HTTP/1.1", "[]").await;
+        assert_eq!(status, "200 OK");
+        let v: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["error"]["code"], INVALID_REQUEST);
+        assert_eq!(v["id"], Value::Null);
+
+        let (status, _, _) = exchange(addr, "GET /openrpc.json HTTP/1.1", "").await;
+        assert_eq!(status, "200 OK");
+        let (status, _, _) = exchange(addr, "GET /nothing HTTP/1.1", "").await;
+        assert_eq!(status, "404 Not Found");
+        let (status, _, _) = exchange(addr, "POST / HTTP/1.1\r\nTransfer-Encoding: gzip", "").await;
+        assert_eq!(status, "400 Bad Request");
+    }
+
+    /// a masked frame, as a client sends them
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut f = frame(opcode, payload);
+        if !fin {
+            f[0] &= 0x7f;
+        }
+        f[1] |= 0x80;
+        let at = f.len() - payload.len();
+        let mask = [1, 2, 3, 4];
+        let body: Vec<u8> = f
+            .split_off(at)
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        f.extend(mask);
+        f.extend(body);
+        f
+    }
+
+    /// open a WebSocket
+    async fn upgrade(addr: std::net::SocketAddr) -> TcpStream {
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        stream
+            .get_mut()
+            .write_all(
+                b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            read_line(&mut stream).await.unwrap(),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        let mut accept = None;
+        loop {
+            let line = read_line(&mut stream).await.unwrap();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(v) = line.strip_prefix("Sec-WebSocket-Accept: ") {
+                accept = Some(v.to_string());
+            }
+        }
+        // the example from RFC 6455
+        assert_eq!(accept.as_deref(), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(stream.buffer().is_empty());
+        stream.into_inner()
+    }
+
+    /// read a frame sent by the server, which must not be masked
+    async fn server_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let b0 = stream.read_u8().await.unwrap();
+        let b1 = stream.read_u8().await.unwrap();
+        assert_eq!(b1 & 0x80, 0);
+        let len = match b1 & 0x7f {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            n => n as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        (b0 & 0x0f, payload)
+    }
+
+    #[tokio::test]
+    async fn test_websocket_frames() {
+        let addr = listen(Origins::default()).await;
+        let mut ws = upgrade(addr).await;
+        // a fragmented message, with a ping between its fragments
+        let msg = br#"{"jsonrpc":"2.0","id":5,"method":"rpc.discover"}"#;
+        ws.write_all(&masked(false, TEXT, &msg[..10]))
+            .await
+            .unwrap();
+        ws.write_all(&masked(true, PING, b"hi")).await.unwrap();
+        ws.write_all(&masked(false, CONTINUATION, &msg[10..20]))
+            .await
+            .unwrap();
+        ws.write_all(&masked(true, CONTINUATION, &msg[20..]))
+            .await
+            .unwrap();
+        assert_eq!(server_frame(&mut ws).await, (PONG, b"hi".to_vec()));
+        let (opcode, payload) = server_frame(&mut ws).await;
+        assert_eq!(opcode, TEXT);
+        let v: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(v["id"], 5);
+        assert_eq!(v["result"]["openrpc"], "1.2.6");
+        // closing is answered with the same code
+        ws.write_all(&masked(true, CLOSE, &1000u16.to_be_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut ws).await,
+            (CLOSE, 1000u16.to_be_bytes().to_vec())
+        );
+
+        // an unmasked frame is a protocol error
+        let mut ws = upgrade(addr).await;
+        ws.write_all(&frame(TEXT, msg)).await.unwrap();
+        assert_eq!(
+            server_frame(&mut ws).await,
+            (CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())
+        );
+
+        // so is a continuation without a message
+        let mut ws = upgrade(addr).await;
+        ws.write_all(&masked(true, CONTINUATION, msg))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut ws).await,
+            (CLOSE, PROTOCOL_ERROR.to_be_bytes().to_vec())
+        );
+
+        // a frame which is too large is refused from its header
+        let mut ws = upgrade(addr).await;
+        let mut header = vec![0x80 | TEXT, 0x80 | 127];
+        header.extend((MAX_BODY as u64 + 1).to_be_bytes());
+        ws.write_all(&header).await.unwrap();
+        assert_eq!(
+            server_frame(&mut ws).await,
+            (CLOSE, TOO_BIG.to_be_bytes().to_vec())
+        );
+
+        // text must be UTF-8
+        let mut ws = upgrade(addr).await;
+        ws.write_all(&masked(true, TEXT, &[0xff, 0xfe]))
+            .await
+            .unwrap();
+        assert_eq!(
+            server_frame(&mut ws).await,
+            (CLOSE, INVALID_DATA.to_be_bytes().to_vec())
+        );
+    }
+}
@@ -4,6 +4,9 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod http;
+pub mod pool;
 pub mod request;
+pub mod rpc;
 pub mod server;
 pub use request::*;
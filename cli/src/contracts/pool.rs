@@ -0,0 +1,137 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a pool of instantiated plugins, so concurrent requests don't have to wait
+//! on (or re-instantiate) the same module
+use sapio_wasm_plugin::host::WasmPluginHandle;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Idle plugin instances, by module and the settings they were created with.
+pub struct ModulePool {
+    idle: Mutex<BTreeMap<String, Vec<WasmPluginHandle<Value>>>>,
+    max_idle: usize,
+}
+
+impl ModulePool {
+    /// keep at most `max_idle` idle instances of each module
+    pub fn new(max_idle: usize) -> Self {
+        ModulePool {
+            idle: Default::default(),
+            max_idle,
+        }
+    }
+    /// take an idle instance, if there is one
+    pub fn checkout(&self, key: &str) -> Option<WasmPluginHandle<Value>> {
+        self.idle.lock().unwrap().get_mut(key)?.pop()
+    }
+    /// return an instance for reuse. It is replaced by a new instance of the
+    /// same module, so that nothing the last request did to its memory,
+    /// globals, logs or settings carries over to the next one.
+    pub fn checkin(&self, key: String, handle: WasmPluginHandle<Value>) {
+        if self.idle.lock().unwrap().get(&key).map_or(0, Vec::len) >= self.max_idle {
+            return;
+        }
+        let handle = match handle.reinstantiate() {
+            Ok(h) => h,
+            Err(_) => return,
+        };
+        let mut idle = self.idle.lock().unwrap();
+        let instances = idle.entry(key).or_default();
+        if instances.len() < self.max_idle {
+            instances.push(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use emulator_connect::{CTVAvailable, CTVEmulator};
+    use sapio_wasm_plugin::host::{
+        limits::{CancelToken, PluginLimits},
+        plugin_handle::SyncModuleLocator,
+        PluginHandle,
+    };
+    use std::sync::Arc;
+
+    /// a v2 plugin whose name grows by a letter on every call, counting the
+    /// calls in its memory, and whose `get-api` never returns (but calls the
+    /// host, so that it can be stopped). `get-logo` is its name.
+    pub(crate) fn plugin() -> Vec<u8> {
+        let wat = r#"
+        (module
+            (import "env" "sapio_v1_wasm_plugin_lookup_module_name"
+                (func $lookup (param i32 i32 i32 i32)))
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const 4096))
+            (data (i32.const 16) "aaaaaaaaaaaaaaaa")
+            (func (export "cabi_realloc") (param i32 i32 i32 i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get 3))))
+            (func $name (result i32)
+                (i32.store (i32.const 2048) (i32.add (i32.load (i32.const 2048)) (i32.const 1)))
+                (i32.store (i32.const 1024) (i32.const 16))
+                (i32.store (i32.const 1028) (i32.load (i32.const 2048)))
+                (i32.const 1024))
+            (func $api (result i32)
+                (loop $spin
+                    (call $lookup (i32.const 0) (i32.const 0) (i32.const 3072) (i32.const 3104))
+                    (br $spin))
+                (unreachable))
+            (func $post (param i32))
+            (func (export "sapio:plugin/plugin@2.0.0#create")
+                (param i32 i32 i32 i32 i32 i64 i32 i32) (result i32)
+                (unreachable))
+            (export "sapio:plugin/plugin@2.0.0#get-name" (func $name))
+            (export "sapio:plugin/plugin@2.0.0#get-logo" (func $name))
+            (export "sapio:plugin/plugin@2.0.0#get-api" (func $api))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#get-name" (func $post))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#get-logo" (func $post))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#get-api" (func $post))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#create" (func $post))
+        )"#;
+        wasmer::wat2wasm(wat.as_bytes()).unwrap().into_owned()
+    }
+
+    #[test]
+    fn test_checkin_starts_fresh() {
+        let dir = std::env::temp_dir().join(format!("sapio-module-pool-{}", std::process::id()));
+        let emulator: Arc<dyn CTVEmulator> = Arc::new(CTVAvailable);
+        let handle = WasmPluginHandle::<Value>::new(
+            &dir,
+            &emulator,
+            SyncModuleLocator::Bytes(plugin()),
+            bitcoin::Network::Regtest,
+            None,
+            PluginLimits::unlimited(),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(handle.get_name().unwrap(), "a");
+        assert_eq!(handle.get_name().unwrap(), "aa");
+        let token = CancelToken::new();
+        handle.set_cancel(Some(token.clone()));
+        token.cancel();
+        assert!(handle.get_name().is_err());
+
+        let pool = ModulePool::new(1);
+        pool.checkin("k".into(), handle);
+        let handle = pool.checkout("k").unwrap();
+        assert!(pool.checkout("k").is_none());
+        // neither the memory nor the cancelled token carried over
+        assert_eq!(handle.get_name().unwrap(), "a");
+
+        // no more than max_idle instances are kept
+        let other = handle.reinstantiate().unwrap();
+        pool.checkin("k".into(), handle);
+        pool.checkin("k".into(), other);
+        assert!(pool.checkout("k").is_some());
+        assert!(pool.checkout("k").is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -9,7 +9,9 @@ use bitcoincore_rpc_async::RpcApi;
 use emulator_connect::{CTVAvailable, CTVEmulator};
 use sapio::{
     contract::{
-        object::{LinkedPSBT, ObjectMetadata, Program, SapioStudioObject},
+        object::{
+            satisfaction::TxSatisfaction, LinkedPSBT, ObjectMetadata, Program, SapioStudioObject,
+        },
         Compiled,
     },
     template::{OutputMeta, TemplateMetadata},
@@ -17,12 +19,22 @@ use sapio::{
     Context,
 };
 use sapio_base::{
-    effects::{MapEffectDB, PathFragment},
+    effects::{EffectPath, MapEffectDB, PathFragment},
     serialization_helpers::SArc,
-    txindex::{TxIndex, TxIndexLogger},
+    txindex::TxIndex,
 };
+use sapio_tools::disk_index::DiskTxIndex;
 use sapio_wasm_plugin::{
-    host::{plugin_handle::ModuleLocator, PluginHandle, WasmPluginHandle},
+    host::{
+        abi::AbiVersion,
+        compile_cache::{CacheScope, CacheStats, CompileCache},
+        limits::{CancelToken, PluginLimits},
+        manifest::{PublisherTrust, VerifiedPublisher},
+        plugin_handle::{ModuleLocator, WASMCacheID},
+        registry::PluginRegistry,
+        PluginHandle, WasmPluginHandle,
+    },
+    log::{LogLevel, LogRecord},
     CreateArgs, API,
 };
 use schemars::JsonSchema;
@@ -33,11 +45,13 @@ use std::{
     collections::{BTreeMap, HashMap},
     convert::TryInto,
     error::Error,
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
-    sync::Arc,
+    str::FromStr,
+    sync::{Arc, Mutex},
 };
 
+use super::pool::ModulePool;
 use crate::{config::EmulatorConfig, util::create_mock_output};
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -48,20 +62,41 @@ pub struct Common {
     #[schemars(with = "String")]
     pub net: bitcoin::Network,
     pub plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+    #[serde(default)]
+    pub plugin_limits: PluginLimits,
+    #[serde(default)]
+    pub trusted_publishers: PublisherTrust,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct List;
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ListReturn {
     items: BTreeMap<String, String>,
+    /// the verified publisher of each item which has one
+    #[serde(default)]
+    publishers: BTreeMap<String, VerifiedPublisher>,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Call {
     pub params: serde_json::Value,
+    /// # Log Level
+    /// Return the plugins' log records at this level or more severe
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+    /// # No Cache
+    /// Compile everything, without reading or writing the compilation cache
+    #[serde(default)]
+    pub no_cache: bool,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct CallReturn {
     result: Value,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    logs: Vec<LogRecord>,
+    /// # Cache
+    /// How the compilations of this call were served by the compilation cache
+    #[serde(default)]
+    cache: CacheStats,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Bind {
@@ -72,10 +107,25 @@ pub struct Bind {
     pub use_mock: bool,
     pub outpoint: Option<OutPoint>,
     pub use_txn: Option<String>,
+    /// # Transaction Index
+    /// Where the index of bound transactions is kept between runs, by default
+    /// `txindex` beside the modules directory
+    #[serde(default)]
+    pub tx_index: Option<PathBuf>,
     pub compiled: Compiled,
 }
 pub type BindReturn = Program;
 #[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Satisfaction {
+    /// # Program
+    /// A bound program, e.g. the result of a bind
+    pub program: Program,
+}
+/// # Satisfaction Return
+/// For every transaction of each object, which of its script paths can be
+/// satisfied and which signatures are still needed
+pub type SatisfactionReturn = BTreeMap<SArc<EffectPath>, Vec<TxSatisfaction>>;
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Api;
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct ApiReturn {
@@ -93,12 +143,28 @@ pub struct Info;
 pub struct InfoReturn {
     name: String,
     description: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    publisher: Option<VerifiedPublisher>,
+    /// # ABI
+    /// The plugin ABI the module was built against
+    abi: AbiVersion,
+}
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+pub struct Load {
+    /// # Version
+    /// The semver to register the module as, defaulting to the version in
+    /// its manifest. Only signed modules are registered.
+    #[serde(default)]
+    pub version: Option<String>,
 }
-#[derive(Serialize, Deserialize, JsonSchema)]
-pub struct Load;
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct LoadReturn {
     key: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    publisher: Option<VerifiedPublisher>,
+    /// the version the module was registered as, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -110,6 +176,7 @@ pub enum Command {
     Logo(Logo),
     Info(Info),
     Load(Load),
+    Satisfaction(Satisfaction),
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub enum CommandReturn {
@@ -120,6 +187,7 @@ pub enum CommandReturn {
     Logo(LogoReturn),
     Info(InfoReturn),
     Load(LoadReturn),
+    Satisfaction(SatisfactionReturn),
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -133,8 +201,17 @@ pub struct Response {
     pub result: Result<CommandReturn, RequestError>,
 }
 
+impl Response {
+    /// a response for a request which failed outside of its handler
+    pub fn error(msg: String) -> Self {
+        Response {
+            result: Err(RequestError(msg.into())),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Debug)]
-pub struct RequestError(Value);
+pub struct RequestError(pub Value);
 
 impl Display for RequestError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -158,34 +235,95 @@ impl Request {
         };
         Ok(emulator)
     }
-    pub async fn handle(self) -> Response {
-        let v = self.handle_inner().await.map_err(|e| -> RequestError {
-            e.downcast::<RequestError>()
-                .map(|d| *d)
-                .unwrap_or_else(|e| RequestError(e.to_string().into()))
-        });
+    /// handle the request, reusing plugin instances from `pool` (and returning
+    /// them to it) and caching compilations in `cache` if they are given.
+    /// Plugin calls are stopped once `cancel` is cancelled.
+    pub async fn handle(
+        self,
+        pool: Option<&ModulePool>,
+        cache: Option<&Arc<CompileCache>>,
+        cancel: Option<&CancelToken>,
+    ) -> Response {
+        let v = self
+            .handle_inner(pool, cache, cancel)
+            .await
+            .map_err(|e| -> RequestError {
+                e.downcast::<RequestError>()
+                    .map(|d| *d)
+                    .unwrap_or_else(|e| RequestError(e.to_string().into()))
+            });
         Response { result: v }
     }
-    pub async fn handle_inner(self) -> ResultT<CommandReturn> {
+    pub async fn handle_inner(
+        self,
+        pool: Option<&ModulePool>,
+        cache: Option<&Arc<CompileCache>>,
+        cancel: Option<&CancelToken>,
+    ) -> ResultT<CommandReturn> {
         let emulator = self.get_emulator().await?;
         // create the future to get the sph,
         // but do not await it since not all calls will use it.
         let Request { context, command } = self;
         let Common {
             path,
+            emulator: emulator_config,
             module_locator,
             net,
             plugin_map,
-            ..
+            plugin_limits,
+            trusted_publishers,
         } = context;
-        let default_sph = || -> Result<_, &'static str> {
-            Ok(WasmPluginHandle::<Value>::new_async(
-                &path,
-                &emulator,
-                module_locator.ok_or("Expected to have exactly one of key or file")?,
+        // instances may only be shared between requests with the same settings
+        let pool_key = |id: &str| {
+            format!(
+                "{}:{:?}:{}",
+                id,
+                plugin_map,
+                serde_json::to_string(&(
+                    &path,
+                    &emulator_config,
+                    net,
+                    plugin_limits,
+                    &trusted_publishers
+                ))
+                .unwrap_or_default()
+            )
+        };
+        // compilations depend on the emulator too
+        let cache_salt = serde_json::to_string(&emulator_config).unwrap_or_default();
+        let (path_ref, emulator_ref, map_ref, trust_ref, key_ref) = (
+            &path,
+            &emulator,
+            &plugin_map,
+            &trusted_publishers,
+            &pool_key,
+        );
+        let default_sph = move || async move {
+            let locator = module_locator.ok_or("Expected to have exactly one of key or file")?;
+            if let (Some(pool), ModuleLocator::Key(k)) = (pool, &locator) {
+                let id = WASMCacheID::from_str(k)?.to_string();
+                if let Some(sph) = pool.checkout(&key_ref(&id)) {
+                    sph.set_cancel(cancel.cloned());
+                    return Ok(sph);
+                }
+            }
+            let sph = WasmPluginHandle::<Value>::new_async(
+                path_ref,
+                emulator_ref,
+                locator,
                 net,
-                plugin_map.clone(),
-            ))
+                map_ref.clone(),
+                plugin_limits,
+                trust_ref,
+            )
+            .await?;
+            sph.set_cancel(cancel.cloned());
+            ResultT::Ok(sph)
+        };
+        let release = |sph: WasmPluginHandle<Value>| {
+            if let Some(pool) = pool {
+                pool.checkin(pool_key(&sph.id().to_string()), sph)
+            }
         };
         match command {
             Command::List(_list) => {
@@ -194,16 +332,25 @@ impl Request {
                     emulator.clone(),
                     context.net,
                     plugin_map,
+                    plugin_limits,
+                    &trusted_publishers,
                 )?;
                 let m = plugins
                     .iter()
                     .map(|p| p.get_name().map(|name| (p.id().to_string(), name)))
                     .collect::<Result<BTreeMap<_, _>, _>>()?;
-                Ok(CommandReturn::List(ListReturn { items: m }))
+                let publishers = plugins
+                    .iter()
+                    .filter_map(|p| p.publisher().map(|v| (p.id().to_string(), v.clone())))
+                    .collect();
+                Ok(CommandReturn::List(ListReturn {
+                    items: m,
+                    publishers,
+                }))
             }
             Command::Call(call) => {
                 let params = call.params;
-                let sph = default_sph()?.await?;
+                let sph = default_sph().await?;
 
                 let api = sph.get_api()?;
                 let schema = serde_json::to_value(api.input())?;
@@ -216,26 +363,47 @@ impl Request {
                     Err(RequestError(v.into()))?;
                 }
                 let create_args: CreateArgs<serde_json::Value> = serde_json::from_value(params)?;
-                let v = sph.call(&PathFragment::Root.into(), &create_args)?;
-                Ok(CommandReturn::Call(CallReturn { result: v }))
+                let no_cache = call.no_cache;
+                sph.set_compile_cache(cache.filter(|_| !no_cache).map(|c| CacheScope {
+                    cache: c.clone(),
+                    salt: cache_salt.clone(),
+                }));
+                let v = sph.call(&PathFragment::Root.into(), &create_args);
+                let cache = sph.take_cache_stats();
+                let v = v?;
+                // always taken, so that the handle doesn't keep them
+                let logs = sph.take_logs();
+                let logs = match call.log_level {
+                    Some(level) => logs.into_iter().filter(|r| r.enabled(level)).collect(),
+                    None => vec![],
+                };
+                release(sph);
+                Ok(CommandReturn::Call(CallReturn {
+                    result: v,
+                    logs,
+                    cache,
+                }))
             }
-            Command::Bind(bind) => Ok(CommandReturn::Bind(bind.call(net, emulator).await?)),
+            Command::Bind(bind) => Ok(CommandReturn::Bind(bind.call(net, emulator, &path).await?)),
+            Command::Satisfaction(satisfaction) => Ok(CommandReturn::Satisfaction(
+                satisfaction.program.check_satisfaction()?,
+            )),
             Command::Api(_api) => {
-                let sph = default_sph()?.await?;
-                Ok(CommandReturn::Api(ApiReturn {
-                    api: sph.get_api()?,
-                }))
+                let sph = default_sph().await?;
+                let api = sph.get_api()?;
+                release(sph);
+                Ok(CommandReturn::Api(ApiReturn { api }))
             }
             Command::Logo(_logo) => {
-                let sph = default_sph()?.await?;
-                Ok(CommandReturn::Logo(LogoReturn {
-                    logo: sph.get_logo()?.into(),
-                }))
+                let sph = default_sph().await?;
+                let logo = sph.get_logo()?.into();
+                release(sph);
+                Ok(CommandReturn::Logo(LogoReturn { logo }))
             }
             Command::Info(_info) => {
-                let sph = default_sph()?.await?;
+                let sph = default_sph().await?;
                 let api = sph.get_api()?;
-                Ok(CommandReturn::Info(InfoReturn {
+                let info = InfoReturn {
                     name: sph.get_name()?,
                     description: api
                         .input()
@@ -245,23 +413,58 @@ impl Request {
                         .and_then(|m| m.description.as_ref())
                         .unwrap()
                         .clone(),
-                }))
+                    publisher: sph.publisher().cloned(),
+                    abi: sph.abi(),
+                };
+                release(sph);
+                Ok(CommandReturn::Info(info))
             }
-            Command::Load(_load) => {
-                let sph = default_sph()?.await?;
+            Command::Load(load) => {
+                let sph = default_sph().await?;
+                let version = if sph.manifest().is_some() {
+                    let version = load
+                        .version
+                        .as_deref()
+                        .map(semver::Version::parse)
+                        .transpose()?;
+                    let entry = sph.registry_entry(version)?;
+                    let version = entry.version.to_string();
+                    PluginRegistry::update(&path, |registry| registry.register(entry))?;
+                    Some(version)
+                } else if load.version.is_some() {
+                    return Err("Only plugins with a signed manifest can be registered".into());
+                } else {
+                    None
+                };
                 Ok(CommandReturn::Load(LoadReturn {
                     key: sph.id().to_string(),
+                    publisher: sph.publisher().cloned(),
+                    version,
                 }))
             }
         }
     }
 }
 
+/// Opens the transaction index at `path`, or shares the one already open, as
+/// the database can only be opened once at a time.
+pub(crate) fn open_tx_index(path: PathBuf) -> Result<DiskTxIndex, Box<dyn Error>> {
+    static OPEN: Mutex<BTreeMap<PathBuf, DiskTxIndex>> = Mutex::new(BTreeMap::new());
+    let mut open = OPEN.lock().unwrap();
+    if let Some(index) = open.get(&path) {
+        return Ok(index.clone());
+    }
+    let index = DiskTxIndex::open(&path)?;
+    open.insert(path, index.clone());
+    Ok(index)
+}
+
 impl Bind {
     async fn call(
         self,
         net: bitcoin::Network,
         emulator: Arc<dyn CTVEmulator>,
+        modules: &Path,
     ) -> Result<BindReturn, Box<dyn Error>> {
         let Bind {
             client_url,
@@ -269,9 +472,13 @@ impl Bind {
             use_base64: _,
             use_mock,
             use_txn,
+            tx_index,
             compiled,
             outpoint,
         } = self;
+        let tx_index = open_tx_index(
+            tx_index.unwrap_or_else(|| modules.parent().unwrap_or(modules).join("txindex")),
+        )?;
         let use_txn = use_txn
             .map(|buf| base64::decode(buf.as_bytes()))
             .transpose()?
@@ -332,14 +539,15 @@ impl Bind {
                 return Err(Err(RequestError("Must have a valid address".into()))?);
             }
         };
-        let logger = Rc::new(TxIndexLogger::new());
-        (*logger).add_tx(Arc::new(tx.clone()))?;
+        let index = Rc::new(tx_index.clone());
+        index.add_tx(Arc::new(tx.clone()))?;
         let mut bound = compiled.bind_psbt(
             OutPoint::new(tx.txid(), vout as u32),
             BTreeMap::new(),
-            logger,
+            index,
             emulator.as_ref(),
         )?;
+        tx_index.flush()?;
         if outpoint.is_none() {
             let added_output_metadata = vec![OutputMeta::default(); tx.output.len()];
             let output_metadata = vec![ObjectMetadata::default(); tx.output.len()];
@@ -361,6 +569,8 @@ impl Bind {
                         },
                         output_metadata,
                         added_output_metadata,
+                        cpfp: None,
+                        needs_child: false,
                     }
                     .into()],
                 },
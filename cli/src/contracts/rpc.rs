@@ -0,0 +1,453 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! JSON-RPC 2.0 methods for the studio server, one per `Command` variant
+use super::server::{Job, Message, Progress};
+use super::*;
+use futures_util::future::join_all;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// the request was handled, but returned a `RequestError`
+pub const REQUEST_FAILED: i64 = -32000;
+
+/// calls `$m!(method name, Command variant, argument type, return type)` for
+/// each method
+macro_rules! for_each_method {
+    ($m:ident) => {
+        $m!("list", List, List, ListReturn);
+        $m!("call", Call, Call, CallReturn);
+        $m!("bind", Bind, Bind, BindReturn);
+        $m!("api", Api, Api, ApiReturn);
+        $m!("logo", Logo, Logo, LogoReturn);
+        $m!("info", Info, Info, InfoReturn);
+        $m!("load", Load, Load, LoadReturn);
+        $m!(
+            "satisfaction",
+            Satisfaction,
+            Satisfaction,
+            SatisfactionReturn
+        );
+    };
+}
+
+/// # Method Params
+/// The params of every command method, by name
+#[derive(Deserialize)]
+struct MethodParams {
+    context: Common,
+    #[serde(default)]
+    args: Value,
+}
+
+fn error(id: Value, code: i64, message: &str, data: Option<Value>) -> Value {
+    let mut e = json!({"code": code, "message": message});
+    if let Some(d) = data {
+        e["data"] = d;
+    }
+    json!({"jsonrpc": "2.0", "id": id, "error": e})
+}
+
+fn success(id: Value, result: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "result": result})
+}
+
+/// A connection which can cancel its requests and receive their progress,
+/// e.g. a WebSocket.
+pub struct Session {
+    /// the JSON-RPC id of each of the session's jobs still running
+    jobs: Mutex<BTreeMap<u64, Value>>,
+    /// progress of the session's jobs, by JSON-RPC id
+    progress: UnboundedSender<(Value, Progress)>,
+    forward: UnboundedSender<(Option<u64>, Progress)>,
+}
+
+impl Session {
+    /// Progress notifications for the session's requests are sent to
+    /// `progress` with the request's id. Must be called in a tokio runtime.
+    pub fn new(progress: UnboundedSender<(Value, Progress)>) -> Arc<Self> {
+        let (forward, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let session = Arc::new(Session {
+            jobs: Default::default(),
+            progress,
+            forward,
+        });
+        let weak = Arc::downgrade(&session);
+        tokio::spawn(async move {
+            while let Some((Some(job), p)) = rx.recv().await {
+                let session = match weak.upgrade() {
+                    Some(s) => s,
+                    None => break,
+                };
+                let id = session.jobs.lock().unwrap().get(&job).cloned();
+                if let Some(id) = id {
+                    let _ = session.progress.send((id, p));
+                }
+            }
+        });
+        session
+    }
+    fn job_for(&self, id: &Value) -> Option<u64> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, v)| *v == id)
+            .map(|(k, _)| *k)
+    }
+}
+
+/// Dispatches JSON-RPC messages to a studio `Server`.
+pub struct RpcHandler {
+    server: UnboundedSender<Message>,
+    next_job: AtomicU64,
+}
+
+impl RpcHandler {
+    pub fn new(server: UnboundedSender<Message>) -> Self {
+        RpcHandler {
+            server,
+            next_job: AtomicU64::new(0),
+        }
+    }
+
+    /// Cancels every job of `session` still running, e.g. when its client
+    /// disconnects.
+    pub fn cancel_session(&self, session: &Session) {
+        let jobs: Vec<u64> = session.jobs.lock().unwrap().keys().cloned().collect();
+        for job in jobs {
+            let _ = self.server.send(Message::Cancel(job));
+        }
+    }
+
+    /// Handles a single request or a batch. Returns `None` if there is
+    /// nothing to send back, i.e., only notifications were sent.
+    pub async fn handle(&self, body: &[u8], session: Option<&Arc<Session>>) -> Option<Value> {
+        let msg: Value = match serde_json::from_slice(body) {
+            Ok(v) => v,
+            Err(e) => {
+                return Some(error(
+                    Value::Null,
+                    PARSE_ERROR,
+                    "Parse error",
+                    Some(e.to_string().into()),
+                ))
+            }
+        };
+        match msg {
+            Value::Array(batch) if batch.is_empty() => {
+                Some(error(Value::Null, INVALID_REQUEST, "Invalid Request", None))
+            }
+            Value::Array(batch) => {
+                let responses: Vec<Value> =
+                    join_all(batch.into_iter().map(|m| self.handle_one(m, session)))
+                        .await
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            m => self.handle_one(m, session).await,
+        }
+    }
+
+    async fn handle_one(&self, msg: Value, session: Option<&Arc<Session>>) -> Option<Value> {
+        let obj = match msg {
+            Value::Object(o) => o,
+            _ => return Some(error(Value::Null, INVALID_REQUEST, "Invalid Request", None)),
+        };
+        let id = obj.get("id").cloned();
+        let rid = id.clone().unwrap_or(Value::Null);
+        let method = match (obj.get("jsonrpc"), obj.get("method")) {
+            (Some(Value::String(v)), Some(Value::String(m))) if v == "2.0" => m.clone(),
+            _ => return Some(error(rid, INVALID_REQUEST, "Invalid Request", None)),
+        };
+        let params = obj.get("params").cloned().unwrap_or(Value::Null);
+        let result = self.dispatch(&method, params, id.as_ref(), session).await;
+        // notifications get no response
+        let id = id?;
+        Some(match result {
+            Ok(v) => success(id, v),
+            Err((code, message, data)) => error(id, code, &message, data),
+        })
+    }
+
+    async fn dispatch(
+        &self,
+        method: &str,
+        params: Value,
+        id: Option<&Value>,
+        session: Option<&Arc<Session>>,
+    ) -> Result<Value, (i64, String, Option<Value>)> {
+        let invalid = |e: serde_json::Error| {
+            (
+                INVALID_PARAMS,
+                "Invalid params".into(),
+                Some(e.to_string().into()),
+            )
+        };
+        let variant = match method {
+            "rpc.discover" => return Ok(openrpc()),
+            "cancel" => {
+                let session = session.ok_or_else(|| {
+                    (
+                        METHOD_NOT_FOUND,
+                        "Cancel is only available over WebSocket".into(),
+                        None,
+                    )
+                })?;
+                #[derive(Deserialize)]
+                struct CancelParams {
+                    id: Value,
+                }
+                let CancelParams { id } = serde_json::from_value(params).map_err(invalid)?;
+                let cancelled = match session.job_for(&id) {
+                    Some(job) => self.server.send(Message::Cancel(job)).is_ok(),
+                    None => false,
+                };
+                return Ok(cancelled.into());
+            }
+            _ => {
+                let mut variant = None;
+                macro_rules! find {
+                    ($name:literal, $variant:ident, $args:ty, $ret:ty) => {
+                        if method == $name {
+                            variant = Some(stringify!($variant));
+                        }
+                    };
+                }
+                for_each_method!(find);
+                variant.ok_or_else(|| (METHOD_NOT_FOUND, "Method not found".into(), None))?
+            }
+        };
+        let MethodParams { context, args } = serde_json::from_value(params).map_err(invalid)?;
+        let command: Command = serde_json::from_value(json!({ variant: args })).map_err(invalid)?;
+        let request = Request { context, command };
+
+        let job = self.next_job.fetch_add(1, Ordering::Relaxed);
+        let progress = match (session, id) {
+            (Some(s), Some(id)) => {
+                s.jobs.lock().unwrap().insert(job, id.clone());
+                Some(s.forward.clone())
+            }
+            _ => None,
+        };
+        let (tx, rx) = oneshot::channel();
+        let sent = self.server.send(Message::Run(Job {
+            id: Some(job),
+            request,
+            respond: tx,
+            progress,
+        }));
+        let response = match sent {
+            Ok(()) => rx.await.ok(),
+            Err(_) => None,
+        };
+        if let Some(s) = session {
+            s.jobs.lock().unwrap().remove(&job);
+        }
+        let response =
+            response.ok_or_else(|| (INTERNAL_ERROR, "Server Stopped".to_string(), None))?;
+        match response.result {
+            Ok(r) => {
+                let mut v =
+                    serde_json::to_value(r).map_err(|e| (INTERNAL_ERROR, e.to_string(), None))?;
+                // unwrap the CommandReturn variant
+                Ok(v.get_mut(variant).map(Value::take).unwrap_or(Value::Null))
+            }
+            Err(e) => Err((REQUEST_FAILED, "Request failed".into(), Some(e.0))),
+        }
+    }
+}
+
+/// The OpenRPC document describing the server's methods
+pub fn openrpc() -> Value {
+    let mut gen = SchemaGenerator::new(SchemaSettings::openapi3());
+    let context = gen.subschema_for::<Common>();
+    let mut methods = vec![];
+    macro_rules! describe {
+        ($name:literal, $variant:ident, $args:ty, $ret:ty) => {
+            methods.push(json!({
+                "name": $name,
+                "paramStructure": "by-name",
+                "params": [
+                    {"name": "context", "required": true, "schema": context},
+                    {"name": "args", "required": true, "schema": gen.subschema_for::<$args>()},
+                ],
+                "result": {"name": stringify!($ret), "schema": gen.subschema_for::<$ret>()},
+                "errors": [{"code": REQUEST_FAILED, "message": "Request failed"}],
+            }));
+        };
+    }
+    for_each_method!(describe);
+    methods.push(json!({
+        "name": "cancel",
+        "description": "Cancel a request made over the same WebSocket. Its progress is sent as `progress` notifications.",
+        "paramStructure": "by-name",
+        "params": [{"name": "id", "required": true, "schema": {}}],
+        "result": {"name": "cancelled", "schema": {"type": "boolean"}},
+    }));
+    json!({
+        "openrpc": "1.2.6",
+        "info": {
+            "title": "Sapio Studio Server",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "methods": methods,
+        "components": {"schemas": gen.take_definitions()},
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::server::Server;
+    use super::*;
+    use sapio_wasm_plugin::host::{limits::PluginLimits, plugin_handle::ModuleLocator};
+    use std::time::Duration;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_batches_and_ids() {
+        let (tx, _rx) = unbounded_channel();
+        let handler = RpcHandler::new(tx);
+        let handle = |body: &'static str| handler.handle(body.as_bytes(), None);
+
+        // notifications get no response, alone or in a batch
+        let notification = r#"{"jsonrpc":"2.0","method":"rpc.discover"}"#;
+        assert!(handle(notification).await.is_none());
+        assert!(handle(
+            r#"[{"jsonrpc":"2.0","method":"rpc.discover"}, {"jsonrpc":"2.0","method":"nothing"}]"#
+        )
+        .await
+        .is_none());
+
+        let v = handle("[]").await.unwrap();
+        assert_eq!(v["error"]["code"], INVALID_REQUEST);
+        assert_eq!(v["id"], Value::Null);
+        let v = handle("{").await.unwrap();
+        assert_eq!(v["error"]["code"], PARSE_ERROR);
+        assert_eq!(v["id"], Value::Null);
+
+        // each response echoes its request's id, in order, and members which
+        // aren't requests at all get a null id
+        let v = handle(
+            r#"[
+                {"jsonrpc":"2.0","id":1,"method":"rpc.discover"},
+                {"jsonrpc":"2.0","method":"rpc.discover"},
+                3,
+                {"jsonrpc":"1.0","id":"x","method":"rpc.discover"},
+                {"jsonrpc":"2.0","id":"z","method":"nothing"},
+                {"jsonrpc":"2.0","id":null,"method":"cancel","params":{"id":1}},
+                {"jsonrpc":"2.0","id":2.5,"method":"logo","params":{}}
+            ]"#,
+        )
+        .await
+        .unwrap();
+        let v = v.as_array().unwrap();
+        assert_eq!(v.len(), 6);
+        assert_eq!(v[0]["id"], 1);
+        assert_eq!(v[0]["result"]["openrpc"], "1.2.6");
+        let errors: Vec<(Value, Value)> = v[1..]
+            .iter()
+            .map(|r| (r["id"].clone(), r["error"]["code"].clone()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (Value::Null, INVALID_REQUEST.into()),
+                ("x".into(), INVALID_REQUEST.into()),
+                ("z".into(), METHOD_NOT_FOUND.into()),
+                // cancelling is only possible within a session
+                (Value::Null, METHOD_NOT_FOUND.into()),
+                (2.5.into(), INVALID_PARAMS.into()),
+            ]
+        );
+    }
+
+    async fn next(progressed: &mut UnboundedReceiver<(Value, Progress)>) -> (Value, Progress) {
+        timeout(Duration::from_secs(30), progressed.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_session() {
+        let dir = std::env::temp_dir().join(format!("sapio-rpc-{}", std::process::id()));
+        let (server, tx, shutdown) = Server::new(1);
+        server.run();
+        let handler = Arc::new(RpcHandler::new(tx));
+        let context = serde_json::to_value(Common {
+            path: dir.clone(),
+            emulator: None,
+            module_locator: Some(ModuleLocator::Bytes(super::super::pool::test::plugin())),
+            net: bitcoin::Network::Regtest,
+            plugin_map: None,
+            plugin_limits: PluginLimits::unlimited(),
+            trusted_publishers: Default::default(),
+        })
+        .unwrap();
+        let request = |id: Value, method: &str| {
+            json!({"jsonrpc": "2.0", "id": id, "method": method, "params": {"context": context, "args": null}})
+            .to_string()
+        };
+
+        let v = handler
+            .handle(request("logo".into(), "logo").as_bytes(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            v,
+            json!({"jsonrpc": "2.0", "id": "logo", "result": {"logo": "a"}})
+        );
+
+        let (progress, mut progressed) = unbounded_channel();
+        let session = Session::new(progress);
+        // the test plugin's api never returns
+        let spinning = tokio::spawn({
+            let (handler, session, body) =
+                (handler.clone(), session.clone(), request(9.into(), "api"));
+            async move { handler.handle(body.as_bytes(), Some(&session)).await }
+        });
+        assert_eq!(next(&mut progressed).await, (9.into(), Progress::Queued));
+        assert_eq!(next(&mut progressed).await, (9.into(), Progress::Started));
+        let cancel = r#"{"jsonrpc":"2.0","id":10,"method":"cancel","params":{"id":9}}"#;
+        let v = handler
+            .handle(cancel.as_bytes(), Some(&session))
+            .await
+            .unwrap();
+        assert_eq!(v["result"], true);
+        assert_eq!(next(&mut progressed).await, (9.into(), Progress::Cancelled));
+        let v = spinning.await.unwrap().unwrap();
+        assert_eq!(v["id"], 9);
+        assert_eq!(v["error"]["code"], REQUEST_FAILED);
+        assert_eq!(v["error"]["data"], "Cancelled");
+        // nothing is left to cancel
+        let v = handler
+            .handle(cancel.as_bytes(), Some(&session))
+            .await
+            .unwrap();
+        assert_eq!(v["result"], false);
+        let _ = shutdown.send(());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
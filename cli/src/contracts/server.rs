@@ -4,51 +4,360 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 use tokio::{
     select,
     sync::{
         broadcast,
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
+        oneshot, Semaphore,
     },
 };
 
 use crate::contracts::Request;
 
+use super::pool::ModulePool;
 use super::Response;
+use sapio_wasm_plugin::host::compile_cache::CompileCache;
+use sapio_wasm_plugin::host::limits::CancelToken;
+
+/// # Progress
+/// Sent as a request moves through the server
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// waiting for a free worker
+    Queued,
+    /// running on a worker
+    Started,
+    /// the response has been sent
+    Finished,
+    /// cancelled, or the response is no longer wanted
+    Cancelled,
+}
+
+/// A request for the server to run
+pub struct Job {
+    /// requests with an id may be cancelled with `Message::Cancel`
+    pub id: Option<u64>,
+    pub request: Request,
+    pub respond: oneshot::Sender<Response>,
+    /// where to send progress notifications, if wanted
+    pub progress: Option<UnboundedSender<(Option<u64>, Progress)>>,
+}
+
+pub enum Message {
+    Run(Job),
+    /// Cancel a queued or running request. A running plugin call is stopped
+    /// the next time it runs wasm code, and its result is dropped.
+    Cancel(u64),
+}
+
+/// # Client Message
+/// What a studio client sends to the server
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ClientMessage {
+    Cancel {
+        cancel: u64,
+    },
+    Tagged {
+        id: u64,
+        request: Request,
+    },
+    /// Untagged requests are handled one at a time, and get a bare `Response`
+    Plain(Request),
+}
+
+/// # Server Message
+/// What the server sends back for a tagged request
+#[derive(Serialize, JsonSchema)]
+pub enum ServerMessage {
+    Progress { id: u64, progress: Progress },
+    Response { id: u64, response: Response },
+}
+
+/// how many compilations the server keeps in memory
+const CACHED_COMPILATIONS: usize = 4096;
+/// how many bytes of compilations to keep on disk in each module directory
+const CACHED_BYTES_ON_DISK: u64 = 1 << 30;
+
+type Cancellers = Arc<Mutex<BTreeMap<u64, oneshot::Sender<()>>>>;
 
 pub struct Server {
-    chan: UnboundedReceiver<(Request, oneshot::Sender<Response>)>,
+    chan: UnboundedReceiver<Message>,
     shutdown: tokio::sync::broadcast::Receiver<()>,
+    workers: usize,
 }
 
 impl Server {
-    pub fn new() -> (
-        Self,
-        UnboundedSender<(Request, oneshot::Sender<Response>)>,
-        broadcast::Sender<()>,
-    ) {
+    /// a server which runs up to `workers` requests at once
+    pub fn new(workers: usize) -> (Self, UnboundedSender<Message>, broadcast::Sender<()>) {
         let (a, b) = unbounded_channel();
         let (c, d) = broadcast::channel(1);
         let s = Server {
             chan: b,
             shutdown: d,
+            workers: std::cmp::max(workers, 1),
         };
         (s, a, c)
     }
-    pub fn run(mut self) {
+    pub fn run(self) {
         tokio::spawn(async move {
-            let mut shutdown = self.shutdown;
+            let Server {
+                mut chan,
+                mut shutdown,
+                workers,
+            } = self;
+            let permits = Arc::new(Semaphore::new(workers));
+            let pool = Arc::new(ModulePool::new(workers));
+            let cache = Arc::new(CompileCache::new(CACHED_COMPILATIONS, CACHED_BYTES_ON_DISK));
+            let cancellers: Cancellers = Default::default();
             'terminate: loop {
                 select! {
                     _ = shutdown.recv() => {
                         break 'terminate;
                     }
-                    Some((req, resp)) = self.chan.recv() => {
-                        resp.send(req.handle().await);
+                    Some(msg) = chan.recv() => match msg {
+                        Message::Cancel(id) => {
+                            if let Some(cancel) = cancellers.lock().unwrap().remove(&id) {
+                                let _ = cancel.send(());
+                            }
+                        }
+                        Message::Run(job) => {
+                            let (cancel, cancelled) = oneshot::channel();
+                            if let Some(id) = job.id {
+                                let mut cancellers = cancellers.lock().unwrap();
+                                if cancellers.contains_key(&id) {
+                                    let _ = job
+                                        .respond
+                                        .send(Response::error(format!("Duplicate Request ID {}", id)));
+                                    continue 'terminate;
+                                }
+                                cancellers.insert(id, cancel);
+                            }
+                            tokio::spawn(run_job(
+                                job,
+                                cancelled,
+                                permits.clone(),
+                                pool.clone(),
+                                cache.clone(),
+                                cancellers.clone(),
+                            ));
+                        }
                     }
                 }
             }
         });
     }
 }
+
+/// waits for a worker, then runs the job on a blocking thread since plugins
+/// are CPU bound.
+async fn run_job(
+    job: Job,
+    mut cancelled: oneshot::Receiver<()>,
+    permits: Arc<Semaphore>,
+    pool: Arc<ModulePool>,
+    cache: Arc<CompileCache>,
+    cancellers: Cancellers,
+) {
+    let Job {
+        id,
+        request,
+        mut respond,
+        progress,
+    } = job;
+    let notify = |p| {
+        if let Some(tx) = &progress {
+            let _ = tx.send((id, p));
+        }
+    };
+    notify(Progress::Queued);
+    let token = CancelToken::new();
+    let work = async {
+        let permit = permits.clone().acquire_owned().await.expect("Never Closed");
+        notify(Progress::Started);
+        let rt = tokio::runtime::Handle::current();
+        let token = token.clone();
+        tokio::task::spawn_blocking(move || {
+            // hold the worker until the plugin stops, even if cancelled
+            let _permit = permit;
+            rt.block_on(request.handle(Some(&pool), Some(&cache), Some(&token)))
+        })
+        .await
+    };
+    enum Outcome<T> {
+        Done(T),
+        Cancelled,
+        Abandoned,
+    }
+    let outcome = select! {
+        r = work => Outcome::Done(r),
+        Ok(()) = &mut cancelled => Outcome::Cancelled,
+        _ = respond.closed() => Outcome::Abandoned,
+    };
+    if !matches!(outcome, Outcome::Done(_)) {
+        // stop the plugin, so that it frees its worker
+        token.cancel();
+    }
+    match outcome {
+        Outcome::Done(r) => {
+            let _ = respond.send(r.unwrap_or_else(|e| Response::error(e.to_string())));
+            notify(Progress::Finished);
+        }
+        Outcome::Cancelled => {
+            // the canceller was already removed, and the id may be reused
+            let _ = respond.send(Response::error("Cancelled".into()));
+            notify(Progress::Cancelled);
+            return;
+        }
+        Outcome::Abandoned => notify(Progress::Cancelled),
+    }
+    if let Some(id) = id {
+        cancellers.lock().unwrap().remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contracts::{Api, Command, Common, Logo, RequestError};
+    use sapio_wasm_plugin::host::{limits::PluginLimits, plugin_handle::ModuleLocator};
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// a request for the test plugin, whose `Api` never finishes and whose
+    /// `Logo` returns right away
+    fn request(dir: &PathBuf, command: Command) -> Request {
+        Request {
+            context: Common {
+                path: dir.clone(),
+                emulator: None,
+                module_locator: Some(ModuleLocator::Bytes(super::super::pool::test::plugin())),
+                net: bitcoin::Network::Regtest,
+                plugin_map: None,
+                plugin_limits: PluginLimits::unlimited(),
+                trusted_publishers: Default::default(),
+            },
+            command,
+        }
+    }
+
+    fn submit(
+        tx: &UnboundedSender<Message>,
+        id: u64,
+        request: Request,
+    ) -> (
+        oneshot::Receiver<Response>,
+        UnboundedReceiver<(Option<u64>, Progress)>,
+    ) {
+        let (respond, response) = oneshot::channel();
+        let (progress, progressed) = unbounded_channel();
+        let job = Job {
+            id: Some(id),
+            request,
+            respond,
+            progress: Some(progress),
+        };
+        assert!(tx.send(Message::Run(job)).is_ok());
+        (response, progressed)
+    }
+
+    async fn next(progressed: &mut UnboundedReceiver<(Option<u64>, Progress)>) -> Progress {
+        timeout(Duration::from_secs(30), progressed.recv())
+            .await
+            .expect("progress in time")
+            .expect("progress")
+            .1
+    }
+
+    fn error(response: Response) -> Option<String> {
+        match response.result {
+            Err(RequestError(serde_json::Value::String(s))) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sapio-server-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parallel_jobs() {
+        let dir = test_dir("parallel");
+        let (server, tx, shutdown) = Server::new(2);
+        server.run();
+        let (r1, mut p1) = submit(&tx, 1, request(&dir, Command::Api(Api)));
+        let (r2, mut p2) = submit(&tx, 2, request(&dir, Command::Api(Api)));
+        for p in [&mut p1, &mut p2] {
+            assert_eq!(next(p).await, Progress::Queued);
+            assert_eq!(next(p).await, Progress::Started);
+        }
+        for (id, r, p) in [(1, r1, &mut p1), (2, r2, &mut p2)] {
+            assert!(tx.send(Message::Cancel(id)).is_ok());
+            assert_eq!(next(p).await, Progress::Cancelled);
+            assert_eq!(error(r.await.unwrap()).as_deref(), Some("Cancelled"));
+        }
+        let _ = shutdown.send(());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_queued_and_running() {
+        let dir = test_dir("cancel");
+        let (server, tx, shutdown) = Server::new(1);
+        server.run();
+        let (running, mut p1) = submit(&tx, 1, request(&dir, Command::Api(Api)));
+        assert_eq!(next(&mut p1).await, Progress::Queued);
+        assert_eq!(next(&mut p1).await, Progress::Started);
+        let (queued, mut p2) = submit(&tx, 2, request(&dir, Command::Api(Api)));
+        assert_eq!(next(&mut p2).await, Progress::Queued);
+        // the only worker is busy
+        assert!(timeout(Duration::from_millis(200), p2.recv())
+            .await
+            .is_err());
+
+        assert!(tx.send(Message::Cancel(2)).is_ok());
+        assert_eq!(next(&mut p2).await, Progress::Cancelled);
+        assert_eq!(error(queued.await.unwrap()).as_deref(), Some("Cancelled"));
+        assert!(tx.send(Message::Cancel(1)).is_ok());
+        assert_eq!(next(&mut p1).await, Progress::Cancelled);
+        assert_eq!(error(running.await.unwrap()).as_deref(), Some("Cancelled"));
+
+        // the cancelled plugin stopped, freeing the worker, and its id may be reused
+        let (done, mut p3) = submit(&tx, 1, request(&dir, Command::Logo(Logo)));
+        assert_eq!(next(&mut p3).await, Progress::Queued);
+        assert_eq!(next(&mut p3).await, Progress::Started);
+        assert_eq!(next(&mut p3).await, Progress::Finished);
+        assert!(done.await.unwrap().result.is_ok());
+        let _ = shutdown.send(());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_duplicate_id() {
+        let dir = test_dir("duplicate");
+        let (server, tx, shutdown) = Server::new(2);
+        server.run();
+        let (running, mut p1) = submit(&tx, 7, request(&dir, Command::Api(Api)));
+        assert_eq!(next(&mut p1).await, Progress::Queued);
+        let (duplicate, mut p2) = submit(&tx, 7, request(&dir, Command::Logo(Logo)));
+        assert_eq!(
+            error(duplicate.await.unwrap()).as_deref(),
+            Some("Duplicate Request ID 7")
+        );
+        // the duplicate never ran
+        assert!(p2.recv().await.is_none());
+
+        assert!(tx.send(Message::Cancel(7)).is_ok());
+        assert_eq!(next(&mut p1).await, Progress::Started);
+        assert_eq!(next(&mut p1).await, Progress::Cancelled);
+        assert_eq!(error(running.await.unwrap()).as_deref(), Some("Cancelled"));
+        let _ = shutdown.send(());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -5,7 +5,9 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 #[deny(missing_docs)]
-use crate::contracts::server::Server;
+use crate::contracts::http::{self, Origins};
+use crate::contracts::rpc::{self, RpcHandler};
+use crate::contracts::server::{ClientMessage, Job, Message, Server, ServerMessage};
 use crate::contracts::Api;
 use crate::contracts::Bind;
 use crate::contracts::Call;
@@ -17,6 +19,7 @@ use crate::contracts::Load;
 use crate::contracts::Logo;
 use crate::contracts::Request;
 use crate::contracts::Response;
+use crate::contracts::Satisfaction;
 use bitcoin::consensus::serialize;
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::util::bip32::ExtendedPrivKey;
@@ -25,7 +28,7 @@ use bitcoin::Network;
 use clap::clap_app;
 use clap::ArgMatches;
 use config::*;
-use emulator_connect::servers::hd::HDOracleEmulator;
+use emulator_connect::servers::hd::{ClientPolicy, HDOracleEmulator, RateLimit};
 use emulator_connect::CTVAvailable;
 use emulator_connect::CTVEmulator;
 use sapio::contract::Compiled;
@@ -39,6 +42,7 @@ use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 use tokio::sync::oneshot;
 use util::*;
+mod chain;
 pub mod config;
 mod contracts;
 mod util;
@@ -99,10 +103,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         (@arg stdin: --stdin  "Run in Synchronous mode")
         (@arg interface: --interface +takes_value "The Interface to Bind")
       )
+      (@arg workers: --workers +takes_value "How many requests to run at once (defaults to the number of CPUs)")
+      (@arg allow_origin: --("allow-origin") +takes_value +multiple "Browser origins which may call the JSON-RPC server (\"*\" for any)")
      )
      (@subcommand schemas =>
       (about: "print input and output schemas")
      )
+     (@subcommand openrpc =>
+      (about: "print the OpenRPC document for the JSON-RPC server")
+     )
     )
     (@subcommand emulator =>
      (@setting SubcommandRequiredElseHelp)
@@ -115,6 +124,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
      (@subcommand get_key =>
       (about: "Get Signing Condition")
       (@arg psbt: -p --psbt +takes_value +required #{1,2} {check_file} "The file containing the PSBT to Get a Key For")
+      (@arg input_index: -i --input_index +takes_value "The input the contract is spent from (defaults to 0)")
      )
      (@subcommand show =>
       (about: "Show a psbt")
@@ -125,8 +135,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       (@arg sync: --sync  "Run in Synchronous mode")
       (@arg seed: +takes_value +required {check_file} "The file containing the Seed")
       (@arg interface: +required +takes_value "The Interface to Bind")
+      (@group clients +required =>
+       (@arg allow: --allow +takes_value {check_file} "A file of client keys (hex x-only, one per line) to serve")
+       (@arg open: --open "Serve any client")
+      )
+      (@arg rate_burst: --("rate-burst") +takes_value "How many requests a client (or address) may make at once (default 20)")
+      (@arg rate_per_second: --("rate-per-second") +takes_value "How many requests per second a client (or address) may make after a burst (default 2)")
+     )
+     (@subcommand client_key =>
+      (about: "Show the key this client authenticates to emulator servers with")
      )
      )
+     (@subcommand chain =>
+      (@setting SubcommandRequiredElseHelp)
+      (about: "Follow bound contracts on chain")
+      (@subcommand monitor =>
+       (about: "Watch bound programs, printing each change to them and what may be done next as JSON lines")
+       (@arg program: +required +multiple {check_file} "Files holding the bound programs (the output of contract bind) to watch")
+       (@arg blocks: --blocks +takes_value {check_file} "Read hex encoded blocks, one per line, from this file instead of the node")
+       (@arg start: --start +takes_value "The height of the first block to read (defaults to 0 for a file, or the node's next block)")
+       (@arg follow: --follow +takes_value "Keep polling the node for new blocks, every this many seconds")
+       (@arg tx_index: --tx_index +takes_value "Directory of the transaction index to record transactions in (defaults to txindex in the workspace)")
+      )
+      (@subcommand broadcast =>
+       (about: "Sign and broadcast the transactions of bound programs as they become valid, printing what was done as JSON lines")
+       (@arg program: +required +multiple {check_file} "Files holding the bound programs (the output of contract bind) to broadcast")
+       (@arg key: -k --key +takes_value +required {check_file} "The file to read the signing key from")
+       (@arg prefer: --prefer +takes_value +multiple "Txids to broadcast, in order of preference, where alternative branches are valid at once (otherwise none are)")
+       (@arg blocks: --blocks +takes_value {check_file} "Read hex encoded blocks, one per line, from this file instead of the node")
+       (@arg start: --start +takes_value "The height of the first block to read (defaults to 0 for a file, or the node's next block)")
+       (@arg follow: --follow +takes_value "Keep polling the node for new blocks, every this many seconds")
+       (@arg dry_run: --dry_run "Report what would be broadcast without sending it to the node")
+      )
+     )
      (@subcommand psbt =>
       (@setting SubcommandRequiredElseHelp)
       (about: "Perform operations on PSBTs")
@@ -146,6 +187,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             (@arg txn: --txn +takes_value "Use this specific transaction ")
             (@arg mock: --mock "Create a fake output for this txn.")
        )
+       (@arg tx_index: --tx_index +takes_value "Directory of the transaction index to bind with, kept between runs (defaults to txindex in the workspace)")
        (@arg json: "JSON to Bind")
       )
       (@subcommand create =>
@@ -156,11 +198,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         (@arg key:  -k --key +takes_value "Which Contract to Create, given a WASM Hash")
        )
        (@arg json: "JSON of args")
+       (@arg log_level: --("log-level") +takes_value possible_values(&["error", "warn", "info", "debug", "trace"]) "Include the plugins' logs at this level or more severe")
+       (@arg no_cache: --("no-cache") "Compile everything, without reading or writing the compilation cache")
       )
       (@subcommand load =>
        (about: "Load a wasm contract module, returns the hex sha3 hash key")
        (@arg workspace: -w --workspace +takes_value "Where to copy the contract file")
        (@arg file: -f --file +required +takes_value {check_file} "Which Contract to Create, given a WASM Plugin file")
+       (@arg semver: --semver +takes_value "The version to register a signed module as in the plugin registry")
       )
       (@subcommand api =>
        (about: "Machine Readable API for a plugin, pipe into jq for pretty formatting.")
@@ -186,6 +231,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         (@arg key:  -k --key +takes_value "Which Contract to Create, given a WASM Hash")
        )
       )
+      (@subcommand satisfaction =>
+       (about: "Show which script paths of a bound program's transactions can be satisfied, and the signatures still needed")
+       (@arg program: {check_file} "File holding the bound program (the output of contract bind), otherwise read from stdin")
+      )
       (@subcommand list =>
        (about: "list available contracts")
        (@arg workspace: -w --workspace +takes_value "Where to search for.")
@@ -315,13 +364,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 Some(("get_key", args)) => {
                     let psbt = decode_psbt_file(args, "psbt")?;
-                    let h = emulator.get_signer_for(psbt.extract_tx().get_ctv_hash(0))?;
+                    let idx: u32 = args
+                        .value_of("input_index")
+                        .map(str::parse)
+                        .transpose()?
+                        .unwrap_or(0);
+                    let h = emulator.get_signer_for(psbt.extract_tx().get_ctv_hash(idx))?;
                     println!("{}", h);
                 }
                 Some(("show", args)) => {
                     let psbt = decode_psbt_file(args, "psbt")?;
                     println!("{:?}", psbt);
                 }
+                Some(("client_key", _)) => {
+                    let emcfg = config
+                        .active
+                        .emulator_nodes
+                        .as_ref()
+                        .ok_or("No Emulators Configured")?;
+                    if emcfg.client_seed.is_none() {
+                        Err("Set client_seed in the emulator config to have a stable key")?;
+                    }
+                    println!("{}", emcfg.get_client_key()?.x_only_public_key().0);
+                }
                 Some(("server", args)) => {
                     let filename = args.value_of("seed").unwrap();
                     let contents = tokio::fs::read(filename).await?;
@@ -329,13 +394,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let root = ExtendedPrivKey::new_master(config.network, &contents[..]).unwrap();
                     let pk_root = ExtendedPubKey::from_priv(&Secp256k1::new(), &root);
                     let sync_mode = args.is_present("sync");
-                    let oracle = HDOracleEmulator::new(root, sync_mode);
+                    // any client is only served when asked for
+                    let clients = match (args.value_of("allow"), args.is_present("open")) {
+                        (Some(allow), false) => {
+                            ClientPolicy::from_allow_list(&tokio::fs::read_to_string(allow).await?)?
+                        }
+                        (None, true) => ClientPolicy::Any,
+                        _ => Err("Pass exactly one of --allow or --open")?,
+                    };
+                    let open = matches!(clients, ClientPolicy::Any);
+                    let mut rate = RateLimit::default();
+                    if let Some(burst) = args.value_of("rate_burst") {
+                        rate.burst = burst.parse()?;
+                    }
+                    if let Some(per_second) = args.value_of("rate_per_second") {
+                        rate.per_second = per_second.parse()?;
+                    }
+                    let oracle =
+                        HDOracleEmulator::new(root, sync_mode, clients).with_rate_limit(rate);
                     let interface = args.value_of("interface").unwrap();
                     let server = oracle.bind(interface);
                     let status = serde_json::json! {{
                         "interface": interface,
                         "pk": pk_root,
                         "sync": sync_mode,
+                        "open": open,
+                        "rate": {"burst": rate.burst, "per_second": rate.per_second},
                     }};
                     println!("{}", serde_json::to_string_pretty(&status).unwrap());
                     server.await?;
@@ -343,6 +427,57 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 _ => unreachable!(),
             }
         }
+        Some(("chain", matches)) => {
+            let config = config(custom_config).await?;
+            let tx_index = |args: &clap::ArgMatches| {
+                args.value_of("tx_index")
+                    .map(Into::into)
+                    .unwrap_or_else(|| {
+                        let mut p = util::get_data_dir("org", "judica", "sapio-cli");
+                        p.push("txindex");
+                        p
+                    })
+            };
+            let node = chain::Node {
+                url: config.active.api_node.url.clone(),
+                auth: config.active.api_node.auth.clone(),
+            };
+            let blocks = |args: &clap::ArgMatches| -> Result<chain::Blocks, Box<dyn Error>> {
+                let start = args.value_of("start").map(str::parse).transpose()?;
+                Ok(match args.value_of("blocks") {
+                    Some(file) => chain::Blocks::File(file.into(), start.unwrap_or(0)),
+                    None => chain::Blocks::Node {
+                        start,
+                        follow: args
+                            .value_of("follow")
+                            .map(str::parse)
+                            .transpose()?
+                            .map(std::time::Duration::from_secs),
+                    },
+                })
+            };
+            match matches.subcommand() {
+                Some(("monitor", args)) => {
+                    let programs = args.values_of("program").unwrap().map(Into::into).collect();
+                    chain::monitor(programs, node, blocks(args)?, tx_index(args)).await?;
+                }
+                Some(("broadcast", args)) => {
+                    let programs = args.values_of("program").unwrap().map(Into::into).collect();
+                    let buf = tokio::fs::read(args.value_of_os("key").unwrap()).await?;
+                    let key = sapio_psbt::SigningKey::read_key_from_buf(&buf[..])?;
+                    let prefer = args
+                        .values_of("prefer")
+                        .into_iter()
+                        .flatten()
+                        .map(bitcoin::Txid::from_str)
+                        .collect::<Result<_, _>>()?;
+                    let blocks = blocks(args)?;
+                    let dry_run = args.is_present("dry_run");
+                    chain::broadcast(programs, key, prefer, node, blocks, dry_run).await?;
+                }
+                _ => unreachable!(),
+            }
+        }
         Some(("psbt", matches)) => match matches.subcommand() {
             Some(("finalize", args)) => {
                 let psbt_str = args.value_of("psbt");
@@ -356,12 +491,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(("studio", matches)) => match matches.subcommand() {
             Some(("server", args)) => {
                 let from_stdin = args.is_present("stdin");
+                let workers = match args.value_of("workers") {
+                    Some(w) => w.parse()?,
+                    None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+                };
                 if from_stdin {
-                    run_server_stdin().await?;
-                } else {
-                    args.value_of("interface");
+                    run_server_stdin(workers).await?;
+                } else if let Some(interface) = args.value_of("interface") {
+                    let (server, send_server, _shutdown_server) = Server::new(workers);
+                    server.run();
+                    let origins = Origins(
+                        args.values_of("allow_origin")
+                            .map(|v| v.map(String::from).collect())
+                            .unwrap_or_default(),
+                    );
+                    http::serve(interface, Arc::new(RpcHandler::new(send_server)), origins)
+                        .await
+                        .map_err(|e| e.to_string())?;
                 }
             }
+            Some(("openrpc", _args)) => {
+                println!("{}", serde_json::to_string_pretty(&rpc::openrpc())?);
+            }
             Some(("schemas", _args)) => {
                 println!(
                     "{}",
@@ -387,6 +538,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .map(|(x, y)| (x.into_bytes().into(), y.into()))
                     .collect()
             });
+            let plugin_limits = config.active.plugin_limits.unwrap_or_default();
+            let trusted_publishers = config.active.trusted_publishers.unwrap_or_default();
             let context = |args: &clap::ArgMatches| -> Result<Common, &'static str> {
                 let module_locator = args
                     .value_of("file")
@@ -403,9 +556,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     module_locator,
                     net: network,
                     plugin_map,
+                    plugin_limits,
+                    trusted_publishers: trusted_publishers.clone(),
                 })
             };
-            let (server, send_server, shutdown_server) = Server::new();
+            let (server, send_server, shutdown_server) = Server::new(1);
 
             let msg = match matches.subcommand() {
                 Some(("bind", args)) => {
@@ -416,6 +571,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         command: bind_command(&args, client_url, client_auth).await?,
                     }
                 }
+                Some(("satisfaction", args)) => {
+                    let bytes = match args.value_of_os("program") {
+                        Some(file) => tokio::fs::read(file).await?,
+                        None => {
+                            let mut buf = vec![];
+                            tokio::io::stdin().read_to_end(&mut buf).await?;
+                            buf
+                        }
+                    };
+                    Request {
+                        context: context(&args)?,
+                        command: Command::Satisfaction(Satisfaction {
+                            program: chain::parse_program(&bytes)?,
+                        }),
+                    }
+                }
                 Some(("list", args)) => Request {
                     context: context(&args)?,
                     command: Command::List(List),
@@ -431,7 +602,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     };
                     Request {
                         context: context(&args)?,
-                        command: Command::Call(Call { params }),
+                        command: Command::Call(Call {
+                            params,
+                            log_level: args
+                                .value_of("log_level")
+                                .map(|l| serde_json::from_value(l.into()))
+                                .transpose()?,
+                            no_cache: args.is_present("no_cache"),
+                        }),
                     }
                 }
                 Some(("api", args)) => Request {
@@ -448,13 +626,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 },
                 Some(("load", args)) => Request {
                     context: context(&args)?,
-                    command: Command::Load(Load),
+                    command: Command::Load(Load {
+                        version: args.value_of("semver").map(String::from),
+                    }),
                 },
                 _ => unreachable!(),
             };
             server.run();
             let (tx, rx) = oneshot::channel();
-            send_server.send((msg, tx)).map_err(|_e| "Failed to Send")?;
+            send_server
+                .send(Message::Run(Job {
+                    id: None,
+                    request: msg,
+                    respond: tx,
+                    progress: None,
+                }))
+                .map_err(|_e| "Failed to Send")?;
             println!("{}", serde_json::to_string_pretty(&rx.await?)?);
             shutdown_server.send(())?;
         }
@@ -464,12 +651,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn run_server_stdin() -> Result<(), Box<dyn Error>> {
-    let (server, send_server, shutdown_server) = Server::new();
+/// Reads `ClientMessage`s from stdin. Tagged requests run concurrently and
+/// their progress and responses are written as `ServerMessage`s, while plain
+/// requests are answered in order with a bare `Response`.
+async fn run_server_stdin(workers: usize) -> Result<(), Box<dyn Error>> {
+    let (server, send_server, shutdown_server) = Server::new(workers);
     server.run();
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
     let stream = tokio::task::spawn_blocking(move || {
-        let stream = Deserializer::from_reader(std::io::stdin()).into_iter::<Request>();
+        let stream = Deserializer::from_reader(std::io::stdin()).into_iter::<ClientMessage>();
         for json in stream {
             // if a bad json is read, break.
             if tx.send(json).is_err() {
@@ -477,14 +667,68 @@ async fn run_server_stdin() -> Result<(), Box<dyn Error>> {
             }
         }
     });
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+    let progress_printer = tokio::spawn(async move {
+        while let Some((id, progress)) = progress_rx.recv().await {
+            if let Some(id) = id {
+                if let Ok(s) =
+                    serde_json::to_string_pretty(&ServerMessage::Progress { id, progress })
+                {
+                    println!("{}", s);
+                }
+            }
+        }
+    });
+    let mut tagged = vec![];
     while let Some(json) = rx.recv().await {
         let (b_tx, b_rx) = oneshot::channel();
+        let (job, id) = match json? {
+            ClientMessage::Cancel { cancel } => {
+                send_server
+                    .send(Message::Cancel(cancel))
+                    .map_err(|_e| "Failed to Send")?;
+                continue;
+            }
+            ClientMessage::Tagged { id, request } => (
+                Job {
+                    id: Some(id),
+                    request,
+                    respond: b_tx,
+                    progress: Some(progress_tx.clone()),
+                },
+                Some(id),
+            ),
+            ClientMessage::Plain(request) => (
+                Job {
+                    id: None,
+                    request,
+                    respond: b_tx,
+                    progress: None,
+                },
+                None,
+            ),
+        };
         send_server
-            .send((json?, b_tx))
+            .send(Message::Run(job))
             .map_err(|_e| "Failed to Send")?;
-
-        println!("{}", serde_json::to_string_pretty(&b_rx.await?)?);
+        match id {
+            Some(id) => tagged.push(tokio::spawn(async move {
+                if let Ok(response) = b_rx.await {
+                    if let Ok(s) =
+                        serde_json::to_string_pretty(&ServerMessage::Response { id, response })
+                    {
+                        println!("{}", s);
+                    }
+                }
+            })),
+            None => println!("{}", serde_json::to_string_pretty(&b_rx.await?)?),
+        }
+    }
+    for t in tagged {
+        t.await?;
     }
+    drop(progress_tx);
+    progress_printer.await?;
     shutdown_server.send(())?;
     stream.await?;
     Ok(())
@@ -502,6 +746,7 @@ async fn bind_command(
         .map(serde_json::from_str)
         .transpose()?;
     let use_txn = args.value_of("txn").map(String::from);
+    let tx_index = args.value_of("tx_index").map(std::path::PathBuf::from);
     let compiled: Compiled = if let Some(json) = args.value_of("json") {
         serde_json::from_str(json)?
     } else {
@@ -516,6 +761,7 @@ async fn bind_command(
         use_mock,
         outpoint,
         use_txn,
+        tx_index,
         compiled,
     }))
 }
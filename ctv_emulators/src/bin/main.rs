@@ -23,7 +23,20 @@ async fn main() -> Result<(), std::io::Error> {
         ExtendedPrivKey::new_master(bitcoin::network::constants::Network::Regtest, &contents[..])
             .unwrap();
     let pk_root = ExtendedPubKey::from_private(&Secp256k1::new(), &root);
-    let oracle = HDOracleEmulator::new(root, true);
+    // any client is only served when asked for
+    let clients = match std::env::args().nth(3).as_deref() {
+        Some("--open") => ClientPolicy::Any,
+        Some(allow_list) => {
+            ClientPolicy::from_allow_list(&tokio::fs::read_to_string(allow_list).await?)?
+        }
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "No Allow List given (or --open to serve any client)",
+            ))
+        }
+    };
+    let oracle = HDOracleEmulator::new(root, true, clients);
     let server = oracle.bind(
         std::env::args()
             .nth(2)
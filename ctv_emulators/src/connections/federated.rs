@@ -5,19 +5,86 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+/// How long to wait for a member to sign, unless set otherwise
+pub const DEFAULT_MEMBER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An emulator which can sign without blocking, so that a federation can
+/// wait on all of its members at once.
+pub trait FederationMember: CTVEmulator {
+    /// Adds the Emulators signature to the PSBT, if any. Dropping the future
+    /// abandons the request.
+    fn sign_async(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> BoxFuture<'_, Result<PartiallySignedTransaction, EmulatorError>>;
+}
+
 /// Creates a multi-condition emulator with a certain threshold.
 /// It implements CTVEmulator so that it itself can be used as a trait object.
+///
+/// Members are asked to sign concurrently, and signing finishes as soon as
+/// `threshold` of them have, so a slow or dead member only costs its timeout
+/// when it is needed to reach the threshold. Members still signing then are
+/// dropped, abandoning their requests.
 pub struct FederatedEmulatorConnection {
-    emulators: Vec<Arc<dyn CTVEmulator>>,
+    emulators: Vec<Arc<dyn FederationMember>>,
     threshold: u8,
+    timeout: Duration,
+    /// kept alive for `handle`, if the federation made its own
+    _runtime: Option<Arc<tokio::runtime::Runtime>>,
+    handle: Handle,
 }
 
 impl FederatedEmulatorConnection {
-    pub fn new(emulators: Vec<Arc<dyn CTVEmulator>>, threshold: u8) -> Self {
-        FederatedEmulatorConnection {
+    /// Creates a federation requiring `threshold` of `emulators` to sign.
+    ///
+    /// As with `HDOracleEmulatorConnection`, a runtime must be passed if not
+    /// created in an async context.
+    pub fn new(
+        emulators: Vec<Arc<dyn FederationMember>>,
+        threshold: u8,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+    ) -> Result<Self, EmulatorError> {
+        Self::check_threshold(emulators.len(), threshold)?;
+        Ok(FederatedEmulatorConnection {
             emulators,
             threshold,
+            timeout: DEFAULT_MEMBER_TIMEOUT,
+            handle: Handle::try_current().unwrap_or_else(|_e| {
+                runtime
+                    .as_ref()
+                    .expect("Must pass a runtime if not in async context")
+                    .handle()
+                    .clone()
+            }),
+            _runtime: runtime,
+        })
+    }
+    /// Checks that `threshold` of `members` is a meaningful federation.
+    pub fn check_threshold(members: usize, threshold: u8) -> Result<(), EmulatorError> {
+        if members == 0 {
+            return Err(EmulatorError::NoEmulators);
         }
+        if threshold == 0 {
+            return Err(EmulatorError::ZeroThreshold);
+        }
+        if threshold as usize > members {
+            return Err(EmulatorError::ThresholdTooHigh {
+                threshold: threshold as usize,
+                members,
+            });
+        }
+        Ok(())
+    }
+    /// Sets how long each member has to sign.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 }
 
@@ -32,11 +99,191 @@ impl CTVEmulator for FederatedEmulatorConnection {
     }
     fn sign(
         &self,
-        mut b: PartiallySignedTransaction,
+        b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        for emulator in self.emulators.iter() {
-            b = emulator.sign(b)?;
+        tokio::task::block_in_place(|| self.handle.block_on(self.sign_async(b)))
+    }
+}
+
+impl FederationMember for FederatedEmulatorConnection {
+    fn sign_async(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> BoxFuture<'_, Result<PartiallySignedTransaction, EmulatorError>> {
+        Box::pin(async move {
+            let threshold = self.threshold as usize;
+            let mut pending: FuturesUnordered<_> = self
+                .emulators
+                .iter()
+                .enumerate()
+                .map(|(i, emulator)| {
+                    let sign = tokio::time::timeout(self.timeout, emulator.sign_async(b.clone()));
+                    async move {
+                        let r = match sign.await {
+                            Ok(r) => r,
+                            Err(_) => Err(EmulatorError::Timeout(self.timeout)),
+                        };
+                        (i, r)
+                    }
+                })
+                .collect();
+            let mut signed = vec![];
+            let mut failed = vec![];
+            // stops once the threshold is met or out of reach, dropping (and
+            // so abandoning) the requests still pending
+            while signed.len() < threshold && signed.len() + pending.len() >= threshold {
+                let (i, r) = match pending.next().await {
+                    Some(next) => next,
+                    None => break,
+                };
+                match r.and_then(|psbt| {
+                    b.combine(psbt)
+                        .or_else(|_e| input_error("Fault Signed PSBT"))
+                        .map_err(EmulatorError::from)
+                }) {
+                    Ok(()) => signed.push(i),
+                    Err(e) => failed.push((i, e)),
+                }
+            }
+            if signed.len() >= threshold {
+                return Ok(b);
+            }
+            signed.sort_unstable();
+            failed.sort_by_key(|(i, _)| *i);
+            Err(EmulatorError::FederationFailed {
+                threshold,
+                signed,
+                failed,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::Transaction;
+
+    /// signs (or refuses, with `refuse`) after `delay`
+    struct Member {
+        delay: Duration,
+        refuse: bool,
+    }
+
+    impl CTVEmulator for Member {
+        fn get_signer_for(&self, _h: Sha256) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Unsatisfiable)
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            std::thread::sleep(self.delay);
+            self.result(b)
+        }
+    }
+
+    impl Member {
+        fn result(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            if self.refuse {
+                Err(EmulatorError::Refused("no".into()))
+            } else {
+                Ok(b)
+            }
+        }
+    }
+
+    impl FederationMember for Member {
+        fn sign_async(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> BoxFuture<'_, Result<PartiallySignedTransaction, EmulatorError>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                self.result(b)
+            })
+        }
+    }
+
+    fn federation(members: &[(u64, bool)], threshold: u8) -> FederatedEmulatorConnection {
+        let members = members
+            .iter()
+            .map(|(ms, refuse)| -> Arc<dyn FederationMember> {
+                Arc::new(Member {
+                    delay: Duration::from_millis(*ms),
+                    refuse: *refuse,
+                })
+            })
+            .collect();
+        FederatedEmulatorConnection::new(members, threshold, None)
+            .unwrap()
+            .with_timeout(Duration::from_millis(200))
+    }
+
+    fn psbt() -> PartiallySignedTransaction {
+        PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap()
+    }
+
+    fn indexes(failed: &[(usize, EmulatorError)]) -> Vec<usize> {
+        failed.iter().map(|(i, _)| *i).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_threshold() {
+        // the threshold is met without waiting on the member that never
+        // signs in time
+        let start = std::time::Instant::now();
+        let f = federation(&[(0, false), (60_000, false), (10, false)], 2);
+        assert!(f.sign_async(psbt()).await.is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        // and through the blocking interface
+        assert!(tokio::task::block_in_place(|| f.sign(psbt())).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_member_timeout() {
+        let f = federation(&[(0, false), (60_000, false)], 2);
+        match f.sign_async(psbt()).await {
+            Err(EmulatorError::FederationFailed {
+                threshold,
+                signed,
+                failed,
+            }) => {
+                assert_eq!(threshold, 2);
+                assert_eq!(signed, vec![0]);
+                assert_eq!(indexes(&failed), vec![1]);
+                assert!(matches!(failed[0].1, EmulatorError::Timeout(_)));
+            }
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failures() {
+        // once the threshold is out of reach, the rest are not waited for
+        let f = federation(&[(0, false), (10, true), (20, true), (60_000, false)], 3);
+        match f.sign_async(psbt()).await {
+            Err(EmulatorError::FederationFailed { signed, failed, .. }) => {
+                assert_eq!(signed, vec![0]);
+                assert_eq!(indexes(&failed), vec![1, 2]);
+                assert!(failed
+                    .iter()
+                    .all(|(_, e)| matches!(e, EmulatorError::Refused(_))));
+            }
+            r => panic!("unexpected {:?}", r.map(|_| ())),
         }
-        Ok(b)
+        assert!(matches!(
+            FederatedEmulatorConnection::new(vec![], 1, None),
+            Err(EmulatorError::NoEmulators)
+        ));
     }
 }
@@ -5,23 +5,32 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
-/// HDOracleEmulatorConnection wraps a tokio runtime and a TCPStream
-/// with a key to be able to talk to an Oracle server.
+/// HDOracleEmulatorConnection wraps a tokio runtime and a `SecureChannel`
+/// with a key to be able to talk to an Oracle server, authenticating as
+/// `client_key`.
 ///
 /// Note that because HDOracleEmulatorConnection uses block_in_place/block_on
 /// internally in the trait object because the CTVEmulator trait is not async.
 ///
 /// This seems to be a limitation with tokio / rust around using async inside non-async
-/// traits.
+/// traits. In an async context, use `FederationMember::sign_async` instead.
+///
+/// A request which does not finish within `timeout` is dropped along with
+/// its connection, so the next request starts on a fresh one.
 pub struct HDOracleEmulatorConnection {
     pub runtime: Option<Arc<tokio::runtime::Runtime>>,
     pub handle: tokio::runtime::Handle,
-    pub connection: Mutex<Option<TcpStream>>,
+    pub connection: Mutex<Option<SecureChannel>>,
     pub reconnect: SocketAddr,
     pub root: ExtendedPubKey,
+    pub client_key: Keypair,
     pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    pub timeout: Duration,
 }
 
+/// How long a request to an emulator may take, unless set otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl HDOracleEmulatorConnection {
     /// Helper function to derive an EPK
     fn derive(&self, h: Sha256) -> Result<ExtendedPubKey, Error> {
@@ -43,6 +52,7 @@ impl HDOracleEmulatorConnection {
     pub async fn new<A: ToSocketAddrs + std::fmt::Display + Clone>(
         address: A,
         root: ExtendedPubKey,
+        client_key: Keypair,
         runtime: Option<Arc<tokio::runtime::Runtime>>,
         secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
     ) -> Result<Self, std::io::Error> {
@@ -64,31 +74,40 @@ impl HDOracleEmulatorConnection {
             }),
             runtime,
             root,
+            client_key,
             secp,
+            timeout: DEFAULT_TIMEOUT,
         })
     }
-
-    /// make a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await
+    /// Sets how long a request may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
-    /// receive a response via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: secure response by limiting the length to a max value.
-    /// This is not super critical because presumably the oracles are not trying to OOM your system.
-    async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        let t: T = serde_json::from_slice::<T>(&v[..])?;
-        Ok(t)
+    /// Sends `b` to be signed, connecting first if there is no connection.
+    async fn request(
+        &self,
+        conn: Option<SecureChannel>,
+        b: PartiallySignedTransaction,
+    ) -> Result<(SecureChannel, msgs::Response), std::io::Error> {
+        let mut conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let stream = TcpStream::connect(&self.reconnect).await?;
+                SecureChannel::connect(stream, self.root.public_key, &self.client_key).await?
+            }
+        };
+        conn.send(&msgs::Request::SignPSBT(msgs::PSBT(b))).await?;
+        let response = conn.recv::<msgs::Response>().await?;
+        Ok((conn, response))
     }
 }
 
+use super::federated::FederationMember;
+use crate::transport::SecureChannel;
+use bitcoin::secp256k1::Keypair;
+use futures_util::future::BoxFuture;
+use std::time::Duration;
 use tokio::{runtime::Handle, sync::Mutex};
 impl CTVEmulator for HDOracleEmulatorConnection {
     fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
@@ -96,27 +115,88 @@ impl CTVEmulator for HDOracleEmulatorConnection {
     }
     fn sign(
         &self,
-        mut b: PartiallySignedTransaction,
+        b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        let inp: Result<PartiallySignedTransaction, std::io::Error> =
-            tokio::task::block_in_place(|| {
-                self.handle.block_on(async {
-                    let mut mconn = self.connection.lock().await;
-                    loop {
-                        if let Some(conn) = &mut *mconn {
-                            Self::request(conn, &msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
-                                .await?;
-                            conn.flush().await?;
-                            return Ok(Self::response::<msgs::PSBT>(conn).await?.0);
-                        } else {
-                            *mconn = Some(TcpStream::connect(&self.reconnect).await?);
-                        }
-                    }
-                })
-            });
+        tokio::task::block_in_place(|| self.handle.block_on(self.sign_async(b)))
+    }
+}
 
-        b.combine(inp?)
-            .or_else(|_e| input_error("Fault Signed PSBT"))?;
-        Ok(b)
+impl FederationMember for HDOracleEmulatorConnection {
+    fn sign_async(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> BoxFuture<'_, Result<PartiallySignedTransaction, EmulatorError>> {
+        Box::pin(async move {
+            let mut mconn = self.connection.lock().await;
+            // the connection is only put back once a request completes, so
+            // one which failed or timed out part way is dropped rather than
+            // reused in an unknown state
+            let conn = mconn.take();
+            let inp = match tokio::time::timeout(self.timeout, self.request(conn, b.clone())).await
+            {
+                Ok(Ok((conn, response))) => {
+                    *mconn = Some(conn);
+                    response
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => return Err(EmulatorError::Timeout(self.timeout)),
+            };
+            drop(mconn);
+            let inp = match inp {
+                msgs::Response::Signed(msgs::PSBT(psbt)) => psbt,
+                msgs::Response::Refused(why) => return Err(EmulatorError::Refused(why)),
+            };
+            b.combine(inp)
+                .or_else(|_e| input_error("Fault Signed PSBT"))?;
+            Ok(b)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::Transaction;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_timeout() {
+        // a server which accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((s, _)) = listener.accept().await {
+                streams.push(s);
+            }
+        });
+        let secp = Arc::new(Secp256k1::new());
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[7u8; 32]).unwrap();
+        let client_key = Keypair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let conn = HDOracleEmulatorConnection::new(
+            addr,
+            ExtendedPubKey::from_priv(&secp, &root),
+            client_key,
+            None,
+            secp,
+        )
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(100));
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                conn.sign_async(psbt.clone()).await,
+                Err(EmulatorError::Timeout(_))
+            ));
+            // the abandoned connection is not kept
+            assert!(conn.connection.lock().await.is_none());
+        }
+        accepted.abort();
     }
 }
@@ -27,6 +27,7 @@ const MAX_MSG: usize = 1_000_000;
 pub mod connections;
 mod msgs;
 pub mod servers;
+pub mod transport;
 
 thread_local! {
     pub static SECP: Secp256k1<All> = Secp256k1::new();
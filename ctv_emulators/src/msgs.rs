@@ -25,6 +25,15 @@ pub enum Request {
     SignPSBT(PSBT),
 }
 
+/// Wrapper for response serialization
+#[derive(Serialize, Deserialize)]
+pub enum Response {
+    /// the PSBT, with the emulator's signatures added
+    Signed(PSBT),
+    /// the server would not sign, and why
+    Refused(String),
+}
+
 /// A visitor tage for a SafePSBT type that is size limited
 /// Serialized/deserialized with a size tag internally.
 struct SafePSBT(usize);
@@ -5,6 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use crate::transport::SecureChannel;
 use bitcoin::util::sighash::Prevouts;
 use bitcoin::util::taproot::TapLeafHash;
 use bitcoin::util::taproot::TapSighashHash;
@@ -12,43 +13,209 @@ use bitcoin::SchnorrSig;
 use bitcoin::Script;
 use bitcoin::TxOut;
 use bitcoin::XOnlyPublicKey;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a client has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a refused client has to send the request it is refused
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often buckets which have refilled are forgotten
+const BUCKET_SWEEP: Duration = Duration::from_secs(60);
+
+/// Which clients may request signatures from an emulator
+#[derive(Clone, Debug)]
+pub enum ClientPolicy {
+    /// any client, although requests are still authenticated and encrypted
+    Any,
+    /// only clients authenticating with one of these keys
+    Allow(BTreeSet<XOnlyPublicKey>),
+}
+
+impl ClientPolicy {
+    /// parses an allow-list of hex x-only keys, one per line. Blank lines and
+    /// lines starting with `#` are ignored.
+    pub fn from_allow_list(s: &str) -> Result<Self, std::io::Error> {
+        s.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                XOnlyPublicKey::from_str(l)
+                    .or_else(|_| input_error(&format!("Bad Client Key {}", l)))
+            })
+            .collect::<Result<_, _>>()
+            .map(ClientPolicy::Allow)
+    }
+    fn allows(&self, client: &XOnlyPublicKey) -> bool {
+        match self {
+            ClientPolicy::Any => true,
+            ClientPolicy::Allow(keys) => keys.contains(client),
+        }
+    }
+}
+
+/// How many requests each client, and each peer address, may make, as a
+/// token bucket holding `burst` requests and refilling at `per_second`.
+/// Connections count against their peer address's bucket too, so clients
+/// minting new keys are still limited.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            burst: 20,
+            per_second: 2.0,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// what a bucket limits
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum RateKey {
+    Client(XOnlyPublicKey),
+    Peer(IpAddr),
+}
+
+struct Buckets {
+    buckets: BTreeMap<RateKey, Bucket>,
+    swept: Instant,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Buckets {
+            buckets: Default::default(),
+            swept: Instant::now(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct HDOracleEmulator {
     root: ExtendedPrivKey,
     debug: bool,
+    clients: ClientPolicy,
+    rate: RateLimit,
+    buckets: Arc<Mutex<Buckets>>,
 }
 
 impl HDOracleEmulator {
-    /// create a new HDOracleEmulator
+    /// create a new HDOracleEmulator, serving the clients allowed by `clients`
     ///
     /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
-    pub fn new(root: ExtendedPrivKey, debug: bool) -> Self {
-        HDOracleEmulator { root, debug }
+    pub fn new(root: ExtendedPrivKey, debug: bool, clients: ClientPolicy) -> Self {
+        HDOracleEmulator {
+            root,
+            debug,
+            clients,
+            rate: Default::default(),
+            buckets: Default::default(),
+        }
+    }
+    /// sets how many requests each client may make
+    pub fn with_rate_limit(mut self, rate: RateLimit) -> Self {
+        self.rate = rate;
+        self
     }
     /// binds a HDOracleEmulator to a socket interface and runs the server
     ///
+    /// Clients connect over a `SecureChannel`, in which the server
+    /// authenticates with the key of its root xpub.
+    ///
     /// This will only return when debug = false if The TcpListener fails.
     /// When debug = true, then we join each connection one at a time and return
     /// any errors.
     pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
-        let listener = TcpListener::bind(a).await?;
+        self.serve(TcpListener::bind(a).await?).await
+    }
+    /// runs the server on an already bound `listener`, as `bind` does
+    pub async fn serve(self, listener: TcpListener) -> std::io::Result<()> {
+        let server = SECP.with(|secp| self.root.to_keypair(secp));
         loop {
-            let (mut socket, _) = listener.accept().await?;
+            let (socket, peer) = listener.accept().await?;
             {
                 let this = self.clone();
-                let j: tokio::task::JoinHandle<Result<(), std::io::Error>> =
-                    tokio::spawn(async move {
+                let j: tokio::task::JoinHandle<Result<(), std::io::Error>> = tokio::spawn(
+                    async move {
+                        if !this.rate_limit(&[RateKey::Peer(peer.ip())]) {
+                            tracing::warn!(target: "sapio_emulator::audit", %peer, "rate limited connection");
+                            return Ok(());
+                        }
+                        let (mut channel, client) = tokio::time::timeout(
+                            HANDSHAKE_TIMEOUT,
+                            SecureChannel::accept(socket, &server),
+                        )
+                        .await
+                        .or_else(|_| input_error("Handshake Timed Out"))??;
+                        if !this.clients.allows(&client) {
+                            tracing::warn!(target: "sapio_emulator::audit", %peer, %client, "refused unknown client");
+                            // answer the first request, so the client learns
+                            // why before the connection closes, if it sends
+                            // one soon
+                            tokio::time::timeout(REFUSAL_TIMEOUT, channel.recv::<msgs::Request>())
+                                .await
+                                .or_else(|_| input_error("Refused Client Timed Out"))??;
+                            return channel
+                                .send(&msgs::Response::Refused("Client Not Allowed".into()))
+                                .await;
+                        }
                         loop {
-                            socket.readable().await?;
-                            this.handle(&mut socket).await?;
+                            this.handle(&mut channel, peer, client).await?;
                         }
-                    });
+                    },
+                );
                 if self.debug {
                     tokio::join!(j).0??;
                 }
             }
         }
     }
+    /// takes a token from each of `keys`' buckets, if they all have one
+    fn rate_limit(&self, keys: &[RateKey]) -> bool {
+        let now = Instant::now();
+        let burst = self.rate.burst as f64;
+        let per_second = self.rate.per_second;
+        let mut buckets = self.buckets.lock().unwrap();
+        // a bucket idle long enough to refill is the same as a new one
+        if now.duration_since(buckets.swept) >= BUCKET_SWEEP {
+            buckets.swept = now;
+            buckets.buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last).as_secs_f64() * per_second < burst
+            });
+        }
+        let mut allowed = true;
+        for key in keys {
+            let bucket = buckets.buckets.entry(*key).or_insert(Bucket {
+                tokens: burst,
+                last: now,
+            });
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.last).as_secs_f64() * per_second)
+                .min(burst);
+            bucket.last = now;
+            allowed &= bucket.tokens >= 1.0;
+        }
+        if allowed {
+            for key in keys {
+                if let Some(bucket) = buckets.buckets.get_mut(key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+        allowed
+    }
     /// helper to get an EPK for the oracle.
     fn derive(&self, h: Sha256, secp: &Secp256k1<All>) -> Result<ExtendedPrivKey, Error> {
         let c = hash_to_child_vec(h);
@@ -57,7 +224,10 @@ impl HDOracleEmulator {
 
     /// Signs a PSBT with the correct derived key.
     ///
-    /// Always signs for spending index 0.
+    /// Signs every input which has Taproot spend info attached (as set by
+    /// `bind_psbt`), deriving the key from the template hash at that input's
+    /// index. Only leaves which contain the derived key are signed, so inputs
+    /// belonging to other contracts in a batch are left alone.
     ///
     /// May fail to sign if the PSBT is not properly formatted
     fn sign(
@@ -66,87 +236,323 @@ impl HDOracleEmulator {
         secp: &Secp256k1<All>,
     ) -> Result<PartiallySignedTransaction, std::io::Error> {
         let tx = b.clone().extract_tx();
-        let h = tx.get_ctv_hash(0);
         let utxos: Vec<TxOut> = b
             .inputs
             .iter()
             .map(|o| o.witness_utxo.clone())
             .collect::<Option<Vec<TxOut>>>()
             .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
-        let key = self
-            .derive(h, secp)
-            .map_err(|_| input_err("Could Not Derive Key"))?;
-        let untweaked = key.to_keypair(secp);
-        let pk = XOnlyPublicKey::from_keypair(&untweaked);
         let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
-        let input_zero = &mut b.inputs[0];
-        use bitcoin::schnorr::TapTweak;
-        let tweaked = untweaked
-            .tap_tweak(secp, input_zero.tap_merkle_root)
-            .into_inner();
-        let tweaked_pk = tweaked.public_key();
         let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
         let prevouts = &Prevouts::All(&utxos);
-        let mut get_sig = |path, kp| {
-            let annex = None;
-            let sighash: TapSighashHash = sighash
-                .taproot_signature_hash(0, prevouts, annex, path, hash_ty)
-                .expect("Signature hash cannot fail...");
-            let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..])
-                .expect("Size must be correct.");
-            let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
-            SchnorrSig { sig, hash_ty }
-        };
-        if let Some(true) = input_zero.witness_utxo.as_ref().map(|v| {
-            v.script_pubkey
-                == Script::new_v1_p2tr_tweaked(
-                    XOnlyPublicKey::from(tweaked_pk).dangerous_assume_tweaked(),
-                )
-        }) {
-            let sig = get_sig(None, &tweaked);
-            input_zero.tap_key_sig = Some(sig);
-        }
-        for tlh in input_zero
-            .tap_scripts
-            .values()
-            .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
-        {
-            let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked);
-            input_zero.tap_script_sigs.insert((pk.0.clone(), tlh), sig);
+        for (idx, input) in b.inputs.iter_mut().enumerate() {
+            if input.tap_internal_key.is_none() && input.tap_scripts.is_empty() {
+                continue;
+            }
+            let h = tx.get_ctv_hash(idx as u32);
+            let key = self
+                .derive(h, secp)
+                .map_err(|_| input_err("Could Not Derive Key"))?;
+            let untweaked = key.to_keypair(secp);
+            let pk = XOnlyPublicKey::from_keypair(&untweaked).0;
+            use bitcoin::schnorr::TapTweak;
+            let tweaked = untweaked
+                .tap_tweak(secp, input.tap_merkle_root)
+                .into_inner();
+            let tweaked_pk = tweaked.public_key();
+            let mut get_sig = |path, kp| {
+                let annex = None;
+                let sighash: TapSighashHash = sighash
+                    .taproot_signature_hash(idx, prevouts, annex, path, hash_ty)
+                    .expect("Signature hash cannot fail...");
+                let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..])
+                    .expect("Size must be correct.");
+                let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+                SchnorrSig { sig, hash_ty }
+            };
+            if let Some(true) = input.witness_utxo.as_ref().map(|v| {
+                v.script_pubkey
+                    == Script::new_v1_p2tr_tweaked(
+                        XOnlyPublicKey::from(tweaked_pk).dangerous_assume_tweaked(),
+                    )
+            }) {
+                let sig = get_sig(None, &tweaked);
+                input.tap_key_sig = Some(sig);
+            }
+            let pk_bytes = pk.serialize();
+            let leaves: Vec<TapLeafHash> = input
+                .tap_scripts
+                .values()
+                .filter(|(script, _)| {
+                    script
+                        .as_bytes()
+                        .windows(pk_bytes.len())
+                        .any(|w| w == &pk_bytes[..])
+                })
+                .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
+                .collect();
+            for tlh in leaves {
+                let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked);
+                input.tap_script_sigs.insert((pk.clone(), tlh), sig);
+            }
         }
         Ok(b)
     }
 
     /// the main server business logic.
     ///
-    /// - on receiving Request::SignPSBT, signs the PSBT.
-    async fn handle(&self, t: &mut TcpStream) -> Result<(), std::io::Error> {
-        let request = Self::requested(t).await?;
+    /// - on receiving Request::SignPSBT, signs the PSBT, unless `client` or
+    ///   `peer`'s address is over its rate limit.
+    ///
+    /// Every request is logged to the `sapio_emulator::audit` tracing target.
+    async fn handle(
+        &self,
+        t: &mut SecureChannel,
+        peer: SocketAddr,
+        client: XOnlyPublicKey,
+    ) -> Result<(), std::io::Error> {
+        let request: msgs::Request = t.recv().await?;
         match request {
             msgs::Request::SignPSBT(msgs::PSBT(unsigned)) => {
-                let psbt = SECP.with(|secp| self.sign(unsigned, secp))?;
-                Self::respond(t, &msgs::PSBT(psbt)).await
+                let txid = unsigned.unsigned_tx.txid();
+                let inputs = unsigned.inputs.len();
+                if !self.rate_limit(&[RateKey::Client(client), RateKey::Peer(peer.ip())]) {
+                    tracing::warn!(target: "sapio_emulator::audit", %peer, %client, %txid, inputs, "rate limited");
+                    return t
+                        .send(&msgs::Response::Refused("Rate Limited".into()))
+                        .await;
+                }
+                match SECP.with(|secp| self.sign(unsigned, secp)) {
+                    Ok(psbt) => {
+                        tracing::info!(target: "sapio_emulator::audit", %peer, %client, %txid, inputs, "signed");
+                        t.send(&msgs::Response::Signed(msgs::PSBT(psbt))).await
+                    }
+                    Err(e) => {
+                        tracing::warn!(target: "sapio_emulator::audit", %peer, %client, %txid, inputs, error = %e, "failed to sign");
+                        t.send(&msgs::Response::Refused(e.to_string())).await
+                    }
+                }
             }
         }
     }
+}
 
-    /// receive a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: DoS Critical: limit the allowed max length we will attempt to derserialize
-    async fn requested(t: &mut TcpStream) -> Result<msgs::Request, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        Ok(serde_json::from_slice(&v[..])?)
-    }
-
-    /// respond via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn respond<T: Serialize>(t: &mut TcpStream, r: &T) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await?;
-        t.flush().await
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connections::federated::FederationMember;
+    use crate::connections::hd::HDOracleEmulatorConnection;
+    use bitcoin::secp256k1::Keypair;
+    use bitcoin::Transaction;
+    use miniscript::DescriptorTrait;
+    use sapio::declare;
+
+    fn key(b: u8) -> Keypair {
+        SECP.with(|secp| Keypair::from_seckey_slice(secp, &[b; 32]).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refused_client() {
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[7u8; 32]).unwrap();
+        let allowed = key(1);
+        let clients =
+            ClientPolicy::Allow(vec![allowed.x_only_public_key().0].into_iter().collect());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(HDOracleEmulator::new(root, false, clients).serve(listener));
+        let secp = Arc::new(Secp256k1::new());
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap();
+        for (client, refused) in [(allowed, false), (key(2), true)] {
+            let conn = HDOracleEmulatorConnection::new(
+                addr,
+                ExtendedPubKey::from_priv(&secp, &root),
+                client,
+                None,
+                secp.clone(),
+            )
+            .await
+            .unwrap();
+            let r = conn.sign_async(psbt.clone()).await;
+            assert_eq!(
+                matches!(r, Err(EmulatorError::Refused(ref why)) if why == "Client Not Allowed"),
+                refused
+            );
+            assert_eq!(r.is_ok(), !refused);
+        }
+        server.abort();
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[7u8; 32]).unwrap();
+        let oracle =
+            HDOracleEmulator::new(root, false, ClientPolicy::Any).with_rate_limit(RateLimit {
+                burst: 2,
+                per_second: 0.001,
+            });
+        let peer = RateKey::Peer([127, 0, 0, 1].into());
+        let a = RateKey::Client(key(1).x_only_public_key().0);
+        let b = RateKey::Client(key(2).x_only_public_key().0);
+        assert!(oracle.rate_limit(&[a, peer]));
+        // a new key from the same address shares its bucket
+        assert!(oracle.rate_limit(&[b, peer]));
+        assert!(!oracle.rate_limit(&[b, peer]));
+        // but not from another address
+        assert!(oracle.rate_limit(&[b, RateKey::Peer([127, 0, 0, 2].into())]));
+        assert_eq!(oracle.buckets.lock().unwrap().buckets.len(), 4);
+
+        // buckets which have refilled are forgotten
+        let oracle = oracle.with_rate_limit(RateLimit {
+            burst: 2,
+            per_second: 1e9,
+        });
+        std::thread::sleep(Duration::from_millis(1));
+        oracle.buckets.lock().unwrap().swept = Instant::now() - BUCKET_SWEEP;
+        assert!(oracle.rate_limit(&[a]));
+        assert_eq!(oracle.buckets.lock().unwrap().buckets.len(), 1);
+    }
+
+    /// a contract whose coin is spent at input 1 of its template
+    struct Batched;
+    impl Batched {
+        #[sapio::then]
+        fn batch(self, ctx: sapio::Context) {
+            let to = Script::new_v1_p2tr(&Secp256k1::new(), key(3).x_only_public_key().0, None);
+            let to = sapio::contract::Compiled::from_address(
+                bitcoin::Address::from_script(&to, bitcoin::Network::Regtest).unwrap(),
+                None,
+            );
+            let amount = ctx.funds();
+            ctx.template()
+                .add_sequence()
+                .set_ctv_index(1)?
+                .add_output(amount, &to, None)?
+                .into()
+        }
+    }
+    impl sapio::contract::Contract for Batched {
+        declare! {then, Self::batch}
+        declare! {non updatable}
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sign_ctv_index() {
+        use sapio::contract::abi::studio::SapioStudioFormat;
+        use sapio::contract::object::SupportedDescriptors;
+        use sapio::contract::{Compilable, Context};
+        use sapio_base::effects::{EffectPath, MapEffectDB};
+        use sapio_base::txindex::{TxIndex, TxIndexLogger};
+        use std::convert::TryFrom;
+        use std::rc::Rc;
+
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[7u8; 32]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            tokio::spawn(HDOracleEmulator::new(root, false, ClientPolicy::Any).serve(listener));
+        let secp = Arc::new(Secp256k1::new());
+        let conn = Arc::new(
+            HDOracleEmulatorConnection::new(
+                addr,
+                ExtendedPubKey::from_priv(&secp, &root),
+                key(1),
+                None,
+                secp.clone(),
+            )
+            .await
+            .unwrap(),
+        );
+        let amount = bitcoin::Amount::from_sat(100_000);
+        let compiled = Batched
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                amount,
+                conn.clone(),
+                EffectPath::try_from("batched").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            ))
+            .unwrap();
+        let (ctv, tmpl) = compiled.ctv_to_tx.iter().next().unwrap();
+        assert_eq!(tmpl.ctv_index, 1);
+
+        // input 0 is someone else's coin, batched with ours at input 1
+        let script = match &compiled.descriptor {
+            Some(SupportedDescriptors::XOnly(d)) => d.script_pubkey(),
+            _ => panic!("Not a Taproot Descriptor"),
+        };
+        let theirs = Script::new_v1_p2tr(&secp, key(2).x_only_public_key().0, None);
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 5_000,
+                    script_pubkey: theirs,
+                },
+                TxOut {
+                    value: amount.as_sat(),
+                    script_pubkey: script,
+                },
+            ],
+        };
+        let index = Rc::new(TxIndexLogger::new());
+        let txid = index.add_tx(Arc::new(funding)).unwrap();
+        let ours = bitcoin::OutPoint { txid, vout: 1 };
+        let other = bitcoin::OutPoint { txid, vout: 0 };
+        let program = tokio::task::block_in_place(|| {
+            compiled.bind_psbt(
+                ours,
+                vec![(*ctv, vec![Some(other), None])].into_iter().collect(),
+                index.clone(),
+                &*conn,
+            )
+        })
+        .unwrap();
+
+        let txs: Vec<_> = program.program.values().flat_map(|o| &o.txs).collect();
+        assert_eq!(txs.len(), 1);
+        let psbt: PartiallySignedTransaction = match txs[0] {
+            SapioStudioFormat::LinkedPSBT { psbt, .. } => {
+                bitcoin::consensus::deserialize(&base64::decode(psbt).unwrap()[..]).unwrap()
+            }
+            _ => panic!("Not a PSBT"),
+        };
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input[0].previous_output, other);
+        assert_eq!(tx.input[1].previous_output, ours);
+        assert_eq!(tx.get_ctv_hash(1), *ctv);
+
+        // the input which is not the contract's is left alone
+        let theirs = &psbt.inputs[0];
+        assert!(theirs.tap_key_sig.is_none() && theirs.tap_script_sigs.is_empty());
+        assert!(theirs.tap_scripts.is_empty());
+
+        // the contract's input is signed with the key for its template
+        let signer = ExtendedPubKey::from_priv(&secp, &root)
+            .derive_pub(&secp, &hash_to_child_vec(*ctv))
+            .unwrap()
+            .to_x_only_pub();
+        let input = &psbt.inputs[1];
+        assert_eq!(input.tap_script_sigs.len(), 1);
+        let ((pk, leaf), sig) = input.tap_script_sigs.iter().next().unwrap();
+        assert_eq!(*pk, signer);
+        let utxos: Vec<TxOut> = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.clone().unwrap())
+            .collect();
+        let sighash = bitcoin::util::sighash::SighashCache::new(tx)
+            .taproot_script_spend_signature_hash(1, &Prevouts::All(&utxos), *leaf, sig.hash_ty)
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..]).unwrap();
+        secp.verify_schnorr(&sig.sig, &msg, pk).unwrap();
+        server.abort();
     }
 }
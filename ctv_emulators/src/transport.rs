@@ -0,0 +1,553 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An authenticated, encrypted channel between emulator clients and servers.
+//!
+//! The channel is the Lightning Network's BOLT 8 transport,
+//! `Noise_XK_secp256k1_ChaChaPoly_SHA256`, with our own prologue:
+//!
+//! - the client (initiator) already knows the server's static key, the
+//!   public key of its root xpub, from its config;
+//! - the server learns the client's static key in the third act, and whether
+//!   that key may use the server is up to the caller;
+//! - transport messages are ChaCha20-Poly1305 (RFC 8439) under a per
+//!   direction nonce, with keys rotated every 1000 nonces, so messages cannot
+//!   be tampered with, reordered, replayed, or reflected.
+//!
+//! BOLT 8 messages carry at most 65535 bytes, so larger messages are sent as
+//! a run of full messages ended by one which is not full.
+use super::*;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, HashEngine};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::{Keypair, PublicKey, SecretKey, XOnlyPublicKey};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_XK_secp256k1_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"sapio/emulator/v3";
+const CLIENT_TAG: &[u8] = b"sapio/emulator/v3/client";
+/// the only handshake version defined by BOLT 8
+const VERSION: u8 = 0;
+/// a cipher's key is rotated after this many uses
+const KEY_ROTATION: u64 = 1000;
+/// the most a single BOLT 8 message may carry
+const MAX_CHUNK: usize = u16::MAX as usize;
+/// a PSBT of `MAX_MSG` bytes, as JSON, fits in a message
+const MAX_FRAME: usize = 5 * MAX_MSG;
+const TAG_LEN: usize = 16;
+const ACT_ONE_LEN: usize = 50;
+const ACT_THREE_LEN: usize = 66;
+
+/// The key a client authenticates with, derived from the contents of a seed
+/// file.
+pub fn client_key_from_seed(seed: &[u8]) -> Result<Keypair, std::io::Error> {
+    let sk = SecretKey::from_slice(&sha256::Hash::hash(&[CLIENT_TAG, seed].concat())[..])
+        .map_err(|_| input_err("Seed Does Not Make a Valid Key"))?;
+    Ok(SECP.with(|secp| Keypair::from_secret_key(secp, &sk)))
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut e = HmacEngine::<sha256::Hash>::new(key);
+    for p in parts {
+        e.input(p);
+    }
+    Hmac::<sha256::Hash>::from_engine(e).into_inner()
+}
+
+/// HKDF (RFC 5869) with SHA256, for two outputs
+fn hkdf(salt: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let prk = hmac(salt, &[ikm]);
+    let t1 = hmac(&prk, &[&[1]]);
+    let t2 = hmac(&prk, &[&t1, &[2]]);
+    (t1, t2)
+}
+
+/// BOLT 8's ECDH, the SHA256 of the compressed shared point
+fn ecdh(point: &PublicKey, scalar: &SecretKey) -> [u8; 32] {
+    SharedSecret::new(point, scalar).secret_bytes()
+}
+
+fn public(sk: &SecretKey) -> PublicKey {
+    SECP.with(|secp| PublicKey::from_secret_key(secp, sk))
+}
+
+/// Noise's ChaCha20-Poly1305 nonce: 32 zero bits and then `n` little endian
+fn nonce(n: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&n.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20-Poly1305 encryption, returning the ciphertext and tag
+fn encrypt_with_ad(key: &[u8; 32], n: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            &nonce(n),
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .expect("Messages Are Never Too Large to Encrypt")
+}
+
+/// ChaCha20-Poly1305 decryption of a ciphertext and tag, failing if either
+/// was tampered with
+fn decrypt_with_ad(
+    key: &[u8; 32],
+    n: u64,
+    ad: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, std::io::Error> {
+    if data.len() < TAG_LEN {
+        return input_error("Message Too Short");
+    }
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(&nonce(n), Payload { msg: data, aad: ad })
+        .map_err(|_| input_err("Bad Message Authentication"))
+}
+
+/// one direction of an established channel
+struct CipherState {
+    k: [u8; 32],
+    n: u64,
+    ck: [u8; 32],
+}
+
+impl CipherState {
+    fn next(&mut self) {
+        self.n += 1;
+        if self.n == KEY_ROTATION {
+            let (ck, k) = hkdf(&self.ck, &self.k);
+            self.ck = ck;
+            self.k = k;
+            self.n = 0;
+        }
+    }
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let c = encrypt_with_ad(&self.k, self.n, &[], plaintext);
+        self.next();
+        c
+    }
+    fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let p = decrypt_with_ad(&self.k, self.n, &[], data)?;
+        self.next();
+        Ok(p)
+    }
+    /// a BOLT 8 message: its encrypted length and then its encrypted body
+    fn seal(&mut self, m: &[u8]) -> Vec<u8> {
+        let mut out = self.encrypt(&(m.len() as u16).to_be_bytes());
+        out.extend(self.encrypt(m));
+        out
+    }
+}
+
+/// The state of a BOLT 8 handshake, for either side
+struct Handshake {
+    ck: [u8; 32],
+    h: [u8; 32],
+    temp_k: [u8; 32],
+    /// our static key
+    s: SecretKey,
+    /// our ephemeral key
+    e: SecretKey,
+}
+
+impl Handshake {
+    /// starts a handshake with the responder's static key `responder`
+    fn new(responder: &PublicKey, s: SecretKey, e: SecretKey, prologue: &[u8]) -> Self {
+        let h = sha256::Hash::hash(PROTOCOL_NAME).into_inner();
+        let mut hs = Handshake {
+            ck: h,
+            h,
+            temp_k: [0; 32],
+            s,
+            e,
+        };
+        hs.mix_hash(prologue);
+        hs.mix_hash(&responder.serialize());
+        hs
+    }
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = sha256::Hash::hash(&[&self.h[..], data].concat()).into_inner();
+    }
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, k) = hkdf(&self.ck, ikm);
+        self.ck = ck;
+        self.temp_k = k;
+    }
+    fn encrypt(&mut self, n: u64, plaintext: &[u8]) -> Vec<u8> {
+        let c = encrypt_with_ad(&self.temp_k, n, &self.h, plaintext);
+        self.mix_hash(&c);
+        c
+    }
+    fn decrypt(&mut self, n: u64, c: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let p = decrypt_with_ad(&self.temp_k, n, &self.h, c)?;
+        self.mix_hash(c);
+        Ok(p)
+    }
+    /// the cipher states for (sending, receiving)
+    fn split(&self, initiator: bool) -> (CipherState, CipherState) {
+        let (k1, k2) = hkdf(&self.ck, &[]);
+        let (sk, rk) = if initiator { (k1, k2) } else { (k2, k1) };
+        let cipher = |k| CipherState {
+            k,
+            n: 0,
+            ck: self.ck,
+        };
+        (cipher(sk), cipher(rk))
+    }
+    /// Act One or Two: our ephemeral key, and a tag proving we derived the
+    /// same key from its ECDH with `theirs`
+    fn write_act(&mut self, theirs: &PublicKey) -> [u8; ACT_ONE_LEN] {
+        let e = public(&self.e).serialize();
+        self.mix_hash(&e);
+        self.mix_key(&ecdh(theirs, &self.e));
+        let c = self.encrypt(0, &[]);
+        let mut m = [0u8; ACT_ONE_LEN];
+        m[0] = VERSION;
+        m[1..34].copy_from_slice(&e);
+        m[34..].copy_from_slice(&c);
+        m
+    }
+    /// reads Act One or Two, returning the other side's ephemeral key.
+    /// `ours` is the key of ours it is combined with.
+    fn read_act(
+        &mut self,
+        m: &[u8; ACT_ONE_LEN],
+        ours: &SecretKey,
+    ) -> Result<PublicKey, std::io::Error> {
+        if m[0] != VERSION {
+            return input_error("Bad Handshake Version");
+        }
+        let re = PublicKey::from_slice(&m[1..34]).map_err(|_| input_err("Bad Ephemeral Key"))?;
+        self.mix_hash(&m[1..34]);
+        self.mix_key(&ecdh(&re, ours));
+        self.decrypt(0, &m[34..])
+            .map_err(|_| input_err("Handshake Failed"))?;
+        Ok(re)
+    }
+    /// Act One, by the initiator
+    fn act_one(&mut self, responder: &PublicKey) -> [u8; ACT_ONE_LEN] {
+        self.write_act(responder)
+    }
+    /// reads Act One, by the responder
+    fn read_act_one(&mut self, m: &[u8; ACT_ONE_LEN]) -> Result<PublicKey, std::io::Error> {
+        let s = self.s;
+        self.read_act(m, &s)
+    }
+    /// Act Two, by the responder
+    fn act_two(&mut self, re: &PublicKey) -> [u8; ACT_ONE_LEN] {
+        self.write_act(re)
+    }
+    /// reads Act Two, by the initiator
+    fn read_act_two(&mut self, m: &[u8; ACT_ONE_LEN]) -> Result<PublicKey, std::io::Error> {
+        let e = self.e;
+        self.read_act(m, &e)
+    }
+    /// Act Three, by the initiator: our static key, encrypted, and the
+    /// cipher states for the channel
+    fn act_three(mut self, re: &PublicKey) -> ([u8; ACT_THREE_LEN], CipherState, CipherState) {
+        let c = self.encrypt(1, &public(&self.s).serialize());
+        self.mix_key(&ecdh(re, &self.s));
+        let t = self.encrypt(0, &[]);
+        let mut m = [0u8; ACT_THREE_LEN];
+        m[0] = VERSION;
+        m[1..50].copy_from_slice(&c);
+        m[50..].copy_from_slice(&t);
+        let (send, recv) = self.split(true);
+        (m, send, recv)
+    }
+    /// reads Act Three, by the responder, returning the initiator's static
+    /// key and the cipher states for the channel
+    fn read_act_three(
+        mut self,
+        m: &[u8; ACT_THREE_LEN],
+    ) -> Result<(PublicKey, CipherState, CipherState), std::io::Error> {
+        if m[0] != VERSION {
+            return input_error("Bad Handshake Version");
+        }
+        let rs = self
+            .decrypt(1, &m[1..50])
+            .map_err(|_| input_err("Handshake Failed"))?;
+        let rs = PublicKey::from_slice(&rs).map_err(|_| input_err("Bad Client Key"))?;
+        self.mix_key(&ecdh(&rs, &self.e));
+        self.decrypt(0, &m[50..])
+            .map_err(|_| input_err("Handshake Failed"))?;
+        let (send, recv) = self.split(false);
+        Ok((rs, send, recv))
+    }
+}
+
+/// An established channel over a TcpStream
+pub struct SecureChannel {
+    stream: TcpStream,
+    send: CipherState,
+    recv: CipherState,
+}
+
+impl SecureChannel {
+    /// Connects to a server, checking it holds `server`'s key and
+    /// authenticating as `client`.
+    pub async fn connect(
+        mut stream: TcpStream,
+        server: PublicKey,
+        client: &Keypair,
+    ) -> Result<Self, std::io::Error> {
+        let e = SecretKey::new(&mut bitcoin::secp256k1::rand::thread_rng());
+        let mut hs = Handshake::new(&server, client.secret_key(), e, PROLOGUE);
+        stream.write_all(&hs.act_one(&server)).await?;
+        stream.flush().await?;
+        let mut act_two = [0u8; ACT_ONE_LEN];
+        stream.read_exact(&mut act_two).await?;
+        let re = hs
+            .read_act_two(&act_two)
+            .map_err(|_| input_err("Server Did Not Authenticate"))?;
+        let (act_three, send, recv) = hs.act_three(&re);
+        stream.write_all(&act_three).await?;
+        stream.flush().await?;
+        Ok(SecureChannel { stream, send, recv })
+    }
+
+    /// Accepts a client, proving we hold `server`, and returns the channel
+    /// and the key the client authenticated as. Whether that key may use the
+    /// server is up to the caller.
+    pub async fn accept(
+        mut stream: TcpStream,
+        server: &Keypair,
+    ) -> Result<(Self, XOnlyPublicKey), std::io::Error> {
+        let e = SecretKey::new(&mut bitcoin::secp256k1::rand::thread_rng());
+        let mut hs = Handshake::new(&server.public_key(), server.secret_key(), e, PROLOGUE);
+        let mut act_one = [0u8; ACT_ONE_LEN];
+        stream.read_exact(&mut act_one).await?;
+        let re = hs.read_act_one(&act_one)?;
+        stream.write_all(&hs.act_two(&re)).await?;
+        stream.flush().await?;
+        let mut act_three = [0u8; ACT_THREE_LEN];
+        stream.read_exact(&mut act_three).await?;
+        let (client, send, recv) = hs
+            .read_act_three(&act_three)
+            .map_err(|_| input_err("Client Did Not Authenticate"))?;
+        Ok((
+            SecureChannel { stream, send, recv },
+            client.x_only_public_key().0,
+        ))
+    }
+
+    async fn send_bytes(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut out = vec![];
+        let mut chunks = data.chunks(MAX_CHUNK);
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            out.extend(self.send.seal(chunk));
+            // a message ends with one that is not full, empty if need be
+            if chunk.len() < MAX_CHUNK {
+                break;
+            }
+        }
+        self.stream.write_all(&out).await?;
+        self.stream.flush().await
+    }
+
+    /// receives a message of at most `max` bytes
+    async fn recv_bytes(&mut self, max: usize) -> Result<Vec<u8>, std::io::Error> {
+        let mut data = vec![];
+        loop {
+            let mut lc = [0u8; 2 + TAG_LEN];
+            self.stream.read_exact(&mut lc).await?;
+            let l = self.recv.decrypt(&lc)?;
+            let l = u16::from_be_bytes([l[0], l[1]]) as usize;
+            if data.len() + l > max {
+                return input_error("Message Too Long");
+            }
+            let mut c = vec![0u8; l + TAG_LEN];
+            self.stream.read_exact(&mut c).await?;
+            data.extend(self.recv.decrypt(&c)?);
+            if l < MAX_CHUNK {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// send a message as JSON
+    pub async fn send<T: Serialize>(&mut self, t: &T) -> Result<(), std::io::Error> {
+        self.send_bytes(&serde_json::to_vec(t)?).await
+    }
+
+    /// receive a JSON message of at most `MAX_FRAME` bytes
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<T, std::io::Error> {
+        let v = self.recv_bytes(MAX_FRAME).await?;
+        Ok(serde_json::from_slice(&v)?)
+    }
+
+    /// the address of the other end
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+    fn sk(b: u8) -> SecretKey {
+        SecretKey::from_slice(&[b; 32]).unwrap()
+    }
+
+    /// BOLT 8's test vectors, with its "lightning" prologue
+    #[test]
+    fn test_bolt8_vectors() {
+        let rs = public(&sk(0x21));
+        let mut initiator = Handshake::new(&rs, sk(0x11), sk(0x12), b"lightning");
+        let mut responder = Handshake::new(&rs, sk(0x21), sk(0x22), b"lightning");
+        let act_one = initiator.act_one(&rs);
+        assert_eq!(act_one[..], unhex("00036360e856310ce5d294e8be33fc807077dc56ac80d95d9cd4ddbd21325eff73f70df6086551151f58b8afe6c195782c6a")[..]);
+        let re = responder.read_act_one(&act_one).unwrap();
+        let act_two = responder.act_two(&re);
+        assert_eq!(act_two[..], unhex("0002466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f276e2470b93aac583c9ef6eafca3f730ae")[..]);
+        let re = initiator.read_act_two(&act_two).unwrap();
+        let (act_three, mut send, mut recv) = initiator.act_three(&re);
+        assert_eq!(act_three[..], unhex("00b9e3a702e93e3a9948c2ed6e5fd7590a6e1c3a0344cfc9d5b57357049aa22355361aa02e55a8fc28fef5bd6d71ad0c38228dc68b1c466263b47fdf31e560e139ba")[..]);
+        assert_eq!(
+            send.k[..],
+            unhex("969ab31b4d288cedf6218839b27a3e2140827047f2c0f01bf5c04435d43511a9")[..]
+        );
+        assert_eq!(
+            recv.k[..],
+            unhex("bb9020b8965f4df047e07f955f3c4b88418984aadc5cdb35096b9ea8fa5c3442")[..]
+        );
+        let (client, mut r_send, mut r_recv) = responder.read_act_three(&act_three).unwrap();
+        assert_eq!(client, public(&sk(0x11)));
+        assert_eq!(r_send.k, recv.k);
+        assert_eq!(r_recv.k, send.k);
+
+        let expected = [
+            (
+                0,
+                "cf2b30ddf0cf3f80e7c35a6e6730b59fe802473180f396d88a8fb0db8cbcf25d2f214cf9ea1d95",
+            ),
+            (
+                1,
+                "72887022101f0b6753e0c7de21657d35a4cb2a1f5cde2650528bbc8f837d0f0d7ad833b1a256a1",
+            ),
+            (
+                500,
+                "178cb9d7387190fa34db9c2d50027d21793c9bc2d40b1e14dcf30ebeeeb220f48364f7a4c68bf8",
+            ),
+            (
+                501,
+                "1b186c57d44eb6de4c057c49940d79bb838a145cb528d6e8fd26dbe50a60ca2c104b56b60e45bd",
+            ),
+            (
+                1000,
+                "4a2f3cc3b5e78ddb83dcb426d9863d9d9a723b0337c89dd0b005d89f8d3c05c52b76b29b740f09",
+            ),
+            (
+                1001,
+                "2ecd8c8a5629d0d02ab457a0fdd0f7b90a192cd46be5ecb6ca570bfc5e268338b1a16cf4ef2d36",
+            ),
+        ];
+        let mut expected = expected.iter().peekable();
+        for i in 0..1002 {
+            let m = send.seal(b"hello");
+            if let Some((_, hex)) = expected.next_if(|(j, _)| *j == i) {
+                assert_eq!(m[..], unhex(hex)[..], "message {}", i);
+            }
+            assert_eq!(r_recv.decrypt(&m[..18]).unwrap(), vec![0, 5]);
+            assert_eq!(r_recv.decrypt(&m[18..]).unwrap(), b"hello");
+        }
+        // and the other way
+        let m = r_send.seal(b"hi");
+        assert_eq!(recv.decrypt(&m[..18]).unwrap(), vec![0, 2]);
+        assert_eq!(recv.decrypt(&m[18..]).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn test_handshake_fails() {
+        let server = public(&sk(0x21));
+        // a client expecting some other server
+        let mut initiator = Handshake::new(&public(&sk(0x31)), sk(0x11), sk(0x12), PROLOGUE);
+        let mut responder = Handshake::new(&server, sk(0x21), sk(0x22), PROLOGUE);
+        assert!(responder
+            .read_act_one(&initiator.act_one(&public(&sk(0x31))))
+            .is_err());
+        // a tampered act three
+        let mut initiator = Handshake::new(&server, sk(0x11), sk(0x12), PROLOGUE);
+        let mut responder = Handshake::new(&server, sk(0x21), sk(0x22), PROLOGUE);
+        let re = responder.read_act_one(&initiator.act_one(&server)).unwrap();
+        let re = initiator.read_act_two(&responder.act_two(&re)).unwrap();
+        let (mut act_three, _, _) = initiator.act_three(&re);
+        act_three[10] ^= 1;
+        assert!(responder.read_act_three(&act_three).is_err());
+    }
+
+    async fn pair() -> (SecureChannel, SecureChannel, XOnlyPublicKey) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = SECP.with(|secp| Keypair::from_secret_key(secp, &sk(0x21)));
+        let client = SECP.with(|secp| Keypair::from_secret_key(secp, &sk(0x11)));
+        let accept = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            SecureChannel::accept(s, &server).await.unwrap()
+        });
+        let c = SecureChannel::connect(
+            TcpStream::connect(addr).await.unwrap(),
+            server.public_key(),
+            &client,
+        )
+        .await
+        .unwrap();
+        let (s, key) = accept.await.unwrap();
+        assert_eq!(key, client.x_only_public_key().0);
+        (c, s, key)
+    }
+
+    #[tokio::test]
+    async fn test_channel() {
+        let (mut c, mut s, _) = pair().await;
+        c.send(&"hello").await.unwrap();
+        assert_eq!(s.recv::<String>().await.unwrap(), "hello");
+        // larger than one BOLT 8 message, and exactly a multiple of one
+        for len in [3 * MAX_CHUNK + 7, 2 * MAX_CHUNK] {
+            let big = vec![7u8; len];
+            s.send_bytes(&big).await.unwrap();
+            assert_eq!(c.recv_bytes(MAX_FRAME).await.unwrap(), big);
+        }
+        s.send_bytes(&vec![0u8; 100]).await.unwrap();
+        assert!(c.recv_bytes(99).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tampered() {
+        let (c, mut s, _) = pair().await;
+        let mut send = c.send;
+        let mut m = send.seal(b"hello");
+        m[20] ^= 1;
+        let mut stream = c.stream;
+        stream.write_all(&m).await.unwrap();
+        assert!(s.recv_bytes(MAX_FRAME).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let (c, mut s, _) = pair().await;
+        let mut send = c.send;
+        let m = send.seal(b"hello");
+        let mut stream = c.stream;
+        stream.write_all(&m).await.unwrap();
+        assert_eq!(s.recv_bytes(MAX_FRAME).await.unwrap(), b"hello");
+        // the same message again is under a used nonce
+        stream.write_all(&m).await.unwrap();
+        assert!(s.recv_bytes(MAX_FRAME).await.is_err());
+    }
+}
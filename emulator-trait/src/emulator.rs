@@ -18,6 +18,35 @@ pub enum EmulatorError {
     NetworkIssue(std::io::Error),
     /// Error was caused by BIP32
     BIP32Error(bitcoin::util::bip32::Error),
+    /// The emulator would not sign, e.g. because the client is not allowed
+    /// to use it or is making too many requests
+    Refused(String),
+    /// The emulator did not respond within the given time
+    Timeout(std::time::Duration),
+    /// Fewer than `threshold` members of a federation signed
+    FederationFailed {
+        /// how many signatures were needed
+        threshold: usize,
+        /// the indexes of the members which signed
+        signed: Vec<usize>,
+        /// the indexes of the members which failed, and why. Members which
+        /// were not waited for, once the threshold was out of reach, are in
+        /// neither list.
+        failed: Vec<(usize, EmulatorError)>,
+    },
+    /// A federation was configured without any members
+    NoEmulators,
+    /// A federation was configured with a threshold of zero, which any
+    /// spender could satisfy
+    ZeroThreshold,
+    /// A federation was configured with a threshold larger than its number
+    /// of members
+    ThresholdTooHigh {
+        /// the configured threshold
+        threshold: usize,
+        /// the number of members
+        members: usize,
+    },
 }
 impl fmt::Display for EmulatorError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -10,7 +10,8 @@ use bitcoin::util::bip32::*;
 use bitcoin::Script;
 use bitcoin::TxOut;
 use emulator_connect::connections::hd::HDOracleEmulatorConnection;
-use emulator_connect::servers::hd::HDOracleEmulator;
+use emulator_connect::servers::hd::{ClientPolicy, HDOracleEmulator};
+use emulator_connect::transport::client_key_from_seed;
 use emulator_connect::*;
 use sapio::contract::*;
 use sapio::*;
@@ -54,12 +55,14 @@ fn test_connect() {
         ExtendedPrivKey::new_master(bitcoin::network::constants::Network::Regtest, &[44u8; 32])
             .unwrap();
     let pk_root = ExtendedPubKey::from_private(&secp, &root);
+    let client_key = client_key_from_seed(&[7u8; 32]).unwrap();
+    let clients = ClientPolicy::Allow(vec![client_key.x_only_public_key().0].into_iter().collect());
     let rt1 = Arc::new(tokio::runtime::Runtime::new().unwrap());
     let (shutdown, quit) = tokio::sync::oneshot::channel();
     {
         let rt = rt1.clone();
         std::thread::spawn(move || {
-            let oracle = HDOracleEmulator::new(root, true);
+            let oracle = HDOracleEmulator::new(root, true, clients);
             rt.block_on(async {
                 let server = tokio::spawn(oracle.bind("127.0.0.1:8080"));
                 quit.await.unwrap();
@@ -89,6 +92,7 @@ fn test_connect() {
         HDOracleEmulatorConnection::new(
             "127.0.0.1:8080",
             pk_root,
+            client_key,
             rt2.clone(),
             Arc::new(Secp256k1::new()),
         )
@@ -10,12 +10,17 @@ use super::*;
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 /// # Lookup Parameters
 /// - either using a hash key (exact); or
-/// - name (user configured)
+/// - name (user configured); or
+/// - name and semver requirement (from the host's plugin registry)
 pub enum LookupFrom {
     /// # Provide the Hex Encoded Hash of the WASM Module
     HashKey(String),
     /// # Give a Configurable Name
     Name(String),
+    /// # Give a Name and Version Requirement
+    /// e.g., `NameVersion("vault", "^1.2")` picks the newest registered
+    /// vault plugin compatible with 1.2
+    NameVersion(String, String),
     /// # Get the currently executing module hash
     This,
 }
@@ -28,6 +33,7 @@ impl LookupFrom {
                 Some(r)
             }
             LookupFrom::Name(name) => lookup_module_name(name),
+            LookupFrom::NameVersion(name, req) => lookup_module_version(name, req),
             LookupFrom::This => lookup_this_module_name(),
         }
     }
@@ -7,6 +7,7 @@
 ///! Various utils for working with modules
 use super::*;
 
+use crate::log::LogRecord;
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 
@@ -17,6 +18,15 @@ pub fn log(s: &str) {
     }
 }
 
+/// Send a structured record to the host's log. See `log_info!` and friends.
+pub fn log_record(r: &LogRecord) {
+    if let Ok(s) = serde_json::to_string(r) {
+        unsafe {
+            sapio_v1_wasm_plugin_log(s.as_ptr() as i32, s.len() as i32);
+        }
+    }
+}
+
 /// Given a 32 byte plugin identifier, create a new contract instance.
 pub fn call_path<S: Serialize, T>(
     path: &EffectPath,
@@ -125,6 +135,28 @@ pub fn lookup_module_name(key: &str) -> Option<[u8; 32]> {
     }
 }
 
+/// lookup a plugin module's key given a name and a semver requirement, e.g.
+/// "^1.2". The host picks the newest compatible version it has registered.
+pub fn lookup_module_version(name: &str, req: &str) -> Option<[u8; 32]> {
+    let mut res = [0u8; 32];
+    let mut ok = 0u8;
+    unsafe {
+        sapio_v1_wasm_plugin_lookup_module_version(
+            name.as_ptr() as i32,
+            name.len() as i32,
+            req.as_ptr() as i32,
+            req.len() as i32,
+            &mut res as *mut [u8; 32] as i32,
+            &mut ok as *mut u8 as i32,
+        )
+    };
+    if ok == 0 {
+        None
+    } else {
+        Some(res)
+    }
+}
+
 /// Get the current executing module's hash
 pub fn lookup_this_module_name() -> Option<[u8; 32]> {
     let mut res = [0u8; 32];
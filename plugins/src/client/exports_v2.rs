@@ -0,0 +1,196 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The v2 ABI exports: the `plugin` interface of `wit/sapio-plugin.wit`,
+//! lowered with the canonical ABI.
+//!
+//! Arguments are (pointer, length) pairs allocated by the host with
+//! `cabi_realloc`, which the plugin takes ownership of. Results are written
+//! to a static return area, and freed when the host calls the matching
+//! `cabi_post_` function.
+use super::*;
+use sapio::contract::CompilationError;
+use std::alloc::{alloc, dealloc, realloc, Layout};
+
+/// a stub to make the compiler happy
+fn sapio_v2_wasm_plugin_client_create_nullptr(
+    _p: &str,
+    _c: CreateArgs<serde_json::Value>,
+) -> Result<serde_json::Value, CompilationError> {
+    panic!("No Function Registered");
+}
+
+/// a static mut that gets set when a Plugin::register method gets called
+/// in order to enable binding when the type is registered
+pub(crate) static mut SAPIO_V2_WASM_PLUGIN_CLIENT_CREATE_PTR: fn(
+    &str,
+    CreateArgs<serde_json::Value>,
+) -> Result<
+    serde_json::Value,
+    CompilationError,
+> = sapio_v2_wasm_plugin_client_create_nullptr;
+
+/// big enough for the largest result, `result<compiled, plugin-error>`. On
+/// wasm32 each word is 4 bytes, matching the canonical layout.
+static mut RET_AREA: [usize; 4] = [0; 4];
+
+/// the canonical ABI allocator
+#[no_mangle]
+unsafe extern "C" fn cabi_realloc(
+    old: *mut u8,
+    old_size: usize,
+    align: usize,
+    new_size: usize,
+) -> *mut u8 {
+    let old_layout = Layout::from_size_align_unchecked(old_size, align);
+    match (old_size, new_size) {
+        (0, 0) => align as *mut u8,
+        (_, 0) => {
+            dealloc(old, old_layout);
+            align as *mut u8
+        }
+        (0, _) => alloc(Layout::from_size_align_unchecked(new_size, align)),
+        _ => realloc(old, old_layout, new_size),
+    }
+}
+
+/// take ownership of a string the host passed in
+unsafe fn lift_string(ptr: *mut u8, len: usize) -> String {
+    let bytes = if len == 0 {
+        vec![]
+    } else {
+        Vec::from_raw_parts(ptr, len, len)
+    };
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into())
+}
+
+/// write a string as a (pointer, length) pair
+unsafe fn lower_string(s: String, at: *mut usize) {
+    let len = s.len();
+    *at = Box::into_raw(s.into_boxed_str()) as *mut u8 as usize;
+    *at.add(1) = len;
+}
+
+/// free a string written with `lower_string`
+unsafe fn free_string(at: *const usize) {
+    let ptr = *at as *mut u8;
+    let len = *at.add(1);
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+}
+
+/// exports a `func() -> string` and its `cabi_post_` function
+macro_rules! string_export {
+    ($name:literal, $f:ident, $post:ident, $body:expr) => {
+        #[export_name = concat!("sapio:plugin/plugin@2.0.0#", $name)]
+        unsafe extern "C" fn $f() -> *mut usize {
+            let ret = std::ptr::addr_of_mut!(RET_AREA) as *mut usize;
+            lower_string($body, ret);
+            ret
+        }
+        #[export_name = concat!("cabi_post_sapio:plugin/plugin@2.0.0#", $name)]
+        unsafe extern "C" fn $post(ret: *mut usize) {
+            free_string(ret)
+        }
+    };
+}
+
+string_export!(
+    "get-name",
+    sapio_v2_wasm_plugin_client_get_name,
+    sapio_v2_wasm_plugin_client_post_get_name,
+    (*std::ptr::addr_of!(SAPIO_PLUGIN_NAME)).to_string()
+);
+string_export!(
+    "get-logo",
+    sapio_v2_wasm_plugin_client_get_logo,
+    sapio_v2_wasm_plugin_client_post_get_logo,
+    base64::encode(*std::ptr::addr_of!(SAPIO_PLUGIN_LOGO))
+);
+string_export!(
+    "get-api",
+    sapio_v2_wasm_plugin_client_get_api,
+    sapio_v2_wasm_plugin_client_post_get_api,
+    {
+        let api = SAPIO_V1_WASM_PLUGIN_CLIENT_GET_CREATE_ARGUMENTS_PTR();
+        if api.is_null() {
+            String::new()
+        } else {
+            CString::from_raw(api).into_string().unwrap_or_default()
+        }
+    }
+);
+
+/// create an instance of the plugin's contract. Returns a
+/// `result<compiled, plugin-error>`.
+#[export_name = "sapio:plugin/plugin@2.0.0#create"]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn sapio_v2_wasm_plugin_client_create(
+    path: *mut u8,
+    path_len: usize,
+    arguments: *mut u8,
+    arguments_len: usize,
+    network: u32,
+    amount: u64,
+    effects: *mut u8,
+    effects_len: usize,
+) -> *mut usize {
+    let path = lift_string(path, path_len);
+    let arguments = lift_string(arguments, arguments_len);
+    let effects = lift_string(effects, effects_len);
+    let result = (|| {
+        let network = match network {
+            0 => bitcoin::Network::Bitcoin,
+            1 => bitcoin::Network::Testnet,
+            2 => bitcoin::Network::Signet,
+            3 => bitcoin::Network::Regtest,
+            n => {
+                return Err(CompilationError::InternalModuleError(format!(
+                    "Unknown Network {}",
+                    n
+                )))
+            }
+        };
+        let args = CreateArgs {
+            arguments: serde_json::from_str(&arguments)
+                .map_err(CompilationError::DeserializationError)?,
+            context: ContextualArguments {
+                network,
+                amount: bitcoin::Amount::from_sat(amount),
+                effects: serde_json::from_str(&effects)
+                    .map_err(CompilationError::DeserializationError)?,
+            },
+        };
+        let compiled = SAPIO_V2_WASM_PLUGIN_CLIENT_CREATE_PTR(&path, args)?;
+        serde_json::to_string(&compiled).map_err(CompilationError::SerializationError)
+    })();
+    let ret = std::ptr::addr_of_mut!(RET_AREA) as *mut usize;
+    match result {
+        Ok(json) => {
+            *ret = 0;
+            lower_string(json, ret.add(1));
+        }
+        Err(e) => {
+            let (case, msg) = match e {
+                CompilationError::DeserializationError(e) => (0, e.to_string()),
+                CompilationError::InternalModuleError(s) => (2, s),
+                e => (1, e.to_string()),
+            };
+            *ret = 1;
+            *ret.add(1) = case;
+            lower_string(msg, ret.add(2));
+        }
+    }
+    ret
+}
+
+/// frees the result of `create`
+#[export_name = "cabi_post_sapio:plugin/plugin@2.0.0#create"]
+unsafe extern "C" fn sapio_v2_wasm_plugin_client_post_create(ret: *mut usize) {
+    match *ret {
+        0 => free_string(ret.add(1)),
+        _ => free_string(ret.add(2)),
+    }
+}
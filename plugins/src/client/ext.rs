@@ -13,6 +13,8 @@ extern "C" {
     pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(hash: i32) -> i32;
     /// use the hosts stdout to log a string. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(a: i32, len: i32);
+    /// send a JSON `LogRecord` to the host's structured log
+    pub fn sapio_v1_wasm_plugin_log(record: i32, len: i32);
     /// Create an instance of a contract by "trampolining" through the host to use another
     /// plugin identified by key.
     pub fn sapio_v1_wasm_plugin_create_contract(
@@ -36,6 +38,17 @@ extern "C" {
     /// out is written and must be 32 bytes of writable memory.
     /// if name == 0 and name_len == 0, then return the current module
     pub fn sapio_v1_wasm_plugin_lookup_module_name(name: i32, name_len: i32, out: i32, ok: i32);
+    /// lookup a plugin key from a name and a semver requirement.
+    /// if ok == 1, result is valid.
+    /// out is written and must be 32 bytes of writable memory.
+    pub fn sapio_v1_wasm_plugin_lookup_module_version(
+        name: i32,
+        name_len: i32,
+        req: i32,
+        req_len: i32,
+        out: i32,
+        ok: i32,
+    );
 }
 
 #[no_mangle]
@@ -0,0 +1,69 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! macros for structured logging to the host
+pub use crate::log::{LogLevel, LogRecord};
+pub use crate::{log_debug, log_error, log_event, log_info, log_trace, log_warn};
+
+/// Log a message at a `LogLevel`, with optional key-value fields before a `;`.
+///
+/// ```ignore
+/// log_event!(LogLevel::Info, "compiling {}", name);
+/// log_event!(LogLevel::Warn, amount = amt, fee = fee; "fee is over {}%", 10);
+/// ```
+#[macro_export]
+macro_rules! log_event {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::client::log_record(&$crate::log::LogRecord {
+            level: $level,
+            target: module_path!().into(),
+            message: format!($($arg)+),
+            fields: {
+                let mut fields = std::collections::BTreeMap::new();
+                $(fields.insert(stringify!($key).into(), $crate::log::to_field(&$value));)+
+                fields
+            },
+            module: None,
+            path: None,
+        })
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::client::log_record(&$crate::log::LogRecord {
+            level: $level,
+            target: module_path!().into(),
+            message: format!($($arg)+),
+            fields: Default::default(),
+            module: None,
+            path: None,
+        })
+    };
+}
+
+/// `log_event!` at `LogLevel::Error`
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::LogLevel::Error, $($arg)+) };
+}
+/// `log_event!` at `LogLevel::Warn`
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::LogLevel::Warn, $($arg)+) };
+}
+/// `log_event!` at `LogLevel::Info`
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::LogLevel::Info, $($arg)+) };
+}
+/// `log_event!` at `LogLevel::Debug`
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::LogLevel::Debug, $($arg)+) };
+}
+/// `log_event!` at `LogLevel::Trace`
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => { $crate::log_event!($crate::log::LogLevel::Trace, $($arg)+) };
+}
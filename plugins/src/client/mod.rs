@@ -12,7 +12,11 @@ use sapio::contract::Compiled;
 
 pub mod api;
 mod exports;
+mod exports_v2;
 mod ext;
 use exports::*;
+use exports_v2::*;
+pub mod log;
+pub use self::log::*;
 pub mod plugin;
 pub use plugin::Plugin;
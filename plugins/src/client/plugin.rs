@@ -62,7 +62,31 @@ where
     ) -> Result<Self::Output, CompilationError> {
         let s = CString::from_raw(c);
         let path = CString::from_raw(p);
-        let CreateArgs::<Self::InputWrapper> {
+        let args =
+            serde_json::from_slice(s.to_bytes()).map_err(CompilationError::DeserializationError)?;
+        let cstring_to_string = path.to_str().map_err(|e| {
+            CompilationError::InternalModuleError(format!("Path Invalid: {}", e.to_string()))
+        })?;
+        Self::create_typed(cstring_to_string, args)
+    }
+
+    /// creates an instance of the plugin for the v2 ABI, which passes the
+    /// arguments already split out
+    fn create_value(
+        path: &str,
+        args: CreateArgs<serde_json::Value>,
+    ) -> Result<serde_json::Value, CompilationError> {
+        serde_json::to_value(Self::create_typed(path, args)?)
+            .map_err(CompilationError::SerializationError)
+    }
+
+    /// creates an instance of the plugin at `path`, the JSON of an
+    /// `EffectPath`
+    fn create_typed(
+        path: &str,
+        args: CreateArgs<serde_json::Value>,
+    ) -> Result<Self::Output, CompilationError> {
+        let CreateArgs {
             arguments,
             context:
                 ContextualArguments {
@@ -70,7 +94,9 @@ where
                     amount,
                     effects,
                 },
-        } = serde_json::from_slice(s.to_bytes()).map_err(CompilationError::DeserializationError)?;
+        } = args;
+        let arguments: Self::InputWrapper =
+            serde_json::from_value(arguments).map_err(CompilationError::DeserializationError)?;
         // TODO: In theory, these trampoline bounds are robust/serialization safe...
         // But the API needs stiching to the parent in a sane way...
         let caller = lookup_this_module_name()
@@ -80,12 +106,8 @@ where
                     "Host Error: Should always be able to identify module's own ID".into(),
                 )
             })?;
-        let cstring_to_string = path.to_str().map_err(|e| {
-            CompilationError::InternalModuleError(format!("Path Invalid: {}", e.to_string()))
-        })?;
-
-        let parsed_rpath = serde_json::from_str(cstring_to_string)
-            .map_err(CompilationError::DeserializationError)?;
+        let parsed_rpath =
+            serde_json::from_str(path).map_err(CompilationError::DeserializationError)?;
         let path: EffectPath = EffectPath::push_owned(
             Some(EffectPath::push(
                 Some(Arc::new(parsed_rpath)),
@@ -109,6 +131,7 @@ where
     unsafe fn register(name: &'static str, logo: Option<&'static [u8]>) {
         SAPIO_V1_WASM_PLUGIN_CLIENT_GET_CREATE_ARGUMENTS_PTR = Self::get_api_inner;
         SAPIO_V1_WASM_PLUGIN_CLIENT_CREATE_PTR = Self::create;
+        SAPIO_V2_WASM_PLUGIN_CLIENT_CREATE_PTR = Self::create_value;
         SAPIO_PLUGIN_NAME = name;
         if let Some(logo) = logo {
             SAPIO_PLUGIN_LOGO = logo;
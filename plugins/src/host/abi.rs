@@ -0,0 +1,361 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The plugin ABIs, and bounds checked access to a plugin's memory.
+//!
+//! v1 plugins pass NUL terminated JSON strings through
+//! `sapio_v1_wasm_plugin_*` exports. v2 plugins export the `plugin` interface
+//! of `wit/sapio-plugin.wit`, lowered with the canonical ABI; its `json`
+//! values are lowered as strings.
+use super::limits::Meter;
+use sapio::contract::CompilationError;
+use sapio_base::effects::EffectPath;
+use sapio_base::plugin_args::CreateArgs;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::ops::Range;
+use wasmer::{Instance, Memory, Module, NativeFunc, RuntimeError, WasmTypeList};
+
+/// the prefix of the names of the v2 exports
+pub const V2_EXPORT_PREFIX: &str = "sapio:plugin/plugin@2.0.0#";
+
+/// # ABI Version
+/// Which ABI a plugin was built against
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbiVersion {
+    V1,
+    V2,
+}
+
+impl AbiVersion {
+    /// v2 modules export `create` from the `plugin` interface, everything
+    /// else is v1
+    pub fn detect(module: &Module) -> Self {
+        let create = format!("{}create", V2_EXPORT_PREFIX);
+        if module.exports().any(|e| e.name() == create) {
+            AbiVersion::V2
+        } else {
+            AbiVersion::V1
+        }
+    }
+}
+
+/// the bytes `[ptr, ptr + len)` of `memory`, if they are all in bounds.
+/// Pointers and lengths are unsigned in wasm.
+fn range(memory: &Memory, ptr: i32, len: i32) -> Result<Range<usize>, CompilationError> {
+    let start = ptr as u32 as usize;
+    start
+        .checked_add(len as u32 as usize)
+        .filter(|end| *end <= memory.view::<u8>().len())
+        .map(|end| start..end)
+        .ok_or(CompilationError::ModuleMemoryOutOfBounds(ptr, len))
+}
+
+/// read `len` bytes at `ptr`
+pub fn read_bytes(memory: &Memory, ptr: i32, len: i32) -> Result<Vec<u8>, CompilationError> {
+    let r = range(memory, ptr, len)?;
+    Ok(memory.view::<u8>()[r].iter().map(Cell::get).collect())
+}
+
+/// write `bytes` at `ptr`
+pub fn write_bytes(memory: &Memory, ptr: i32, bytes: &[u8]) -> Result<(), CompilationError> {
+    let r = range(memory, ptr, bytes.len() as i32)?;
+    for (dst, src) in memory.view::<u8>()[r].iter().zip(bytes) {
+        dst.set(*src);
+    }
+    Ok(())
+}
+
+/// read a NUL terminated string at `ptr`, without the NUL
+pub fn read_c_string(memory: &Memory, ptr: i32) -> Result<Vec<u8>, CompilationError> {
+    let view = memory.view::<u8>();
+    let start = range(memory, ptr, 0)?.start;
+    let bytes: Vec<u8> = view[start..]
+        .iter()
+        .map(Cell::get)
+        .take_while(|b| *b != 0)
+        .collect();
+    if start + bytes.len() == view.len() {
+        return Err(CompilationError::ModuleABIViolation(format!(
+            "String at {} is not NUL terminated",
+            ptr
+        )));
+    }
+    Ok(bytes)
+}
+
+fn read_u8(memory: &Memory, ptr: i32) -> Result<u8, CompilationError> {
+    Ok(read_bytes(memory, ptr, 1)?[0])
+}
+
+fn read_i32(memory: &Memory, ptr: i32) -> Result<i32, CompilationError> {
+    let mut b = [0; 4];
+    b.copy_from_slice(&read_bytes(memory, ptr, 4)?);
+    Ok(i32::from_le_bytes(b))
+}
+
+/// read a lowered `string`, i.e., a (pointer, length) pair at `ptr`
+fn read_string(memory: &Memory, ptr: i32) -> Result<String, CompilationError> {
+    let bytes = read_bytes(memory, read_i32(memory, ptr)?, read_i32(memory, ptr + 4)?)?;
+    String::from_utf8(bytes)
+        .map_err(|e| CompilationError::ModuleABIViolation(format!("Invalid String: {}", e)))
+}
+
+fn to_json<T: Serialize>(t: &T) -> Result<String, CompilationError> {
+    serde_json::to_string(t).map_err(CompilationError::SerializationError)
+}
+
+/// the flattened parameters of `create`: the path, the arguments, and the
+/// contextual arguments' network, amount and effects
+type CreateParams = (i32, i32, i32, i32, i32, i64, i32, i32);
+
+/// The exports of a v2 plugin
+pub struct V2Exports {
+    realloc: NativeFunc<(i32, i32, i32, i32), i32>,
+    get_name: (NativeFunc<(), i32>, NativeFunc<i32, ()>),
+    get_logo: (NativeFunc<(), i32>, NativeFunc<i32, ()>),
+    get_api: (NativeFunc<(), i32>, NativeFunc<i32, ()>),
+    create: (NativeFunc<CreateParams, i32>, NativeFunc<i32, ()>),
+}
+
+fn export<Args: WasmTypeList, Rets: WasmTypeList>(
+    instance: &Instance,
+    name: &str,
+) -> Result<NativeFunc<Args, Rets>, CompilationError> {
+    instance
+        .exports
+        .get_native_function(name)
+        .map_err(|_| CompilationError::ModuleCouldNotFindFunction(name.into()))
+}
+
+/// a function and its `cabi_post_` function
+fn with_post<Args: WasmTypeList>(
+    instance: &Instance,
+    name: &str,
+) -> Result<(NativeFunc<Args, i32>, NativeFunc<i32, ()>), CompilationError> {
+    let name = format!("{}{}", V2_EXPORT_PREFIX, name);
+    Ok((
+        export(instance, &name)?,
+        export(instance, &format!("cabi_post_{}", name))?,
+    ))
+}
+
+impl V2Exports {
+    pub(crate) fn new(instance: &Instance) -> Result<Self, CompilationError> {
+        Ok(V2Exports {
+            realloc: export(instance, "cabi_realloc")?,
+            get_name: with_post(instance, "get-name")?,
+            get_logo: with_post(instance, "get-logo")?,
+            get_api: with_post(instance, "get-api")?,
+            create: with_post(instance, "create")?,
+        })
+    }
+
+    /// lower `s` into the plugin's memory. The plugin owns the allocation
+    /// once it is passed to a call.
+    fn pass_string(
+        &self,
+        meter: &Meter,
+        memory: &Memory,
+        s: &str,
+    ) -> Result<(i32, i32), CompilationError> {
+        let len = s.len() as i32;
+        let ptr = meter
+            .run(|| self.realloc.call(0, 0, 1, len))?
+            .map_err(|e| CompilationError::ModuleCouldNotAllocateError(len, e.into()))?;
+        write_bytes(memory, ptr, s.as_bytes())?;
+        Ok((ptr, len))
+    }
+
+    /// call a `func() -> string`, then let the plugin free the result
+    fn call_string(
+        meter: &Meter,
+        memory: &Memory,
+        (f, post): &(NativeFunc<(), i32>, NativeFunc<i32, ()>),
+        err: fn(RuntimeError) -> CompilationError,
+    ) -> Result<String, CompilationError> {
+        let ret = meter.run(|| f.call())?.map_err(err)?;
+        let s = read_string(memory, ret);
+        meter.run(|| post.call(ret))?.map_err(err)?;
+        s
+    }
+
+    pub(crate) fn get_name(
+        &self,
+        meter: &Meter,
+        memory: &Memory,
+    ) -> Result<String, CompilationError> {
+        Self::call_string(meter, memory, &self.get_name, |e| {
+            CompilationError::ModuleCouldNotGetName(e.into())
+        })
+    }
+
+    pub(crate) fn get_logo(
+        &self,
+        meter: &Meter,
+        memory: &Memory,
+    ) -> Result<String, CompilationError> {
+        Self::call_string(meter, memory, &self.get_logo, |e| {
+            CompilationError::ModuleCouldNotGetLogo(e.into())
+        })
+    }
+
+    pub(crate) fn get_api(
+        &self,
+        meter: &Meter,
+        memory: &Memory,
+    ) -> Result<String, CompilationError> {
+        Self::call_string(meter, memory, &self.get_api, |e| {
+            CompilationError::ModuleCouldNotGetAPI(e.into())
+        })
+    }
+
+    /// call `create`, returning the JSON of the compiled contract
+    pub(crate) fn create(
+        &self,
+        meter: &Meter,
+        memory: &Memory,
+        path: &EffectPath,
+        c: &CreateArgs<serde_json::Value>,
+    ) -> Result<Vec<u8>, CompilationError> {
+        let path_s = to_json(path)?;
+        let args_s = to_json(&c.arguments)?;
+        let effects_s = to_json(&c.context.effects)?;
+        let network = match c.context.network {
+            bitcoin::Network::Bitcoin => 0,
+            bitcoin::Network::Testnet => 1,
+            bitcoin::Network::Signet => 2,
+            bitcoin::Network::Regtest => 3,
+        };
+        let (path_p, path_l) = self.pass_string(meter, memory, &path_s)?;
+        let (args_p, args_l) = self.pass_string(meter, memory, &args_s)?;
+        let (effects_p, effects_l) = self.pass_string(meter, memory, &effects_s)?;
+        let amount = c.context.amount.as_sat() as i64;
+        let err = |e: RuntimeError| {
+            CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e.into())
+        };
+        let (f, post) = &self.create;
+        let ret = meter
+            .run(|| {
+                f.call(
+                    path_p, path_l, args_p, args_l, network, amount, effects_p, effects_l,
+                )
+            })?
+            .map_err(err)?;
+        // result<compiled, plugin-error>: the case is at 0 and the payload at
+        // 4. plugin-error's case is at 4 and its string at 8.
+        let lifted = (|| match read_u8(memory, ret)? {
+            0 => Ok(Ok(read_string(memory, ret + 4)?)),
+            1 => {
+                let msg = read_string(memory, ret + 8)?;
+                Ok(Err(match read_u8(memory, ret + 4)? {
+                    0 => CompilationError::ModuleFailedAPICheck(msg),
+                    1 => CompilationError::ModuleCompilationErrorUnsendable(msg),
+                    2 => CompilationError::InternalModuleError(msg),
+                    n => {
+                        return Err(CompilationError::ModuleABIViolation(format!(
+                            "Unknown plugin-error case {}",
+                            n
+                        )))
+                    }
+                }))
+            }
+            n => Err(CompilationError::ModuleABIViolation(format!(
+                "Unknown result case {}",
+                n
+            ))),
+        })();
+        meter.run(|| post.call(ret))?.map_err(err)?;
+        Ok(lifted??.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::limits::{compile, PluginLimits};
+    use super::*;
+    use sapio_base::effects::PathFragment;
+    use sapio_base::plugin_args::ContextualArguments;
+    use wasmer::{imports, wat2wasm};
+
+    #[test]
+    fn test_v2_abi() {
+        // a plugin whose `create` echoes its arguments on regtest, and fails
+        // with them otherwise. `get-api` returns an out of bounds string.
+        let wat = r#"
+        (module
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const 2048))
+            (data (i32.const 16) "v2 plugin")
+            (func (export "cabi_realloc") (param i32 i32 i32 i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get 3))))
+            (func $name (result i32)
+                (i32.store (i32.const 1024) (i32.const 16))
+                (i32.store (i32.const 1028) (i32.const 9))
+                (i32.const 1024))
+            (func $api (result i32)
+                (i32.store (i32.const 1024) (i32.const 65530))
+                (i32.store (i32.const 1028) (i32.const 100))
+                (i32.const 1024))
+            (func $post (param i32))
+            (func (export "sapio:plugin/plugin@2.0.0#create")
+                (param $path i32) (param $path_len i32) (param $args i32) (param $args_len i32)
+                (param $net i32) (param $amount i64) (param $effects i32) (param $effects_len i32)
+                (result i32)
+                (if (i32.eq (local.get $net) (i32.const 3))
+                    (then
+                        (i32.store8 (i32.const 1040) (i32.const 0))
+                        (i32.store (i32.const 1044) (local.get $args))
+                        (i32.store (i32.const 1048) (local.get $args_len)))
+                    (else
+                        (i32.store8 (i32.const 1040) (i32.const 1))
+                        (i32.store8 (i32.const 1044) (i32.const 1))
+                        (i32.store (i32.const 1048) (local.get $args))
+                        (i32.store (i32.const 1052) (local.get $args_len))))
+                (i32.const 1040))
+            (export "sapio:plugin/plugin@2.0.0#get-name" (func $name))
+            (export "sapio:plugin/plugin@2.0.0#get-logo" (func $name))
+            (export "sapio:plugin/plugin@2.0.0#get-api" (func $api))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#get-name" (func $post))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#get-logo" (func $post))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#get-api" (func $post))
+            (export "cabi_post_sapio:plugin/plugin@2.0.0#create" (func $post))
+        )"#;
+        let limits = PluginLimits::default();
+        let (store, refused) = limits.store();
+        let module = compile(&store, &wat2wasm(wat.as_bytes()).unwrap()).unwrap();
+        assert_eq!(AbiVersion::detect(&module), AbiVersion::V2);
+        let instance = Instance::new(&module, &imports! {}).unwrap();
+        let meter = Meter::new(&instance, limits, refused, Default::default()).unwrap();
+        let memory = instance.exports.get_memory("memory").unwrap();
+        let v2 = V2Exports::new(&instance).unwrap();
+
+        assert_eq!(v2.get_name(&meter, memory).unwrap(), "v2 plugin");
+        assert!(matches!(
+            v2.get_api(&meter, memory),
+            Err(CompilationError::ModuleMemoryOutOfBounds(65530, 100))
+        ));
+
+        let path = EffectPath::push_owned(None, PathFragment::Root);
+        let mut args = CreateArgs {
+            arguments: serde_json::json!({"a": 1}),
+            context: ContextualArguments {
+                network: bitcoin::Network::Regtest,
+                amount: bitcoin::Amount::from_sat(5),
+                effects: Default::default(),
+            },
+        };
+        let ok = v2.create(&meter, memory, &path, &args).unwrap();
+        assert_eq!(ok, br#"{"a":1}"#);
+        args.context.network = bitcoin::Network::Bitcoin;
+        assert!(matches!(
+            v2.create(&meter, memory, &path, &args),
+            Err(CompilationError::ModuleCompilationErrorUnsendable(m)) if m == r#"{"a":1}"#
+        ));
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A content addressed cache of compiled contracts, in memory and on disk.
+//!
+//! Plugins are deterministic, so a plugin's output is fixed by what it is
+//! given: the module, its arguments, network, amount, the effects at or
+//! below the path it is created at, and the path itself (which names the
+//! contract's continuation points). Modules it calls are found through the
+//! plugin map and registry, so those are part of the key too, as are the
+//! limits it ran under (a compilation that ran out of fuel under one limit
+//! may succeed under another) and a salt for anything else the host varies
+//! (e.g., the CTV emulator).
+//!
+//! The on disk half is bounded in size: once a directory's cache grows past
+//! the limit, the least recently used compilations are removed.
+use crate::host::limits::PluginLimits;
+use bitcoin::hashes::{sha256, Hash};
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// bump when the format of what is cached changes
+const CACHE_VERSION: u32 = 2;
+/// where the on disk cache lives, under the module directory
+pub const CACHE_DIR: &str = "compiled";
+
+/// # Cache Stats
+/// How compilations were served by the compilation cache
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// served from memory
+    pub memory_hits: u64,
+    /// served from disk
+    pub disk_hits: u64,
+    /// compiled by the plugin
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn merge(&mut self, other: CacheStats) {
+        self.memory_hits += other.memory_hits;
+        self.disk_hits += other.disk_hits;
+        self.misses += other.misses;
+    }
+}
+
+/// What a compilation is keyed on
+#[derive(Serialize)]
+pub struct CacheKeyMaterial<'a> {
+    pub module: String,
+    pub path: &'a EffectPath,
+    pub arguments: &'a Value,
+    pub network: bitcoin::Network,
+    pub amount: u64,
+    pub effects: MapEffectDB,
+    pub module_map: &'a BTreeMap<Vec<u8>, [u8; 32]>,
+    pub limits: &'a PluginLimits,
+    /// the hash of the registry index, if there is one
+    pub registry: Option<sha256::Hash>,
+    pub salt: &'a str,
+}
+
+impl<'a> CacheKeyMaterial<'a> {
+    /// the key for this material, a hash of its canonical JSON
+    pub fn key(&self) -> Result<sha256::Hash, serde_json::Error> {
+        let v = canonical(serde_json::to_value(self)?);
+        let s = serde_json::to_string(&(CACHE_VERSION, v))?;
+        Ok(sha256::Hash::hash(s.as_bytes()))
+    }
+}
+
+/// `v` with the keys of every object sorted, so equal values have equal
+/// JSON
+fn canonical(v: Value) -> Value {
+    match v {
+        Value::Object(m) => {
+            let sorted: BTreeMap<String, Value> =
+                m.into_iter().map(|(k, v)| (k, canonical(v))).collect();
+            Value::Object(sorted.into_iter().collect())
+        }
+        Value::Array(a) => Value::Array(a.into_iter().map(canonical).collect()),
+        v => v,
+    }
+}
+
+struct Memory {
+    entries: BTreeMap<sha256::Hash, Arc<Value>>,
+    /// insertion order, for eviction
+    order: VecDeque<sha256::Hash>,
+}
+
+/// the hash of a file, and the modification time and length it was taken at
+type FileHash = (Option<SystemTime>, u64, sha256::Hash);
+
+/// The in memory half of the cache, shared by every plugin using it. The on
+/// disk half is under the `CACHE_DIR` of each module directory.
+pub struct CompileCache {
+    memory: Mutex<Memory>,
+    max_entries: usize,
+    /// bytes written under each directory's `CACHE_DIR`, once it is known
+    disk: Mutex<BTreeMap<PathBuf, u64>>,
+    max_disk_bytes: u64,
+    /// hashes of files read for keys (e.g., the registry index)
+    hashes: Mutex<BTreeMap<PathBuf, FileHash>>,
+}
+
+impl CompileCache {
+    /// keep at most `max_entries` compilations in memory and at most
+    /// `max_disk_bytes` of them on disk in each module directory
+    pub fn new(max_entries: usize, max_disk_bytes: u64) -> Self {
+        CompileCache {
+            memory: Mutex::new(Memory {
+                entries: Default::default(),
+                order: Default::default(),
+            }),
+            max_entries,
+            disk: Default::default(),
+            max_disk_bytes,
+            hashes: Default::default(),
+        }
+    }
+
+    /// the hash of the file at `path`, or None if it can't be read. The hash
+    /// is only recomputed when the file's modification time or length
+    /// changes.
+    pub fn file_hash(&self, path: &Path) -> Option<sha256::Hash> {
+        let meta = match std::fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => {
+                self.hashes.lock().unwrap().remove(path);
+                return None;
+            }
+        };
+        let (modified, len) = (meta.modified().ok(), meta.len());
+        if let Some((m, l, h)) = self.hashes.lock().unwrap().get(path) {
+            if modified.is_some() && *m == modified && *l == len {
+                return Some(*h);
+            }
+        }
+        let h = sha256::Hash::hash(&std::fs::read(path).ok()?);
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), (modified, len, h));
+        Some(h)
+    }
+
+    fn file(dir: &Path, key: &sha256::Hash) -> PathBuf {
+        dir.join(CACHE_DIR).join(format!("{}.json", key))
+    }
+
+    fn remember(&self, key: sha256::Hash, v: Arc<Value>) {
+        let mut m = self.memory.lock().unwrap();
+        if m.entries.insert(key, v).is_none() {
+            m.order.push_back(key);
+        }
+        while m.order.len() > self.max_entries {
+            if let Some(old) = m.order.pop_front() {
+                m.entries.remove(&old);
+            }
+        }
+    }
+
+    /// look up a compilation, from memory or else from `dir`, recording how
+    /// it was found in `stats`
+    pub fn get(&self, dir: &Path, key: &sha256::Hash, stats: &mut CacheStats) -> Option<Value> {
+        if let Some(v) = self.memory.lock().unwrap().entries.get(key) {
+            stats.memory_hits += 1;
+            return Some((**v).clone());
+        }
+        let file = Self::file(dir, key);
+        let v: Option<Value> = std::fs::read(&file)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok());
+        match v {
+            Some(v) => {
+                stats.disk_hits += 1;
+                // mark it as recently used, for eviction
+                let _ = std::fs::File::options()
+                    .write(true)
+                    .open(&file)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                self.remember(*key, Arc::new(v.clone()));
+                Some(v)
+            }
+            None => {
+                stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// store a compilation in memory and in `dir`. Failing to write to disk
+    /// only means it will be compiled again by the next process.
+    pub fn put(&self, dir: &Path, key: sha256::Hash, v: &Value) {
+        self.remember(key, Arc::new(v.clone()));
+        let file = Self::file(dir, &key);
+        let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+        let written = (|| -> std::io::Result<u64> {
+            std::fs::create_dir_all(dir.join(CACHE_DIR))?;
+            let bytes = serde_json::to_vec(v)?;
+            std::fs::write(&tmp, &bytes)?;
+            std::fs::rename(&tmp, &file)?;
+            Ok(bytes.len() as u64)
+        })();
+        if let Ok(written) = written {
+            let mut disk = self.disk.lock().unwrap();
+            let size = match disk.get_mut(dir) {
+                Some(size) => {
+                    *size += written;
+                    *size
+                }
+                None => *disk.entry(dir.to_owned()).or_insert(Self::disk_usage(dir)),
+            };
+            if size > self.max_disk_bytes {
+                disk.insert(dir.to_owned(), self.evict(dir));
+            }
+        }
+    }
+
+    /// the compilations on disk in `dir`, with their size and when they were
+    /// last used
+    fn on_disk(dir: &Path) -> Vec<(SystemTime, u64, PathBuf)> {
+        let entries = match std::fs::read_dir(dir.join(CACHE_DIR)) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .flatten()
+            .filter(|e| e.path().extension().map_or(false, |x| x == "json"))
+            .filter_map(|e| {
+                let meta = e.metadata().ok()?;
+                Some((
+                    meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    meta.len(),
+                    e.path(),
+                ))
+            })
+            .collect()
+    }
+
+    fn disk_usage(dir: &Path) -> u64 {
+        Self::on_disk(dir).iter().map(|(_, len, _)| len).sum()
+    }
+
+    /// remove the least recently used compilations in `dir` until it is at
+    /// most three quarters of the limit, so that it isn't rescanned on every
+    /// write. Returns the size left.
+    fn evict(&self, dir: &Path) -> u64 {
+        let mut files = Self::on_disk(dir);
+        files.sort();
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        let target = self.max_disk_bytes - self.max_disk_bytes / 4;
+        for (_, len, path) in files {
+            if size <= target {
+                break;
+            }
+            if std::fs::remove_file(&path).is_ok() {
+                size -= len;
+            }
+        }
+        size
+    }
+}
+
+/// A cache, and the salt for the settings of the plugins using it
+#[derive(Clone)]
+pub struct CacheScope {
+    pub cache: Arc<CompileCache>,
+    pub salt: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::effects::PathFragment;
+
+    #[test]
+    fn test_compile_cache() {
+        let dir = std::env::temp_dir().join(format!("sapio-compile-cache-{}", std::process::id()));
+        let path = EffectPath::push_owned(None, PathFragment::Root);
+        let module_map = BTreeMap::new();
+        let limits = PluginLimits::default();
+        let material = |arguments: &Value| {
+            CacheKeyMaterial {
+                module: "00".into(),
+                path: &path,
+                arguments,
+                network: bitcoin::Network::Regtest,
+                amount: 100,
+                effects: Default::default(),
+                module_map: &module_map,
+                limits: &limits,
+                registry: None,
+                salt: "",
+            }
+            .key()
+            .unwrap()
+        };
+        let a = serde_json::json!({"a": 1, "b": [{"y": 2, "x": 3}]});
+        let mut b = serde_json::Map::new();
+        b.insert("b".into(), serde_json::json!([{"x": 3, "y": 2}]));
+        b.insert("a".into(), 1.into());
+        let key = material(&a);
+        assert_eq!(key, material(&Value::Object(b)));
+        assert_ne!(key, material(&serde_json::json!({"a": 2})));
+
+        let mut stats = CacheStats::default();
+        let cache = CompileCache::new(1, u64::MAX);
+        assert_eq!(cache.get(&dir, &key, &mut stats), None);
+        cache.put(&dir, key, &a);
+        assert_eq!(cache.get(&dir, &key, &mut stats), Some(a.clone()));
+        // a fresh cache (e.g., another process) finds it on disk
+        let cache = CompileCache::new(1, u64::MAX);
+        assert_eq!(cache.get(&dir, &key, &mut stats), Some(a));
+        assert_eq!(
+            stats,
+            CacheStats {
+                memory_hits: 1,
+                disk_hits: 1,
+                misses: 1
+            }
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_eviction() {
+        let dir = std::env::temp_dir().join(format!("sapio-compile-evict-{}", std::process::id()));
+        let v = serde_json::json!("x".repeat(100));
+        let size = serde_json::to_vec(&v).unwrap().len() as u64;
+        let cache = CompileCache::new(0, 4 * size);
+        let keys: Vec<_> = (0u8..8).map(|i| sha256::Hash::hash(&[i])).collect();
+        for key in &keys {
+            cache.put(&dir, *key, &v);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let left = CompileCache::disk_usage(&dir);
+        assert!(left <= 4 * size);
+        let mut stats = CacheStats::default();
+        // the newest is kept, the oldest evicted
+        assert!(cache.get(&dir, &keys[7], &mut stats).is_some());
+        assert!(cache.get(&dir, &keys[0], &mut stats).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_hash() {
+        let dir = std::env::temp_dir().join(format!("sapio-file-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("registry.json");
+        let cache = CompileCache::new(0, u64::MAX);
+        assert_eq!(cache.file_hash(&file), None);
+        std::fs::write(&file, b"{}").unwrap();
+        assert_eq!(cache.file_hash(&file), Some(sha256::Hash::hash(b"{}")));
+        std::fs::write(&file, b"{\"a\":1}").unwrap();
+        assert_eq!(
+            cache.file_hash(&file),
+            Some(sha256::Hash::hash(b"{\"a\":1}"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,664 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! resource limits (fuel, memory, and wall-clock time) for running plugins
+use loupe::{MemoryUsage, MemoryUsageTracker};
+use sapio::contract::error::ModuleLimit;
+use sapio::contract::CompilationError;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use wasmer::vm::{Memory, MemoryError, MemoryStyle, Table, TableStyle};
+use wasmer::vm::{VMMemoryDefinition, VMTableDefinition};
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    BaseTunables, CompilerConfig, Cranelift, Global, Instance, MemoryType, Module, Pages,
+    RuntimeError, Store, TableType, Target, Tunables, Universal, Val,
+};
+use wasmer_middlewares::metering::{
+    get_remaining_points, set_remaining_points, Metering, MeteringPoints,
+};
+
+/// the globals `Metering` exports from the modules it compiles
+const METERING_EXPORTS: [&str; 2] = [
+    "wasmer_metering_remaining_points",
+    "wasmer_metering_points_exhausted",
+];
+/// reason for an interrupt when a call ran out of time
+const INTERRUPT_TIMEOUT: i32 = 1;
+/// reason for an interrupt when a call was cancelled
+const INTERRUPT_CANCELLED: i32 = 2;
+
+/// default for `PluginLimits::fuel`
+pub const DEFAULT_FUEL: u64 = 20_000_000_000;
+/// default for `PluginLimits::memory_pages` (512 MiB)
+pub const DEFAULT_MEMORY_PAGES: u32 = 8192;
+/// default for `PluginLimits::timeout_ms`
+pub const DEFAULT_TIMEOUT_MS: u64 = 60_000;
+
+/// # Plugin Limits
+/// Resource limits applied to each call into a plugin. A `None` limit is
+/// unlimited.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PluginLimits {
+    /// # Fuel
+    /// The max number of wasm instructions a single call may execute
+    pub fuel: Option<u64>,
+    /// # Memory Pages
+    /// The max size of a plugin's linear memory, in 64 KiB pages
+    pub memory_pages: Option<u32>,
+    /// # Timeout
+    /// The max wall-clock time of a single call, in milliseconds
+    pub timeout_ms: Option<u64>,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        PluginLimits {
+            fuel: Some(DEFAULT_FUEL),
+            memory_pages: Some(DEFAULT_MEMORY_PAGES),
+            timeout_ms: Some(DEFAULT_TIMEOUT_MS),
+        }
+    }
+}
+
+impl PluginLimits {
+    /// no limits at all
+    pub fn unlimited() -> Self {
+        PluginLimits {
+            fuel: None,
+            memory_pages: None,
+            timeout_ms: None,
+        }
+    }
+
+    /// Creates a store which caps the memory of instances created in it.
+    ///
+    /// The store can't compile modules itself, they must be compiled with
+    /// `compile` (or loaded from a cache of modules compiled that way) so
+    /// that they are metered.
+    ///
+    /// Returns the store and a flag which is set when the memory cap refuses
+    /// to grow a memory.
+    pub(crate) fn store(&self) -> (Store, Arc<AtomicBool>) {
+        let engine = Universal::headless().engine();
+        let base = BaseTunables::for_target(&Target::default());
+        let refused = Arc::new(AtomicBool::new(false));
+        let store = match self.memory_pages {
+            Some(pages) => Store::new_with_tunables(
+                &engine,
+                LimitingTunables {
+                    limit: Pages(pages),
+                    refused: refused.clone(),
+                    base,
+                },
+            ),
+            None => Store::new_with_tunables(&engine, base),
+        };
+        (store, refused)
+    }
+}
+
+/// Compiles `wasm` with fuel metering into a module for `store`, charging
+/// one unit of fuel per instruction.
+///
+/// `Metering` keeps the globals of the module it instruments, so every module
+/// is compiled by its own engine and then moved into `store`.
+pub(crate) fn compile(store: &Store, wasm: &[u8]) -> Result<Module, Box<dyn Error>> {
+    let mut compiler = Cranelift::default();
+    compiler.push_middleware(Arc::new(Metering::new(0, |_: &Operator| 1)));
+    let compiling = Store::new(&Universal::new(compiler).engine());
+    let bytes = Module::new(&compiling, wasm)?.serialize()?;
+    // Safety: the bytes were just serialized by the same version of wasmer
+    Ok(unsafe { Module::deserialize(store, &bytes[..]) }?)
+}
+
+/// whether a module was compiled with the current fuel metering, modules
+/// cached by older versions were not.
+pub(crate) fn is_metered(module: &Module) -> bool {
+    let exports: Vec<_> = module.exports().map(|e| e.name().to_string()).collect();
+    METERING_EXPORTS
+        .iter()
+        .all(|name| exports.iter().any(|e| e == name))
+}
+
+/// Tunables which cap the maximum size of every memory.
+struct LimitingTunables<T: Tunables> {
+    limit: Pages,
+    /// set when a memory is refused for being too large
+    refused: Arc<AtomicBool>,
+    base: T,
+}
+
+impl<T: Tunables> MemoryUsage for LimitingTunables<T> {
+    fn size_of_val(&self, tracker: &mut dyn MemoryUsageTracker) -> usize {
+        std::mem::size_of_val(self) + self.base.size_of_val(tracker)
+    }
+}
+
+impl<T: Tunables> LimitingTunables<T> {
+    /// lower the maximum of `ty` to the limit, or fail if it can't fit.
+    fn adjust(&self, ty: &MemoryType) -> Result<MemoryType, MemoryError> {
+        if ty.minimum > self.limit {
+            self.refused.store(true, Ordering::SeqCst);
+            return Err(MemoryError::MinimumMemoryTooLarge {
+                min_requested: ty.minimum,
+                max_allowed: self.limit,
+            });
+        }
+        let mut adjusted = *ty;
+        if ty.maximum.map_or(true, |m| m > self.limit) {
+            adjusted.maximum = Some(self.limit);
+        }
+        Ok(adjusted)
+    }
+    fn wrap(&self, m: Arc<dyn Memory>) -> Arc<dyn Memory> {
+        Arc::new(LimitedMemory {
+            inner: m,
+            refused: self.refused.clone(),
+        })
+    }
+}
+
+impl<T: Tunables> Tunables for LimitingTunables<T> {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        let adjusted = self.adjust(memory).unwrap_or(*memory);
+        self.base.memory_style(&adjusted)
+    }
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<Arc<dyn Memory>, MemoryError> {
+        let adjusted = self.adjust(ty)?;
+        Ok(self.wrap(self.base.create_host_memory(&adjusted, style)?))
+    }
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<Arc<dyn Memory>, MemoryError> {
+        let adjusted = self.adjust(ty)?;
+        Ok(self.wrap(
+            self.base
+                .create_vm_memory(&adjusted, style, vm_definition_location)?,
+        ))
+    }
+    fn create_host_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+    ) -> Result<Arc<dyn Table>, String> {
+        self.base.create_host_table(ty, style)
+    }
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<Arc<dyn Table>, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+/// A memory which records when a grow is refused.
+#[derive(Debug)]
+struct LimitedMemory {
+    inner: Arc<dyn Memory>,
+    refused: Arc<AtomicBool>,
+}
+
+impl MemoryUsage for LimitedMemory {
+    fn size_of_val(&self, tracker: &mut dyn MemoryUsageTracker) -> usize {
+        std::mem::size_of_val(self) + self.inner.size_of_val(tracker)
+    }
+}
+
+impl Memory for LimitedMemory {
+    fn ty(&self) -> MemoryType {
+        self.inner.ty()
+    }
+    fn style(&self) -> &MemoryStyle {
+        self.inner.style()
+    }
+    fn size(&self) -> Pages {
+        self.inner.size()
+    }
+    fn grow(&self, delta: Pages) -> Result<Pages, MemoryError> {
+        self.inner.grow(delta).map_err(|e| {
+            self.refused.store(true, Ordering::SeqCst);
+            e
+        })
+    }
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        self.inner.vmmemory()
+    }
+}
+
+/// Why the host wants the current call into an instance to stop, if it does.
+///
+/// Wasm running on one thread can't safely be stopped from another, so the
+/// watchdog and `CancelToken` only raise an interrupt. The thread running
+/// the call acts on it when the plugin next calls into the host (see
+/// `Interrupt::poll`), by taking all of the call's remaining fuel so that it
+/// traps at its next metering point. A call which never calls into the host
+/// is only stopped by running out of fuel.
+#[derive(Clone, Default)]
+pub struct Interrupt(Arc<AtomicI32>);
+
+impl Interrupt {
+    /// ask the current call to stop for `reason`
+    fn raise(&self, reason: i32) {
+        self.0.store(reason, Ordering::SeqCst);
+    }
+
+    /// clear the interrupt, returning its reason
+    fn take(&self) -> i32 {
+        self.0.swap(0, Ordering::SeqCst)
+    }
+
+    /// From a host function, on the thread running the call: if the call
+    /// was interrupted, take the fuel left in `remaining` (the first of the
+    /// instance's `METERING_EXPORTS`).
+    pub(crate) fn poll(&self, remaining: &Global) {
+        if self.0.load(Ordering::SeqCst) != 0 {
+            let _ = remaining.set(Val::I64(0));
+        }
+    }
+}
+
+/// One thread which interrupts calls past their deadline, shared by all
+/// meters.
+struct Watchdog {
+    deadlines: Mutex<BTreeMap<(Instant, u64), Interrupt>>,
+    changed: Condvar,
+    next: AtomicU64,
+}
+
+/// A deadline which is cancelled when dropped.
+struct Armed {
+    key: (Instant, u64),
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        watchdog().deadlines.lock().unwrap().remove(&self.key);
+    }
+}
+
+fn watchdog() -> &'static Watchdog {
+    static WATCHDOG: OnceLock<Watchdog> = OnceLock::new();
+    WATCHDOG.get_or_init(|| {
+        std::thread::Builder::new()
+            .name("sapio-plugin-watchdog".into())
+            .spawn(|| watchdog().watch())
+            .expect("the watchdog thread can be started");
+        Watchdog {
+            deadlines: Default::default(),
+            changed: Condvar::new(),
+            next: AtomicU64::new(0),
+        }
+    })
+}
+
+impl Watchdog {
+    fn arm(&self, timeout: Duration, interrupt: Interrupt) -> Armed {
+        let key = (
+            Instant::now() + timeout,
+            self.next.fetch_add(1, Ordering::Relaxed),
+        );
+        self.deadlines.lock().unwrap().insert(key, interrupt);
+        self.changed.notify_one();
+        Armed { key }
+    }
+
+    fn watch(&self) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            deadlines = match deadlines.keys().next().cloned() {
+                None => self.changed.wait(deadlines).unwrap(),
+                Some(key) if key.0 <= now => {
+                    if let Some(interrupt) = deadlines.remove(&key) {
+                        interrupt.raise(INTERRUPT_TIMEOUT);
+                    }
+                    deadlines
+                }
+                Some(key) => self.changed.wait_timeout(deadlines, key.0 - now).unwrap().0,
+            };
+        }
+    }
+}
+
+/// Cancels the plugin calls it is attached to, which stop once they next call
+/// into the host (see `Interrupt`). Calls made after it is cancelled fail at
+/// once.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<Mutex<CancelState>>);
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    /// the calls running now
+    running: BTreeMap<u64, Interrupt>,
+    next: u64,
+}
+
+/// A running call, which stops being cancellable when dropped.
+struct Watching<'a> {
+    token: &'a CancelToken,
+    id: u64,
+}
+
+impl Drop for Watching<'_> {
+    fn drop(&mut self) {
+        self.token.0.lock().unwrap().running.remove(&self.id);
+    }
+}
+
+impl CancelToken {
+    /// a token which is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// cancel the calls running now and all later ones
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.cancelled = true;
+        for interrupt in state.running.values() {
+            interrupt.raise(INTERRUPT_CANCELLED);
+        }
+    }
+
+    /// whether `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().unwrap().cancelled
+    }
+
+    /// interrupt the call `interrupt` belongs to on cancel, or `None` if
+    /// already cancelled
+    fn watch(&self, interrupt: Interrupt) -> Option<Watching<'_>> {
+        let mut state = self.0.lock().unwrap();
+        if state.cancelled {
+            return None;
+        }
+        let id = state.next;
+        state.next += 1;
+        state.running.insert(id, interrupt);
+        Some(Watching { token: self, id })
+    }
+}
+
+/// Enforces a `PluginLimits` on calls into one instance.
+pub(crate) struct Meter {
+    limits: PluginLimits,
+    instance: Instance,
+    interrupt: Interrupt,
+    memory_refused: Arc<AtomicBool>,
+    cancel: Mutex<Option<CancelToken>>,
+}
+
+impl Meter {
+    /// `interrupt` must be the one the instance's host functions poll.
+    pub(crate) fn new(
+        instance: &Instance,
+        limits: PluginLimits,
+        memory_refused: Arc<AtomicBool>,
+        interrupt: Interrupt,
+    ) -> Result<Self, CompilationError> {
+        for name in METERING_EXPORTS.iter() {
+            instance
+                .exports
+                .get_global(name)
+                .map_err(|e| CompilationError::ModuleRuntimeError(e.into()))?;
+        }
+        Ok(Meter {
+            limits,
+            instance: instance.clone(),
+            interrupt,
+            memory_refused,
+            cancel: Mutex::new(None),
+        })
+    }
+
+    /// the flag set when the memory cap refuses to grow a memory
+    pub(crate) fn memory_refused(&self) -> Arc<AtomicBool> {
+        self.memory_refused.clone()
+    }
+
+    /// cancel calls with `token`, or stop doing so
+    pub(crate) fn set_cancel(&self, token: Option<CancelToken>) {
+        *self.cancel.lock().unwrap() = token;
+    }
+
+    /// Runs a call into the instance within the limits, unless it is
+    /// cancelled.
+    ///
+    /// The outer error is set if a limit was hit or the call was cancelled,
+    /// the inner one for any other failure of the call.
+    ///
+    /// Note that the timeout and cancellation only stop a call when it calls
+    /// into the host (see `Interrupt`), so a call spinning in wasm runs until
+    /// its fuel is spent, and one blocked inside a host function is not
+    /// interrupted.
+    pub(crate) fn run<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, RuntimeError>,
+    ) -> Result<Result<T, RuntimeError>, CompilationError> {
+        let fuel = self.limits.fuel.unwrap_or(u64::MAX);
+        set_remaining_points(&self.instance, fuel);
+        self.memory_refused.store(false, Ordering::SeqCst);
+        self.interrupt.take();
+        let cancel = self.cancel.lock().unwrap().clone();
+        let watching = match &cancel {
+            Some(token) => match token.watch(self.interrupt.clone()) {
+                Some(w) => Some(w),
+                None => return Err(CompilationError::ModuleCancelled),
+            },
+            None => None,
+        };
+        let armed = self
+            .limits
+            .timeout_ms
+            .map(|ms| watchdog().arm(Duration::from_millis(ms), self.interrupt.clone()));
+        let res = f();
+        drop(armed);
+        drop(watching);
+        match self.interrupt.take() {
+            INTERRUPT_TIMEOUT => {
+                return Err(CompilationError::ModuleExceededLimit(ModuleLimit::Timeout(
+                    self.limits.timeout_ms.unwrap_or_default(),
+                )))
+            }
+            INTERRUPT_CANCELLED => return Err(CompilationError::ModuleCancelled),
+            _ => {}
+        }
+        if res.is_err() {
+            if get_remaining_points(&self.instance) == MeteringPoints::Exhausted {
+                return Err(CompilationError::ModuleExceededLimit(ModuleLimit::Fuel(
+                    fuel,
+                )));
+            }
+            if let Some(pages) = self.limits.memory_pages {
+                if self.memory_refused.load(Ordering::SeqCst) {
+                    return Err(CompilationError::ModuleExceededLimit(ModuleLimit::Memory(
+                        pages,
+                    )));
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasmer::{imports, wat2wasm, Function, LazyInit, WasmerEnv};
+
+    /// the state of the `tick` host function
+    #[derive(WasmerEnv, Clone)]
+    struct Tick {
+        interrupt: Interrupt,
+        #[wasmer(export(name = "wasmer_metering_remaining_points"))]
+        remaining_fuel: LazyInit<Global>,
+    }
+
+    fn tick(env: &Tick) {
+        env.interrupt.poll(env.remaining_fuel.get_ref().unwrap());
+    }
+
+    fn instance(limits: PluginLimits, wat: &str) -> (Instance, Meter) {
+        let (store, refused) = limits.store();
+        let module = compile(&store, &wat2wasm(wat.as_bytes()).unwrap()).unwrap();
+        assert!(is_metered(&module));
+        let env = Tick {
+            interrupt: Interrupt::default(),
+            remaining_fuel: LazyInit::new(),
+        };
+        let interrupt = env.interrupt.clone();
+        let imports = imports! {
+            "env" => { "tick" => Function::new_native_with_env(&store, env, tick) }
+        };
+        let instance = Instance::new(&module, &imports).unwrap();
+        let meter = Meter::new(&instance, limits, refused, interrupt).unwrap();
+        (instance, meter)
+    }
+
+    #[test]
+    fn test_limits() {
+        let wat = r#"
+        (module
+            (import "env" "tick" (func $tick))
+            (memory (export "memory") 1)
+            (func (export "spin") (loop (br 0)))
+            (func (export "spin_tick") (loop (call $tick) (br 0)))
+            (func (export "count") (param i32) (result i32)
+                (local $i i32)
+                (loop
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if 0 (i32.lt_u (local.get $i) (local.get 0))))
+                (local.get $i))
+            (func (export "grow") (result i32) (memory.grow (i32.const 100)))
+        )"#;
+        let limits = PluginLimits {
+            fuel: Some(100_000),
+            memory_pages: Some(10),
+            timeout_ms: None,
+        };
+        let (i, meter) = instance(limits, wat);
+        let count = i.exports.get_native_function::<i32, i32>("count").unwrap();
+        assert_eq!(meter.run(|| count.call(100)).unwrap().unwrap(), 100);
+        assert!(matches!(
+            meter.run(|| count.call(1_000_000)),
+            Err(CompilationError::ModuleExceededLimit(ModuleLimit::Fuel(
+                100_000
+            )))
+        ));
+        // fuel is reset for every call
+        assert_eq!(meter.run(|| count.call(100)).unwrap().unwrap(), 100);
+        // a refused grow is not itself an error for wasm
+        let grow = i.exports.get_native_function::<(), i32>("grow").unwrap();
+        assert_eq!(meter.run(|| grow.call()).unwrap().unwrap(), -1);
+
+        // a call which never reaches the host is stopped by its fuel
+        let spin = i.exports.get_native_function::<(), ()>("spin").unwrap();
+        assert!(matches!(
+            meter.run(|| spin.call()),
+            Err(CompilationError::ModuleExceededLimit(ModuleLimit::Fuel(
+                100_000
+            )))
+        ));
+
+        let (i, meter) = instance(
+            PluginLimits {
+                fuel: None,
+                memory_pages: None,
+                timeout_ms: Some(100),
+            },
+            wat,
+        );
+        let spin = i
+            .exports
+            .get_native_function::<(), ()>("spin_tick")
+            .unwrap();
+        assert!(matches!(
+            meter.run(|| spin.call()),
+            Err(CompilationError::ModuleExceededLimit(ModuleLimit::Timeout(
+                100
+            )))
+        ));
+        // the interrupt is cleared for the next call
+        let count = i.exports.get_native_function::<i32, i32>("count").unwrap();
+        assert_eq!(meter.run(|| count.call(100)).unwrap().unwrap(), 100);
+
+        let (i, meter) = instance(PluginLimits::unlimited(), wat);
+        let spin = i
+            .exports
+            .get_native_function::<(), ()>("spin_tick")
+            .unwrap();
+        let token = CancelToken::new();
+        meter.set_cancel(Some(token.clone()));
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        assert!(matches!(
+            meter.run(|| spin.call()),
+            Err(CompilationError::ModuleCancelled)
+        ));
+        canceller.join().unwrap();
+        // later calls fail at once
+        let count = i.exports.get_native_function::<i32, i32>("count").unwrap();
+        assert!(matches!(
+            meter.run(|| count.call(100)),
+            Err(CompilationError::ModuleCancelled)
+        ));
+        meter.set_cancel(None);
+        assert_eq!(meter.run(|| count.call(100)).unwrap().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_compile_many() {
+        let limits = PluginLimits::default();
+        let (store, refused) = limits.store();
+        let wasm = |n: i32| {
+            wat2wasm(
+                format!(
+                    r#"(module (func (export "n") (result i32) (i32.const {})))"#,
+                    n
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+            .into_owned()
+        };
+        for n in 0..3 {
+            let module = compile(&store, &wasm(n)).unwrap();
+            assert!(is_metered(&module));
+            let instance = Instance::new(&module, &imports! {}).unwrap();
+            let f = instance
+                .exports
+                .get_native_function::<(), i32>("n")
+                .unwrap();
+            let meter =
+                Meter::new(&instance, limits, refused.clone(), Interrupt::default()).unwrap();
+            assert_eq!(meter.run(|| f.call()).unwrap().unwrap(), n);
+        }
+        // the store can't compile unmetered modules itself
+        assert!(Module::new(&store, &wasm(0)).is_err());
+    }
+}
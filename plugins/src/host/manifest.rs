@@ -0,0 +1,284 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! signed manifests describing a plugin, and the publishers a host trusts
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{schnorr, Keypair, Message, Secp256k1, Signing, Verification};
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::Display;
+
+/// tag for the hash of a manifest which publishers sign
+const MANIFEST_TAG: &[u8] = b"sapio/plugin-manifest";
+
+/// Errors from checking a manifest
+#[derive(Debug)]
+pub enum ManifestError {
+    /// The signature does not match the manifest and publisher
+    BadSignature,
+    /// The manifest is for a different module
+    ModuleMismatch {
+        /// the module the manifest is for
+        expected: String,
+        /// the module being loaded
+        found: String,
+    },
+    /// The module's API does not match the manifest
+    ApiSchemaMismatch,
+    /// The module imports a host function the manifest does not allow
+    ImportNotAllowed(String),
+    /// The publisher is not in the trust list
+    UntrustedPublisher(XOnlyPublicKey),
+    /// Signed manifests are required, but the module has none
+    Unsigned(String),
+    /// Other errors
+    Custom(Box<dyn Error>),
+}
+
+impl Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for ManifestError {}
+
+/// # Plugin Manifest
+/// A description of a plugin, which is signed by its publisher.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct PluginManifest {
+    /// # Name
+    pub name: String,
+    /// # Version
+    pub version: String,
+    /// # Publisher
+    /// The key which signs the manifest
+    #[schemars(with = "String")]
+    pub publisher: XOnlyPublicKey,
+    /// # Module
+    /// The cache key (hex) of the WASM module
+    pub module: String,
+    /// # API Schema Hash
+    /// The sha256 of the module's JSON API
+    #[schemars(with = "String")]
+    pub api_schema_hash: sha256::Hash,
+    /// # Allowed Imports
+    /// The host functions the module may import
+    pub allowed_imports: BTreeSet<String>,
+    /// # Dependencies
+    /// Semver requirements on the plugins this one uses, by name
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+/// # Signed Plugin Manifest
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct SignedPluginManifest {
+    /// # Manifest
+    pub manifest: PluginManifest,
+    /// # Signature
+    /// The publisher's Schnorr signature of the manifest
+    #[schemars(with = "String")]
+    pub signature: schnorr::Signature,
+}
+
+/// hash a plugin API for `PluginManifest::api_schema_hash`
+pub fn hash_api(api: &serde_json::Value) -> Result<sha256::Hash, ManifestError> {
+    serde_json::to_vec(api)
+        .map(|v| sha256::Hash::hash(&v[..]))
+        .map_err(|e| ManifestError::Custom(Box::new(e)))
+}
+
+impl PluginManifest {
+    /// the message the publisher signs
+    pub fn sighash(&self) -> Result<Message, ManifestError> {
+        let tag = sha256::Hash::hash(MANIFEST_TAG);
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(&serde_json::to_vec(self).map_err(|e| ManifestError::Custom(Box::new(e)))?);
+        Message::from_digest_slice(&sha256::Hash::from_engine(engine)[..])
+            .map_err(|e| ManifestError::Custom(Box::new(e)))
+    }
+    /// sign the manifest with the publisher's key
+    pub fn sign<C: Signing>(
+        self,
+        secp: &Secp256k1<C>,
+        key: &Keypair,
+    ) -> Result<SignedPluginManifest, ManifestError> {
+        if XOnlyPublicKey::from_keypair(key).0 != self.publisher {
+            return Err(ManifestError::BadSignature);
+        }
+        let signature = secp.sign_schnorr_no_aux_rand(&self.sighash()?, key);
+        Ok(SignedPluginManifest {
+            manifest: self,
+            signature,
+        })
+    }
+}
+
+impl SignedPluginManifest {
+    /// check the publisher's signature
+    pub fn verify_signature<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+    ) -> Result<(), ManifestError> {
+        secp.verify_schnorr(
+            &self.signature,
+            &self.manifest.sighash()?,
+            &self.manifest.publisher,
+        )
+        .map_err(|_| ManifestError::BadSignature)
+    }
+    /// check that the manifest is for `module` and allows all its imports
+    pub fn check_module<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        module: &str,
+        imports: I,
+    ) -> Result<(), ManifestError> {
+        if self.manifest.module != module {
+            return Err(ManifestError::ModuleMismatch {
+                expected: self.manifest.module.clone(),
+                found: module.into(),
+            });
+        }
+        for import in imports {
+            if !self.manifest.allowed_imports.contains(import) {
+                return Err(ManifestError::ImportNotAllowed(import.into()));
+            }
+        }
+        Ok(())
+    }
+    /// check that `api` matches the manifest
+    pub fn check_api(&self, api: &serde_json::Value) -> Result<(), ManifestError> {
+        if hash_api(api)? != self.manifest.api_schema_hash {
+            return Err(ManifestError::ApiSchemaMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// # Verified Publisher
+/// A publisher whose signature on a module's manifest was checked
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedPublisher {
+    /// # Label
+    /// The name the trust list gives the publisher
+    pub label: String,
+    /// # Key
+    #[schemars(with = "String")]
+    pub key: XOnlyPublicKey,
+    /// # Version
+    /// The version of the module the publisher signed
+    pub version: String,
+}
+
+/// # Publisher Trust
+/// Which plugin publishers the host trusts
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PublisherTrust {
+    /// # Require Signed
+    /// Refuse to run plugins without a manifest signed by a trusted publisher
+    #[serde(default)]
+    pub require_signed: bool,
+    /// # Publishers
+    /// Trusted publisher keys, by label
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub publishers: BTreeMap<String, XOnlyPublicKey>,
+}
+
+impl PublisherTrust {
+    /// Checks a module's manifest (if any) against the trust list.
+    ///
+    /// Returns the publisher if it is trusted. A module without a trusted
+    /// publisher is an error only if `require_signed` is set, but a bad
+    /// signature is always an error.
+    pub fn check(
+        &self,
+        module: &str,
+        manifest: Option<&SignedPluginManifest>,
+    ) -> Result<Option<VerifiedPublisher>, ManifestError> {
+        let manifest = match manifest {
+            Some(m) => m,
+            None if self.require_signed => return Err(ManifestError::Unsigned(module.into())),
+            None => return Ok(None),
+        };
+        manifest.verify_signature(&Secp256k1::verification_only())?;
+        let key = manifest.manifest.publisher;
+        match self.publishers.iter().find(|(_, k)| **k == key) {
+            Some((label, _)) => Ok(Some(VerifiedPublisher {
+                label: label.clone(),
+                key,
+                version: manifest.manifest.version.clone(),
+            })),
+            None if self.require_signed => Err(ManifestError::UntrustedPublisher(key)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_manifest() {
+        let secp = Secp256k1::new();
+        let key = Keypair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let publisher = XOnlyPublicKey::from_keypair(&key).0;
+        let api = serde_json::json!({"arguments": {}, "returns": {}});
+        let manifest = PluginManifest {
+            name: "test".into(),
+            version: "1.0.0".into(),
+            publisher,
+            module: "ab".repeat(32),
+            api_schema_hash: hash_api(&api).unwrap(),
+            allowed_imports: vec!["sapio_v1_wasm_plugin_debug_log_string".to_string()]
+                .into_iter()
+                .collect(),
+            dependencies: Default::default(),
+        };
+        let signed = manifest.clone().sign(&secp, &key).unwrap();
+        signed.verify_signature(&secp).unwrap();
+        signed.check_api(&api).unwrap();
+        signed
+            .check_module(
+                &"ab".repeat(32),
+                vec!["sapio_v1_wasm_plugin_debug_log_string"],
+            )
+            .unwrap();
+        assert!(matches!(
+            signed.check_module(
+                &"ab".repeat(32),
+                vec!["sapio_v1_wasm_plugin_create_contract"]
+            ),
+            Err(ManifestError::ImportNotAllowed(_))
+        ));
+        let mut tampered = signed.clone();
+        tampered.manifest.version = "2.0.0".into();
+        assert!(tampered.verify_signature(&secp).is_err());
+
+        let mut trust = PublisherTrust::default();
+        assert_eq!(trust.check("m", Some(&signed)).unwrap(), None);
+        trust.require_signed = true;
+        assert!(matches!(
+            trust.check("m", None),
+            Err(ManifestError::Unsigned(_))
+        ));
+        assert!(matches!(
+            trust.check("m", Some(&signed)),
+            Err(ManifestError::UntrustedPublisher(_))
+        ));
+        trust.publishers.insert("judica".into(), publisher);
+        assert_eq!(
+            trust.check("m", Some(&signed)).unwrap().unwrap().label,
+            "judica"
+        );
+        assert!(trust.check("m", Some(&tampered)).is_err());
+    }
+}
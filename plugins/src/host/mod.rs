@@ -12,14 +12,18 @@ pub use plugin_handle::WasmPluginHandle;
 use sapio::contract::CompilationError;
 use sapio_base::plugin_args::CreateArgs;
 use sapio_ctv_emulator_trait::CTVEmulator;
-use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use wasmer::*;
 
+pub mod abi;
+pub mod compile_cache;
+pub mod limits;
+pub mod manifest;
 pub mod plugin_handle;
+pub mod registry;
 pub mod wasm_cache;
 
 /// The state that host-side functions need to be able to use
@@ -32,24 +36,87 @@ pub struct HostEnvironmentInner {
     pub store: Arc<Mutex<Store>>,
     pub net: bitcoin::Network,
     pub emulator: Arc<dyn CTVEmulator>,
+    pub limits: limits::PluginLimits,
+    pub trust: manifest::PublisherTrust,
+    /// structured log records from this plugin (and plugins it called)
+    pub logs: Arc<Mutex<Vec<crate::log::LogRecord>>>,
+    /// the EffectPath of the contract being created, if any
+    pub current_path: Option<String>,
+    /// where compilations are cached, if they are
+    pub compile_cache: Option<compile_cache::CacheScope>,
+    /// how this plugin's (and the plugins it called) compilations were served
+    pub cache_stats: Arc<Mutex<compile_cache::CacheStats>>,
+    /// cancels this plugin's calls (and those of plugins it calls), if set
+    pub cancel: Option<limits::CancelToken>,
+    /// raised when this instance's current call should stop
+    pub interrupt: limits::Interrupt,
     #[wasmer(export)]
     pub memory: LazyInit<Memory>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_allocate_bytes"))]
+    /// the fuel left for the current call, taken when it is interrupted
+    #[wasmer(export(name = "wasmer_metering_remaining_points"))]
+    pub remaining_fuel: LazyInit<Global>,
+    // the v1 exports are optional, since v2 plugins need not have them
+    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_allocate_bytes", optional = true))]
     pub allocate_wasm_bytes: LazyInit<NativeFunc<i32, i32>>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_create_arguments"))]
+    /// used to pass results to v2 plugins without `allocate_wasm_bytes`
+    #[wasmer(export(name = "cabi_realloc", optional = true))]
+    pub cabi_realloc: LazyInit<NativeFunc<(i32, i32, i32, i32), i32>>,
+    #[wasmer(export(
+        name = "sapio_v1_wasm_plugin_client_get_create_arguments",
+        optional = true
+    ))]
     pub get_api: LazyInit<NativeFunc<(), i32>>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_name"))]
+    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_name", optional = true))]
     pub get_name: LazyInit<NativeFunc<(), i32>>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_logo"))]
+    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_logo", optional = true))]
     pub get_logo: LazyInit<NativeFunc<(), i32>>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_drop_allocation"))]
+    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_drop_allocation", optional = true))]
     pub forget: LazyInit<NativeFunc<i32, ()>>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_create"))]
+    #[wasmer(export(name = "sapio_v1_wasm_plugin_client_create", optional = true))]
     pub create: LazyInit<NativeFunc<(i32, i32), i32>>,
-    #[wasmer(export(name = "sapio_v1_wasm_plugin_entry_point"))]
+    #[wasmer(export(name = "sapio_v1_wasm_plugin_entry_point", optional = true))]
     pub init: LazyInit<NativeFunc<(), ()>>,
 }
 
+impl HostEnvironmentInner {
+    /// stop the current call if it was interrupted, see `limits::Interrupt`
+    fn poll_interrupt(&self) {
+        if let Some(remaining) = self.remaining_fuel.get_ref() {
+            self.interrupt.poll(remaining);
+        }
+    }
+
+    /// the same settings, with none of the state of a call or an instance
+    pub fn fresh(&self) -> Self {
+        HostEnvironmentInner {
+            path: self.path.clone(),
+            this: self.this,
+            module_map: self.module_map.clone(),
+            store: self.store.clone(),
+            net: self.net,
+            emulator: self.emulator.clone(),
+            limits: self.limits,
+            trust: self.trust.clone(),
+            logs: Default::default(),
+            current_path: None,
+            compile_cache: None,
+            cache_stats: Default::default(),
+            cancel: None,
+            interrupt: Default::default(),
+            memory: LazyInit::new(),
+            remaining_fuel: LazyInit::new(),
+            get_api: LazyInit::new(),
+            get_name: LazyInit::new(),
+            get_logo: LazyInit::new(),
+            forget: LazyInit::new(),
+            create: LazyInit::new(),
+            init: LazyInit::new(),
+            allocate_wasm_bytes: LazyInit::new(),
+            cabi_realloc: LazyInit::new(),
+        }
+    }
+}
+
 /// Wrapped Plugin Env so that we don't duplicate state for each function.
 /// We must be careful to ensure we don't see deadlocks.
 ///
@@ -65,10 +132,78 @@ mod exports {
 
     use super::*;
     use sapio_base::effects::EffectPath;
+    fn memory(env: &HostEnvironmentInner) -> Result<&Memory, CompilationError> {
+        env.memory_ref().ok_or_else(|| {
+            CompilationError::ModuleFailedToGetMemory("Memory Not Initialized".into())
+        })
+    }
+
+    /// read `len` bytes of plugin memory at `at`
+    fn read_bytes(
+        env: &HostEnvironmentInner,
+        at: i32,
+        len: i32,
+    ) -> Result<Vec<u8>, CompilationError> {
+        abi::read_bytes(memory(env)?, at, len)
+    }
+
+    /// write a found key to `out` and set `ok`. If `out` is out of bounds,
+    /// `ok` is not set.
+    fn write_key(env: &HostEnvironmentInner, key: Option<[u8; 32]>, out: i32, ok: i32) {
+        let _ = (|| {
+            let memory = memory(env)?;
+            let is_ok = match key {
+                Some(b) => {
+                    abi::write_bytes(memory, out, &b)?;
+                    1
+                }
+                None => 0,
+            };
+            abi::write_bytes(memory, ok, &[is_ok])
+        })();
+    }
+
+    /// copy `s` into plugin memory as a NUL terminated string, which the
+    /// plugin must free. v2 plugins without `allocate_wasm_bytes` get an
+    /// allocation from `cabi_realloc`.
+    fn pass_string(env: &HostEnvironmentInner, s: &str) -> Result<i32, CompilationError> {
+        let len = s.len() as i32;
+        let ptr = if let Some(allocate) = env.allocate_wasm_bytes_ref() {
+            allocate.call(len)
+        } else if let Some(realloc) = env.cabi_realloc_ref() {
+            realloc.call(0, 0, 1, len + 1)
+        } else {
+            return Err(CompilationError::ModuleCouldNotFindFunction(
+                "allocate_wasm_bytes".into(),
+            ));
+        }
+        .map_err(|e| CompilationError::ModuleCouldNotAllocateError(len, e.into()))?;
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        abi::write_bytes(memory(env)?, ptr, &bytes)?;
+        Ok(ptr)
+    }
+
+    /// the module of the newest version of `name` in the registry matching
+    /// `req`, if the name belongs to a trusted publisher
+    fn resolve_registry(
+        env: &HostEnvironmentInner,
+        name: &str,
+        req: &semver::VersionReq,
+    ) -> Option<[u8; 32]> {
+        let registry = registry::PluginRegistry::open(&env.path).ok()?;
+        let entry = registry.resolve_trusted(name, req, &env.trust)?;
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(&entry.module, &mut key).ok()?;
+        Some(key)
+    }
+
     /// lookup a plugin key from a human reable name.
     /// if ok == 1, result is valid.
     /// out is written and must be 32 bytes of writable memory.
     /// if name == 0 and name_len == 0, then return the current module
+    /// names not in the module map resolve to their newest registered version,
+    /// if a trusted publisher registered them
     pub fn sapio_v1_wasm_plugin_lookup_module_name(
         env: &HostEnvironment,
         key: i32,
@@ -77,35 +212,41 @@ mod exports {
         ok: i32,
     ) {
         let env = env.lock().unwrap();
-        let m_hash = {
-            if key == 0 && len == 0 {
-                Some(&env.this)
-            } else {
-                let mut buf = vec![0u8; len as usize];
-                for (src, dst) in env.memory_ref().unwrap().view()
-                    [key as usize..(key + len) as usize]
-                    .iter()
-                    .map(Cell::get)
-                    .zip(buf.iter_mut())
-                {
-                    *dst = src;
-                }
-                env.module_map.get(&buf)
-            }
-        };
-        let is_ok = if let Some(b) = m_hash {
-            let out = out as usize;
-            for (src, dst) in b
-                .iter()
-                .zip(env.memory_ref().unwrap().view::<u8>()[out..out + 32].iter())
-            {
-                dst.set(*src);
-            }
-            1
+        env.poll_interrupt();
+        let m_hash = if key == 0 && len == 0 {
+            Some(env.this)
         } else {
-            0
+            read_bytes(&env, key, len).ok().and_then(|buf| {
+                env.module_map.get(&buf).cloned().or_else(|| {
+                    let name = String::from_utf8(buf).ok()?;
+                    resolve_registry(&env, &name, &semver::VersionReq::STAR)
+                })
+            })
         };
-        env.memory_ref().unwrap().view::<u8>()[ok as usize].set(is_ok);
+        write_key(&env, m_hash, out, ok);
+    }
+
+    /// lookup a plugin key from the registry by name and semver requirement
+    /// (e.g. "^1.2"), choosing the newest matching version. Only names which
+    /// a trusted publisher registered are found.
+    /// if ok == 1, result is valid.
+    /// out is written and must be 32 bytes of writable memory.
+    pub fn sapio_v1_wasm_plugin_lookup_module_version(
+        env: &HostEnvironment,
+        name: i32,
+        name_len: i32,
+        req: i32,
+        req_len: i32,
+        out: i32,
+        ok: i32,
+    ) {
+        let env = env.lock().unwrap();
+        env.poll_interrupt();
+        let read_string = |at, len| String::from_utf8(read_bytes(&env, at, len).ok()?).ok();
+        let m_hash = read_string(name, name_len)
+            .zip(read_string(req, req_len).and_then(|r| semver::VersionReq::parse(&r).ok()))
+            .and_then(|(name, req)| resolve_registry(&env, &name, &req));
+        write_key(&env, m_hash, out, ok);
     }
 
     /// Create an instance of a contract by "trampolining" through the host to use another
@@ -158,27 +299,22 @@ mod exports {
 
     fn wasm_plugin_action(env: &HostEnvironment, key: i32, action: Action) -> i32 {
         let env = env.lock().unwrap();
-        const KEY_LEN: usize = 32;
-        let key = key as usize;
-        let h = wasmer_cache::Hash::new({
-            let mut buf = [0u8; KEY_LEN];
-            for (src, dst) in env.memory_ref().unwrap().view()[key..key + KEY_LEN]
-                .iter()
-                .map(Cell::get)
-                .zip(buf.iter_mut())
-            {
-                *dst = src;
+        env.poll_interrupt();
+        let h = match read_bytes(&env, key, 32) {
+            Ok(buf) => {
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&buf);
+                wasmer_cache::Hash::new(key).to_string()
             }
-            buf
-        })
-        .to_string();
+            Err(_) => return 0,
+        };
         enum InternalAction {
             GetAPI,
             GetName,
             GetLogo,
             Create(CreateArgs<serde_json::Value>, EffectPath),
         }
-        let action_to_take = match action {
+        let action_to_take: Result<InternalAction, CompilationError> = match action {
             Action::GetAPI => Ok(InternalAction::GetAPI),
             Action::GetName => Ok(InternalAction::GetName),
             Action::GetLogo => Ok(InternalAction::GetLogo),
@@ -188,46 +324,25 @@ mod exports {
                 json,
                 json_len,
                 ..
-            } => {
-                // use this buffer twice, so make it the max size
-                let mut v = vec![0u8; std::cmp::max(json_len, path_len) as usize];
-                for (src, dst) in env.memory_ref().unwrap().view()
-                    [json as usize..(json + json_len) as usize]
-                    .iter()
-                    .map(Cell::get)
-                    .zip(v.iter_mut())
-                {
-                    *dst = src;
-                }
-                let create_args = serde_json::from_str(
-                    &String::from_utf8_lossy(&v[..json_len as usize])
-                        .to_owned()
-                        .to_string(),
-                )
-                .map_err(CompilationError::DeserializationError);
-
-                for (src, dst) in env.memory_ref().unwrap().view()
-                    [path as usize..(path + path_len) as usize]
-                    .iter()
-                    .map(Cell::get)
-                    .zip(v.iter_mut())
-                {
-                    *dst = src;
-                }
-                let effectpath: Result<EffectPath, _> = serde_json::from_str(
-                    &String::from_utf8_lossy(&v[..path_len as usize])
-                        .to_owned()
-                        .to_string(),
-                )
-                .map_err(CompilationError::DeserializationError);
-
-                create_args.and_then(|c| effectpath.map(|e| InternalAction::Create(c, e)))
-            }
+            } => (|| {
+                let create_args = serde_json::from_slice(&read_bytes(&env, json, json_len)?)
+                    .map_err(CompilationError::DeserializationError)?;
+                let effectpath: EffectPath =
+                    serde_json::from_slice(&read_bytes(&env, path, path_len)?)
+                        .map_err(CompilationError::DeserializationError)?;
+                Ok(InternalAction::Create(create_args, effectpath))
+            })(),
         };
         let emulator = env.emulator.clone();
         let mmap = env.module_map.clone();
         let path = env.path.clone();
         let net = env.net;
+        let limits = env.limits;
+        let trust = env.trust.clone();
+        let logs = env.logs.clone();
+        let compile_cache = env.compile_cache.clone();
+        let cache_stats = env.cache_stats.clone();
+        let cancel = env.cancel.clone();
         let key = wasmer_cache::Hash::from_str(&h).map(SyncModuleLocator::Key);
         // Use serde_json::Value for the WasmPluginHandle Output type
         match key.map(|module_locator| {
@@ -237,9 +352,15 @@ mod exports {
                 module_locator,
                 net,
                 Some(mmap),
+                limits,
+                &trust,
             )
         }) {
             Ok(Ok(sph)) => {
+                sph.set_compile_cache(compile_cache);
+                sph.set_cancel(cancel);
+                let nested_logs = sph.log_sink();
+                let nested_stats = sph.cache_stats_sink();
                 let comp_s = (move || -> Result<serde_json::Value, CompilationError> {
                     let value = match action_to_take? {
                         InternalAction::GetName => Ok(sph.get_name().and_then(|m| {
@@ -260,31 +381,19 @@ mod exports {
                     };
                     Ok(value??)
                 })();
+                {
+                    let mut logs = logs.lock().unwrap();
+                    for record in nested_logs.lock().unwrap().drain(..) {
+                        crate::log::push_capped(&mut logs, record);
+                    }
+                }
+                let nested_stats = *nested_stats.lock().unwrap();
+                cache_stats.lock().unwrap().merge(nested_stats);
                 return (move || -> Result<i32, CompilationError> {
                     // serialize the reuslt, not just the output.
                     let comp_s = serde_json::to_string(&comp_s.map_err(|s| s.to_string()))
                         .map_err(CompilationError::SerializationError)?;
-                    let bytes: i32 = env
-                        .allocate_wasm_bytes_ref()
-                        .ok_or_else(|| {
-                            CompilationError::ModuleCouldNotFindFunction(
-                                "allocate_wasm_bytes".into(),
-                            )
-                        })?
-                        .call(comp_s.len() as i32)
-                        .map_err(|e| {
-                            CompilationError::ModuleCouldNotAllocateError(
-                                comp_s.len() as i32,
-                                e.into(),
-                            )
-                        })?;
-                    for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
-                        .iter()
-                        .zip(comp_s.as_bytes())
-                    {
-                        byte.set(*c);
-                    }
-                    Ok(bytes)
+                    pass_string(&env, &comp_s)
                 })()
                 .unwrap_or(0);
             }
@@ -295,45 +404,51 @@ mod exports {
     /// use the hosts stdout to log a string. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(env: &HostEnvironment, a: i32, len: i32) {
         let env = env.lock().unwrap();
+        env.poll_interrupt();
         let stdout = std::io::stdout();
         let lock = stdout.lock();
         let mut w = std::io::BufWriter::new(lock);
-        let mem = env.memory_ref().unwrap().view::<u8>();
-        for byte in mem[a as usize..(a + len) as usize].iter().map(Cell::get) {
-            w.write(&[byte]).unwrap();
+        if let Ok(bytes) = read_bytes(&env, a, len) {
+            w.write_all(&bytes).unwrap();
         }
         w.write("\n".as_bytes()).unwrap();
     }
 
+    /// record a structured log record (JSON `LogRecord`) from the plugin,
+    /// and emit it to `tracing`.
+    pub fn sapio_v1_wasm_plugin_log(env: &HostEnvironment, record: i32, len: i32) {
+        let env = env.lock().unwrap();
+        env.poll_interrupt();
+        let parsed = read_bytes(&env, record, len)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()));
+        let mut record: crate::log::LogRecord = match parsed {
+            Ok(r) => r,
+            Err(e) => crate::log::LogRecord {
+                level: crate::log::LogLevel::Warn,
+                target: "sapio_plugin".into(),
+                message: format!("Malformed log record: {}", e),
+                fields: Default::default(),
+                module: None,
+                path: None,
+            },
+        };
+        record.module = Some(hex::encode(env.this));
+        if record.path.is_none() {
+            record.path = env.current_path.clone();
+        }
+        record.emit();
+        crate::log::push_capped(&mut env.logs.lock().unwrap(), record);
+    }
+
     /// for the provided hash value, get the clause the oracle will satisfy
     pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(env: &HostEnvironment, hash: i32) -> i32 {
         let env = env.lock().unwrap();
-        let hash = hash as usize;
-        let h = sha256::Hash::from_inner({
-            let mut buf = [0u8; 32];
-            for (src, dst) in env.memory_ref().unwrap().view()[hash..hash + 32]
-                .iter()
-                .map(Cell::get)
-                .zip(buf.iter_mut())
-            {
-                *dst = src;
-            }
-            buf
-        });
+        env.poll_interrupt();
+        let h = sha256::Hash::from_slice(&read_bytes(&env, hash, 32).unwrap()).unwrap();
         let clause = env.emulator.get_signer_for(h).unwrap();
         let s = serde_json::to_string_pretty(&clause).unwrap();
-        let bytes = env
-            .allocate_wasm_bytes_ref()
-            .unwrap()
-            .call(s.len() as i32)
-            .unwrap();
-        for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
-            .iter()
-            .zip(s.as_bytes())
-        {
-            byte.set(*c);
-        }
-        bytes
+        pass_string(&env, &s).unwrap()
     }
 
     /// get the oracle to sign the psbt passed in
@@ -343,30 +458,11 @@ mod exports {
         len: u32,
     ) -> i32 {
         let env = env.lock().unwrap();
-        let mut buf = vec![0u8; len as usize];
-        let psbt = psbt as usize;
-        for (src, dst) in env.memory_ref().unwrap().view()[psbt..]
-            .iter()
-            .map(Cell::get)
-            .zip(buf.iter_mut())
-        {
-            *dst = src;
-        }
+        env.poll_interrupt();
+        let buf = read_bytes(&env, psbt, len as i32).unwrap();
         let psbt: PartiallySignedTransaction = serde_json::from_slice(&buf[..]).unwrap();
         let psbt = env.emulator.sign(psbt).unwrap();
-        buf.clear();
         let s = serde_json::to_string_pretty(&psbt).unwrap();
-        let bytes = env
-            .allocate_wasm_bytes_ref()
-            .unwrap()
-            .call(s.len() as i32)
-            .unwrap();
-        for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
-            .iter()
-            .zip(s.as_bytes())
-        {
-            byte.set(*c);
-        }
-        bytes
+        pass_string(&env, &s).unwrap()
     }
 }
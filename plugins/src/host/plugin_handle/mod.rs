@@ -8,13 +8,12 @@ use super::wasm_cache;
 use crate::CreateArgs;
 
 use sapio_ctv_emulator_trait::NullEmulator;
-use std::cell::Cell;
 use std::collections::BTreeMap;
 
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 pub use wasm::*;
-use wasmer::{imports, Function, ImportObject, Instance, LazyInit, MemoryView, Module, Store};
+use wasmer::{imports, Function, ImportObject, Instance, LazyInit, Module, Store};
 pub use wasmer_cache::Hash as WASMCacheID;
 
 mod wasm;
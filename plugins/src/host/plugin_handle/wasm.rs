@@ -6,11 +6,22 @@
 
 //!  a plugin handle for a wasm plugin.
 use super::*;
+use crate::host::abi::{self, AbiVersion, V2Exports};
+use crate::host::compile_cache::{CacheKeyMaterial, CacheScope, CacheStats};
 use crate::host::exports::*;
+use crate::host::limits::{is_metered, CancelToken, Meter, PluginLimits};
+use crate::host::manifest::{
+    hash_api, ManifestError, PluginManifest, PublisherTrust, SignedPluginManifest,
+    VerifiedPublisher,
+};
+use crate::host::registry::{RegistryEntry, REGISTRY_FILE};
 use crate::host::wasm_cache::get_all_keys_from_fs;
 use crate::host::{HostEnvironment, HostEnvironmentInner};
+use crate::log::LogRecord;
 use crate::plugin_handle::PluginHandle;
 use crate::API;
+use bitcoin::hashes::sha256;
+use sapio::contract::error::ModuleLimit;
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 use sapio_ctv_emulator_trait::CTVEmulator;
@@ -19,12 +30,17 @@ use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::marker::PhantomData;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use wasmer::Memory;
+/// Where to find a module. A module loaded from bytes may come with a signed
+/// manifest; for a file, the manifest is read from `<file>.manifest` if it
+/// exists.
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub enum ModuleLocator {
     Key(String),
     FileName(String),
     Bytes(Vec<u8>),
+    SignedBytes(Vec<u8>, SignedPluginManifest),
     Unknown,
 }
 
@@ -35,8 +51,21 @@ impl ModuleLocator {
                 let key = WASMCacheID::from_str(&k)?;
                 Ok(SyncModuleLocator::Key(key))
             }
-            ModuleLocator::FileName(f) => Ok(SyncModuleLocator::Bytes(tokio::fs::read(f).await?)),
+            ModuleLocator::FileName(f) => {
+                let bytes = tokio::fs::read(&f).await?;
+                match tokio::fs::read(format!("{}.manifest", f)).await {
+                    Ok(m) => Ok(SyncModuleLocator::SignedBytes(
+                        bytes,
+                        serde_json::from_slice(&m[..])?,
+                    )),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        Ok(SyncModuleLocator::Bytes(bytes))
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
             ModuleLocator::Bytes(b) => Ok(SyncModuleLocator::Bytes(b)),
+            ModuleLocator::SignedBytes(b, m) => Ok(SyncModuleLocator::SignedBytes(b, m)),
             ModuleLocator::Unknown => Err(Err(CompilationError::UnknownModule)?),
         }
     }
@@ -44,6 +73,7 @@ impl ModuleLocator {
 pub enum SyncModuleLocator {
     Key(wasmer_cache::Hash),
     Bytes(Vec<u8>),
+    SignedBytes(Vec<u8>, SignedPluginManifest),
 }
 pub struct WasmPluginHandle<Output> {
     store: Store,
@@ -53,6 +83,11 @@ pub struct WasmPluginHandle<Output> {
     instance: Instance,
     key: wasmer_cache::Hash,
     net: bitcoin::Network,
+    meter: Meter,
+    publisher: Option<VerifiedPublisher>,
+    manifest: Option<SignedPluginManifest>,
+    /// set for plugins built against the v2 ABI
+    v2: Option<V2Exports>,
     _pd: PhantomData<Output>,
 }
 impl<Output> WasmPluginHandle<Output> {
@@ -61,23 +96,85 @@ impl<Output> WasmPluginHandle<Output> {
         self.key
     }
 
+    /// the publisher of this plugin, if its manifest is signed by a trusted
+    /// publisher
+    pub fn publisher(&self) -> Option<&VerifiedPublisher> {
+        self.publisher.as_ref()
+    }
+    /// the structured log records of this plugin, and of the plugins it
+    /// called, since they were last taken. At most `log::MAX_LOG_RECORDS` are kept.
+    pub fn logs(&self) -> Vec<LogRecord> {
+        self.log_sink().lock().unwrap().clone()
+    }
+    /// take the structured log records of this plugin (and the plugins it
+    /// called), leaving none
+    pub fn take_logs(&self) -> Vec<LogRecord> {
+        std::mem::take(&mut *self.log_sink().lock().unwrap())
+    }
+    pub(crate) fn log_sink(&self) -> Arc<Mutex<Vec<LogRecord>>> {
+        self.env.lock().unwrap().logs.clone()
+    }
+    /// the ABI the plugin was built against
+    pub fn abi(&self) -> AbiVersion {
+        if self.v2.is_some() {
+            AbiVersion::V2
+        } else {
+            AbiVersion::V1
+        }
+    }
+    /// cache this plugin's compilations (and those of the plugins it calls)
+    /// in `scope`, or stop caching them
+    pub fn set_compile_cache(&self, scope: Option<CacheScope>) {
+        self.env.lock().unwrap().compile_cache = scope;
+    }
+    /// cancel this plugin's calls (and those of the plugins it calls) with
+    /// `token`, or stop doing so
+    pub fn set_cancel(&self, token: Option<CancelToken>) {
+        self.meter.set_cancel(token.clone());
+        self.env.lock().unwrap().cancel = token;
+    }
+    /// how this plugin's (and the plugins it called) compilations were
+    /// served by the cache so far
+    pub fn cache_stats(&self) -> CacheStats {
+        *self.cache_stats_sink().lock().unwrap()
+    }
+    /// take the cache stats, resetting them
+    pub fn take_cache_stats(&self) -> CacheStats {
+        std::mem::take(&mut *self.cache_stats_sink().lock().unwrap())
+    }
+    pub(crate) fn cache_stats_sink(&self) -> Arc<Mutex<CacheStats>> {
+        self.env.lock().unwrap().cache_stats.clone()
+    }
+    /// the plugin's signed manifest, if it has one
+    pub fn manifest(&self) -> Option<&SignedPluginManifest> {
+        self.manifest.as_ref()
+    }
+
     /// load all the cached keys as plugins upfront.
     pub fn load_all_keys<I: Into<PathBuf> + Clone>(
         path: I,
         emulator: NullEmulator,
         net: bitcoin::Network,
         plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+        limits: PluginLimits,
+        trust: &PublisherTrust,
     ) -> Result<Vec<Self>, Box<dyn Error>> {
         let mut r = vec![];
         for key in get_all_keys_from_fs(path.clone())? {
-            let wph = Self::new(
+            match Self::new(
                 path.clone(),
                 &emulator,
                 SyncModuleLocator::Key(WASMCacheID::from_str(&key)?),
                 net,
                 plugin_map.clone(),
-            )?;
-            r.push(wph)
+                limits,
+                trust,
+            ) {
+                Ok(wph) => r.push(wph),
+                // plugins the trust list rejects can't be run, so skip them
+                Err(e) if e.is::<ManifestError>() => continue,
+                Err(e) => return Err(e),
+            }
         }
         Ok(r)
     }
@@ -88,6 +185,8 @@ impl<Output> WasmPluginHandle<Output> {
         module_locator: ModuleLocator,
         net: bitcoin::Network,
         plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+        limits: PluginLimits,
+        trust: &PublisherTrust,
     ) -> Result<Self, Box<dyn Error>> {
         Self::new(
             path,
@@ -95,10 +194,19 @@ impl<Output> WasmPluginHandle<Output> {
             module_locator.locate().await?,
             net,
             plugin_map,
+            limits,
+            trust,
         )
     }
     /// Create an plugin handle. Only one of key or file should be set, and one
     /// should be set.
+    ///
+    /// Every call into the plugin (including its initialization) is subject
+    /// to `limits`.
+    ///
+    /// The module's manifest (given with the bytes, or stored in the cache
+    /// when the module was loaded) is checked against `trust`.
+    /// A new manifest is stored in the cache once it has been checked.
     /// TODO: Revert to async?
     pub fn new<I: Into<PathBuf> + Clone>(
         path: I,
@@ -106,44 +214,81 @@ impl<Output> WasmPluginHandle<Output> {
         module_locator: SyncModuleLocator,
         net: bitcoin::Network,
         plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
+        limits: PluginLimits,
+        trust: &PublisherTrust,
     ) -> Result<Self, Box<dyn Error>> {
-        let store = Store::default();
+        let (store, memory_refused) = limits.store();
 
-        let (module, key) = match module_locator {
-            SyncModuleLocator::Bytes(wasm_bytes) => {
-                match wasm_cache::load_module(path.clone(), &store, &wasm_bytes[..]) {
-                    Ok(module) => module,
-                    Err(_) => {
-                        let store = Store::default();
-                        let module = Module::new(&store, &wasm_bytes)?;
-                        let key = wasm_cache::store_module(path.clone(), &module, &wasm_bytes)?;
-                        (module, key)
-                    }
+        // returns the bytes of modules which still need to be cached
+        let compile = |wasm_bytes: Vec<u8>| -> Result<_, Box<dyn Error>> {
+            match wasm_cache::load_module(path.clone(), &store, &wasm_bytes[..]) {
+                // modules cached before metering was added get recompiled
+                Ok((module, key)) if is_metered(&module) => Ok((module, key, None)),
+                _ => {
+                    let module = crate::host::limits::compile(&store, &wasm_bytes[..])?;
+                    let key = WASMCacheID::generate(&wasm_bytes[..]);
+                    Ok((module, key, Some(wasm_bytes)))
                 }
             }
-            SyncModuleLocator::Key(key) => wasm_cache::load_module_key(path.clone(), &store, key)?,
         };
-
-        macro_rules! create_imports {
-            ($store:ident, $env:ident $(,$names:ident)*) =>
-            {
-                imports! {
-                    "env" =>  {
-                        $( std::stringify!($names) => Function::new_native_with_env( &$store, $env.clone(), $names) ,)*
-                    }
+        let mut new_manifest = None;
+        let (module, key, uncached) = match module_locator {
+            SyncModuleLocator::Bytes(wasm_bytes) => compile(wasm_bytes)?,
+            SyncModuleLocator::SignedBytes(wasm_bytes, manifest) => {
+                new_manifest = Some(manifest);
+                compile(wasm_bytes)?
+            }
+            SyncModuleLocator::Key(key) => {
+                let (module, key) = wasm_cache::load_module_key(path.clone(), &store, key)?;
+                if is_metered(&module) {
+                    (module, key, None)
+                } else {
+                    // recompile from the source stored with the module
+                    let wasm_bytes =
+                        wasm_cache::load_source(path.clone(), key)?.ok_or_else(|| {
+                            CompilationError::InternalModuleError(format!(
+                            "Module {} was cached without metering or its source, load it again",
+                            key.to_string()
+                        ))
+                        })?;
+                    compile(wasm_bytes)?
                 }
-            };
+            }
+        };
+        let id = key.to_string();
+        let manifest: Option<SignedPluginManifest> = match new_manifest.clone() {
+            Some(m) => Some(m),
+            None => wasm_cache::load_manifest(path.clone(), key)?,
+        };
+        if let Some(m) = &manifest {
+            let imports: Vec<String> = module.imports().map(|i| i.name().to_string()).collect();
+            m.check_module(&id, imports.iter().map(String::as_str))?;
+        }
+        let publisher = trust.check(&id, manifest.as_ref())?;
+        // only modules which are trusted are kept in the cache
+        if let Some(wasm_bytes) = uncached {
+            wasm_cache::store_module(path.clone(), &module, &wasm_bytes[..])?;
         }
+
         let mut this = [0; 32];
         this.clone_from_slice(&hex::decode(key.to_string())?);
-        let mut wasm_ctv_emulator = Arc::new(Mutex::new(HostEnvironmentInner {
-            path: path.into().clone(),
+        let env = HostEnvironmentInner {
+            path: path.clone().into(),
             this,
             module_map: plugin_map.unwrap_or_else(BTreeMap::new).into(),
             store: Arc::new(Mutex::new(store.clone())),
             net,
             emulator: emulator.clone(),
+            limits,
+            trust: trust.clone(),
+            logs: Default::default(),
+            current_path: None,
+            compile_cache: None,
+            cache_stats: Default::default(),
+            cancel: None,
+            interrupt: Default::default(),
             memory: LazyInit::new(),
+            remaining_fuel: LazyInit::new(),
             get_api: LazyInit::new(),
             get_name: LazyInit::new(),
             get_logo: LazyInit::new(),
@@ -151,30 +296,93 @@ impl<Output> WasmPluginHandle<Output> {
             create: LazyInit::new(),
             init: LazyInit::new(),
             allocate_wasm_bytes: LazyInit::new(),
-        }));
+            cabi_realloc: LazyInit::new(),
+        };
+        let this = Self::instantiate(store, memory_refused, env, module, key, publisher, manifest)?;
+        if let Some(m) = new_manifest {
+            m.check_api(&this.get_api_json()?)?;
+            wasm_cache::store_manifest(path, key, &m)?;
+        }
+        Ok(this)
+    }
+
+    /// A new instance of this plugin's module, sharing none of its state, for
+    /// reuse by another caller.
+    pub fn reinstantiate(&self) -> Result<Self, Box<dyn Error>> {
+        let env = self.env.lock().unwrap().fresh();
+        Self::instantiate(
+            self.store.clone(),
+            self.meter.memory_refused(),
+            env,
+            self.module.clone(),
+            self.key,
+            self.publisher.clone(),
+            self.manifest.clone(),
+        )
+    }
+
+    /// instantiate a checked module and run its entry point
+    fn instantiate(
+        store: Store,
+        memory_refused: Arc<AtomicBool>,
+        env: HostEnvironmentInner,
+        module: Module,
+        key: WASMCacheID,
+        publisher: Option<VerifiedPublisher>,
+        manifest: Option<SignedPluginManifest>,
+    ) -> Result<Self, Box<dyn Error>> {
+        macro_rules! create_imports {
+            ($store:ident, $env:ident $(,$names:ident)*) =>
+            {
+                imports! {
+                    "env" =>  {
+                        $( std::stringify!($names) => Function::new_native_with_env( &$store, $env.clone(), $names) ,)*
+                    }
+                }
+            };
+        }
+        let (net, limits, interrupt) = (env.net, env.limits, env.interrupt.clone());
+        let mut wasm_ctv_emulator = Arc::new(Mutex::new(env));
         let import_object = create_imports!(
             store,
             wasm_ctv_emulator,
             sapio_v1_wasm_plugin_ctv_emulator_signer_for,
             sapio_v1_wasm_plugin_ctv_emulator_sign,
             sapio_v1_wasm_plugin_debug_log_string,
+            sapio_v1_wasm_plugin_log,
             sapio_v1_wasm_plugin_create_contract,
             sapio_v1_wasm_plugin_get_api,
             sapio_v1_wasm_plugin_get_name,
             sapio_v1_wasm_plugin_get_logo,
-            sapio_v1_wasm_plugin_lookup_module_name
+            sapio_v1_wasm_plugin_lookup_module_name,
+            sapio_v1_wasm_plugin_lookup_module_version
         );
 
-        let instance = Instance::new(&module, &import_object)?;
+        let instance = Instance::new(&module, &import_object).map_err(|e| {
+            if memory_refused.load(Ordering::SeqCst) {
+                CompilationError::ModuleExceededLimit(ModuleLimit::Memory(
+                    limits.memory_pages.unwrap_or_default(),
+                ))
+                .into()
+            } else {
+                Box::<dyn Error>::from(e)
+            }
+        })?;
         use wasmer::WasmerEnv;
         wasm_ctv_emulator.init_with_instance(&instance)?;
+        let meter = Meter::new(&instance, limits, memory_refused, interrupt)?;
+        let v2 = match AbiVersion::detect(&module) {
+            AbiVersion::V1 => None,
+            AbiVersion::V2 => Some(V2Exports::new(&instance)?),
+        };
 
-        wasm_ctv_emulator
-            .lock()
-            .unwrap()
-            .init_ref()
-            .ok_or("No Init Function Specified")?
-            .call()?;
+        // v2 plugins need not have an entry point
+        let init = wasm_ctv_emulator.lock().unwrap().init_ref().cloned();
+        match init {
+            Some(init) => meter.run(|| init.call())??,
+            None if v2.is_none() => return Err("No Init Function Specified".into()),
+            None => {}
+        }
 
         Ok(WasmPluginHandle {
             store,
@@ -184,32 +392,174 @@ impl<Output> WasmPluginHandle<Output> {
             module,
             instance,
             key,
+            meter,
+            publisher,
+            manifest,
+            v2,
             _pd: Default::default(),
         })
     }
 
+    /// the plugin's API, as JSON
+    fn get_api_json(&self) -> Result<serde_json::Value, CompilationError> {
+        if let Some(v2) = &self.v2 {
+            let s = v2.get_api(&self.meter, self.get_memory()?)?;
+            return serde_json::from_str(&s).map_err(CompilationError::DeserializationError);
+        }
+        let f = self
+            .env
+            .lock()
+            .unwrap()
+            .get_api_ref()
+            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_api".into()))?
+            .clone();
+        let p = self
+            .meter
+            .run(|| f.call())?
+            .map_err(|e| CompilationError::ModuleCouldNotGetAPI(e.into()))?;
+        let v = self.read_to_vec(p)?;
+        self.forget(p)?;
+        serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)
+    }
+
+    /// A manifest for this plugin, for `publisher` to sign. It allows exactly
+    /// the host functions the module imports.
+    pub fn unsigned_manifest(
+        &self,
+        name: String,
+        version: String,
+        publisher: bitcoin::XOnlyPublicKey,
+    ) -> Result<PluginManifest, Box<dyn Error>> {
+        Ok(PluginManifest {
+            name,
+            version,
+            publisher,
+            module: self.key.to_string(),
+            api_schema_hash: hash_api(&self.get_api_json()?)?,
+            allowed_imports: self
+                .module
+                .imports()
+                .map(|i| i.name().to_string())
+                .collect(),
+            dependencies: Default::default(),
+        })
+    }
+
+    /// An entry for this plugin in a `PluginRegistry`, under the name,
+    /// publisher and dependencies in its manifest. The version defaults to
+    /// the manifest's. Only signed plugins can be registered.
+    pub fn registry_entry(
+        &self,
+        version: Option<semver::Version>,
+    ) -> Result<RegistryEntry, Box<dyn Error>> {
+        let m = match self.manifest.as_ref() {
+            Some(m) => &m.manifest,
+            None => return Err("Only plugins with a signed manifest can be registered".into()),
+        };
+        let version = match version {
+            Some(v) => v,
+            None => semver::Version::parse(&m.version)?,
+        };
+        let dependencies = m
+            .dependencies
+            .iter()
+            .map(|(n, r)| Ok((n.clone(), semver::VersionReq::parse(r)?)))
+            .collect::<Result<_, semver::Error>>()?;
+        Ok(RegistryEntry {
+            name: m.name.clone(),
+            version,
+            publisher: m.publisher,
+            module: self.key.to_string(),
+            api: self.get_api_json()?,
+            dependencies,
+        })
+    }
+
+    /// create an instance of the plugin's contract, as JSON
+    fn create_json(
+        &self,
+        path: &EffectPath,
+        c: &CreateArgs<serde_json::Value>,
+    ) -> Result<serde_json::Value, CompilationError> {
+        if let Some(v2) = &self.v2 {
+            let buf = v2.create(&self.meter, self.get_memory()?, path, c)?;
+            return serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError);
+        }
+        let arg_str = serde_json::to_string(c).map_err(CompilationError::SerializationError)?;
+        let args_ptr = self.pass_string(&arg_str)?;
+        let path_str = serde_json::to_string(path).map_err(CompilationError::SerializationError)?;
+        let path_ptr = self.pass_string(&path_str)?;
+        let create_func = self.env.lock().unwrap().create.clone();
+        let create_func = create_func
+            .get_ref()
+            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("create".into()))?;
+        let result_ptr = self
+            .meter
+            .run(|| create_func.call(path_ptr, args_ptr))?
+            .map_err(|e| {
+                CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e.into())
+            })?;
+        let buf = self.read_to_vec(result_ptr)?;
+        self.forget(result_ptr)?;
+        let v: Result<serde_json::Value, String> =
+            serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError)?;
+        v.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+    }
+
+    /// the cache for creating `c` at `path`, the directory its compilations
+    /// are stored under, and their key, if compilations are cached
+    fn cache_key(
+        &self,
+        path: &EffectPath,
+        c: &CreateArgs<serde_json::Value>,
+    ) -> Option<(CacheScope, PathBuf, sha256::Hash)> {
+        let env = self.env.lock().unwrap();
+        let scope = env.compile_cache.clone()?;
+        let registry = scope.cache.file_hash(&env.path.join(REGISTRY_FILE));
+        let key = CacheKeyMaterial {
+            module: self.key.to_string(),
+            path,
+            arguments: &c.arguments,
+            network: c.context.network,
+            amount: c.context.amount.as_sat(),
+            effects: c.context.effects.subtree(path),
+            module_map: &env.module_map,
+            limits: &env.limits,
+            registry,
+            salt: &scope.salt,
+        }
+        .key()
+        .ok()?;
+        Some((scope, env.path.clone(), key))
+    }
+
     /// forget an allocated pointer
     pub fn forget(&self, p: i32) -> Result<(), CompilationError> {
-        Ok(self
+        let forget = self
             .env
             .lock()
             .unwrap()
             .forget_ref()
             .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("forget".into()))?
-            .call(p)
-            .map_err(|e| CompilationError::ModuleCouldNotDeallocate(p, e.into()))?)
+            .clone();
+        self.meter
+            .run(|| forget.call(p))?
+            .map_err(|e| CompilationError::ModuleCouldNotDeallocate(p, e.into()))
     }
 
     /// create an allocation
     pub fn allocate(&self, len: i32) -> Result<i32, CompilationError> {
-        self.env
+        let allocate = self
+            .env
             .lock()
             .unwrap()
             .allocate_wasm_bytes_ref()
             .ok_or_else(|| {
                 CompilationError::ModuleCouldNotFindFunction("allocate_wasm_bytes".into())
             })?
-            .call(len)
+            .clone();
+        self.meter
+            .run(|| allocate.call(len))?
             .map_err(|e| CompilationError::ModuleCouldNotAllocateError(len, e.into()))
     }
 
@@ -227,12 +577,7 @@ impl<Output> WasmPluginHandle<Output> {
 
     /// helper for string passing
     fn pass_string_inner(&self, s: &str, offset: i32) -> Result<(), CompilationError> {
-        let memory = self.get_memory()?;
-        let mem: MemoryView<'_, u8> = memory.view();
-        for (idx, byte) in s.as_bytes().iter().enumerate() {
-            mem[idx + offset as usize].set(*byte);
-        }
-        Ok(())
+        abi::write_bytes(self.get_memory()?, offset, s.as_bytes())
     }
 
     fn get_memory(&self) -> Result<&Memory, CompilationError> {
@@ -243,13 +588,15 @@ impl<Output> WasmPluginHandle<Output> {
     }
     /// read something from wasm memory, null terminated
     fn read_to_vec(&self, p: i32) -> Result<Vec<u8>, CompilationError> {
-        let memory = self.get_memory()?;
-        let mem: MemoryView<'_, u8> = memory.view();
-        Ok(mem[p as usize..]
-            .iter()
-            .map(Cell::get)
-            .take_while(|i| *i != 0)
-            .collect())
+        abi::read_c_string(self.get_memory()?, p)
+    }
+}
+
+/// puts back the path a plugin was creating once a nested create returns
+struct RestorePath<'a>(&'a HostEnvironment, Option<String>);
+impl<'a> Drop for RestorePath<'a> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().current_path = self.1.take();
     }
 }
 
@@ -260,48 +607,46 @@ where
     type Input = CreateArgs<serde_json::Value>;
     type Output = GOutput;
     fn call(&self, path: &EffectPath, c: &Self::Input) -> Result<Self::Output, CompilationError> {
-        let arg_str = serde_json::to_string(c).map_err(CompilationError::SerializationError)?;
-        let args_ptr = self.pass_string(&arg_str)?;
-        let path_str = serde_json::to_string(path).map_err(CompilationError::SerializationError)?;
-        let path_ptr = self.pass_string(&path_str)?;
-        let create_func = {
-            let env = self.env.lock().unwrap();
-            env.create.clone()
-        };
-        let result_ptr = create_func
-            .get_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("create".into()))?
-            .call(path_ptr, args_ptr)
-            .map_err(|e| {
-                CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e.into())
-            })?;
-        let buf = self.read_to_vec(result_ptr)?;
-        self.forget(result_ptr)?;
-        let v: Result<Self::Output, String> =
-            serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError)?;
-        v.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+        let readable_path = String::from(path.clone());
+        let span = tracing::info_span!(
+            "sapio_plugin",
+            module = %self.key.to_string(),
+            path = %readable_path
+        );
+        let _entered = span.enter();
+        let parent_path = self.env.lock().unwrap().current_path.replace(readable_path);
+        let _restore = RestorePath(&self.env, parent_path);
+        let cache = self.cache_key(path, c);
+        if let Some((scope, dir, key)) = &cache {
+            let stats = self.cache_stats_sink();
+            let hit = scope.cache.get(dir, key, &mut stats.lock().unwrap());
+            if let Some(v) = hit {
+                return serde_json::from_value(v).map_err(CompilationError::DeserializationError);
+            }
+        }
+        let v = self.create_json(path, c)?;
+        if let Some((scope, dir, key)) = cache {
+            scope.cache.put(&dir, key, &v);
+        }
+        serde_json::from_value(v).map_err(CompilationError::DeserializationError)
     }
     fn get_api(&self) -> Result<API<Self::Input, Self::Output>, CompilationError> {
-        let p = self
-            .env
-            .lock()
-            .unwrap()
-            .get_api_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_api".into()))?
-            .call()
-            .map_err(|e| CompilationError::ModuleCouldNotGetAPI(e.into()))?;
-        let v = self.read_to_vec(p)?;
-        self.forget(p)?;
-        Ok(serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)?)
+        serde_json::from_value(self.get_api_json()?).map_err(CompilationError::DeserializationError)
     }
     fn get_name(&self) -> Result<String, CompilationError> {
-        let p = self
+        if let Some(v2) = &self.v2 {
+            return v2.get_name(&self.meter, self.get_memory()?);
+        }
+        let f = self
             .env
             .lock()
             .unwrap()
             .get_name_ref()
             .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_name".into()))?
-            .call()
+            .clone();
+        let p = self
+            .meter
+            .run(|| f.call())?
             .map_err(|e| CompilationError::ModuleCouldNotGetName(e.into()))?;
         let v = self.read_to_vec(p)?;
         self.forget(p)?;
@@ -309,13 +654,19 @@ where
     }
 
     fn get_logo(&self) -> Result<String, CompilationError> {
-        let p = self
+        if let Some(v2) = &self.v2 {
+            return v2.get_logo(&self.meter, self.get_memory()?);
+        }
+        let f = self
             .env
             .lock()
             .unwrap()
             .get_logo_ref()
             .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_logo".into()))?
-            .call()
+            .clone();
+        let p = self
+            .meter
+            .run(|| f.call())?
             .map_err(|e| CompilationError::ModuleCouldNotGetLogo(e.into()))?;
         let v = self.read_to_vec(p)?;
         self.forget(p)?;
@@ -0,0 +1,350 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! a local registry of plugins by name and semantic version
+use super::manifest::PublisherTrust;
+use bitcoin::XOnlyPublicKey;
+use fs2::FileExt;
+use schemars::JsonSchema;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// name of the index file in the module directory
+pub const REGISTRY_FILE: &str = "registry.json";
+/// name of the file locked while the index is updated
+const LOCK_FILE: &str = "registry.json.lock";
+
+/// Errors from the plugin registry
+#[derive(Debug)]
+pub enum RegistryError {
+    /// A different module is already registered with this name and version
+    VersionConflict {
+        /// plugin name
+        name: String,
+        /// the version registered twice
+        version: Version,
+    },
+    /// The name is already registered by a different publisher
+    PublisherConflict {
+        /// plugin name
+        name: String,
+        /// the publisher the name belongs to
+        publisher: XOnlyPublicKey,
+    },
+    /// No registered plugin satisfies a dependency
+    UnmetDependency {
+        /// the plugin depended on
+        name: String,
+        /// the requirement which could not be met
+        req: VersionReq,
+    },
+    /// Error reading or writing the index
+    Io(std::io::Error),
+    /// Error (de)serializing the index
+    Json(serde_json::Error),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for RegistryError {}
+impl From<std::io::Error> for RegistryError {
+    fn from(e: std::io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+impl From<serde_json::Error> for RegistryError {
+    fn from(e: serde_json::Error) -> Self {
+        RegistryError::Json(e)
+    }
+}
+
+/// # Registry Entry
+/// A single version of a plugin
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct RegistryEntry {
+    /// # Name
+    pub name: String,
+    /// # Version
+    #[schemars(with = "String")]
+    pub version: Version,
+    /// # Publisher
+    /// The key which signed the module's manifest. Every version of a name
+    /// must have the same publisher.
+    #[schemars(with = "String")]
+    pub publisher: XOnlyPublicKey,
+    /// # Module
+    /// The cache key (hex) of the WASM module
+    pub module: String,
+    /// # API
+    /// The module's JSON API
+    pub api: serde_json::Value,
+    /// # Dependencies
+    /// The plugins this one uses, by name
+    #[serde(default)]
+    #[schemars(with = "BTreeMap<String, String>")]
+    pub dependencies: BTreeMap<String, VersionReq>,
+}
+
+/// A directory index of plugins, stored as `registry.json` in the module
+/// directory.
+///
+/// Only plugins with a signed manifest are registered, and a name belongs to
+/// the publisher who first registered it.
+#[derive(Debug, Default)]
+pub struct PluginRegistry {
+    path: PathBuf,
+    entries: BTreeMap<String, BTreeMap<Version, RegistryEntry>>,
+}
+
+impl PluginRegistry {
+    /// open the registry in the module directory `dir`; it is empty if there
+    /// is no index yet.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, RegistryError> {
+        let path = dir.as_ref().join(REGISTRY_FILE);
+        let mut registry = PluginRegistry {
+            path,
+            entries: Default::default(),
+        };
+        match std::fs::read(&registry.path) {
+            Ok(v) => {
+                let entries: Vec<RegistryEntry> = serde_json::from_slice(&v[..])?;
+                for e in entries {
+                    registry.insert(e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(registry)
+    }
+
+    /// Opens the registry in `dir`, applies `f` and saves it, while holding a
+    /// lock so that concurrent updates are not lost. Nothing is saved if `f`
+    /// fails.
+    pub fn update<P, T, F>(dir: P, f: F) -> Result<T, RegistryError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut PluginRegistry) -> Result<T, RegistryError>,
+    {
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(dir.as_ref().join(LOCK_FILE))?;
+        lock.lock_exclusive()?;
+        let res = Self::open(dir).and_then(|mut registry| {
+            let t = f(&mut registry)?;
+            registry.save()?;
+            Ok(t)
+        });
+        lock.unlock()?;
+        res
+    }
+
+    /// write the index to disk, replacing the old one at once so that readers
+    /// never see a partial index
+    fn save(&self) -> Result<(), RegistryError> {
+        let entries: Vec<&RegistryEntry> = self.iter().collect();
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&entries)?)?;
+        Ok(std::fs::rename(&tmp, &self.path)?)
+    }
+
+    fn insert(&mut self, entry: RegistryEntry) {
+        self.entries
+            .entry(entry.name.clone())
+            .or_default()
+            .insert(entry.version.clone(), entry);
+    }
+
+    /// Adds a plugin version, after checking that its dependencies can be
+    /// met and that the name belongs to its publisher. Registering the same
+    /// module again is a no-op.
+    pub fn register(&mut self, entry: RegistryEntry) -> Result<(), RegistryError> {
+        if let Some(owner) = self.publisher(&entry.name) {
+            if owner != entry.publisher {
+                return Err(RegistryError::PublisherConflict {
+                    name: entry.name,
+                    publisher: owner,
+                });
+            }
+        }
+        if let Some(existing) = self.get(&entry.name, &entry.version) {
+            if existing.module == entry.module {
+                return Ok(());
+            }
+            return Err(RegistryError::VersionConflict {
+                name: entry.name,
+                version: entry.version,
+            });
+        }
+        for (name, req) in &entry.dependencies {
+            if self.resolve(name, req).is_none() {
+                return Err(RegistryError::UnmetDependency {
+                    name: name.clone(),
+                    req: req.clone(),
+                });
+            }
+        }
+        self.insert(entry);
+        Ok(())
+    }
+
+    /// a specific version of a plugin
+    pub fn get(&self, name: &str, version: &Version) -> Option<&RegistryEntry> {
+        self.entries.get(name)?.get(version)
+    }
+
+    /// the publisher a name belongs to, if it is registered
+    pub fn publisher(&self, name: &str) -> Option<XOnlyPublicKey> {
+        self.entries.get(name)?.values().next().map(|e| e.publisher)
+    }
+
+    /// the newest version of a plugin which satisfies `req`
+    pub fn resolve(&self, name: &str, req: &VersionReq) -> Option<&RegistryEntry> {
+        self.entries
+            .get(name)?
+            .values()
+            .rev()
+            .find(|e| req.matches(&e.version))
+    }
+
+    /// the newest version of a plugin which satisfies `req`, if the name
+    /// belongs to a publisher in `trust`
+    pub fn resolve_trusted(
+        &self,
+        name: &str,
+        req: &VersionReq,
+        trust: &PublisherTrust,
+    ) -> Option<&RegistryEntry> {
+        let publisher = self.publisher(name)?;
+        if !trust.publishers.values().any(|k| *k == publisher) {
+            return None;
+        }
+        self.resolve(name, req)
+    }
+
+    /// all registered plugin versions
+    pub fn iter(&self) -> impl Iterator<Item = &RegistryEntry> {
+        self.entries.values().flat_map(|v| v.values())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{Keypair, Secp256k1};
+    fn publisher(seed: u8) -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        Keypair::from_seckey_slice(&secp, &[seed; 32])
+            .unwrap()
+            .x_only_public_key()
+            .0
+    }
+    fn entry(name: &str, version: &str, deps: &[(&str, &str)]) -> RegistryEntry {
+        RegistryEntry {
+            name: name.into(),
+            version: Version::parse(version).unwrap(),
+            publisher: publisher(1),
+            module: format!("{}-{}", name, version),
+            api: serde_json::Value::Null,
+            dependencies: deps
+                .iter()
+                .map(|(n, r)| (n.to_string(), VersionReq::parse(r).unwrap()))
+                .collect(),
+        }
+    }
+    #[test]
+    fn test_registry() {
+        let dir = std::env::temp_dir().join(format!("sapio-registry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut r = PluginRegistry::open(&dir).unwrap();
+        assert!(matches!(
+            r.register(entry("pool", "1.0.0", &[("vault", "^1.2")])),
+            Err(RegistryError::UnmetDependency { .. })
+        ));
+        for v in ["1.1.0", "1.2.0", "1.4.1", "2.0.0"] {
+            r.register(entry("vault", v, &[])).unwrap();
+        }
+        r.register(entry("pool", "1.0.0", &[("vault", "^1.2")]))
+            .unwrap();
+        // same module again is fine, a different one is not
+        r.register(entry("vault", "1.2.0", &[])).unwrap();
+        let mut other = entry("vault", "1.2.0", &[]);
+        other.module = "other".into();
+        assert!(matches!(
+            r.register(other),
+            Err(RegistryError::VersionConflict { .. })
+        ));
+        // nor is a new version from someone else
+        let mut squatter = entry("vault", "3.0.0", &[]);
+        squatter.publisher = publisher(2);
+        assert!(matches!(
+            r.register(squatter),
+            Err(RegistryError::PublisherConflict { .. })
+        ));
+        let req = VersionReq::parse("^1.2").unwrap();
+        assert_eq!(r.resolve("vault", &req).unwrap().module, "vault-1.4.1");
+        assert_eq!(
+            r.resolve("vault", &VersionReq::STAR).unwrap().module,
+            "vault-2.0.0"
+        );
+        assert!(r
+            .resolve("vault", &VersionReq::parse("^3").unwrap())
+            .is_none());
+        // only names of trusted publishers resolve for plugins
+        let mut trust = PublisherTrust::default();
+        assert!(r.resolve_trusted("vault", &req, &trust).is_none());
+        trust.publishers.insert("other".into(), publisher(2));
+        assert!(r.resolve_trusted("vault", &req, &trust).is_none());
+        trust.publishers.insert("judica".into(), publisher(1));
+        assert_eq!(
+            r.resolve_trusted("vault", &req, &trust).unwrap().module,
+            "vault-1.4.1"
+        );
+
+        r.save().unwrap();
+        let reopened = PluginRegistry::open(&dir).unwrap();
+        assert_eq!(reopened.iter().count(), 5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_update() {
+        let dir =
+            std::env::temp_dir().join(format!("sapio-registry-update-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    PluginRegistry::update(&dir, |r| {
+                        r.register(entry("vault", &format!("1.{}.0", i), &[]))
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // nothing is lost, and a failed update changes nothing
+        assert!(PluginRegistry::update(&dir, |r| {
+            r.register(entry("pool", "1.0.0", &[]))?;
+            r.register(entry("pool", "2.0.0", &[("missing", "*")]))
+        })
+        .is_err());
+        let r = PluginRegistry::open(&dir).unwrap();
+        assert_eq!(r.iter().count(), 8);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,6 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! tools for caching compilations of wasm plugins to disk
+use super::manifest::SignedPluginManifest;
 use std::path::PathBuf;
 use wasmer::{DeserializeError, Module, SerializeError, Store};
 use wasmer_cache::{Cache, FileSystemCache, Hash};
@@ -14,6 +15,13 @@ pub fn get_all_keys_from_fs<I: Into<PathBuf>>(
     path: I,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     std::fs::read_dir(path.into())?
+        // manifests, sources, the registry index and the compilation cache are
+        // stored alongside the modules, which have no extension (but on windows)
+        .filter(|entry| {
+            entry.as_ref().map_or(true, |e| {
+                e.path().is_file() && e.path().extension().map_or(true, |x| x == "dll")
+            })
+        })
         .map(|entry| {
             match entry.map(|x| {
                 x.path()
@@ -50,13 +58,14 @@ pub fn load_module_key<I: Into<PathBuf>>(
     unsafe { f.load(store, key) }.map(|m| (m, key))
 }
 
-/// store a module into the cache
+/// store a module into the cache, along with the bytes it was compiled from
 pub fn store_module<I: Into<PathBuf>>(
     path: I,
     module: &Module,
     bytes: &[u8],
 ) -> Result<Hash, SerializeError> {
-    let mut cache = FileSystemCache::new(path)?;
+    let path = path.into();
+    let mut cache = FileSystemCache::new(path.clone())?;
     #[cfg(target_os = "windows")]
     {
         cache.set_cache_extension(Some("dll"))
@@ -64,5 +73,60 @@ pub fn store_module<I: Into<PathBuf>>(
     let key = Hash::generate(bytes);
 
     cache.store(key, module)?;
+    std::fs::write(source_path(path, key), bytes)?;
     Ok(key)
 }
+
+/// extension of the files which hold the wasm a module was compiled from
+const SOURCE_EXTENSION: &str = "wasm";
+
+fn source_path<I: Into<PathBuf>>(path: I, key: Hash) -> PathBuf {
+    let mut p = path.into();
+    p.push(format!("{}.{}", key.to_string(), SOURCE_EXTENSION));
+    p
+}
+
+/// load the wasm a cached module was compiled from, if it was stored
+pub fn load_source<I: Into<PathBuf>>(
+    path: I,
+    key: Hash,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    match std::fs::read(source_path(path, key)) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// extension of the files which hold the manifest for a module
+const MANIFEST_EXTENSION: &str = "manifest";
+
+fn manifest_path<I: Into<PathBuf>>(path: I, key: Hash) -> PathBuf {
+    let mut p = path.into();
+    p.push(format!("{}.{}", key.to_string(), MANIFEST_EXTENSION));
+    p
+}
+
+/// store a signed manifest for a module into the cache
+pub fn store_manifest<I: Into<PathBuf>>(
+    path: I,
+    key: Hash,
+    manifest: &SignedPluginManifest,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(std::fs::write(
+        manifest_path(path, key),
+        serde_json::to_vec(manifest)?,
+    )?)
+}
+
+/// load the signed manifest for a module from the cache, if it has one
+pub fn load_manifest<I: Into<PathBuf>>(
+    path: I,
+    key: Hash,
+) -> Result<Option<SignedPluginManifest>, Box<dyn std::error::Error>> {
+    match std::fs::read(manifest_path(path, key)) {
+        Ok(v) => Ok(Some(serde_json::from_slice(&v[..])?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
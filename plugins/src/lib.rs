@@ -29,6 +29,8 @@ pub mod host;
 #[cfg(feature = "client")]
 pub mod client;
 
+pub mod log;
+
 pub mod plugin_handle;
 
 pub use sapio_base::plugin_args::*;
@@ -0,0 +1,155 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! structured log records which plugins send to the host
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// # Log Level
+/// Ordered from most to least severe
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// # Log Record
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    /// # Level
+    pub level: LogLevel,
+    /// # Target
+    /// The rust module the record was logged from
+    pub target: String,
+    /// # Message
+    pub message: String,
+    /// # Fields
+    /// Key-value data attached to the record
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, serde_json::Value>,
+    /// # Module
+    /// The key of the plugin which logged the record, filled in by the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// # Path
+    /// The EffectPath being compiled, filled in by the host if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+impl LogRecord {
+    /// whether the record is at `level` or more severe
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        self.level <= level
+    }
+}
+
+/// the most records the host keeps for a plugin until they are taken, later
+/// ones are only emitted
+pub const MAX_LOG_RECORDS: usize = 4096;
+
+/// add `record` to `logs`, unless `MAX_LOG_RECORDS` are already kept. The
+/// first record dropped is replaced by a warning that records were dropped.
+pub fn push_capped(logs: &mut Vec<LogRecord>, record: LogRecord) {
+    if logs.len() < MAX_LOG_RECORDS {
+        logs.push(record);
+    } else if logs.len() == MAX_LOG_RECORDS {
+        logs.push(LogRecord {
+            level: LogLevel::Warn,
+            target: "sapio_plugin".into(),
+            message: format!(
+                "More than {} log records, the rest were dropped",
+                MAX_LOG_RECORDS
+            ),
+            fields: Default::default(),
+            module: record.module,
+            path: record.path,
+        });
+    }
+}
+
+/// convert a field value for a `LogRecord`, used by the logging macros
+pub fn to_field<T: Serialize>(t: &T) -> serde_json::Value {
+    serde_json::to_value(t).unwrap_or(serde_json::Value::Null)
+}
+
+#[cfg(feature = "host")]
+impl LogRecord {
+    /// emit the record as a `tracing` event, inside the current span
+    pub fn emit(&self) {
+        let fields = serde_json::Value::from(
+            self.fields
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<serde_json::Map<_, _>>(),
+        );
+        let module = self.module.as_deref().unwrap_or_default();
+        let path = self.path.as_deref().unwrap_or_default();
+        macro_rules! emit {
+            ($level:expr) => {
+                tracing::event!(
+                    target: "sapio_plugin",
+                    $level,
+                    module,
+                    path,
+                    plugin_target = %self.target,
+                    fields = %fields,
+                    "{}",
+                    self.message
+                )
+            };
+        }
+        match self.level {
+            LogLevel::Error => emit!(tracing::Level::ERROR),
+            LogLevel::Warn => emit!(tracing::Level::WARN),
+            LogLevel::Info => emit!(tracing::Level::INFO),
+            LogLevel::Debug => emit!(tracing::Level::DEBUG),
+            LogLevel::Trace => emit!(tracing::Level::TRACE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_log_record() {
+        let r: LogRecord = serde_json::from_str(
+            r#"{"level": "warn", "target": "vault", "message": "fee too high", "fields": {"fee": 100}}"#,
+        )
+        .unwrap();
+        assert_eq!(r.fields["fee"], 100);
+        assert!(r.enabled(LogLevel::Warn));
+        assert!(r.enabled(LogLevel::Trace));
+        assert!(!r.enabled(LogLevel::Error));
+        assert_eq!(
+            serde_json::to_value(&r).unwrap().get("module"),
+            None,
+            "unset host fields are skipped"
+        );
+    }
+
+    #[test]
+    fn test_push_capped() {
+        let r: LogRecord =
+            serde_json::from_str(r#"{"level": "info", "target": "vault", "message": "hi"}"#)
+                .unwrap();
+        let mut logs = vec![];
+        for _ in 0..MAX_LOG_RECORDS + 10 {
+            push_capped(&mut logs, r.clone());
+        }
+        assert_eq!(logs.len(), MAX_LOG_RECORDS + 1);
+        assert_eq!(logs[MAX_LOG_RECORDS - 1], r);
+        assert_eq!(logs[MAX_LOG_RECORDS].level, LogLevel::Warn);
+    }
+}
@@ -56,6 +56,25 @@ impl MapEffectDB {
     pub fn skip_serializing(&self) -> bool {
         self.effects.is_empty()
     }
+
+    /// the effects at `at` or below it, which are the only ones a contract
+    /// created at `at` can see
+    pub fn subtree(&self, at: &EffectPath) -> MapEffectDB {
+        let at = String::from(at.clone());
+        let below = format!("{}/", at);
+        MapEffectDB {
+            effects: self
+                .effects
+                .iter()
+                .filter(|(p, _)| {
+                    let p = String::from((*p.0).clone());
+                    p == at || p.starts_with(&below)
+                })
+                .map(|(p, v)| (p.clone(), v.clone()))
+                .collect(),
+            empty: Default::default(),
+        }
+    }
 }
 
 impl EffectDB for MapEffectDB {
@@ -31,6 +31,7 @@ pub enum PathFragment {
     DefaultEffect,
     Effects,
     Metadata,
+    InternalKey,
     Branch(u64),
     Named(SArc<String>),
 }
@@ -54,6 +55,7 @@ impl From<&PathFragment> for String {
             PathFragment::DefaultEffect => "@default_effect".into(),
             PathFragment::Effects => "@effects".into(),
             PathFragment::Metadata => "@metadata".into(),
+            PathFragment::InternalKey => "@internal_key".into(),
             PathFragment::Branch(u) => format!("#{}", u),
             PathFragment::Named(SArc(a)) => a.as_ref().clone(),
         }
@@ -101,6 +103,7 @@ impl TryFrom<&str> for PathFragment {
             "@default_effect" => PathFragment::DefaultEffect,
             "@effects" => PathFragment::Effects,
             "@metadata" => PathFragment::Metadata,
+            "@internal_key" => PathFragment::InternalKey,
             n if n.starts_with('#') => PathFragment::Branch(FromStr::from_str(&n[1..])?),
             n if n.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') => {
                 PathFragment::Named(SArc(Arc::new(s.into())))
@@ -4,6 +4,7 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::effects::EffectPath;
 use bitcoin::hash_types::*;
 use std::collections::BTreeMap;
 use std::sync::Arc;
@@ -15,6 +16,7 @@ pub enum TxIndexError {
     UnknownTxid(Txid),
     IndexTooHigh(u32),
     RpcError(Box<dyn std::error::Error>),
+    DatabaseError(Box<dyn std::error::Error>),
 }
 impl std::error::Error for TxIndexError {}
 
@@ -34,6 +36,36 @@ pub trait TxIndex {
             .ok_or(TxIndexError::IndexTooHigh(b.vout))
     }
     fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid>;
+    /// add a transaction, recording the contract path that created it.
+    /// Indexes which do not track origins may ignore the path.
+    fn add_tx_from(&self, tx: Arc<bitcoin::Transaction>, _created_by: &EffectPath) -> Result<Txid> {
+        self.add_tx(tx)
+    }
+    /// add a transaction seen confirmed in the block at `height`, where the
+    /// block before had median time past `mtp`.
+    /// Indexes which do not track confirmations may just add it.
+    fn add_confirmed_tx(
+        &self,
+        tx: Arc<bitcoin::Transaction>,
+        _height: u32,
+        _mtp: u32,
+    ) -> Result<Txid> {
+        self.add_tx(tx)
+    }
+    /// forget that a transaction confirmed, as its block was disconnected.
+    /// A transaction which was only added as confirmed is removed.
+    fn remove_confirmed_tx(&self, _txid: &Txid) -> Result<()> {
+        Ok(())
+    }
+    /// the height and median time past a transaction was added as confirmed
+    /// with, if it was
+    fn confirmation(&self, _txid: &Txid) -> Result<Option<(u32, u32)>> {
+        Ok(None)
+    }
+    /// the transaction added as confirmed which spends `out`, if any
+    fn confirmed_spend(&self, _out: &bitcoin::OutPoint) -> Result<Option<Txid>> {
+        Ok(None)
+    }
 }
 pub struct TxIndexLogger {
     map: Mutex<BTreeMap<Txid, Arc<bitcoin::Transaction>>>,
@@ -89,4 +121,31 @@ where
             self.cache.add_tx(tx)
         }
     }
+    fn add_tx_from(&self, tx: Arc<bitcoin::Transaction>, created_by: &EffectPath) -> Result<Txid> {
+        let txid = tx.txid();
+        if self.cache.lookup_tx(&txid).is_ok() {
+            Ok(txid)
+        } else {
+            self.primary.add_tx_from(tx.clone(), created_by)?;
+            self.cache.add_tx_from(tx, created_by)
+        }
+    }
+    fn add_confirmed_tx(
+        &self,
+        tx: Arc<bitcoin::Transaction>,
+        height: u32,
+        mtp: u32,
+    ) -> Result<Txid> {
+        self.primary.add_confirmed_tx(tx.clone(), height, mtp)?;
+        self.cache.add_tx(tx)
+    }
+    fn remove_confirmed_tx(&self, txid: &Txid) -> Result<()> {
+        self.primary.remove_confirmed_tx(txid)
+    }
+    fn confirmation(&self, txid: &Txid) -> Result<Option<(u32, u32)>> {
+        self.primary.confirmation(txid)
+    }
+    fn confirmed_spend(&self, out: &bitcoin::OutPoint) -> Result<Option<Txid>> {
+        self.primary.confirmed_spend(out)
+    }
 }
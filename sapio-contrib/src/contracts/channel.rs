@@ -35,7 +35,7 @@ mod tests {
     fn it_works() {
         db_serde::register_db("mock".to_string(), |_s| Arc::new(Mutex::new(MockDB {})));
         let full = Secp256k1::new();
-        let mut rng = OsRng::new().expect("OsRng");
+        let mut rng = OsRng;
         let public_keys: Vec<_> = (0..3)
             .map(|_| full.generate_keypair(&mut rng).1.into())
             .collect();
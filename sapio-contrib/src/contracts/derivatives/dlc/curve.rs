@@ -0,0 +1,661 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! User defined payout curves.
+//!
+//! A `PayoutCurve` is piecewise: each piece gives every party's payout, in
+//! sats, as an expression in the outcome `x` and the funds `total`, e.g.
+//! `"min(total, x * 1000)"` and `"total - min(total, x * 1000)"`.
+//!
+//! Expressions support `+ - * /`, `^` with a constant whole exponent,
+//! parentheses, decimal constants, `min(..)`, `max(..)` and `floor(..)`.
+//! They are evaluated exactly, with rationals rather than floats, so a
+//! curve gives the same payouts everywhere. The exact payouts must sum to
+//! `total`, and are then rounded to whole sats by largest remainder, which
+//! keeps the sum exact.
+//!
+//! Expressions are at most `MAX_LENGTH` bytes and nest at most `MAX_DEPTH`
+//! deep, so parsing and evaluating them is bounded.
+use sapio::contract::CompilationError;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// the largest exponent allowed, to keep evaluation cheap
+const MAX_EXPONENT: u32 = 16;
+/// the longest expression allowed, in bytes
+pub const MAX_LENGTH: usize = 4096;
+/// the deepest an expression may nest, counting parentheses, function
+/// calls, unary minuses and each operator in a chain
+pub const MAX_DEPTH: usize = 128;
+
+/// Errors in parsing or evaluating a payout curve
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CurveError {
+    /// The expression could not be parsed, at the given byte offset
+    Parse(usize, String),
+    /// An intermediate value was too large to represent
+    Overflow,
+    /// An expression divided by zero
+    DivisionByZero,
+    /// A payout was negative
+    NegativePayout(usize),
+    /// The payouts did not sum to the funds
+    WrongTotal {
+        /// the funds available
+        total: u64,
+        /// what the payouts summed to, as a fraction
+        sum: String,
+    },
+    /// The curve has a different number of payouts than there are parties
+    WrongPartyCount {
+        /// parties in the contract
+        expected: usize,
+        /// payouts in the piece
+        found: usize,
+    },
+    /// The pieces do not start at 0 in strictly increasing order
+    BadPieces,
+    /// A party's payout both rises and falls over the outcomes, first
+    /// changing direction at the given outcome
+    NotMonotonic {
+        /// the party whose payout is not monotonic
+        party: usize,
+        /// the outcome at which it changes direction
+        outcome: u64,
+    },
+    /// Funds were split between weights summing to zero
+    ZeroWeight,
+}
+impl fmt::Display for CurveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for CurveError {}
+
+impl From<CurveError> for CompilationError {
+    fn from(e: CurveError) -> Self {
+        CompilationError::Custom(Box::new(e))
+    }
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a.abs()
+}
+
+/// An exact fraction, in lowest terms with a positive denominator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ratio {
+    n: i128,
+    d: i128,
+}
+
+impl Ratio {
+    fn new(n: i128, d: i128) -> Result<Self, CurveError> {
+        if d == 0 {
+            return Err(CurveError::DivisionByZero);
+        }
+        let g = gcd(n, d).max(1);
+        let s = if d < 0 { -1 } else { 1 };
+        Ok(Ratio {
+            n: s * (n / g),
+            d: s * (d / g),
+        })
+    }
+    fn int(n: i128) -> Self {
+        Ratio { n, d: 1 }
+    }
+    fn add(self, o: Self) -> Result<Self, CurveError> {
+        let n = self
+            .n
+            .checked_mul(o.d)
+            .and_then(|a| o.n.checked_mul(self.d).and_then(|b| a.checked_add(b)));
+        let d = self.d.checked_mul(o.d);
+        Ratio::new(
+            n.ok_or(CurveError::Overflow)?,
+            d.ok_or(CurveError::Overflow)?,
+        )
+    }
+    fn neg(self) -> Result<Self, CurveError> {
+        Ok(Ratio {
+            n: self.n.checked_neg().ok_or(CurveError::Overflow)?,
+            d: self.d,
+        })
+    }
+    fn mul(self, o: Self) -> Result<Self, CurveError> {
+        let n = self.n.checked_mul(o.n).ok_or(CurveError::Overflow)?;
+        let d = self.d.checked_mul(o.d).ok_or(CurveError::Overflow)?;
+        Ratio::new(n, d)
+    }
+    fn div(self, o: Self) -> Result<Self, CurveError> {
+        self.mul(Ratio::new(o.d, o.n)?)
+    }
+    fn floor(self) -> i128 {
+        self.n.div_euclid(self.d)
+    }
+    fn cmp(&self, o: &Self) -> Result<std::cmp::Ordering, CurveError> {
+        let a = self.n.checked_mul(o.d).ok_or(CurveError::Overflow)?;
+        let b = o.n.checked_mul(self.d).ok_or(CurveError::Overflow)?;
+        Ok(a.cmp(&b))
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.n, self.d)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Outcome,
+    Total,
+    Const(Ratio),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+    Pow(Box<Expr>, u32),
+    Min(Vec<Expr>),
+    Max(Vec<Expr>),
+    Floor(Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, x: u64, total: u64) -> Result<Ratio, CurveError> {
+        Ok(match self {
+            Expr::Outcome => Ratio::int(x as i128),
+            Expr::Total => Ratio::int(total as i128),
+            Expr::Const(r) => *r,
+            Expr::Add(a, b) => a.eval(x, total)?.add(b.eval(x, total)?)?,
+            Expr::Sub(a, b) => a.eval(x, total)?.add(b.eval(x, total)?.neg()?)?,
+            Expr::Mul(a, b) => a.eval(x, total)?.mul(b.eval(x, total)?)?,
+            Expr::Div(a, b) => a.eval(x, total)?.div(b.eval(x, total)?)?,
+            Expr::Neg(a) => a.eval(x, total)?.neg()?,
+            Expr::Pow(a, e) => {
+                let a = a.eval(x, total)?;
+                (0..*e).try_fold(Ratio::int(1), |acc, _| acc.mul(a))?
+            }
+            Expr::Min(v) | Expr::Max(v) => {
+                let want = if let Expr::Min(_) = self {
+                    std::cmp::Ordering::Less
+                } else {
+                    std::cmp::Ordering::Greater
+                };
+                let mut best = v[0].eval(x, total)?;
+                for e in &v[1..] {
+                    let r = e.eval(x, total)?;
+                    if r.cmp(&best)? == want {
+                        best = r;
+                    }
+                }
+                best
+            }
+            Expr::Floor(a) => Ratio::int(a.eval(x, total)?.floor()),
+        })
+    }
+}
+
+/// A recursive descent parser for expressions
+struct Parser<'a> {
+    s: &'a [u8],
+    at: usize,
+    /// how deeply nested the expression being parsed is
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn err<T>(&self, msg: &str) -> Result<T, CurveError> {
+        Err(CurveError::Parse(self.at, msg.into()))
+    }
+    /// goes a level deeper, failing past `MAX_DEPTH`
+    fn enter(&mut self) -> Result<(), CurveError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return self.err(&format!("nested more than {} deep", MAX_DEPTH));
+        }
+        Ok(())
+    }
+    fn skip_ws(&mut self) {
+        while self.at < self.s.len() && self.s[self.at].is_ascii_whitespace() {
+            self.at += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.s.get(self.at).cloned()
+    }
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.at += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, c: u8) -> Result<(), CurveError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.err(&format!("expected '{}'", c as char))
+        }
+    }
+    fn expr(&mut self) -> Result<Expr, CurveError> {
+        let depth = self.depth;
+        let mut e = self.term()?;
+        loop {
+            // each operator in a chain nests the chain so far a level deeper
+            if self.eat(b'+') {
+                self.enter()?;
+                e = Expr::Add(Box::new(e), Box::new(self.term()?));
+            } else if self.eat(b'-') {
+                self.enter()?;
+                e = Expr::Sub(Box::new(e), Box::new(self.term()?));
+            } else {
+                self.depth = depth;
+                return Ok(e);
+            }
+        }
+    }
+    fn term(&mut self) -> Result<Expr, CurveError> {
+        let depth = self.depth;
+        let mut e = self.unary()?;
+        loop {
+            if self.eat(b'*') {
+                self.enter()?;
+                e = Expr::Mul(Box::new(e), Box::new(self.unary()?));
+            } else if self.eat(b'/') {
+                self.enter()?;
+                e = Expr::Div(Box::new(e), Box::new(self.unary()?));
+            } else {
+                self.depth = depth;
+                return Ok(e);
+            }
+        }
+    }
+    fn unary(&mut self) -> Result<Expr, CurveError> {
+        self.enter()?;
+        let e = self.unary_inner();
+        self.depth -= 1;
+        e
+    }
+    fn unary_inner(&mut self) -> Result<Expr, CurveError> {
+        if self.eat(b'-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        let e = self.atom()?;
+        if self.eat(b'^') {
+            self.skip_ws();
+            let start = self.at;
+            match self.number()? {
+                Expr::Const(Ratio { n, d: 1 }) if n >= 0 && n <= MAX_EXPONENT as i128 => {
+                    Ok(Expr::Pow(Box::new(e), n as u32))
+                }
+                _ => Err(CurveError::Parse(
+                    start,
+                    format!("exponents must be whole numbers up to {}", MAX_EXPONENT),
+                )),
+            }
+        } else {
+            Ok(e)
+        }
+    }
+    fn number(&mut self) -> Result<Expr, CurveError> {
+        let start = self.at;
+        while self.at < self.s.len()
+            && (self.s[self.at].is_ascii_digit() || self.s[self.at] == b'.')
+        {
+            self.at += 1;
+        }
+        let text = std::str::from_utf8(&self.s[start..self.at]).unwrap_or_default();
+        let (whole, frac) = match text.split_once('.') {
+            Some((w, f)) => (w, f),
+            None => (text, ""),
+        };
+        if whole.is_empty() || frac.contains('.') || frac.len() > 18 {
+            return Err(CurveError::Parse(start, "bad number".into()));
+        }
+        let digits: i128 = format!("{}{}", whole, frac)
+            .parse()
+            .map_err(|_| CurveError::Parse(start, "bad number".into()))?;
+        Ok(Expr::Const(Ratio::new(
+            digits,
+            10i128.pow(frac.len() as u32),
+        )?))
+    }
+    fn atom(&mut self) -> Result<Expr, CurveError> {
+        match self.peek() {
+            Some(b'(') => {
+                self.at += 1;
+                let e = self.expr()?;
+                self.expect(b')')?;
+                Ok(e)
+            }
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.at;
+                while self.at < self.s.len() && self.s[self.at].is_ascii_alphanumeric() {
+                    self.at += 1;
+                }
+                let name = std::str::from_utf8(&self.s[start..self.at]).unwrap_or_default();
+                match name {
+                    "x" => Ok(Expr::Outcome),
+                    "total" => Ok(Expr::Total),
+                    "min" | "max" | "floor" => {
+                        self.expect(b'(')?;
+                        let mut args = vec![self.expr()?];
+                        while self.eat(b',') {
+                            args.push(self.expr()?);
+                        }
+                        self.expect(b')')?;
+                        match (name, args.len()) {
+                            ("floor", 1) => Ok(Expr::Floor(Box::new(args.remove(0)))),
+                            ("min", _) => Ok(Expr::Min(args)),
+                            ("max", _) => Ok(Expr::Max(args)),
+                            _ => Err(CurveError::Parse(start, "floor takes one argument".into())),
+                        }
+                    }
+                    _ => Err(CurveError::Parse(start, format!("unknown name {}", name))),
+                }
+            }
+            _ => self.err("expected a value"),
+        }
+    }
+}
+
+/// A parsed expression, (de)serialized as its text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(try_from = "String", into = "String")]
+#[schemars(transparent)]
+pub struct Expression {
+    text: String,
+    #[serde(skip)]
+    #[schemars(skip)]
+    expr: Option<Expr>,
+}
+
+impl TryFrom<String> for Expression {
+    type Error = CurveError;
+    fn try_from(text: String) -> Result<Self, CurveError> {
+        if text.len() > MAX_LENGTH {
+            return Err(CurveError::Parse(
+                MAX_LENGTH,
+                format!("longer than {} bytes", MAX_LENGTH),
+            ));
+        }
+        let mut p = Parser {
+            s: text.as_bytes(),
+            at: 0,
+            depth: 0,
+        };
+        let expr = p.expr()?;
+        if p.peek().is_some() {
+            return p.err("unexpected input");
+        }
+        Ok(Expression {
+            text,
+            expr: Some(expr),
+        })
+    }
+}
+
+impl From<Expression> for String {
+    fn from(e: Expression) -> String {
+        e.text
+    }
+}
+
+impl std::str::FromStr for Expression {
+    type Err = CurveError;
+    fn from_str(s: &str) -> Result<Self, CurveError> {
+        Expression::try_from(s.to_string())
+    }
+}
+
+impl Expression {
+    /// the exact value of the expression at outcome `x` with `total` funds
+    pub fn eval(&self, x: u64, total: u64) -> Result<Ratio, CurveError> {
+        match &self.expr {
+            Some(e) => e.eval(x, total),
+            None => Expression::try_from(self.text.clone())?.eval(x, total),
+        }
+    }
+}
+
+/// One piece of a `PayoutCurve`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct CurvePiece {
+    /// the first outcome the piece applies to
+    pub from: u64,
+    /// each party's payout in sats, in terms of the outcome `x` and the
+    /// funds `total`
+    pub payouts: Vec<Expression>,
+}
+
+/// # Payout Curve
+/// A piecewise payout curve, each piece applying from its `from` outcome
+/// until the next piece's.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PayoutCurve {
+    /// the pieces, the first from outcome 0, in increasing order
+    pub pieces: Vec<CurvePiece>,
+}
+
+impl PayoutCurve {
+    /// checks the pieces are in order and each has a payout per party
+    pub fn validate(&self, parties: usize) -> Result<(), CurveError> {
+        if self.pieces.first().map(|p| p.from) != Some(0)
+            || self.pieces.windows(2).any(|w| w[0].from >= w[1].from)
+        {
+            return Err(CurveError::BadPieces);
+        }
+        match self.pieces.iter().find(|p| p.payouts.len() != parties) {
+            Some(p) => Err(CurveError::WrongPartyCount {
+                expected: parties,
+                found: p.payouts.len(),
+            }),
+            None => Ok(()),
+        }
+    }
+    /// each party's exact payout at outcome `x`
+    fn exact(&self, x: u64, total: u64, parties: usize) -> Result<Vec<Ratio>, CurveError> {
+        self.validate(parties)?;
+        let piece = self
+            .pieces
+            .iter()
+            .rev()
+            .find(|p| p.from <= x)
+            .ok_or(CurveError::BadPieces)?;
+        piece.payouts.iter().map(|e| e.eval(x, total)).collect()
+    }
+    /// each party's payout in whole sats at outcome `x`, summing to `total`
+    pub fn payouts(&self, x: u64, total: u64, parties: usize) -> Result<Vec<u64>, CurveError> {
+        round_payouts(&self.exact(x, total, parties)?, total)
+    }
+    /// checks each party's exact payout only rises, or only falls, over the
+    /// outcomes `0..=last`, so that outcomes with equal payouts are
+    /// contiguous. The curve is evaluated at every outcome.
+    pub fn check_monotonic(&self, last: u64, total: u64, parties: usize) -> Result<(), CurveError> {
+        let mut prev = self.exact(0, total, parties)?;
+        // the direction each party's payout has moved in, once it has
+        let mut direction = vec![std::cmp::Ordering::Equal; parties];
+        for x in 1..=last {
+            let next = self.exact(x, total, parties)?;
+            for (party, (a, b)) in prev.iter().zip(next.iter()).enumerate() {
+                let o = b.cmp(a)?;
+                if o == std::cmp::Ordering::Equal {
+                    continue;
+                }
+                if direction[party] == std::cmp::Ordering::Equal {
+                    direction[party] = o;
+                } else if direction[party] != o {
+                    return Err(CurveError::NotMonotonic { party, outcome: x });
+                }
+            }
+            prev = next;
+        }
+        Ok(())
+    }
+}
+
+/// Rounds exact payouts, which must be non-negative and sum to `total`, to
+/// whole sats. Each is rounded down, and the sats left over go one each to
+/// the payouts with the largest fractional parts (the earliest first, among
+/// equals), so the rounded payouts still sum to `total`.
+pub fn round_payouts(exact: &[Ratio], total: u64) -> Result<Vec<u64>, CurveError> {
+    let sum = exact.iter().try_fold(Ratio::int(0), |acc, r| acc.add(*r))?;
+    if sum != Ratio::int(total as i128) {
+        return Err(CurveError::WrongTotal {
+            total,
+            sum: sum.to_string(),
+        });
+    }
+    if let Some(i) = exact.iter().position(|r| r.n < 0) {
+        return Err(CurveError::NegativePayout(i));
+    }
+    let mut rounded: Vec<u64> = exact.iter().map(|r| r.floor() as u64).collect();
+    let left = total - rounded.iter().sum::<u64>();
+    let mut by_remainder: Vec<(usize, Ratio)> = exact
+        .iter()
+        .map(|r| r.add(Ratio::int(-r.floor())))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .enumerate()
+        .collect();
+    let mut err = None;
+    by_remainder.sort_by(|(i, a), (j, b)| match b.cmp(a) {
+        Ok(o) => o.then(i.cmp(j)),
+        Err(e) => {
+            err = Some(e);
+            std::cmp::Ordering::Equal
+        }
+    });
+    if let Some(e) = err {
+        return Err(e);
+    }
+    for (i, _) in by_remainder.into_iter().take(left as usize) {
+        rounded[i] += 1;
+    }
+    Ok(rounded)
+}
+
+/// Splits `amount` in proportion to `weights`, rounded to whole sats as by
+/// `round_payouts`.
+pub fn split_by_weight(amount: u64, weights: &[u64]) -> Result<Vec<u64>, CurveError> {
+    let total = weights
+        .iter()
+        .try_fold(0u64, |a, w| a.checked_add(*w))
+        .ok_or(CurveError::Overflow)?;
+    if total == 0 {
+        return Err(CurveError::ZeroWeight);
+    }
+    let exact = weights
+        .iter()
+        .map(|w| Ratio::new(amount as i128 * *w as i128, total as i128))
+        .collect::<Result<Vec<_>, _>>()?;
+    round_payouts(&exact, amount)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_payout_curve() {
+        let curve: PayoutCurve = serde_json::from_value(serde_json::json!({
+            "pieces": [
+                {"from": 0, "payouts": ["0", "total"]},
+                {"from": 10, "payouts": ["total * (x - 10) / 3", "total - total * (x - 10) / 3"]},
+                {"from": 13, "payouts": ["total", "0"]},
+            ]
+        }))
+        .unwrap();
+        assert_eq!(curve.payouts(5, 100, 2).unwrap(), vec![0, 100]);
+        // 33 1/3 and 66 2/3: the leftover sat goes to the larger remainder
+        assert_eq!(curve.payouts(11, 100, 2).unwrap(), vec![33, 67]);
+        assert_eq!(curve.payouts(12, 100, 2).unwrap(), vec![67, 33]);
+        assert_eq!(curve.payouts(1000, 100, 2).unwrap(), vec![100, 0]);
+        assert_eq!(
+            curve.payouts(5, 100, 3),
+            Err(CurveError::WrongPartyCount {
+                expected: 3,
+                found: 2
+            })
+        );
+
+        let e = |s: &str| s.parse::<Expression>().unwrap().eval(3, 100).unwrap();
+        assert_eq!(
+            e("min(x^2, 5) + max(1, 2, 0.5) * floor(7 / 2)"),
+            Ratio::int(11)
+        );
+        assert_eq!(e("-(x - 4) * 0.25"), Ratio::new(1, 4).unwrap());
+        assert!("x +".parse::<Expression>().is_err());
+        assert!("x ^ 100".parse::<Expression>().is_err());
+        assert!("y".parse::<Expression>().is_err());
+        assert_eq!(
+            "x / (x - 3)".parse::<Expression>().unwrap().eval(3, 0),
+            Err(CurveError::DivisionByZero)
+        );
+        // payouts must add up exactly
+        let exact = [Ratio::int(50), Ratio::new(99, 2).unwrap()];
+        assert!(round_payouts(&exact, 100).is_err());
+        let thirds = [Ratio::new(100, 3).unwrap(); 3];
+        assert_eq!(round_payouts(&thirds, 100).unwrap(), vec![34, 33, 33]);
+        assert_eq!(split_by_weight(100, &[1, 1, 1]).unwrap(), vec![34, 33, 33]);
+        assert_eq!(split_by_weight(7, &[3, 1]).unwrap(), vec![5, 2]);
+        assert_eq!(split_by_weight(7, &[0]), Err(CurveError::ZeroWeight));
+    }
+
+    #[test]
+    fn test_limits() {
+        let nested = |n| format!("{}x{}", "(".repeat(n), ")".repeat(n));
+        assert!(nested(MAX_DEPTH / 2).parse::<Expression>().is_ok());
+        assert!(matches!(
+            nested(MAX_DEPTH).parse::<Expression>(),
+            Err(CurveError::Parse(..))
+        ));
+        assert!(matches!(
+            "-".repeat(100_000).parse::<Expression>(),
+            Err(CurveError::Parse(MAX_LENGTH, _))
+        ));
+        let chain = |n| vec!["x"; n].join(" + ");
+        assert!(chain(MAX_DEPTH / 2).parse::<Expression>().is_ok());
+        assert!(chain(MAX_DEPTH + 1).parse::<Expression>().is_err());
+        // a long argument list is flat
+        let args = format!("min({})", vec!["x"; 500].join(","));
+        assert!(args.parse::<Expression>().is_ok());
+    }
+
+    #[test]
+    fn test_monotonic() {
+        let curve = |a: &str, b: &str| -> PayoutCurve {
+            serde_json::from_value(serde_json::json!({
+                "pieces": [{"from": 0, "payouts": [a, b]}]
+            }))
+            .unwrap()
+        };
+        let rising = curve("min(total, x * 10)", "total - min(total, x * 10)");
+        assert_eq!(rising.check_monotonic(20, 100, 2), Ok(()));
+        let vee = curve("total * (x - 5)^2 / 25", "total - total * (x - 5)^2 / 25");
+        assert_eq!(
+            vee.check_monotonic(10, 100, 2),
+            Err(CurveError::NotMonotonic {
+                party: 0,
+                outcome: 6
+            })
+        );
+        // flat stretches don't set a direction
+        let steps = curve("floor(x / 5) * 10", "total - floor(x / 5) * 10");
+        assert_eq!(steps.check_monotonic(50, 100, 2), Ok(()));
+    }
+}
@@ -0,0 +1,960 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A collection of modules for creating derivative contracts with Sapio
+use bitcoin;
+
+use bitcoin::util::amount::Amount;
+
+use bitcoin::XOnlyPublicKey;
+use contract::*;
+use sapio::contract::internal_key::InternalKeyStrategy;
+use sapio::template::Template;
+use sapio::*;
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+
+pub mod curve;
+pub mod numeric;
+pub mod oracle;
+use curve::{split_by_weight, PayoutCurve};
+use numeric::*;
+use oracle::*;
+
+/// each party's payout, in sats, for an outcome, given the funds and the
+/// number of parties
+type Curve = Box<dyn Fn(u64, Amount, usize) -> Result<Vec<u64>, CompilationError>>;
+
+/// Payouts for a numeric (digit decomposed) event, where each run of
+/// outcomes with the same payout gets a single leaf, unlocked by an
+/// attestation to any of the digit prefixes covering the run.
+///
+/// Payouts are only computed at the ends of each run, bisecting between
+/// them, so curves must be monotonic for each party, as the built in ones
+/// are and `Custom` ones are checked to be. Outcomes past `points`, up to
+/// the largest the event's digits allow, pay out as `points` does.
+#[derive(Serialize, Deserialize, JsonSchema, Copy, Clone, Debug)]
+pub struct NumericOutcomes {
+    /// # Rounding
+    /// payouts are rounded down to a multiple of this many sats, so that
+    /// nearby outcomes share a leaf, with the last party getting the rest
+    pub rounding: u64,
+}
+
+/// A DLC, built from a `StandardDLC` or `DLCArgs`
+pub struct DLCContract {
+    oracles: (usize, Vec<Box<dyn DLCOracle>>),
+    curve: Curve,
+    points: u32,
+    parties: Vec<XOnlyPublicKey>,
+    event: Event,
+    /// if not set, there is a leaf per outcome in `0..=points`
+    numeric: Option<NumericOutcomes>,
+    /// a `Custom` curve, checked to be monotonic for numeric outcomes once
+    /// the funds are known
+    custom: Option<PayoutCurve>,
+    musig_cooperate: bool,
+}
+
+impl DLCContract {
+    /// Kept alongside a MuSig key path in case the parties can't run the
+    /// multi-round signing protocol.
+    #[guard]
+    fn cooperate(&self, _ctx: Context) {
+        // all of the parties, as an `And` only takes two
+        Clause::Threshold(
+            self.parties.len(),
+            self.parties.iter().cloned().map(Clause::Key).collect(),
+        )
+    }
+    #[then]
+    fn payout(&self, mut ctx: Context) {
+        let funds = ctx.funds();
+        if self.parties.len() < 2 {
+            return Err(CompilationError::TerminateCompilation);
+        }
+        let parties: Vec<Compiled> = self
+            .parties
+            .iter()
+            .enumerate()
+            .map(|(i, k)| ctx.derive_num(i as u64).and_then(|c| k.compile(c)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut tmpls: Vec<Result<Template, CompilationError>> = vec![];
+        let mut new_ctx = ctx.derive_str(Arc::new("points".to_string()))?;
+
+        let announcements = self
+            .oracles
+            .1
+            .iter()
+            .map(|oracle| oracle.get_announcement(&self.event))
+            .collect::<Result<Vec<_>, _>>()?;
+        let rounding = self.numeric.map_or(1, |n| n.rounding.max(1));
+        let amounts = |i: u64| -> Result<Vec<u64>, CompilationError> {
+            let mut payouts = (self.curve)(i, funds, parties.len())?;
+            if payouts.len() != parties.len()
+                || payouts.iter().try_fold(0u64, |a, p| a.checked_add(*p)) != Some(funds.as_sat())
+            {
+                return Err(CompilationError::TerminateWith(format!(
+                    "Payouts for Outcome {} Do Not Sum to the Funds",
+                    i
+                )));
+            }
+            if let Some((last, rest)) = payouts.split_last_mut() {
+                rest.iter_mut().for_each(|a| *a -= *a % rounding);
+                *last = funds.as_sat() - rest.iter().sum::<u64>();
+            }
+            Ok(payouts)
+        };
+        // the payout for some outcomes is unlocked by the oracles'
+        // attestations to any of them
+        let guard = |outcomes: &[Vec<String>]| -> Result<Clause, CompilationError> {
+            let keys = announcements
+                .iter()
+                .map(|a| {
+                    let mut keys = outcomes
+                        .iter()
+                        .map(|o| Ok(Clause::Key(a.outcome_point(o)?.x_only_public_key().0)))
+                        .collect::<Result<Vec<_>, OracleError>>()?;
+                    Ok(if keys.len() == 1 {
+                        keys.remove(0)
+                    } else {
+                        Clause::Threshold(1, keys)
+                    })
+                })
+                .collect::<Result<Vec<_>, OracleError>>()?;
+            Ok(Clause::Threshold(self.oracles.0, keys))
+        };
+        let leaves: Vec<(Clause, Vec<u64>)> = match self.numeric {
+            None => (0..=self.points as u64)
+                .map(|i| {
+                    let outcomes = announcements
+                        .first()
+                        .map(|a| a.event.descriptor.outcome(i))
+                        .transpose()?;
+                    Ok((
+                        guard(&outcomes.into_iter().collect::<Vec<_>>())?,
+                        amounts(i)?,
+                    ))
+                })
+                .collect::<Result<_, CompilationError>>()?,
+            Some(_) => {
+                if let Some(curve) = &self.custom {
+                    curve.check_monotonic(self.points as u64, funds.as_sat(), parties.len())?;
+                }
+                let (base, nb_digits) = match announcements
+                    .iter()
+                    .map(|a| &a.event.descriptor)
+                    .collect::<Vec<_>>()[..]
+                {
+                    [EventDescriptor::Digits {
+                        base, nb_digits, ..
+                    }, ref rest @ ..]
+                        if rest.iter().all(|d| {
+                            matches!(d, EventDescriptor::Digits { base: b, nb_digits: n, .. }
+                                if b == base && n == nb_digits)
+                        }) =>
+                    {
+                        (*base as u64, *nb_digits)
+                    }
+                    _ => {
+                        return Err(CompilationError::TerminateWith(
+                            "Numeric Outcomes Need Oracles Attesting to the Same Digits".into(),
+                        ))
+                    }
+                };
+                // the largest outcome the oracles may attest to
+                let max = (base as u128)
+                    .checked_pow(nb_digits)
+                    .filter(|_| base >= 2)
+                    .and_then(|n| u64::try_from(n - 1).ok())
+                    .ok_or_else(|| {
+                        CompilationError::TerminateWith("Unsupported Event Digits".into())
+                    })?;
+                if max < self.points as u64 {
+                    return Err(CompilationError::TerminateWith(
+                        "Points Exceed the Event's Digits".into(),
+                    ));
+                }
+                let breaks: Vec<u64> = self
+                    .custom
+                    .iter()
+                    .flat_map(|c| c.pieces.iter().map(|p| p.from))
+                    .collect();
+                let mut intervals = constant_intervals(self.points as u64, &breaks, amounts)?;
+                // outcomes past the last point pay out as it does
+                if let Some((_, hi, _)) = intervals.last_mut() {
+                    *hi = max;
+                }
+                intervals
+                    .into_iter()
+                    .map(|(lo, hi, v)| {
+                        let prefixes: Vec<Vec<String>> = digit_prefixes(lo, hi, base, nb_digits)
+                            .into_iter()
+                            .map(|p| p.iter().map(u64::to_string).collect())
+                            .collect();
+                        Ok((guard(&prefixes)?, v))
+                    })
+                    .collect::<Result<_, CompilationError>>()?
+            }
+        };
+        for (i, (guard, amounts)) in leaves.into_iter().enumerate() {
+            let mut tmpl = new_ctx.derive_num(i as u64)?.template().add_guard(guard);
+            for (party, amount) in parties.iter().zip(amounts) {
+                tmpl = tmpl.add_output(Amount::from_sat(amount), party, None)?;
+            }
+            tmpls.push(Ok(tmpl.into()));
+        }
+        Ok(Box::new(tmpls.into_iter()))
+    }
+}
+
+impl Contract for DLCContract {
+    declare! {then, Self::payout }
+    declare! {finish, Self::cooperate}
+    declare! {non updatable}
+
+    fn internal_key(&self, _ctx: Context) -> Result<InternalKeyStrategy, CompilationError> {
+        Ok(if self.musig_cooperate {
+            InternalKeyStrategy::MuSig(self.parties.clone())
+        } else {
+            InternalKeyStrategy::default()
+        })
+    }
+}
+
+/// # DLC Party
+/// A party to a DLC
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct DLCParty {
+    /// # Key
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub key: XOnlyPublicKey,
+    /// # Long
+    /// For the two sided curves, whether the party is on the side receiving
+    /// the curve's share of the funds, rather than the rest
+    pub long: bool,
+    /// # Weight
+    /// For the two sided curves, the party's share of its side's payout
+    pub weight: u64,
+}
+
+/// A DLC between any number of parties
+pub struct StandardDLC {
+    /// how many of the oracles must attest
+    pub oracles: (usize, Vec<Box<dyn DLCOracle>>),
+    /// outcomes are `0..=points`
+    pub points: u32,
+    /// the parties, in the order their payouts are given
+    pub parties: Vec<DLCParty>,
+    /// the event the oracles attest to
+    pub event: Event,
+    /// how the funds are split for each outcome
+    pub curve: SplitFunctions,
+    /// if set, outcomes are digit decomposed and equal payouts share a leaf
+    pub numeric: Option<NumericOutcomes>,
+    /// if set, the parties can cooperatively spend with the MuSig2 aggregate
+    /// of their keys as the key path
+    pub musig_cooperate: bool,
+}
+
+type Offset = f64;
+type Intercept = f64;
+/// # Payout Curve
+/// The curves besides `Custom` are two sided: they give the share of the funds
+/// paid to the long parties, the short parties getting the rest, and each
+/// side's payout is split by the parties' weights.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub enum SplitFunctions {
+    /// A positive slope Linear Function
+    /// from the interecept parameter to 1.0
+    LinearPositive(Intercept),
+    /// A Geometric starting at the intercept parameter to 1.0
+    GeometricPositive(Intercept),
+    /// offset is subtracted from points to allow moving the center
+    /// let g = x when sigmoid(x) == 0.5
+    /// a positive offset means that h>g, where h = x when sigmoid(x-offset) == 0.5
+    /// a negative offset means that h<g, where h = x when sigmoid(x-offset) == 0.5
+    Sigmoid(Offset),
+    /// A user defined curve, with a payout per party, see `curve`
+    Custom(PayoutCurve),
+}
+
+/// Splits the funds with `r` of them (clamped to `0.0..=1.0`) going to the
+/// long parties and the rest to the short ones, each side split by weight, so
+/// the payouts sum exactly to the funds.
+fn split(r: f64, funds: Amount, parties: &[DLCParty]) -> Result<Vec<u64>, CompilationError> {
+    let long = (funds.as_sat() as f64 * r.clamp(0.0, 1.0)).floor() as u64;
+    let long = long.min(funds.as_sat());
+    let side = |is_long: bool, amount: u64| -> Result<Vec<u64>, CompilationError> {
+        let weights: Vec<u64> = parties
+            .iter()
+            .filter(|p| p.long == is_long)
+            .map(|p| p.weight)
+            .collect();
+        Ok(split_by_weight(amount, &weights)?)
+    };
+    let mut longs = side(true, long)?.into_iter();
+    let mut shorts = side(false, funds.as_sat() - long)?.into_iter();
+    Ok(parties
+        .iter()
+        .filter_map(|p| if p.long { longs.next() } else { shorts.next() })
+        .collect())
+}
+
+impl SplitFunctions {
+    fn get_curve(self, points: u32, parties: Vec<DLCParty>) -> Curve {
+        let two_sided = move |r: Box<dyn Fn(f64) -> f64>| -> Curve {
+            Box::new(move |point: u64, funds: Amount, _n: usize| {
+                split(r(point as f64), funds, &parties)
+            })
+        };
+        match self {
+            SplitFunctions::LinearPositive(b) => {
+                let m = (1.0 - b) / (points as f64);
+                two_sided(Box::new(move |point| m * point + b))
+            }
+            SplitFunctions::GeometricPositive(p) => {
+                // p*j**points = 1
+                // j**points = 1.0/p
+                // log(j**points) = log(1.0/p)
+                // points *log(j) = log(1.0/p)
+                // log(j) = log(1.0/p)/points
+                // j = 2**log(1.0/p)/points
+                let j = ((1.0 / p).log2() / (points as f64)).exp2();
+                two_sided(Box::new(move |point| p * j.powi(point as i32)))
+            }
+            SplitFunctions::Sigmoid(offset) => two_sided(Box::new(move |point| {
+                1.0 / (1.0 + (-(point - offset)).exp())
+            })),
+            SplitFunctions::Custom(curve) => {
+                Box::new(move |point: u64, funds: Amount, n: usize| {
+                    Ok(curve.payouts(point, funds.as_sat(), n)?)
+                })
+            }
+        }
+    }
+}
+
+impl StandardDLC {
+    /// checks the DLC is well formed before compiling it
+    pub fn validate(&self) -> Result<(), CompilationError> {
+        let fail = |s: &str| Err(CompilationError::TerminateWith(s.into()));
+        if self.parties.len() < 2 {
+            return fail("A DLC Needs at Least Two Parties");
+        }
+        let keys: BTreeSet<_> = self.parties.iter().map(|p| p.key).collect();
+        if keys.len() != self.parties.len() {
+            return fail("DLC Parties Must Have Distinct Keys");
+        }
+        let (k, oracles) = (self.oracles.0, self.oracles.1.len());
+        if k == 0 || k > oracles {
+            return fail("DLC Oracle Threshold Must Be Between 1 and the Number of Oracles");
+        }
+        if self.numeric.map_or(false, |n| n.rounding == 0) {
+            return fail("Numeric Outcome Rounding Must Be Positive");
+        }
+        match &self.curve {
+            SplitFunctions::Custom(curve) => curve.validate(self.parties.len())?,
+            _ => {
+                if self.parties.iter().any(|p| p.weight == 0) {
+                    return fail("DLC Party Weights Must Be Positive");
+                }
+                if self.parties.iter().all(|p| p.long) || self.parties.iter().all(|p| !p.long) {
+                    return fail("Two Sided Curves Need Long and Short Parties");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<StandardDLC> for DLCContract {
+    type Error = CompilationError;
+    fn try_from(s: StandardDLC) -> Result<DLCContract, CompilationError> {
+        s.validate()?;
+        let parties = s.parties.iter().map(|p| p.key).collect();
+        let custom = match &s.curve {
+            SplitFunctions::Custom(curve) => Some(curve.clone()),
+            _ => None,
+        };
+        let curve = s.curve.get_curve(s.points, s.parties);
+        Ok(DLCContract {
+            oracles: s.oracles,
+            curve,
+            points: s.points,
+            parties,
+            event: s.event,
+            numeric: s.numeric,
+            custom,
+            musig_cooperate: s.musig_cooperate,
+        })
+    }
+}
+
+/// # DLC
+/// A DLC between any number of parties, over oracles' signed announcements of
+/// the same event
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct DLCArgs {
+    /// # Oracle Threshold
+    /// How many of the oracles must attest
+    pub threshold: usize,
+    /// # Announcements
+    /// One per oracle
+    pub announcements: Vec<OracleAnnouncement>,
+    /// # Points
+    /// Outcomes are `0..=points`
+    pub points: u32,
+    /// # Parties
+    pub parties: Vec<DLCParty>,
+    /// # Payout Curve
+    pub curve: SplitFunctions,
+    /// # Numeric Outcomes
+    pub numeric: Option<NumericOutcomes>,
+    /// # MuSig Cooperate
+    /// Use the MuSig2 aggregate of the parties' keys as the key path
+    #[serde(default)]
+    pub musig_cooperate: bool,
+}
+
+impl TryFrom<DLCArgs> for DLCContract {
+    type Error = CompilationError;
+    fn try_from(a: DLCArgs) -> Result<DLCContract, CompilationError> {
+        let event = match a.announcements.first() {
+            Some(first) => Event(first.event.event_id.clone()),
+            None => {
+                return Err(CompilationError::TerminateWith(
+                    "A DLC Needs an Oracle Announcement".into(),
+                ))
+            }
+        };
+        StandardDLC {
+            oracles: (
+                a.threshold,
+                a.announcements
+                    .into_iter()
+                    .map(|a| Box::new(a) as Box<dyn DLCOracle>)
+                    .collect(),
+            ),
+            points: a.points,
+            parties: a.parties,
+            event,
+            curve: a.curve,
+            numeric: a.numeric,
+            musig_cooperate: a.musig_cooperate,
+        }
+        .try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::Secp256k1;
+    use sapio::contract::internal_key::InternalKeyProof;
+    use sapio_base::effects::EffectPath;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    fn two_sided(keys: &[XOnlyPublicKey]) -> Vec<DLCParty> {
+        keys.iter()
+            .enumerate()
+            .map(|(i, k)| DLCParty {
+                key: *k,
+                long: i == 0,
+                weight: 1,
+            })
+            .collect()
+    }
+    #[test]
+    fn create_dlc() {
+        let secp = Secp256k1::new();
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let outcomes = (0..=1000).map(|i| i.to_string()).collect();
+        let _announcement = o
+            .announce("whatever", EventDescriptor::Enumerated(outcomes))
+            .unwrap();
+        let key = |b| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[b; 32]).unwrap();
+            sk.x_only_public_key(&secp).0
+        };
+        let (pk_a, pk_b) = (key(1), key(2));
+        let d: DLCContract = StandardDLC {
+            oracles: (1, vec![Box::new(o)]),
+            points: 1000,
+            parties: two_sided(&[pk_a, pk_b]),
+            event: Event("whatever".into()),
+            curve: SplitFunctions::LinearPositive(0.1),
+            numeric: None,
+            musig_cooperate: false,
+        }
+        .try_into()
+        .unwrap();
+        // Inner closure, the actual test
+        let ctx = Context::new(
+            bitcoin::network::constants::Network::Bitcoin,
+            Amount::from_sat(1000000000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("dlc").unwrap(),
+            Arc::new(Default::default()),
+        );
+        let _r = d.compile(ctx).unwrap();
+    }
+
+    #[test]
+    fn create_numeric_dlc() {
+        let secp = Secp256k1::new();
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let descriptor = EventDescriptor::Digits {
+            base: 2,
+            nb_digits: 16,
+            unit: String::new(),
+            precision: 0,
+        };
+        let a = o.announce("price", descriptor).unwrap();
+        let key = |b| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[b; 32]).unwrap();
+            sk.x_only_public_key(&secp).0
+        };
+        let d: DLCContract = StandardDLC {
+            oracles: (1, vec![Box::new(o)]),
+            points: 65_535,
+            parties: two_sided(&[key(1), key(2)]),
+            event: Event("price".into()),
+            curve: SplitFunctions::LinearPositive(0.1),
+            numeric: Some(NumericOutcomes {
+                rounding: 50_000_000,
+            }),
+            musig_cooperate: false,
+        }
+        .try_into()
+        .unwrap();
+        let ctx = Context::new(
+            bitcoin::network::constants::Network::Bitcoin,
+            Amount::from_sat(1000000000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("dlc").unwrap(),
+            Arc::new(Default::default()),
+        );
+        let r = d.compile(ctx).unwrap();
+        // a leaf each time the first party's payout steps by 0.5 BTC, rather
+        // than one per outcome
+        assert!(r.ctv_to_tx.len() <= 19);
+
+        // the payouts the curve gives, rounded as the contract does
+        let funds = 1_000_000_000u64;
+        let expected = |i: u64| {
+            let share = (1.0 - 0.1) / 65_535.0 * i as f64 + 0.1;
+            let long = ((funds as f64 * share.clamp(0.0, 1.0)).floor() as u64).min(funds);
+            let long = long - long % 50_000_000;
+            vec![long, funds - long]
+        };
+        fn keys(c: &Clause, out: &mut Vec<XOnlyPublicKey>) {
+            match c {
+                Clause::Key(k) => out.push(*k),
+                Clause::Threshold(_, cs) => cs.iter().for_each(|c| keys(c, out)),
+                _ => {}
+            }
+        }
+        let leaves: Vec<(Vec<XOnlyPublicKey>, Vec<u64>)> = r
+            .ctv_to_tx
+            .values()
+            .map(|t| {
+                let mut ks = vec![];
+                t.guards.iter().for_each(|g| keys(g, &mut ks));
+                (ks, t.tx.output.iter().map(|o| o.value).collect())
+            })
+            .collect();
+        // the leaf an attestation to `i` unlocks, via any of its digit
+        // prefixes
+        let payout = |i: u64| {
+            let digits: Vec<String> = (0..16).rev().map(|d| ((i >> d) & 1).to_string()).collect();
+            let points: Vec<XOnlyPublicKey> = (1..=16)
+                .map(|l| a.outcome_point(&digits[..l]).unwrap().x_only_public_key().0)
+                .collect();
+            let mut unlocked = leaves
+                .iter()
+                .filter(|(ks, _)| ks.iter().any(|k| points.contains(k)));
+            let leaf = unlocked.next().expect("a leaf for every outcome");
+            assert!(unlocked.next().is_none(), "one leaf per outcome");
+            leaf.1.clone()
+        };
+        let boundaries: Vec<u64> = (1..=65_535u64)
+            .filter(|i| expected(*i) != expected(i - 1))
+            .collect();
+        assert_eq!(boundaries.len() + 1, leaves.len());
+        let mut outcomes = vec![0, 1, 12_345, 32_768, 65_534, 65_535];
+        for b in boundaries.iter().step_by(4) {
+            outcomes.extend([b - 1, *b]);
+        }
+        for i in outcomes {
+            assert_eq!(payout(i), expected(i), "outcome {}", i);
+        }
+        assert_eq!(payout(0), vec![100_000_000, 900_000_000]);
+        assert_eq!(payout(65_535), vec![funds, 0]);
+    }
+
+    #[test]
+    fn numeric_dlc_covers_every_outcome() {
+        let secp = Secp256k1::new();
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let descriptor = EventDescriptor::Digits {
+            base: 2,
+            nb_digits: 4,
+            unit: String::new(),
+            precision: 0,
+        };
+        let a = o.announce("price", descriptor).unwrap();
+        let attestations: Vec<OracleAttestation> =
+            (0..16).map(|i| o.attest("price", i).unwrap()).collect();
+        let key = |b| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[b; 32]).unwrap();
+            sk.x_only_public_key(&secp).0
+        };
+        // outcomes run to 15, but the curve only to 10
+        let d: DLCContract = StandardDLC {
+            oracles: (1, vec![Box::new(o)]),
+            points: 10,
+            parties: two_sided(&[key(1), key(2)]),
+            event: Event("price".into()),
+            curve: SplitFunctions::LinearPositive(0.0),
+            numeric: Some(NumericOutcomes { rounding: 1 }),
+            musig_cooperate: false,
+        }
+        .try_into()
+        .unwrap();
+        let ctx = Context::new(
+            bitcoin::network::constants::Network::Bitcoin,
+            Amount::from_sat(1000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("dlc").unwrap(),
+            Arc::new(Default::default()),
+        );
+        let r = d.compile(ctx).unwrap();
+        fn keys(c: &Clause, out: &mut Vec<XOnlyPublicKey>) {
+            match c {
+                Clause::Key(k) => out.push(*k),
+                Clause::Threshold(_, cs) => cs.iter().for_each(|c| keys(c, out)),
+                _ => {}
+            }
+        }
+        // the payouts of the leaves an attestation to `i` unlocks
+        let payouts = |i: usize| -> Vec<Vec<u64>> {
+            let attestation = &attestations[i];
+            let unlocked: Vec<XOnlyPublicKey> = (1..=4)
+                .map(|l| {
+                    let sk = attestation.unlock_key(&a, l).unwrap();
+                    sk.x_only_public_key(&secp).0
+                })
+                .collect();
+            r.ctv_to_tx
+                .values()
+                .filter(|t| {
+                    let mut ks = vec![];
+                    t.guards.iter().for_each(|g| keys(g, &mut ks));
+                    ks.iter().any(|k| unlocked.contains(k))
+                })
+                .map(|t| t.tx.output.iter().map(|o| o.value).collect())
+                .collect()
+        };
+        assert_eq!(payouts(0), vec![vec![0, 1000]]);
+        assert_eq!(payouts(5), vec![vec![500, 500]]);
+        for i in 10..16 {
+            assert_eq!(payouts(i), vec![vec![1000, 0]], "outcome {}", i);
+        }
+    }
+
+    #[test]
+    fn create_custom_curve_dlc() {
+        let secp = Secp256k1::new();
+        let key = |b| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[b; 32]).unwrap();
+            sk.x_only_public_key(&secp).0
+        };
+        let dlc = |payouts: serde_json::Value| -> DLCContract {
+            let o = LocalOracle::new(b"oracle").unwrap();
+            let outcomes = (0..=10).map(|i| i.to_string()).collect();
+            o.announce("odds", EventDescriptor::Enumerated(outcomes))
+                .unwrap();
+            StandardDLC {
+                oracles: (1, vec![Box::new(o)]),
+                points: 10,
+                parties: two_sided(&[key(1), key(2)]),
+                event: Event("odds".into()),
+                curve: SplitFunctions::Custom(
+                    serde_json::from_value(serde_json::json!({
+                        "pieces": [{"from": 0, "payouts": payouts}]
+                    }))
+                    .unwrap(),
+                ),
+                numeric: None,
+                musig_cooperate: false,
+            }
+            .try_into()
+            .unwrap()
+        };
+        let ctx = || {
+            Context::new(
+                bitcoin::network::constants::Network::Bitcoin,
+                Amount::from_sat(1000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("dlc").unwrap(),
+                Arc::new(Default::default()),
+            )
+        };
+        dlc(serde_json::json!([
+            "total * x / 3 / 10",
+            "total - total * x / 3 / 10"
+        ]))
+        .compile(ctx())
+        .unwrap();
+        // a sat short of the funds
+        assert!(dlc(serde_json::json!([
+            "total * x / 10",
+            "total - total * x / 10 - 1"
+        ]))
+        .compile(ctx())
+        .is_err());
+    }
+
+    #[test]
+    fn numeric_custom_curve_is_monotonic() {
+        let secp = Secp256k1::new();
+        let key = |b| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[b; 32]).unwrap();
+            sk.x_only_public_key(&secp).0
+        };
+        let dlc = |payouts: serde_json::Value| -> DLCContract {
+            let o = LocalOracle::new(b"oracle").unwrap();
+            let descriptor = EventDescriptor::Digits {
+                base: 2,
+                nb_digits: 4,
+                unit: String::new(),
+                precision: 0,
+            };
+            o.announce("price", descriptor).unwrap();
+            StandardDLC {
+                oracles: (1, vec![Box::new(o)]),
+                points: 15,
+                parties: two_sided(&[key(1), key(2)]),
+                event: Event("price".into()),
+                curve: SplitFunctions::Custom(
+                    serde_json::from_value(serde_json::json!({
+                        "pieces": [{"from": 0, "payouts": payouts}]
+                    }))
+                    .unwrap(),
+                ),
+                numeric: Some(NumericOutcomes { rounding: 1 }),
+                musig_cooperate: false,
+            }
+            .try_into()
+            .unwrap()
+        };
+        let ctx = || {
+            Context::new(
+                bitcoin::network::constants::Network::Bitcoin,
+                Amount::from_sat(1500),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("dlc").unwrap(),
+                Arc::new(Default::default()),
+            )
+        };
+        dlc(serde_json::json!([
+            "total * x / 15",
+            "total - total * x / 15"
+        ]))
+        .compile(ctx())
+        .unwrap();
+        // rises and then falls
+        assert!(dlc(serde_json::json!([
+            "min(x, 15 - x) * 100",
+            "total - min(x, 15 - x) * 100"
+        ]))
+        .compile(ctx())
+        .is_err());
+    }
+
+    #[test]
+    fn create_pooled_dlc() {
+        let secp = Secp256k1::new();
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let outcomes = (0..=10).map(|i| i.to_string()).collect();
+        let a = o
+            .announce("pool", EventDescriptor::Enumerated(outcomes))
+            .unwrap();
+        let key = |b| {
+            let sk = bitcoin::secp256k1::SecretKey::from_slice(&[b; 32]).unwrap();
+            sk.x_only_public_key(&secp).0
+        };
+        let party = |b, long, weight| DLCParty {
+            key: key(b),
+            long,
+            weight,
+        };
+        let args = DLCArgs {
+            threshold: 1,
+            announcements: vec![a],
+            points: 10,
+            parties: vec![party(1, true, 1), party(2, false, 1), party(3, true, 3)],
+            curve: SplitFunctions::LinearPositive(0.0),
+            numeric: None,
+            musig_cooperate: true,
+        };
+        // plugins take the arguments as JSON
+        let schema = serde_json::to_value(schemars::schema_for!(DLCArgs)).unwrap();
+        assert!(schema["definitions"]["DLCParty"].is_object());
+        let json = serde_json::to_value(&args).unwrap();
+        let d = DLCContract::try_from(serde_json::from_value::<DLCArgs>(json).unwrap()).unwrap();
+        let ctx = Context::new(
+            bitcoin::network::constants::Network::Bitcoin,
+            Amount::from_sat(1000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("dlc").unwrap(),
+            Arc::new(Default::default()),
+        );
+        let r = d.compile(ctx).unwrap();
+        assert_eq!(r.ctv_to_tx.len(), 11);
+        for t in r.ctv_to_tx.values() {
+            let amounts: Vec<u64> = t.outputs.iter().map(|o| o.amount.as_sat()).collect();
+            assert_eq!(amounts.iter().sum::<u64>(), 1000);
+            // the long side is split 1:3
+            assert!(amounts[2].abs_diff(3 * amounts[0]) <= 3);
+        }
+        assert!(matches!(
+            r.metadata.internal_key,
+            Some(InternalKeyProof::MuSig { .. })
+        ));
+
+        let invalid = |parties: Vec<DLCParty>| {
+            DLCContract::try_from(DLCArgs {
+                parties,
+                ..args.clone()
+            })
+            .is_err()
+        };
+        assert!(invalid(vec![party(1, true, 1)]));
+        assert!(invalid(vec![party(1, true, 1), party(1, false, 1)]));
+        assert!(invalid(vec![party(1, true, 1), party(2, true, 1)]));
+        assert!(invalid(vec![party(1, true, 0), party(2, false, 1)]));
+    }
+
+    #[test]
+    fn attestation_unlocks_payout() {
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let outcomes = vec!["heads".to_string(), "tails".to_string()];
+        let a = o
+            .announce("coin", EventDescriptor::Enumerated(outcomes.clone()))
+            .unwrap();
+        a.verify().unwrap();
+        let attestation = o.attest("coin", 1).unwrap();
+        let sk = attestation.unlock_key(&a, 1).unwrap();
+        let pk = bitcoin::secp256k1::PublicKey::from_secret_key(&Secp256k1::new(), &sk);
+        assert_eq!(pk, a.outcome_point(&outcomes[1..]).unwrap());
+        assert_ne!(pk, a.outcome_point(&outcomes[..1]).unwrap());
+
+        let mut forged = attestation.clone();
+        forged.outcomes[0] = "heads".into();
+        assert!(forged.unlock_key(&a, 1).is_err());
+        // an attestation to a number unlocks the points of its digit prefixes
+        let descriptor = EventDescriptor::Digits {
+            base: 10,
+            nb_digits: 4,
+            unit: String::new(),
+            precision: 0,
+        };
+        let a = o.announce("price", descriptor).unwrap();
+        let attestation = o.attest("price", 1234).unwrap();
+        let sk = attestation.unlock_key(&a, 2).unwrap();
+        let pk = bitcoin::secp256k1::PublicKey::from_secret_key(&Secp256k1::new(), &sk);
+        assert_eq!(pk, a.outcome_point(&["1".into(), "2".into()]).unwrap());
+
+        let mut tampered = a.clone();
+        tampered.event.event_id = "dice".into();
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn basic_oracle_checks_key() {
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let impostor = LocalOracle::new(b"impostor").unwrap();
+        let descriptor = EventDescriptor::Enumerated(vec!["heads".into(), "tails".into()]);
+        let announcements = vec![
+            o.announce("coin", descriptor.clone()).unwrap(),
+            impostor.announce("dice", descriptor).unwrap(),
+        ];
+        let file = std::env::temp_dir().join(format!("sapio-dlc-oracle-{}", std::process::id()));
+        std::fs::write(&file, serde_json::to_vec(&announcements).unwrap()).unwrap();
+        let basic = BasicOracle {
+            announcements: file.clone(),
+            key: o.key(),
+        };
+        assert!(basic.get_announcement(&Event("coin".into())).is_ok());
+        assert!(matches!(
+            basic.get_announcement(&Event("dice".into())),
+            Err(OracleError::UnexpectedOracle(k)) if k == impostor.key()
+        ));
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn oracle_tlvs() {
+        let o = LocalOracle::new(b"oracle").unwrap();
+        let a = o
+            .announce(
+                "e",
+                EventDescriptor::Enumerated(vec!["a".into(), "b".into()]),
+            )
+            .unwrap();
+        // the dlcspecs oracle_event layout: the nonces, the maturity, the
+        // descriptor TLV and the event id
+        let event = OracleEvent {
+            maturity_epoch: 1,
+            ..a.event.clone()
+        };
+        let expected = [
+            &[0xfd, 0xd8, 0x22, 50, 0, 1][..],
+            &event.nonces[0].serialize(),
+            &[0, 0, 0, 1],
+            &[0xfd, 0xd8, 0x06, 6, 0, 2, 1, b'a', 1, b'b'],
+            &[1, b'e'],
+        ]
+        .concat();
+        assert_eq!(event.to_tlv().unwrap(), expected);
+        assert_eq!(OracleEvent::from_tlv(&expected).unwrap(), event);
+
+        let tlv = a.to_tlv().unwrap();
+        assert_eq!(OracleAnnouncement::from_tlv(&tlv).unwrap(), a);
+        // the signature covers the whole event
+        let mut tampered = tlv.clone();
+        *tampered.last_mut().unwrap() = b'f';
+        assert!(matches!(
+            OracleAnnouncement::from_tlv(&tampered),
+            Err(OracleError::BadAnnouncementSignature)
+        ));
+        assert!(OracleAnnouncement::from_tlv(&[&tlv[..], &[0]].concat()).is_err());
+        assert!(OracleAnnouncement::from_tlv(&tlv[..tlv.len() - 1]).is_err());
+
+        let descriptor = EventDescriptor::Digits {
+            base: 10,
+            nb_digits: 4,
+            unit: "BTC/USD".into(),
+            precision: -2,
+        };
+        let a = o.announce("price", descriptor).unwrap();
+        assert_eq!(
+            OracleAnnouncement::from_tlv(&a.to_tlv().unwrap()).unwrap(),
+            a
+        );
+        // bigsizes must be minimal
+        assert!(matches!(
+            OracleEvent::from_tlv(&[0xfd, 0x00, 0x05]),
+            Err(OracleError::Tlv("Non-Canonical BigSize"))
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compact payouts for numeric outcomes.
+//!
+//! A numeric event is attested digit by digit, so an oracle's attestation
+//! to any prefix of the digits is enough to unlock a payout shared by every
+//! outcome starting with that prefix. Runs of outcomes with the same payout
+//! are covered by a handful of prefixes, rather than a leaf per outcome.
+
+/// The digit prefixes, in `base`, covering exactly the outcomes in
+/// `lo..=hi` of an event with `nb_digits` digits.
+///
+/// Greedily takes the largest aligned block of outcomes (all those sharing
+/// a prefix) starting at `lo` which fits, which is the fewest prefixes
+/// possible. Prefixes have at least one digit, as there is nothing to
+/// attest to for an empty one.
+pub fn digit_prefixes(lo: u64, hi: u64, base: u64, nb_digits: u32) -> Vec<Vec<u64>> {
+    let mut prefixes = vec![];
+    let mut lo = lo as u128;
+    let (hi, base) = (hi as u128, base as u128);
+    while lo <= hi {
+        // the free digits at the end of the block
+        let mut free = 0;
+        while free + 1 < nb_digits {
+            let size = base.pow(free + 1);
+            if lo % size != 0 || lo + size - 1 > hi {
+                break;
+            }
+            free += 1;
+        }
+        let mut prefix = vec![0; (nb_digits - free) as usize];
+        let mut rest = lo / base.pow(free);
+        for d in prefix.iter_mut().rev() {
+            *d = (rest % base) as u64;
+            rest /= base;
+        }
+        prefixes.push(prefix);
+        lo += base.pow(free);
+    }
+    prefixes
+}
+
+/// Splits `0..=last` into maximal intervals on which `f` is constant,
+/// returning `(start, end, value)` for each.
+///
+/// The outcomes with a value must be contiguous within each piece, the
+/// pieces starting at 0 and at each of `breaks`, as they are for a payout
+/// curve which is monotonic on each piece. So `f` is evaluated at the ends
+/// of each piece, bisecting between outcomes whose values differ, taking
+/// a few calls per interval rather than one per outcome.
+pub fn constant_intervals<T, E, F>(
+    last: u64,
+    breaks: &[u64],
+    mut f: F,
+) -> Result<Vec<(u64, u64, T)>, E>
+where
+    T: PartialEq,
+    F: FnMut(u64) -> Result<T, E>,
+{
+    let mut starts: Vec<u64> = breaks.iter().copied().filter(|b| *b <= last).collect();
+    starts.push(0);
+    starts.sort_unstable();
+    starts.dedup();
+    let mut intervals: Vec<(u64, u64, T)> = vec![];
+    for (i, start) in starts.iter().enumerate() {
+        let hi = starts.get(i + 1).map_or(last, |next| next - 1);
+        let v_hi = f(hi)?;
+        let (mut lo, mut v_lo) = (*start, f(*start)?);
+        loop {
+            // the end of the interval starting at lo
+            let next = if v_lo == v_hi {
+                None
+            } else {
+                // f(a) == v_lo and f(b) != v_lo
+                let (mut a, mut b, mut v_b) = (lo, hi, None);
+                while b - a > 1 {
+                    let m = a + (b - a) / 2;
+                    let v = f(m)?;
+                    if v == v_lo {
+                        a = m;
+                    } else {
+                        b = m;
+                        v_b = Some(v);
+                    }
+                }
+                Some((b, v_b))
+            };
+            let end = next.as_ref().map_or(hi, |(b, _)| b - 1);
+            match intervals.last_mut() {
+                Some((_, e, current)) if *current == v_lo && *e + 1 == lo => *e = end,
+                _ => intervals.push((lo, end, v_lo)),
+            }
+            match next {
+                Some((b, v_b)) => {
+                    v_lo = match v_b {
+                        Some(v) => v,
+                        None => f(b)?,
+                    };
+                    lo = b;
+                }
+                None => break,
+            }
+        }
+    }
+    Ok(intervals)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_digit_prefixes() {
+        let covered = |lo, hi, base: u64, nb_digits| {
+            let prefixes = digit_prefixes(lo, hi, base, nb_digits);
+            let mut outcomes: Vec<u64> = vec![];
+            for p in &prefixes {
+                let start = p.iter().fold(0, |acc, d| acc * base + d);
+                let free = base.pow(nb_digits - p.len() as u32);
+                outcomes.extend(start * free..(start + 1) * free);
+            }
+            assert_eq!(outcomes, (lo..=hi).collect::<Vec<_>>());
+            prefixes.len()
+        };
+        assert_eq!(covered(0, 1023, 2, 10), 2);
+        assert_eq!(covered(0, 511, 2, 10), 1);
+        assert_eq!(covered(3, 12, 2, 4), 4);
+        assert_eq!(covered(5, 5, 2, 4), 1);
+        assert_eq!(covered(100, 299, 10, 3), 2);
+        assert_eq!(covered(123, 4567, 10, 5), 44);
+    }
+
+    #[test]
+    fn test_constant_intervals() {
+        let mut calls = 0;
+        let intervals = constant_intervals(99_999, &[], |i| -> Result<u64, ()> {
+            calls += 1;
+            Ok(i / 25_000)
+        })
+        .unwrap();
+        assert_eq!(
+            intervals,
+            vec![
+                (0, 24_999, 0),
+                (25_000, 49_999, 1),
+                (50_000, 74_999, 2),
+                (75_000, 99_999, 3)
+            ]
+        );
+        // bisecting for each boundary, rather than calling once per outcome
+        assert!(calls <= 4 * 18, "{} calls", calls);
+        // each outcome with a different value
+        assert_eq!(
+            constant_intervals(4, &[], |i| -> Result<u64, ()> { Ok(i) }).unwrap(),
+            (0..=4).map(|i| (i, i, i)).collect::<Vec<_>>()
+        );
+        // a value taken again in a later piece gets its own interval
+        assert_eq!(
+            constant_intervals(5, &[2, 3], |i| -> Result<bool, ()> { Ok(i == 2) }).unwrap(),
+            vec![(0, 1, false), (2, 2, true), (3, 5, false)]
+        );
+        // and equal values on either side of a break are merged
+        assert_eq!(
+            constant_intervals(5, &[3, 9], |_| -> Result<(), ()> { Ok(()) }).unwrap(),
+            vec![(0, 5, ())]
+        );
+        assert_eq!(
+            constant_intervals(0, &[], |_| -> Result<(), ()> { Ok(()) }).unwrap(),
+            vec![(0, 0, ())]
+        );
+        assert_eq!(
+            constant_intervals(1, &[], |i| -> Result<u64, ()> { Ok(i) }).unwrap(),
+            vec![(0, 0, 0), (1, 1, 1)]
+        );
+    }
+}
@@ -0,0 +1,703 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! DLC oracle announcements and attestations.
+//!
+//! An oracle with key `X` announces an event by committing to a nonce `R`
+//! per attested value. It later attests to outcome `m` with the BIP-340
+//! signature `(R, s)`, where `s*G = R + e*X` and `e` is the BIP-340
+//! challenge of `R`, `X` and `m`. So the attestation point `R + e*X` of
+//! every outcome can be computed from the announcement alone, and used as
+//! the key guarding that outcome's payout; `s`, published on attestation,
+//! is its secret key.
+//!
+//! Events are signed in their dlcspecs `oracle_event` TLV encoding, and
+//! announcements may be read from their `oracle_announcement` TLV, so
+//! announcements by other DLC oracles can be checked.
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::secp256k1::{
+    All, Keypair, Message, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey,
+};
+use lazy_static::lazy_static;
+use sapio::contract::CompilationError;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref SECP: Secp256k1<All> = Secp256k1::new();
+}
+
+const ANNOUNCEMENT_TAG: &[u8] = b"DLC/oracle/announcement/v0";
+const ATTESTATION_TAG: &[u8] = b"DLC/oracle/attestation/v0";
+const CHALLENGE_TAG: &[u8] = b"BIP0340/challenge";
+const NONCE_TAG: &[u8] = b"sapio/dlc/local-oracle/nonce";
+
+/// dlcspecs TLV types
+const ENUM_EVENT_DESCRIPTOR_TYPE: u64 = 55302;
+const DIGIT_DECOMPOSITION_EVENT_DESCRIPTOR_TYPE: u64 = 55306;
+const ORACLE_EVENT_TYPE: u64 = 55330;
+const ORACLE_ANNOUNCEMENT_TYPE: u64 = 55332;
+
+/// Errors in oracle announcements and attestations
+#[derive(Debug)]
+pub enum OracleError {
+    /// The announcement's signature is not valid
+    BadAnnouncementSignature,
+    /// The announcement is not by the expected oracle
+    UnexpectedOracle(XOnlyPublicKey),
+    /// The announcement has the wrong number of nonces for its event
+    WrongNonceCount {
+        /// nonces needed by the event descriptor
+        expected: usize,
+        /// nonces in the announcement
+        found: usize,
+    },
+    /// The outcome is not one the event can have
+    NoSuchOutcome(String),
+    /// No announcement for the event could be found
+    NoAnnouncement(String),
+    /// The attestation is for another event or oracle, or does not match
+    /// the announced nonces
+    AttestationMismatch,
+    /// The attestation's signature is not valid
+    BadAttestationSignature(usize),
+    /// Key arithmetic failed (with negligible probability)
+    Secp(bitcoin::secp256k1::Error),
+    /// The announcements could not be read
+    Io(std::io::Error),
+    /// The announcements could not be parsed
+    Json(serde_json::Error),
+    /// A TLV could not be encoded or parsed
+    Tlv(&'static str),
+}
+impl fmt::Display for OracleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for OracleError {}
+
+impl From<bitcoin::secp256k1::Error> for OracleError {
+    fn from(e: bitcoin::secp256k1::Error) -> Self {
+        OracleError::Secp(e)
+    }
+}
+impl From<OracleError> for CompilationError {
+    fn from(e: OracleError) -> Self {
+        CompilationError::Custom(Box::new(e))
+    }
+}
+
+fn tagged_hash(tag: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let t = sha256::Hash::hash(tag);
+    let mut e = sha256::Hash::engine();
+    e.input(&t[..]);
+    e.input(&t[..]);
+    for p in parts {
+        e.input(p);
+    }
+    sha256::Hash::from_engine(e).into_inner()
+}
+
+fn scalar(b: [u8; 32]) -> Result<Scalar, OracleError> {
+    Scalar::from_be_bytes(b).map_err(|_| OracleError::Secp(bitcoin::secp256k1::Error::InvalidTweak))
+}
+
+/// the message an oracle signs to attest to `outcome`
+fn attestation_message(outcome: &str) -> [u8; 32] {
+    tagged_hash(ATTESTATION_TAG, &[outcome.as_bytes()])
+}
+
+/// the BIP-340 challenge for a signature by `x` with nonce `r` on `msg`
+fn challenge(
+    r: &XOnlyPublicKey,
+    x: &XOnlyPublicKey,
+    msg: &[u8; 32],
+) -> Result<Scalar, OracleError> {
+    scalar(tagged_hash(
+        CHALLENGE_TAG,
+        &[&r.serialize(), &x.serialize(), msg],
+    ))
+}
+
+fn write_bigsize(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_bigsize(out, s.len() as u64);
+    out.extend(s.as_bytes());
+}
+
+fn write_tlv(out: &mut Vec<u8>, t: u64, value: &[u8]) {
+    write_bigsize(out, t);
+    write_bigsize(out, value.len() as u64);
+    out.extend(value);
+}
+
+fn u16_len(n: usize, what: &'static str) -> Result<[u8; 2], OracleError> {
+    u16::try_from(n)
+        .map(u16::to_be_bytes)
+        .map_err(|_| OracleError::Tlv(what))
+}
+
+/// Reads the fields of a TLV value, in order
+struct TlvReader<'a>(&'a [u8]);
+
+impl<'a> TlvReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], OracleError> {
+        if self.0.len() < n {
+            return Err(OracleError::Tlv("Unexpected End"));
+        }
+        let (a, b) = self.0.split_at(n);
+        self.0 = b;
+        Ok(a)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], OracleError> {
+        let mut a = [0; N];
+        a.copy_from_slice(self.take(N)?);
+        Ok(a)
+    }
+    fn u16(&mut self) -> Result<u16, OracleError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+    fn u32(&mut self) -> Result<u32, OracleError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+    /// a bigsize, which must be minimally encoded
+    fn bigsize(&mut self) -> Result<u64, OracleError> {
+        let (n, min) = match self.array::<1>()?[0] {
+            0xfd => (u16::from_be_bytes(self.array()?) as u64, 0xfd),
+            0xfe => (u32::from_be_bytes(self.array()?) as u64, 0x10000),
+            0xff => (u64::from_be_bytes(self.array()?), 0x1_0000_0000),
+            n => (n as u64, 0),
+        };
+        if n < min {
+            return Err(OracleError::Tlv("Non-Canonical BigSize"));
+        }
+        Ok(n)
+    }
+    fn string(&mut self) -> Result<String, OracleError> {
+        let len = self.bigsize()?;
+        let len = usize::try_from(len).map_err(|_| OracleError::Tlv("Unexpected End"))?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| OracleError::Tlv("Invalid UTF-8"))
+    }
+    fn x_point(&mut self) -> Result<XOnlyPublicKey, OracleError> {
+        Ok(XOnlyPublicKey::from_slice(self.take(32)?)?)
+    }
+    /// a TLV of type `t`, returning a reader of its value
+    fn tlv(&mut self, t: u64) -> Result<TlvReader<'a>, OracleError> {
+        if self.bigsize()? != t {
+            return Err(OracleError::Tlv("Unexpected Type"));
+        }
+        let len = self.bigsize()?;
+        let len = usize::try_from(len).map_err(|_| OracleError::Tlv("Unexpected End"))?;
+        Ok(TlvReader(self.take(len)?))
+    }
+    /// checks nothing is left over
+    fn finish(self) -> Result<(), OracleError> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(OracleError::Tlv("Trailing Bytes"))
+        }
+    }
+}
+
+/// The outcomes an event may have
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum EventDescriptor {
+    /// one of a list of outcomes, attested with a single nonce
+    Enumerated(Vec<String>),
+    /// a number, attested digit by digit (most significant first) with a
+    /// nonce per digit
+    Digits {
+        /// the base of each digit
+        base: u32,
+        /// how many digits are attested
+        nb_digits: u32,
+        /// the unit of the number, e.g. "BTC/USD"
+        #[serde(default)]
+        unit: String,
+        /// the number attested is the value times `base^precision`
+        #[serde(default)]
+        precision: i32,
+    },
+}
+
+impl EventDescriptor {
+    /// how many nonces announcing the event takes
+    pub fn nonces(&self) -> usize {
+        match self {
+            EventDescriptor::Enumerated(_) => 1,
+            EventDescriptor::Digits { nb_digits, .. } => *nb_digits as usize,
+        }
+    }
+    /// the `enum_event_descriptor` or `digit_decomposition_event_descriptor`
+    /// TLV. Signed numbers are not supported.
+    fn write_tlv(&self, out: &mut Vec<u8>) -> Result<(), OracleError> {
+        let mut v = vec![];
+        match self {
+            EventDescriptor::Enumerated(outcomes) => {
+                v.extend(u16_len(outcomes.len(), "Too Many Outcomes")?);
+                for o in outcomes {
+                    write_string(&mut v, o);
+                }
+                write_tlv(out, ENUM_EVENT_DESCRIPTOR_TYPE, &v);
+            }
+            EventDescriptor::Digits {
+                base,
+                nb_digits,
+                unit,
+                precision,
+            } => {
+                write_bigsize(&mut v, *base as u64);
+                // is_signed
+                v.push(0);
+                write_string(&mut v, unit);
+                v.extend(precision.to_be_bytes());
+                v.extend(u16_len(*nb_digits as usize, "Too Many Digits")?);
+                write_tlv(out, DIGIT_DECOMPOSITION_EVENT_DESCRIPTOR_TYPE, &v);
+            }
+        }
+        Ok(())
+    }
+    fn read_tlv(r: &mut TlvReader) -> Result<Self, OracleError> {
+        // peek at the type
+        let t = TlvReader(r.0).bigsize()?;
+        let descriptor = if t == ENUM_EVENT_DESCRIPTOR_TYPE {
+            let mut v = r.tlv(t)?;
+            let outcomes = (0..v.u16()?)
+                .map(|_| v.string())
+                .collect::<Result<_, _>>()?;
+            v.finish()?;
+            EventDescriptor::Enumerated(outcomes)
+        } else {
+            let mut v = r.tlv(DIGIT_DECOMPOSITION_EVENT_DESCRIPTOR_TYPE)?;
+            let base =
+                u32::try_from(v.bigsize()?).map_err(|_| OracleError::Tlv("Base Too Large"))?;
+            if v.array::<1>()?[0] != 0 {
+                return Err(OracleError::Tlv("Signed Events Are Not Supported"));
+            }
+            let unit = v.string()?;
+            let precision = i32::from_be_bytes(v.array()?);
+            let nb_digits = v.u16()? as u32;
+            v.finish()?;
+            EventDescriptor::Digits {
+                base,
+                nb_digits,
+                unit,
+                precision,
+            }
+        };
+        Ok(descriptor)
+    }
+    /// the strings attested to for the `i`th outcome: the `i`th of an
+    /// enumerated event, or the digits of `i`.
+    pub fn outcome(&self, i: u64) -> Result<Vec<String>, OracleError> {
+        match self {
+            EventDescriptor::Enumerated(outcomes) => outcomes
+                .get(i as usize)
+                .map(|o| vec![o.clone()])
+                .ok_or_else(|| OracleError::NoSuchOutcome(i.to_string())),
+            EventDescriptor::Digits {
+                base, nb_digits, ..
+            } => {
+                let base = *base as u64;
+                let mut digits = vec![String::new(); *nb_digits as usize];
+                let mut rest = i;
+                for d in digits.iter_mut().rev() {
+                    *d = (rest % base).to_string();
+                    rest /= base;
+                }
+                if rest != 0 || base < 2 {
+                    return Err(OracleError::NoSuchOutcome(i.to_string()));
+                }
+                Ok(digits)
+            }
+        }
+    }
+}
+
+/// An event an oracle will attest to
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct OracleEvent {
+    /// the oracle's name for the event
+    pub event_id: String,
+    /// the nonces the oracle will attest with, one per attested value
+    #[schemars(with = "Vec<String>")]
+    pub nonces: Vec<XOnlyPublicKey>,
+    /// the outcomes the event may have
+    pub descriptor: EventDescriptor,
+    /// when the oracle expects to attest, in seconds since the epoch
+    #[serde(default)]
+    pub maturity_epoch: u32,
+}
+
+impl OracleEvent {
+    /// the event's `oracle_event` TLV
+    pub fn to_tlv(&self) -> Result<Vec<u8>, OracleError> {
+        let mut v = vec![];
+        v.extend(u16_len(self.nonces.len(), "Too Many Nonces")?);
+        for n in &self.nonces {
+            v.extend(n.serialize());
+        }
+        v.extend(self.maturity_epoch.to_be_bytes());
+        self.descriptor.write_tlv(&mut v)?;
+        write_string(&mut v, &self.event_id);
+        let mut out = vec![];
+        write_tlv(&mut out, ORACLE_EVENT_TYPE, &v);
+        Ok(out)
+    }
+    fn read_tlv(r: &mut TlvReader) -> Result<Self, OracleError> {
+        let mut v = r.tlv(ORACLE_EVENT_TYPE)?;
+        let nonces = (0..v.u16()?)
+            .map(|_| v.x_point())
+            .collect::<Result<_, _>>()?;
+        let maturity_epoch = v.u32()?;
+        let descriptor = EventDescriptor::read_tlv(&mut v)?;
+        let event_id = v.string()?;
+        v.finish()?;
+        Ok(OracleEvent {
+            event_id,
+            nonces,
+            descriptor,
+            maturity_epoch,
+        })
+    }
+    /// parses an `oracle_event` TLV
+    pub fn from_tlv(bytes: &[u8]) -> Result<Self, OracleError> {
+        let mut r = TlvReader(bytes);
+        let event = Self::read_tlv(&mut r)?;
+        r.finish()?;
+        Ok(event)
+    }
+    fn message(&self) -> Result<[u8; 32], OracleError> {
+        Ok(tagged_hash(ANNOUNCEMENT_TAG, &[&self.to_tlv()?]))
+    }
+}
+
+/// An oracle's signed commitment to attest to an event with given nonces
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct OracleAnnouncement {
+    /// the oracle's key
+    #[schemars(with = "String")]
+    pub oracle_key: XOnlyPublicKey,
+    /// the event being announced
+    pub event: OracleEvent,
+    /// the oracle's signature of `event`
+    #[schemars(with = "String")]
+    pub signature: Signature,
+}
+
+impl OracleAnnouncement {
+    /// the announcement's `oracle_announcement` TLV
+    pub fn to_tlv(&self) -> Result<Vec<u8>, OracleError> {
+        let mut v = self.signature.as_ref().to_vec();
+        v.extend(self.oracle_key.serialize());
+        v.extend(self.event.to_tlv()?);
+        let mut out = vec![];
+        write_tlv(&mut out, ORACLE_ANNOUNCEMENT_TYPE, &v);
+        Ok(out)
+    }
+    /// parses and verifies an `oracle_announcement` TLV
+    pub fn from_tlv(bytes: &[u8]) -> Result<Self, OracleError> {
+        let mut r = TlvReader(bytes);
+        let mut v = r.tlv(ORACLE_ANNOUNCEMENT_TYPE)?;
+        r.finish()?;
+        let signature = Signature::from_slice(v.take(64)?)?;
+        let oracle_key = v.x_point()?;
+        let event = OracleEvent::read_tlv(&mut v)?;
+        v.finish()?;
+        let a = OracleAnnouncement {
+            oracle_key,
+            event,
+            signature,
+        };
+        a.verify()?;
+        Ok(a)
+    }
+    /// checks the announcement was signed by its oracle and has a nonce per
+    /// attested value
+    pub fn verify(&self) -> Result<(), OracleError> {
+        let msg = Message::from_digest(self.event.message()?);
+        SECP.verify_schnorr(&self.signature, &msg, &self.oracle_key)
+            .map_err(|_| OracleError::BadAnnouncementSignature)?;
+        let expected = self.event.descriptor.nonces();
+        if self.event.nonces.len() != expected {
+            return Err(OracleError::WrongNonceCount {
+                expected,
+                found: self.event.nonces.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// the point whose secret the oracle reveals by attesting to `outcome`
+    /// with its `nonce`th nonce
+    pub fn attestation_point(&self, nonce: usize, outcome: &str) -> Result<PublicKey, OracleError> {
+        let r = self
+            .event
+            .nonces
+            .get(nonce)
+            .ok_or(OracleError::WrongNonceCount {
+                expected: nonce + 1,
+                found: self.event.nonces.len(),
+            })?;
+        let e = challenge(r, &self.oracle_key, &attestation_message(outcome))?;
+        let x = PublicKey::from_x_only_public_key(self.oracle_key, Parity::Even);
+        let r = PublicKey::from_x_only_public_key(*r, Parity::Even);
+        Ok(r.combine(&x.mul_tweak(&SECP, &e)?)?)
+    }
+
+    /// the point whose secret the oracle reveals by attesting to the
+    /// values `outcome`, one per nonce in order, which may be a prefix of
+    /// the event's digits
+    pub fn outcome_point(&self, outcome: &[String]) -> Result<PublicKey, OracleError> {
+        let points = outcome
+            .iter()
+            .enumerate()
+            .map(|(i, o)| self.attestation_point(i, o))
+            .collect::<Result<Vec<_>, _>>()?;
+        let points: Vec<&PublicKey> = points.iter().collect();
+        Ok(PublicKey::combine_keys(&points[..])?)
+    }
+}
+
+/// An oracle's attestation to the outcome of an announced event
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct OracleAttestation {
+    /// the event attested to
+    pub event_id: String,
+    /// the oracle's key
+    #[schemars(with = "String")]
+    pub oracle_key: XOnlyPublicKey,
+    /// the values attested to, one per announced nonce
+    pub outcomes: Vec<String>,
+    /// the signatures of `outcomes`, with the announced nonces
+    #[schemars(with = "Vec<String>")]
+    pub signatures: Vec<Signature>,
+}
+
+impl OracleAttestation {
+    /// Checks the attestation against `announcement`, and returns the secret
+    /// key of the point for the first `values` attested values, which
+    /// unlocks the payout guarded by that point.
+    pub fn unlock_key(
+        &self,
+        announcement: &OracleAnnouncement,
+        values: usize,
+    ) -> Result<SecretKey, OracleError> {
+        if self.event_id != announcement.event.event_id
+            || self.oracle_key != announcement.oracle_key
+            || self.outcomes.len() != announcement.event.nonces.len()
+            || self.signatures.len() != self.outcomes.len()
+            || values == 0
+            || values > self.outcomes.len()
+        {
+            return Err(OracleError::AttestationMismatch);
+        }
+        let mut key: Option<SecretKey> = None;
+        for (i, ((sig, outcome), nonce)) in self
+            .signatures
+            .iter()
+            .zip(self.outcomes.iter())
+            .zip(announcement.event.nonces.iter())
+            .enumerate()
+            .take(values)
+        {
+            let bytes = sig.as_ref();
+            if bytes[..32] != nonce.serialize() {
+                return Err(OracleError::AttestationMismatch);
+            }
+            let msg = Message::from_digest(attestation_message(outcome));
+            SECP.verify_schnorr(sig, &msg, &self.oracle_key)
+                .map_err(|_| OracleError::BadAttestationSignature(i))?;
+            let s = SecretKey::from_slice(&bytes[32..])?;
+            key = Some(match key {
+                None => s,
+                Some(k) => k.add_tweak(&Scalar::from(s))?,
+            });
+        }
+        key.ok_or(OracleError::AttestationMismatch)
+    }
+}
+
+/// An event whose outcome an oracle attests to
+pub struct Event(pub String);
+
+/// Something which can announce DLC events
+pub trait DLCOracle {
+    /// get the (verified) announcement of `e`
+    fn get_announcement(&self, e: &Event) -> Result<OracleAnnouncement, OracleError>;
+}
+
+/// An oracle whose announcements are read from a JSON file holding a list
+/// of `OracleAnnouncement`s. Only announcements signed by `key` are
+/// accepted, as anyone able to write the file could otherwise choose the
+/// oracle.
+pub struct BasicOracle {
+    /// the file of announcements
+    pub announcements: PathBuf,
+    /// the oracle's key
+    pub key: XOnlyPublicKey,
+}
+
+impl DLCOracle for BasicOracle {
+    fn get_announcement(&self, e: &Event) -> Result<OracleAnnouncement, OracleError> {
+        let bytes = std::fs::read(&self.announcements).map_err(OracleError::Io)?;
+        let all: Vec<OracleAnnouncement> =
+            serde_json::from_slice(&bytes).map_err(OracleError::Json)?;
+        let a = all
+            .into_iter()
+            .find(|a| a.event.event_id == e.0)
+            .ok_or_else(|| OracleError::NoAnnouncement(e.0.clone()))?;
+        if a.oracle_key != self.key {
+            return Err(OracleError::UnexpectedOracle(a.oracle_key));
+        }
+        a.verify()?;
+        Ok(a)
+    }
+}
+
+/// An announcement passed in directly, e.g. as a plugin argument, announces
+/// its own event.
+impl DLCOracle for OracleAnnouncement {
+    fn get_announcement(&self, e: &Event) -> Result<OracleAnnouncement, OracleError> {
+        if self.event.event_id != e.0 {
+            return Err(OracleError::NoAnnouncement(e.0.clone()));
+        }
+        self.verify()?;
+        Ok(self.clone())
+    }
+}
+
+/// A stand-in oracle running in this process, for testing and local use.
+/// Nonces are derived from the oracle's key and the event, so it keeps no
+/// state besides the announcements it has made.
+pub struct LocalOracle {
+    key: Keypair,
+    announced: Mutex<BTreeMap<String, OracleAnnouncement>>,
+}
+
+impl LocalOracle {
+    /// an oracle whose key is derived from `seed`
+    pub fn new(seed: &[u8]) -> Result<Self, OracleError> {
+        let sk = SecretKey::from_slice(&sha256::Hash::hash(seed)[..])?;
+        Ok(LocalOracle {
+            key: Keypair::from_secret_key(&SECP, &sk),
+            announced: Default::default(),
+        })
+    }
+    /// the oracle's public key
+    pub fn key(&self) -> XOnlyPublicKey {
+        self.key.x_only_public_key().0
+    }
+    /// the `i`th nonce for `event_id`, negated if needed so its point has an
+    /// even y coordinate
+    fn nonce(&self, event_id: &str, i: usize) -> Result<SecretKey, OracleError> {
+        let k = SecretKey::from_slice(&tagged_hash(
+            NONCE_TAG,
+            &[
+                &self.key.secret_bytes(),
+                event_id.as_bytes(),
+                &(i as u64).to_be_bytes(),
+            ],
+        ))?;
+        Ok(match k.x_only_public_key(&SECP).1 {
+            Parity::Even => k,
+            Parity::Odd => k.negate(),
+        })
+    }
+    /// announce an event
+    pub fn announce(
+        &self,
+        event_id: &str,
+        descriptor: EventDescriptor,
+    ) -> Result<OracleAnnouncement, OracleError> {
+        let nonces = (0..descriptor.nonces())
+            .map(|i| Ok(self.nonce(event_id, i)?.x_only_public_key(&SECP).0))
+            .collect::<Result<Vec<_>, OracleError>>()?;
+        let event = OracleEvent {
+            event_id: event_id.into(),
+            nonces,
+            descriptor,
+            maturity_epoch: 0,
+        };
+        let signature =
+            SECP.sign_schnorr_no_aux_rand(&Message::from_digest(event.message()?), &self.key);
+        let a = OracleAnnouncement {
+            oracle_key: self.key(),
+            event,
+            signature,
+        };
+        self.announced
+            .lock()
+            .unwrap()
+            .insert(event_id.into(), a.clone());
+        Ok(a)
+    }
+    /// attest to the `i`th outcome of an announced event (see
+    /// `EventDescriptor::outcome`)
+    pub fn attest(&self, event_id: &str, i: u64) -> Result<OracleAttestation, OracleError> {
+        let a = self.get_announcement(&Event(event_id.into()))?;
+        let outcomes = a.event.descriptor.outcome(i)?;
+        let x = match self.key.x_only_public_key().1 {
+            Parity::Even => self.key.secret_key(),
+            Parity::Odd => self.key.secret_key().negate(),
+        };
+        let signatures = outcomes
+            .iter()
+            .enumerate()
+            .map(|(i, outcome)| {
+                let k = self.nonce(event_id, i)?;
+                let r = k.x_only_public_key(&SECP).0;
+                let e = challenge(&r, &self.key(), &attestation_message(outcome))?;
+                let s = k.add_tweak(&Scalar::from(x.mul_tweak(&e)?))?;
+                Ok(Signature::from_slice(
+                    &[&r.serialize()[..], &s.secret_bytes()[..]].concat(),
+                )?)
+            })
+            .collect::<Result<Vec<_>, OracleError>>()?;
+        Ok(OracleAttestation {
+            event_id: event_id.into(),
+            oracle_key: self.key(),
+            outcomes,
+            signatures,
+        })
+    }
+}
+
+impl DLCOracle for LocalOracle {
+    fn get_announcement(&self, e: &Event) -> Result<OracleAnnouncement, OracleError> {
+        self.announced
+            .lock()
+            .unwrap()
+            .get(&e.0)
+            .cloned()
+            .ok_or_else(|| OracleError::NoAnnouncement(e.0.clone()))
+    }
+}
@@ -4,8 +4,33 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-//! A Price Oracle trait for Derivatives
+//! A Price Oracle trait for Derivatives, and implementations of it
+//!
+//! * `HashOracle`: hashlocks, the oracle revealing the preimage for whichever
+//!   side of each price the settlement price fell on.
+//! * `AttestationOracle`: keys derived from an oracle's xpub, the oracle
+//!   signing with whichever side's key holds.
+//! * `MockOracle`: a local oracle holding the secrets behind both, for tests.
+//!
+//! `OracleSpec` describes any of the public ones as JSON, so that plugins can
+//! take an oracle as an argument, and `CachedOracle` remembers lookups.
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{All, Keypair, Secp256k1};
+use bitcoin::util::bip32::{self, ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::Network;
+use lazy_static::lazy_static;
 use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+lazy_static! {
+    static ref SECP: Secp256k1<All> = Secp256k1::new();
+}
+
+const PREIMAGE_TAG: &[u8] = b"sapio/oracle/mock/preimage";
 /// Placeholder type for a standard way of looking up a stock symbol; can be defined more
 /// concretely but should have a human readable string representation.
 pub type Symbol = String;
@@ -41,3 +66,303 @@ impl Oracle for ThresholdOracle {
         )
     }
 }
+
+/// A hashlock oracle, from the hashes it published for each symbol and price
+/// as `(lt, gte)`. The oracle reveals the `lt` preimage if the settlement price
+/// is below the price, and the `gte` one otherwise.
+///
+/// Prices the oracle has not published hashes for can never be unlocked, so
+/// their clauses are `Unsatisfiable`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Default)]
+pub struct HashOracle {
+    /// the published hashes
+    pub hashes: BTreeMap<Symbol, BTreeMap<i64, (sha256::Hash, sha256::Hash)>>,
+}
+
+impl Oracle for HashOracle {
+    fn get_key_lt_gte(&self, t: &Symbol, price: i64) -> (Clause, Clause) {
+        match self.hashes.get(t).and_then(|h| h.get(&price)) {
+            Some((lt, gte)) => (Clause::Sha256(*lt), Clause::Sha256(*gte)),
+            None => (Clause::Unsatisfiable, Clause::Unsatisfiable),
+        }
+    }
+}
+
+/// How an `AttestationOracle` derives the key for each symbol, price and side
+/// from its xpub
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum DerivationScheme {
+    /// `prefix/symbol/price/side`, where `symbol` is the first 31 bits of the
+    /// symbol's sha256, `price` is three children (2, 31, and 31 bits) of the
+    /// price with its sign bit flipped, and `side` is 0 for `lt` and 1 for
+    /// `gte`. All unhardened.
+    SymbolPriceSide {
+        /// prepended to every path
+        #[schemars(with = "String")]
+        prefix: DerivationPath,
+    },
+}
+
+impl DerivationScheme {
+    /// the path of the key for a symbol, price, and side
+    pub fn path(&self, t: &Symbol, price: i64, gte: bool) -> Result<DerivationPath, bip32::Error> {
+        match self {
+            DerivationScheme::SymbolPriceSide { prefix } => {
+                let h = sha256::Hash::hash(t.as_bytes());
+                let symbol = u32::from_be_bytes([h[0], h[1], h[2], h[3]]) & 0x7fff_ffff;
+                let p = (price as u64) ^ (1 << 63);
+                let children = [
+                    symbol,
+                    (p >> 62) as u32,
+                    ((p >> 31) & 0x7fff_ffff) as u32,
+                    (p & 0x7fff_ffff) as u32,
+                    gte as u32,
+                ]
+                .iter()
+                .map(|i| ChildNumber::from_normal_idx(*i))
+                .collect::<Result<Vec<_>, _>>()?;
+                Ok(prefix.extend(children))
+            }
+        }
+    }
+}
+
+/// A Schnorr attestation oracle, with a key per symbol, price and side derived
+/// from its published xpub. The oracle signs with the `lt` key if the
+/// settlement price is below the price, and with the `gte` one otherwise.
+///
+/// The oracle signs rather than revealing the keys, as revealing an
+/// unhardened child key and the xpub would reveal the oracle's root key.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct AttestationOracle {
+    /// the oracle's published xpub
+    #[schemars(with = "String")]
+    pub xpub: ExtendedPubKey,
+    /// how keys are derived from `xpub`
+    pub scheme: DerivationScheme,
+}
+
+impl AttestationOracle {
+    fn key(&self, t: &Symbol, price: i64, gte: bool) -> Result<Clause, bip32::Error> {
+        let path = self.scheme.path(t, price, gte)?;
+        Ok(Clause::Key(
+            self.xpub.derive_pub(&SECP, &path)?.to_x_only_pub(),
+        ))
+    }
+}
+
+impl Oracle for AttestationOracle {
+    /// If a key can't be derived, which is vanishingly unlikely, its clause is
+    /// `Unsatisfiable`.
+    fn get_key_lt_gte(&self, t: &Symbol, price: i64) -> (Clause, Clause) {
+        let key = |gte| self.key(t, price, gte).unwrap_or(Clause::Unsatisfiable);
+        (key(false), key(true))
+    }
+}
+
+/// # Oracle
+/// Any of the oracles which can be described publicly, for taking oracles as
+/// (plugin) arguments
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum OracleSpec {
+    /// A hashlock oracle
+    Hash(HashOracle),
+    /// A Schnorr attestation oracle
+    Attestation(AttestationOracle),
+    /// `thresh` of `oracles` must agree
+    Threshold {
+        /// how many oracles must agree
+        thresh: usize,
+        /// the oracles
+        oracles: Vec<OracleSpec>,
+    },
+}
+
+impl Oracle for OracleSpec {
+    fn get_key_lt_gte(&self, t: &Symbol, price: i64) -> (Clause, Clause) {
+        match self {
+            OracleSpec::Hash(o) => o.get_key_lt_gte(t, price),
+            OracleSpec::Attestation(o) => o.get_key_lt_gte(t, price),
+            OracleSpec::Threshold { thresh, oracles } => {
+                let (l, r) = oracles.iter().map(|o| o.get_key_lt_gte(t, price)).unzip();
+                (Clause::Threshold(*thresh, l), Clause::Threshold(*thresh, r))
+            }
+        }
+    }
+}
+
+/// Remembers an oracle's answers, so each symbol and price is only looked up
+/// (or derived) once
+pub struct CachedOracle<O: Oracle> {
+    oracle: O,
+    cache: RefCell<BTreeMap<(Symbol, i64), (Clause, Clause)>>,
+}
+
+impl<O: Oracle> CachedOracle<O> {
+    /// Wraps `oracle` with an empty cache
+    pub fn new(oracle: O) -> Self {
+        CachedOracle {
+            oracle,
+            cache: Default::default(),
+        }
+    }
+}
+
+impl<O: Oracle> Oracle for CachedOracle<O> {
+    fn get_key_lt_gte(&self, t: &Symbol, price: i64) -> (Clause, Clause) {
+        if let Some(r) = self.cache.borrow().get(&(t.clone(), price)) {
+            return r.clone();
+        }
+        let r = self.oracle.get_key_lt_gte(t, price);
+        self.cache
+            .borrow_mut()
+            .insert((t.clone(), price), r.clone());
+        r
+    }
+}
+
+/// A local oracle service for tests, holding the secrets behind both a
+/// `HashOracle` and an `AttestationOracle`, and revealing or signing for
+/// prices once a symbol is settled.
+///
+/// As an `Oracle` itself, it gives hashlocks.
+pub struct MockOracle {
+    xpriv: ExtendedPrivKey,
+    scheme: DerivationScheme,
+    settled: RefCell<BTreeMap<Symbol, i64>>,
+}
+
+impl MockOracle {
+    /// An oracle with secrets derived from `seed`
+    pub fn new(seed: &[u8]) -> Result<Self, bip32::Error> {
+        Ok(MockOracle {
+            xpriv: ExtendedPrivKey::new_master(Network::Bitcoin, seed)?,
+            scheme: DerivationScheme::SymbolPriceSide {
+                prefix: DerivationPath::master(),
+            },
+            settled: Default::default(),
+        })
+    }
+    fn preimage(&self, t: &Symbol, price: i64, gte: bool) -> [u8; 32] {
+        let mut e = HmacEngine::<sha256::Hash>::new(&self.xpriv.private_key[..]);
+        e.input(PREIMAGE_TAG);
+        e.input(&sha256::Hash::hash(t.as_bytes())[..]);
+        e.input(&price.to_be_bytes());
+        e.input(&[gte as u8]);
+        Hmac::<sha256::Hash>::from_engine(e).into_inner()
+    }
+    /// the `HashOracle` this oracle would publish for `prices` of `t`
+    pub fn hash_oracle<I: IntoIterator<Item = i64>>(&self, t: &Symbol, prices: I) -> HashOracle {
+        let mut o = HashOracle::default();
+        o.hashes.insert(
+            t.clone(),
+            prices
+                .into_iter()
+                .map(|p| {
+                    let h = |gte| sha256::Hash::hash(&self.preimage(t, p, gte));
+                    (p, (h(false), h(true)))
+                })
+                .collect(),
+        );
+        o
+    }
+    /// the `AttestationOracle` this oracle would publish
+    pub fn attestation_oracle(&self) -> AttestationOracle {
+        AttestationOracle {
+            xpub: ExtendedPubKey::from_priv(&SECP, &self.xpriv),
+            scheme: self.scheme.clone(),
+        }
+    }
+    /// settles `t` at `price`
+    pub fn settle(&self, t: &Symbol, price: i64) {
+        self.settled.borrow_mut().insert(t.clone(), price);
+    }
+    /// whether `t` settled at or above `price`, if it has settled
+    fn side(&self, t: &Symbol, price: i64) -> Option<bool> {
+        self.settled
+            .borrow()
+            .get(t)
+            .map(|settled| *settled >= price)
+    }
+    /// the preimage for the side of `price` that `t` settled on
+    pub fn reveal_preimage(&self, t: &Symbol, price: i64) -> Option<[u8; 32]> {
+        self.side(t, price).map(|gte| self.preimage(t, price, gte))
+    }
+    /// the key for the side of `price` that `t` settled on, for signing
+    pub fn attestation_key(&self, t: &Symbol, price: i64) -> Option<Keypair> {
+        let gte = self.side(t, price)?;
+        let path = self.scheme.path(t, price, gte).ok()?;
+        let sk = self.xpriv.derive_priv(&SECP, &path).ok()?.private_key;
+        Some(Keypair::from_secret_key(&SECP, &sk))
+    }
+}
+
+impl Oracle for MockOracle {
+    fn get_key_lt_gte(&self, t: &Symbol, price: i64) -> (Clause, Clause) {
+        let h = |gte| Clause::Sha256(sha256::Hash::hash(&self.preimage(t, price, gte)));
+        (h(false), h(true))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    #[test]
+    fn test_oracles() {
+        let mock = MockOracle::new(b"oracle").unwrap();
+        let btc = "BTC".to_string();
+        mock.settle(&btc, 30_000);
+        assert_eq!(mock.reveal_preimage(&"ETH".into(), 1), None);
+
+        let hashes = mock.hash_oracle(&btc, vec![-5, 20_000, 30_000, 40_000]);
+        for price in [-5, 20_000, 30_000, 40_000] {
+            let (lt, gte) = hashes.get_key_lt_gte(&btc, price);
+            assert_eq!((lt.clone(), gte.clone()), mock.get_key_lt_gte(&btc, price));
+            let revealed = Clause::Sha256(sha256::Hash::hash(
+                &mock.reveal_preimage(&btc, price).unwrap(),
+            ));
+            assert_eq!(revealed, if price <= 30_000 { gte } else { lt });
+        }
+        assert_eq!(hashes.get_key_lt_gte(&btc, 1).0, Clause::Unsatisfiable);
+
+        let attest = mock.attestation_oracle();
+        for price in [i64::MIN, -1, 0, 30_001, i64::MAX] {
+            let (lt, gte) = attest.get_key_lt_gte(&btc, price);
+            assert_ne!(lt, gte);
+            let key = mock.attestation_key(&btc, price).unwrap();
+            let signer = Clause::Key(key.x_only_public_key().0);
+            assert_eq!(signer, if price <= 30_000 { gte } else { lt });
+        }
+
+        // plugins can take any of them as JSON
+        let spec = OracleSpec::Threshold {
+            thresh: 2,
+            oracles: vec![OracleSpec::Hash(hashes), OracleSpec::Attestation(attest)],
+        };
+        let json = serde_json::to_value(&spec).unwrap();
+        let spec: OracleSpec = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            spec.get_key_lt_gte(&btc, 20_000),
+            (Clause::Threshold(2, _), Clause::Threshold(2, _))
+        ));
+    }
+
+    struct Counting(Cell<usize>);
+    impl Oracle for Counting {
+        fn get_key_lt_gte(&self, _t: &Symbol, _price: i64) -> (Clause, Clause) {
+            self.0.set(self.0.get() + 1);
+            (Clause::Trivial, Clause::Unsatisfiable)
+        }
+    }
+
+    #[test]
+    fn test_cached_oracle() {
+        let cached = CachedOracle::new(Counting(Cell::new(0)));
+        for price in [1, 2, 1, 2, 3] {
+            cached.get_key_lt_gte(&"BTC".into(), price);
+        }
+        cached.get_key_lt_gte(&"ETH".into(), 1);
+        assert_eq!(cached.oracle.0.get(), 4);
+    }
+}
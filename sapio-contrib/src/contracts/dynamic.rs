@@ -6,6 +6,7 @@
 
 //! example of using a dynamic contract
 use bitcoin::Amount;
+use sapio::contract::internal_key::InternalKeyStrategy;
 use sapio::contract::object::ObjectMetadata;
 use sapio::contract::DynamicContract;
 use sapio::contract::*;
@@ -48,6 +49,9 @@ impl AnyContract for D<'static> {
     fn ensure_amount<'a>(&'a self, _ctx: Context) -> Result<Amount, CompilationError> {
         Ok(Amount::from_sat(0))
     }
+    fn internal_key<'a>(&'a self, _ctx: Context) -> Result<InternalKeyStrategy, CompilationError> {
+        Ok(Default::default())
+    }
 }
 
 /// Shows how to make a Dynamic Contract without creating a bespoke type.
@@ -68,15 +72,18 @@ impl DynamicExample {
                         guard: &[],
                         func: |_s, _ctx, _t| Err(CompilationError::TerminateCompilation),
                         name: Arc::new("Empty".into()),
+                        weight: sapio::contract::actions::DEFAULT_BRANCH_WEIGHT,
                     }
                     .into(),
                 )
             }],
             finish: vec![],
+            finish_weights: vec![],
             finish_or: vec![],
             data: "E.g., Create a Vault".into(),
             metadata_f: Box::new(|_s, _c| Ok(Default::default())),
             ensure_amount_f: Box::new(|_s, _c| Ok(Default::default())),
+            internal_key_f: Box::new(|_s, _c| Ok(Default::default())),
         };
         let mut bld = ctx.template();
         let amt = bld.ctx().funds() / 2;
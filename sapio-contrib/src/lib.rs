@@ -8,8 +8,237 @@
 pub mod contracts;
 #[cfg(test)]
 mod tests {
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::util::amount::Amount;
+    use bitcoin::XOnlyPublicKey;
+    use miniscript::{Descriptor, DescriptorTrait, Terminal};
+    use sapio::contract::internal_key::*;
+    use sapio::contract::object::SupportedDescriptors;
+    use sapio::contract::*;
+    use sapio::*;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn key(i: u8) -> XOnlyPublicKey {
+        SecretKey::from_slice(&[i; 32])
+            .unwrap()
+            .x_only_public_key(&Secp256k1::new())
+            .0
+    }
+
+    fn ctx() -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+
+    fn taproot(c: &Compiled) -> &miniscript::descriptor::Tr<XOnlyPublicKey> {
+        match &c.descriptor {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr,
+            _ => panic!("Not a Taproot Descriptor"),
+        }
+    }
+
+    /// the depth of the leaf which is just a check of `k`
+    fn depth_of(c: &Compiled, k: XOnlyPublicKey) -> u8 {
+        match &c.descriptor {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr
+                .iter_scripts()
+                .find(
+                    |(_, ms)| matches!(&ms.node, Terminal::Check(c) if c.node == Terminal::PkK(k)),
+                )
+                .unwrap()
+                .0,
+            _ => panic!("Not a Taproot Descriptor"),
+        }
+    }
+
+    struct Weighted;
+    impl Weighted {
+        #[guard]
+        fn a(self, _ctx: Context) {
+            Clause::Key(key(1))
+        }
+        #[guard]
+        fn b(self, _ctx: Context) {
+            Clause::Key(key(2))
+        }
+        #[guard]
+        fn c(self, _ctx: Context) {
+            Clause::Key(key(3))
+        }
+        #[guard]
+        fn d(self, _ctx: Context) {
+            Clause::Key(key(4))
+        }
+    }
+    impl Contract for Weighted {
+        declare! {finish, Self::a => 10, Self::b, Self::c, Self::d}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn weighted_finish_branches() {
+        let compiled = Weighted.compile(ctx()).unwrap();
+        assert_eq!(depth_of(&compiled, key(1)), 1);
+        for i in 2..=4 {
+            assert!(depth_of(&compiled, key(i)) > 1);
+        }
+    }
+
+    struct KeyedBy(InternalKeyStrategy);
+    impl KeyedBy {
+        #[guard]
+        fn spend(self, _ctx: Context) {
+            Clause::Key(key(5))
+        }
+    }
+    impl Contract for KeyedBy {
+        declare! {finish, Self::spend}
+        declare! {non updatable}
+        fn internal_key(&self, _ctx: Context) -> Result<InternalKeyStrategy, CompilationError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// compiles a contract with the strategy `s`, checking that the key it
+    /// records is the one used and that its proof checks out
+    fn compile_keyed(s: InternalKeyStrategy) -> (Compiled, Option<InternalKeyProof>) {
+        let compiled = KeyedBy(s).compile(ctx()).unwrap();
+        let proof = compiled.metadata.internal_key.clone();
+        if let Some(p) = &proof {
+            assert_eq!(p.key(), *taproot(&compiled).internal_key());
+            assert!(p.verify(&Secp256k1::verification_only()));
+        }
+        (compiled, proof)
+    }
+
+    #[test]
+    fn internal_key_heuristic() {
+        let (compiled, proof) = compile_keyed(InternalKeyStrategy::Heuristic);
+        assert_eq!(proof, None);
+        assert!(compiled.metadata.is_empty());
+        assert_eq!(*taproot(&compiled).internal_key(), key(5));
+        let json = serde_json::to_value(&compiled).unwrap();
+        assert!(json["metadata"].get("internal_key").is_none());
+    }
+
+    #[test]
+    fn internal_key_cooperative() {
+        let (_, proof) = compile_keyed(InternalKeyStrategy::Cooperative(key(6)));
+        assert_eq!(proof, Some(InternalKeyProof::Cooperative { key: key(6) }));
+    }
+
+    #[test]
+    fn internal_key_musig() {
+        let participants = vec![key(6), key(7)];
+        let (_, proof) = compile_keyed(InternalKeyStrategy::MuSig(participants.clone()));
+        match proof {
+            Some(InternalKeyProof::MuSig {
+                key: k,
+                participants: p,
+            }) => {
+                assert_eq!(p, participants);
+                assert!(k != key(6) && k != key(7));
+            }
+            p => panic!("Unexpected Proof {:?}", p),
+        }
+    }
+
+    #[test]
+    fn internal_key_unspendable() {
+        let (_, derived) = compile_keyed(InternalKeyStrategy::Unspendable(None));
+        assert!(matches!(
+            derived,
+            Some(InternalKeyProof::Unspendable { .. })
+        ));
+        let tweak = bitcoin::hashes::Hash::hash(b"tweak");
+        let (_, given) = compile_keyed(InternalKeyStrategy::Unspendable(Some(tweak)));
+        match given {
+            Some(InternalKeyProof::Unspendable { key: k, tweak: t }) => {
+                assert_eq!(t, tweak);
+                assert_ne!(Some(k), derived.map(|p| p.key()));
+                assert_ne!(k, nums_point());
+            }
+            p => panic!("Unexpected Proof {:?}", p),
+        }
+    }
+
+    struct Anchored;
+    impl Anchored {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let amount = ctx.funds();
+            ctx.template()
+                .add_output(amount, &key(8), None)?
+                .add_anchor_output(sapio::template::Anchor::Ephemeral)?
+                .into()
+        }
+    }
+    impl Contract for Anchored {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn ephemeral_anchor_pays_no_fee() {
+        use sapio::contract::abi::studio::SapioStudioFormat;
+        use sapio::contract::object::ObjectError;
+        use sapio_base::txindex::{TxIndex, TxIndexLogger};
+        use std::rc::Rc;
+        let compiled = Anchored.compile(ctx()).unwrap();
+        let script = match &compiled.descriptor {
+            Some(SupportedDescriptors::XOnly(d)) => d.script_pubkey(),
+            _ => panic!("Not a Taproot Descriptor"),
+        };
+        let bind = |value| {
+            let index = Rc::new(TxIndexLogger::new());
+            let txid = index
+                .add_tx(Arc::new(bitcoin::Transaction {
+                    version: 2,
+                    lock_time: 0,
+                    input: vec![],
+                    output: vec![bitcoin::TxOut {
+                        value,
+                        script_pubkey: script.clone(),
+                    }],
+                }))
+                .unwrap();
+            compiled.bind_psbt(
+                bitcoin::OutPoint { txid, vout: 0 },
+                Default::default(),
+                index,
+                &CTVAvailable,
+            )
+        };
+        let program = bind(100_000).unwrap();
+        let txs: Vec<_> = program.program.values().flat_map(|o| &o.txs).collect();
+        assert_eq!(txs.len(), 1);
+        assert!(matches!(
+            txs[0],
+            SapioStudioFormat::LinkedPSBT {
+                needs_child: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            bind(100_001),
+            Err(ObjectError::EphemeralAnchorFee(Some(fee))) if fee.as_sat() == 1
+        ));
+        assert!(matches!(
+            bind(99_999),
+            Err(ObjectError::EphemeralAnchorFee(None))
+        ));
+    }
 }
@@ -8,8 +8,11 @@
 use super::descriptors::*;
 
 pub use crate::contract::abi::studio::*;
+use crate::contract::compiler::util::ctv_leaf_satisfaction_weight;
 use crate::contract::object::Object;
 use crate::contract::object::ObjectError;
+use crate::template::Anchor;
+use crate::template::AnchorOutput;
 use crate::template::Template;
 
 use ::miniscript::*;
@@ -19,6 +22,7 @@ use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin::util::taproot::TaprootBuilder;
 use bitcoin::util::taproot::TaprootSpendInfo;
+use bitcoin::Amount;
 use bitcoin::OutPoint;
 
 use sapio_base::effects::EffectPath;
@@ -36,6 +40,12 @@ impl Object {
     ///
     /// `bind_psbt` accepts a CTVEmulator, a txindex, and a map of outputs to be
     /// bound to specific template hashes.
+    ///
+    /// The contract's coin is placed at each template's `ctv_index`, and the
+    /// map of outputs (indexed by input) fills in the remaining inputs.
+    ///
+    /// Templates with an anchor output also get a skeleton CPFP child PSBT
+    /// spending the anchor.
     pub fn bind_psbt(
         &self,
         out_in: bitcoin::OutPoint,
@@ -75,23 +85,35 @@ impl Object {
                         .map(
                             |(
                                 ctv_hash,
-                                Template {
+                                template @ Template {
                                     metadata_map_s2s,
                                     outputs,
                                     tx,
+                                    ctv_index,
+                                    anchor,
                                     ..
                                 },
                             )| {
+                                let ctv_index = *ctv_index as usize;
+                                if ctv_index >= tx.input.len() {
+                                    return Err(ObjectError::NoSuchInput(ctv_index as u32));
+                                }
                                 let mut tx = tx.clone();
-                                tx.input[0].previous_output = out;
-                                for inp in tx.input[1..].iter_mut() {
-                                    inp.previous_output = mock_out;
-                                    mock_out.vout += 1;
+                                for (i, inp) in tx.input.iter_mut().enumerate() {
+                                    if i == ctv_index {
+                                        inp.previous_output = out;
+                                    } else {
+                                        inp.previous_output = mock_out;
+                                        mock_out.vout += 1;
+                                    }
                                 }
                                 if let Some(outputs) = output_map.get(ctv_hash) {
-                                    for (i, inp) in tx.input.iter_mut().enumerate().skip(1) {
-                                        if let Some(out) = outputs[i] {
-                                            inp.previous_output = out;
+                                    for (i, inp) in tx.input.iter_mut().enumerate() {
+                                        if i == ctv_index {
+                                            continue;
+                                        }
+                                        if let Some(Some(out)) = outputs.get(i) {
+                                            inp.previous_output = *out;
                                         }
                                     }
                                 }
@@ -106,7 +128,8 @@ impl Object {
                                 // Missing other Witness Info.
                                 match descriptor {
                                     Some(SupportedDescriptors::Pk(d)) => {
-                                        psbtx.inputs[0].witness_script = Some(d.explicit_script()?);
+                                        psbtx.inputs[ctv_index].witness_script =
+                                            Some(d.explicit_script()?);
                                     }
                                     Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
                                         let mut builder = TaprootBuilder::new();
@@ -125,7 +148,7 @@ impl Object {
                                                 None,
                                             )
                                         };
-                                        let inp = &mut psbtx.inputs[0];
+                                        let inp = &mut psbtx.inputs[ctv_index];
                                         for item in info.as_script_map().keys() {
                                             let cb =
                                                 info.control_block(item).expect("Must be present");
@@ -136,9 +159,58 @@ impl Object {
                                     }
                                     _ => (),
                                 }
+                                // funding beyond the outputs is paid as fees
+                                let funding = psbtx.inputs[ctv_index]
+                                    .witness_utxo
+                                    .as_ref()
+                                    .map_or(template.max, |o| Amount::from_sat(o.value));
+                                let satisfaction_weight = match descriptor {
+                                    Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+                                        match ctv_leaf_satisfaction_weight(t, ctv_hash) {
+                                            Some(w) => w,
+                                            None => Descriptor::Tr(t.clone())
+                                                .max_satisfaction_weight()?
+                                                as u64,
+                                        }
+                                    }
+                                    Some(SupportedDescriptors::XOnly(d)) => {
+                                        d.max_satisfaction_weight()? as u64
+                                    }
+                                    Some(SupportedDescriptors::Pk(d)) => {
+                                        d.max_satisfaction_weight()? as u64
+                                    }
+                                    None => 0,
+                                };
+                                // ephemeral dust is only relayed on a
+                                // transaction which pays no fee
+                                if let Some(AnchorOutput {
+                                    anchor: Anchor::Ephemeral,
+                                    ..
+                                }) = anchor
+                                {
+                                    match template.fees_from(funding) {
+                                        Some(fees) if fees.as_sat() == 0 => (),
+                                        fees => return Err(ObjectError::EphemeralAnchorFee(fees)),
+                                    }
+                                }
+                                let needs_child =
+                                    template.needs_child(funding, satisfaction_weight);
                                 psbtx = emulator.sign(psbtx)?;
                                 let final_tx = psbtx.clone().extract_tx();
-                                let txid = blockdata.add_tx(Arc::new(final_tx))?;
+                                let txid = final_tx.txid();
+                                let cpfp = anchor.and_then(|a| {
+                                    let anchor_out = final_tx.output.get(a.vout as usize)?.clone();
+                                    let wallet_in = mock_out;
+                                    mock_out.vout += 1;
+                                    Some(cpfp_skeleton(
+                                        &final_tx,
+                                        OutPoint { txid, vout: a.vout },
+                                        anchor_out,
+                                        a.anchor,
+                                        wallet_in,
+                                    ))
+                                });
+                                blockdata.add_tx_from(Arc::new(final_tx), &root_path.0)?;
                                 stack.reserve(outputs.len());
                                 for (vout, v) in outputs.iter().enumerate() {
                                     let vout = vout as u32;
@@ -146,6 +218,8 @@ impl Object {
                                 }
                                 Ok(LinkedPSBT {
                                     psbt: psbtx,
+                                    cpfp,
+                                    needs_child,
                                     metadata: metadata_map_s2s.clone(),
                                     output_metadata: outputs
                                         .iter()
@@ -168,3 +242,35 @@ impl Object {
         Ok(Program { program: result })
     }
 }
+
+/// Creates a skeleton PSBT for a child which spends `anchor` from `parent`,
+/// along with a placeholder input at `wallet_in` (for the wallet to replace
+/// with one of its own coins). The wallet must also add a change output.
+fn cpfp_skeleton(
+    parent: &bitcoin::Transaction,
+    anchor: OutPoint,
+    anchor_out: bitcoin::TxOut,
+    kind: Anchor,
+    wallet_in: OutPoint,
+) -> PartiallySignedTransaction {
+    let input = |previous_output| bitcoin::TxIn {
+        previous_output,
+        script_sig: Default::default(),
+        sequence: 0xffff_fffd,
+        witness: Default::default(),
+    };
+    let tx = bitcoin::Transaction {
+        // a v3 parent must have a v3 child
+        version: parent.version,
+        lock_time: 0,
+        input: vec![input(anchor), input(wallet_in)],
+        output: vec![],
+    };
+    let mut psbtx = PartiallySignedTransaction::from_unsigned_tx(tx)
+        .expect("Unsigned transaction with no witnesses");
+    psbtx.inputs[0].witness_utxo = Some(anchor_out);
+    if let Anchor::Keyed(key) = kind {
+        psbtx.inputs[0].tap_internal_key = Some(key);
+    }
+    psbtx
+}
@@ -28,6 +28,12 @@ pub enum ObjectError {
     UnknownScriptType(bitcoin::Script),
     /// OpReturn Too Long
     OpReturnTooLong,
+    /// A Template's CTV index does not refer to one of its inputs
+    NoSuchInput(u32),
+    /// A Template with an ephemeral anchor would pay this fee (or `None` if
+    /// its funding does not cover its outputs), where relay policy requires
+    /// it to pay none
+    EphemeralAnchorFee(Option<bitcoin::Amount>),
     /// The Error was for an unknown/unhandled reason
     Custom(Box<dyn std::error::Error>),
 }
@@ -14,6 +14,7 @@ pub use descriptors::*;
 
 use crate::contract::abi::continuation::ContinuationPoint;
 pub use crate::contract::abi::studio::*;
+use crate::contract::internal_key::InternalKeyProof;
 use crate::template::Template;
 use crate::util::amountrange::AmountRange;
 use crate::util::extended_address::ExtendedAddress;
@@ -41,6 +42,11 @@ pub struct ObjectMetadata {
     pub extra: BTreeMap<String, serde_json::Value>,
     /// SIMP: Sapio Interactive Metadata Protocol
     pub simp: BTreeMap<i64, serde_json::Value>,
+    /// How the Taproot internal key was selected, filled in by the compiler
+    /// for any but the default
+    /// [`crate::contract::internal_key::InternalKeyStrategy::Heuristic`]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub internal_key: Option<InternalKeyProof>,
 }
 impl ObjectMetadata {
     /// Is there any metadata in this field?
@@ -5,6 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Formats for Sapio Studio
+pub mod satisfaction;
 use crate::contract::abi::continuation::ContinuationPoint;
 use crate::contract::object::ObjectMetadata;
 use crate::template::output::OutputMeta;
@@ -31,6 +32,10 @@ pub struct LinkedPSBT {
     pub output_metadata: Vec<ObjectMetadata>,
     /// added metadata
     pub added_output_metadata: Vec<OutputMeta>,
+    /// a skeleton PSBT for a child spending this transaction's anchor output
+    pub cpfp: Option<PartiallySignedTransaction>,
+    /// whether this transaction must be broadcast along with a CPFP child
+    pub needs_child: bool,
 }
 
 /// Format for a Linked PSBT in Sapio Studio
@@ -49,6 +54,12 @@ pub enum SapioStudioFormat {
         output_metadata: Vec<ObjectMetadata>,
         /// added metadata
         added_output_metadata: Vec<OutputMeta>,
+        /// Base 64 Encoded skeleton PSBT for a CPFP child spending the anchor
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        cpfp_psbt: Option<String>,
+        /// Whether the transaction must be broadcast along with a CPFP child
+        #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+        needs_child: bool,
     },
 }
 
@@ -59,12 +70,15 @@ impl From<LinkedPSBT> for SapioStudioFormat {
             base64::encode(bytes)
         };
         let hex = bitcoin::consensus::encode::serialize_hex(&l.psbt.extract_tx());
+        let cpfp_psbt = l.cpfp.map(|p| base64::encode(serialize(&p)));
         SapioStudioFormat::LinkedPSBT {
             psbt,
             hex,
             metadata: l.metadata,
             output_metadata: l.output_metadata,
             added_output_metadata: l.added_output_metadata,
+            cpfp_psbt,
+            needs_child: l.needs_child,
         }
     }
 }
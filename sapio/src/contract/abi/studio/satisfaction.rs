@@ -0,0 +1,405 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks which script paths of a bound Program can be satisfied, and which
+//! signatures are still missing.
+use super::{Program, SapioStudioFormat};
+use crate::contract::object::ObjectError;
+use ::miniscript::miniscript::satisfy::Preimage32;
+use ::miniscript::psbt::PsbtInputSatisfier;
+use ::miniscript::*;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d};
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::util::taproot::{ControlBlock, LeafVersion, TapLeafHash};
+use bitcoin::{SchnorrSig, SchnorrSighashType, Script, Txid, XOnlyPublicKey};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// # Leaf Satisfaction
+/// Whether a single Taproot leaf of an input can be satisfied
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct LeafSatisfaction {
+    /// # Script
+    #[schemars(with = "String")]
+    pub script: Script,
+    /// # Satisfiable Now
+    /// The leaf can be satisfied with the data already in the PSBT
+    pub satisfiable_now: bool,
+    /// # Satisfiable With Signatures
+    /// The leaf can be satisfied once the missing signatures are added
+    pub satisfiable_with_signatures: bool,
+    /// # Signatures Needed
+    /// The fewest further signatures, from the missing signers, which would
+    /// satisfy the leaf (e.g., `k` less those given for a `multi_a`), or
+    /// null if no signatures would
+    pub signatures_needed: Option<usize>,
+    /// # Missing Signatures
+    /// Keys in the leaf which have not signed
+    #[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")]
+    pub missing_signatures: Vec<XOnlyPublicKey>,
+    /// # Missing Preimages
+    /// Hashes in the leaf whose preimages are not in the PSBT
+    pub missing_preimages: Vec<String>,
+}
+
+/// # Input Satisfaction
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct InputSatisfaction {
+    /// # Input Index
+    pub input: usize,
+    /// # Key Path Signed
+    pub key_path_signed: bool,
+    /// # Leaves
+    pub leaves: Vec<LeafSatisfaction>,
+}
+
+/// # Transaction Satisfaction
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct TxSatisfaction {
+    /// # Transaction ID
+    #[schemars(with = "bitcoin::hashes::sha256d::Hash")]
+    pub txid: Txid,
+    /// # Inputs
+    /// Only inputs with Taproot script information are reported
+    pub inputs: Vec<InputSatisfaction>,
+}
+
+impl TxSatisfaction {
+    /// whether every reported input has at least one leaf (or the key path)
+    /// which is satisfiable now
+    pub fn is_complete(&self) -> bool {
+        self.inputs
+            .iter()
+            .all(|i| i.key_path_signed || i.leaves.iter().any(|l| l.satisfiable_now))
+    }
+}
+
+/// Satisfies as the PSBT input would, but pretends every key has signed.
+struct AllSigned<'a>(PsbtInputSatisfier<'a>);
+
+impl<'a> Satisfier<XOnlyPublicKey> for AllSigned<'a> {
+    fn lookup_tap_leaf_script_sig(
+        &self,
+        _: &XOnlyPublicKey,
+        _: &TapLeafHash,
+    ) -> Option<SchnorrSig> {
+        Some(SchnorrSig {
+            sig: Signature::from_slice(&[1u8; 64]).expect("64 bytes"),
+            hash_ty: SchnorrSighashType::Default,
+        })
+    }
+    fn lookup_tap_control_block_map(
+        &self,
+    ) -> Option<&BTreeMap<ControlBlock, (Script, LeafVersion)>> {
+        Satisfier::<XOnlyPublicKey>::lookup_tap_control_block_map(&self.0)
+    }
+    fn lookup_sha256(&self, h: sha256::Hash) -> Option<Preimage32> {
+        Satisfier::<XOnlyPublicKey>::lookup_sha256(&self.0, h)
+    }
+    fn lookup_hash256(&self, h: sha256d::Hash) -> Option<Preimage32> {
+        Satisfier::<XOnlyPublicKey>::lookup_hash256(&self.0, h)
+    }
+    fn lookup_ripemd160(&self, h: ripemd160::Hash) -> Option<Preimage32> {
+        Satisfier::<XOnlyPublicKey>::lookup_ripemd160(&self.0, h)
+    }
+    fn lookup_hash160(&self, h: hash160::Hash) -> Option<Preimage32> {
+        Satisfier::<XOnlyPublicKey>::lookup_hash160(&self.0, h)
+    }
+    fn check_older(&self, n: u32) -> bool {
+        Satisfier::<XOnlyPublicKey>::check_older(&self.0, n)
+    }
+    fn check_after(&self, n: u32) -> bool {
+        Satisfier::<XOnlyPublicKey>::check_after(&self.0, n)
+    }
+    fn check_tx_template(&self, h: sha256::Hash) -> bool {
+        Satisfier::<XOnlyPublicKey>::check_tx_template(&self.0, h)
+    }
+}
+
+/// The fewest further signatures which would satisfy `ms`, given which keys
+/// have `signed` and `satisfier` for everything but signatures, or `None` if
+/// no signatures would.
+fn signatures_needed<S: Satisfier<XOnlyPublicKey>>(
+    ms: &Miniscript<XOnlyPublicKey, Tap>,
+    signed: &dyn Fn(&XOnlyPublicKey) -> bool,
+    satisfier: &S,
+) -> Option<usize> {
+    let sub = |ms: &Miniscript<XOnlyPublicKey, Tap>| signatures_needed(ms, signed, satisfier);
+    let present = |b: bool| if b { Some(0) } else { None };
+    // the least total of any `k` of `subs`
+    let least_k = |k: usize, mut subs: Vec<usize>| {
+        subs.sort_unstable();
+        if subs.len() < k {
+            None
+        } else {
+            Some(subs[..k].iter().sum())
+        }
+    };
+    match &ms.node {
+        Terminal::True => Some(0),
+        Terminal::False => None,
+        Terminal::PkK(pk) => Some(!signed(pk) as usize),
+        Terminal::PkH(_) => Some(1),
+        Terminal::After(n) => present(satisfier.check_after(*n)),
+        Terminal::Older(n) => present(satisfier.check_older(*n)),
+        Terminal::Sha256(h) => present(satisfier.lookup_sha256(*h).is_some()),
+        Terminal::Hash256(h) => present(satisfier.lookup_hash256(*h).is_some()),
+        Terminal::Ripemd160(h) => present(satisfier.lookup_ripemd160(*h).is_some()),
+        Terminal::Hash160(h) => present(satisfier.lookup_hash160(*h).is_some()),
+        Terminal::TxTemplate(h) => present(satisfier.check_tx_template(*h)),
+        Terminal::Alt(a)
+        | Terminal::Swap(a)
+        | Terminal::Check(a)
+        | Terminal::DupIf(a)
+        | Terminal::Verify(a)
+        | Terminal::NonZero(a)
+        | Terminal::ZeroNotEqual(a) => sub(a),
+        Terminal::AndV(a, b) | Terminal::AndB(a, b) => Some(sub(a)? + sub(b)?),
+        Terminal::AndOr(a, b, c) => {
+            let ab = sub(a).and_then(|a| Some(a + sub(b)?));
+            match (ab, sub(c)) {
+                (Some(x), Some(y)) => Some(std::cmp::min(x, y)),
+                (x, y) => x.or(y),
+            }
+        }
+        Terminal::OrB(a, b) | Terminal::OrD(a, b) | Terminal::OrC(a, b) | Terminal::OrI(a, b) => {
+            least_k(1, vec![sub(a), sub(b)].into_iter().flatten().collect())
+        }
+        Terminal::Thresh(k, subs) => least_k(*k, subs.iter().filter_map(|s| sub(s)).collect()),
+        Terminal::Multi(k, keys) | Terminal::MultiA(k, keys) => {
+            least_k(*k, keys.iter().map(|pk| !signed(pk) as usize).collect())
+        }
+    }
+}
+
+/// Checks every Taproot leaf of every input of `psbt`, using the signatures
+/// and preimages it holds and the sequences and lock time of its transaction.
+pub fn check_psbt(psbt: &PartiallySignedTransaction) -> TxSatisfaction {
+    let inputs = psbt
+        .inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, i)| i.tap_internal_key.is_some() || !i.tap_scripts.is_empty())
+        .map(|(idx, input)| {
+            let leaves = input
+                .tap_scripts
+                .values()
+                .map(|(script, ver)| {
+                    let leaf_hash = TapLeafHash::from_script(script, *ver);
+                    // only Tapscript leaves are miniscript
+                    let ms = match ver {
+                        LeafVersion::TapScript => {
+                            Miniscript::<XOnlyPublicKey, Tap>::parse_insane(script).ok()
+                        }
+                        _ => None,
+                    };
+                    match ms {
+                        Some(ms) => {
+                            let satisfier = PsbtInputSatisfier::new(psbt, idx);
+                            let signed = |pk: &XOnlyPublicKey| {
+                                input.tap_script_sigs.contains_key(&(*pk, leaf_hash))
+                            };
+                            let missing_signatures =
+                                ms.iter_pk().filter(|pk| !signed(pk)).collect();
+                            let missing_preimages = ms
+                                .iter()
+                                .filter_map(|node| match &node.node {
+                                    Terminal::Sha256(h)
+                                        if !input.sha256_preimages.contains_key(h) =>
+                                    {
+                                        Some(h.to_string())
+                                    }
+                                    Terminal::Hash256(h)
+                                        if !input.hash256_preimages.contains_key(h) =>
+                                    {
+                                        Some(h.to_string())
+                                    }
+                                    Terminal::Ripemd160(h)
+                                        if !input.ripemd160_preimages.contains_key(h) =>
+                                    {
+                                        Some(h.to_string())
+                                    }
+                                    Terminal::Hash160(h)
+                                        if !input.hash160_preimages.contains_key(h) =>
+                                    {
+                                        Some(h.to_string())
+                                    }
+                                    _ => None,
+                                })
+                                .collect();
+                            LeafSatisfaction {
+                                script: script.clone(),
+                                satisfiable_now: ms.satisfy(&satisfier).is_ok(),
+                                signatures_needed: signatures_needed(&ms, &signed, &satisfier),
+                                satisfiable_with_signatures: ms
+                                    .satisfy(AllSigned(satisfier))
+                                    .is_ok(),
+                                missing_signatures,
+                                missing_preimages,
+                            }
+                        }
+                        // not a miniscript we can reason about
+                        None => LeafSatisfaction {
+                            script: script.clone(),
+                            satisfiable_now: false,
+                            satisfiable_with_signatures: false,
+                            signatures_needed: None,
+                            missing_signatures: vec![],
+                            missing_preimages: vec![],
+                        },
+                    }
+                })
+                .collect();
+            InputSatisfaction {
+                input: idx,
+                key_path_signed: input.tap_key_sig.is_some(),
+                leaves,
+            }
+        })
+        .collect();
+    TxSatisfaction {
+        txid: psbt.unsigned_tx.txid(),
+        inputs,
+    }
+}
+
+impl Program {
+    /// Checks the satisfiability of every transaction in the program, e.g.,
+    /// to show who still needs to sign what.
+    pub fn check_satisfaction(
+        &self,
+    ) -> Result<BTreeMap<SArc<EffectPath>, Vec<TxSatisfaction>>, ObjectError> {
+        self.program
+            .iter()
+            .map(|(path, obj)| {
+                let txs = obj
+                    .txs
+                    .iter()
+                    .map(|f| match f {
+                        SapioStudioFormat::LinkedPSBT { psbt, .. } => {
+                            let bytes = base64::decode(psbt)
+                                .map_err(|e| ObjectError::Custom(Box::new(e)))?;
+                            let psbt: PartiallySignedTransaction = deserialize(&bytes[..])
+                                .map_err(|e| ObjectError::Custom(Box::new(e)))?;
+                            Ok(check_psbt(&psbt))
+                        }
+                    })
+                    .collect::<Result<Vec<_>, ObjectError>>()?;
+                Ok((path.clone(), txs))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use bitcoin::util::taproot::TaprootBuilder;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_missing_signatures() {
+        let secp = Secp256k1::new();
+        let key = |b: u8| {
+            XOnlyPublicKey::from(PublicKey::from_secret_key(
+                &secp,
+                &SecretKey::from_slice(&[b; 32]).unwrap(),
+            ))
+        };
+        let (a, b) = (key(1), key(2));
+        let ms: Miniscript<XOnlyPublicKey, Tap> =
+            policy::Concrete::<XOnlyPublicKey>::from_str(&format!("and(pk({}),older(10))", a))
+                .unwrap()
+                .compile()
+                .unwrap();
+        let script = ms.encode();
+        let info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(&secp, b)
+            .unwrap();
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                sequence: 10,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let cb = info
+            .control_block(&(script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        psbt.inputs[0]
+            .tap_scripts
+            .insert(cb, (script.clone(), LeafVersion::TapScript));
+        psbt.inputs[0].tap_internal_key = Some(b);
+        let res = check_psbt(&psbt);
+        assert!(!res.is_complete());
+        let leaf = &res.inputs[0].leaves[0];
+        assert_eq!(leaf.missing_signatures, vec![a]);
+        assert_eq!(leaf.signatures_needed, Some(1));
+        assert!(!leaf.satisfiable_now);
+        assert!(leaf.satisfiable_with_signatures);
+        // with too short a sequence, no signature helps
+        psbt.unsigned_tx.input[0].sequence = 9;
+        let leaf = &check_psbt(&psbt).inputs[0].leaves[0];
+        assert!(!leaf.satisfiable_with_signatures);
+        assert_eq!(leaf.signatures_needed, None);
+    }
+
+    #[test]
+    fn test_signatures_needed_multi_a() {
+        let secp = Secp256k1::new();
+        let key = |b: u8| {
+            XOnlyPublicKey::from(PublicKey::from_secret_key(
+                &secp,
+                &SecretKey::from_slice(&[b; 32]).unwrap(),
+            ))
+        };
+        let (a, b, c) = (key(1), key(2), key(3));
+        let ms: Miniscript<XOnlyPublicKey, Tap> =
+            Miniscript::from_str(&format!("multi_a(2,{},{},{})", a, b, c)).unwrap();
+        let script = ms.encode();
+        let info = TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(&secp, a)
+            .unwrap();
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let cb = info
+            .control_block(&(script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        psbt.inputs[0]
+            .tap_scripts
+            .insert(cb, (script.clone(), LeafVersion::TapScript));
+        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let sig = SchnorrSig {
+            sig: Signature::from_slice(&[1u8; 64]).unwrap(),
+            hash_ty: SchnorrSighashType::Default,
+        };
+        psbt.inputs[0].tap_script_sigs.insert((b, leaf_hash), sig);
+        let leaf = &check_psbt(&psbt).inputs[0].leaves[0];
+        assert_eq!(leaf.signatures_needed, Some(1));
+        assert_eq!(leaf.missing_signatures, vec![a, c]);
+        psbt.inputs[0].tap_script_sigs.insert((c, leaf_hash), sig);
+        let leaf = &check_psbt(&psbt).inputs[0].leaves[0];
+        assert_eq!(leaf.signatures_needed, Some(0));
+    }
+}
@@ -42,6 +42,9 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
     /// name derived from Function Name.
     /// N.B. must be renamable by changing this field!
     pub name: Arc<String>,
+    /// relative likelihood of this branch being used, see
+    /// [`super::DEFAULT_BRANCH_WEIGHT`]
+    pub weight: u64,
     /// Type switch to enable/disable compilation with serialized fields
     /// (if negative trait bounds, could remove!)
     pub f: PhantomData<WebAPIStatus>,
@@ -74,6 +77,8 @@ pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
     fn get_guard(&self) -> GuardList<'_, ContractSelf>;
     /// Get the name for this function
     fn get_name(&self) -> &Arc<String>;
+    /// Get the weight of the branches generated by this function
+    fn get_weight(&self) -> u64;
     /// Get the RootSchema for calling this with an update
     fn get_schema(&self) -> &Option<Arc<RootSchema>>;
     /// get if txtmpls returned by the func should modify guards.
@@ -107,6 +112,9 @@ impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf,
     fn get_name(&self) -> &Arc<String> {
         &self.name
     }
+    fn get_weight(&self) -> u64 {
+        self.weight
+    }
     fn get_schema(&self) -> &Option<Arc<RootSchema>> {
         &self.schema
     }
@@ -151,6 +159,9 @@ where
     fn get_name(&self) -> &Arc<String> {
         &self.name
     }
+    fn get_weight(&self) -> u64 {
+        self.weight
+    }
     fn get_schema(&self) -> &Option<Arc<RootSchema>> {
         &self.schema
     }
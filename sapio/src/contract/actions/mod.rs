@@ -16,3 +16,11 @@ pub mod conditional_compile;
 pub use conditional_compile::*;
 pub mod finish;
 pub use finish::*;
+
+/// The weight given to a branch when none is specified.
+///
+/// Weights are relative likelihoods that a branch is used to spend a
+/// contract. The compiler places branches with a higher weight closer to the
+/// root of the Taproot tree so that they have shorter control blocks. A then
+/// function's weight is split between the leaves of the templates it returns.
+pub const DEFAULT_BRANCH_WEIGHT: u64 = 1;
@@ -45,6 +45,9 @@ pub struct ThenFunc<'a, ContractSelf> {
     pub func: fn(&ContractSelf, Context, ThenFuncTypeTag) -> TxTmplIt,
     /// name derived from Function Name.
     pub name: Arc<String>,
+    /// relative likelihood of this branch being used, see
+    /// [`super::DEFAULT_BRANCH_WEIGHT`]
+    pub weight: u64,
 }
 
 impl<'a, ContractSelf, StatefulArgs> From<ThenFunc<'a, ContractSelf>>
@@ -56,6 +59,7 @@ impl<'a, ContractSelf, StatefulArgs> From<ThenFunc<'a, ContractSelf>>
             conditional_compile_if: f.conditional_compile_if,
             func: f.func,
             name: f.name,
+            weight: f.weight,
             coerce_args: ThenFuncTypeTag::coerce_args,
             schema: None,
             f: PhantomData::default(),
@@ -13,6 +13,8 @@ use super::Context;
 use crate::contract::abi::continuation::ContinuationPoint;
 use crate::contract::actions::conditional_compile::CCILWrapper;
 use crate::contract::actions::CallableAsFoF;
+use crate::contract::actions::DEFAULT_BRANCH_WEIGHT;
+use crate::contract::internal_key::InternalKeyProof;
 use crate::contract::TxTmplIt;
 use crate::util::amountrange::AmountRange;
 
@@ -29,7 +31,7 @@ use std::collections::{BTreeMap, BTreeSet};
 
 use std::sync::Arc;
 mod cache;
-mod util;
+pub(crate) mod util;
 use cache::*;
 use util::*;
 /// Used to prevent unintended callers to internal_clone.
@@ -183,106 +185,119 @@ where
         let mut renamer = Renamer::new();
         let (continue_apis, clause_accumulator): (
             ContinueAPIs,
-            Vec<Vec<Miniscript<XOnlyPublicKey, Tap>>>,
-        ) = self
-            .then_fns()
-            .iter()
-            .filter_map(|func| func())
-            // We currently need to allocate for the the Callable as a
-            // trait object since it only exists temporarily.
-            // TODO: Without allocations?
-            .map(|x| -> Box<dyn CallableAsFoF<_, _>> { Box::new(x) })
-            .chain(self.finish_or_fns().iter().filter_map(|func| func()))
-            .map(|mut x| {
-                let new_name = Arc::new(renamer.get_name(x.get_name().as_ref()));
-                x.rename(new_name.clone());
-                let name = PathFragment::Named(SArc(new_name));
-                let f_ctx = action_ctx.derive(name).expect(UNIQUE_DERIVE_PANIC_MSG);
-                (f_ctx, x)
-            })
-            // flat_map will discard any
-            // skippable / never branches here
-            .flat_map(|(mut f_ctx, func)| {
-                let mut this_ctx = f_ctx
-                    // this should always be Ok(_)
-                    .derive(PathFragment::CondCompIf)
-                    .expect(UNIQUE_DERIVE_PANIC_MSG);
-                match CCILWrapper(func.get_conditional_compile_if())
-                    .assemble(self_ref, &mut this_ctx)
-                {
-                    // Throw errors
-                    ConditionalCompileType::Fail(errors) => {
-                        Some(Err(CompilationError::ConditionalCompilationFailed(errors)))
-                    }
-                    // Non nullable
-                    ConditionalCompileType::Required | ConditionalCompileType::NoConstraint => {
-                        Some(Ok((f_ctx, func, Nullable::No)))
+            Vec<Vec<(LeafWeight, Miniscript<XOnlyPublicKey, Tap>)>>,
+        ) =
+            self.then_fns()
+                .iter()
+                .filter_map(|func| func())
+                // We currently need to allocate for the the Callable as a
+                // trait object since it only exists temporarily.
+                // TODO: Without allocations?
+                .map(|x| -> Box<dyn CallableAsFoF<_, _>> { Box::new(x) })
+                .chain(self.finish_or_fns().iter().filter_map(|func| func()))
+                .map(|mut x| {
+                    let new_name = Arc::new(renamer.get_name(x.get_name().as_ref()));
+                    x.rename(new_name.clone());
+                    let name = PathFragment::Named(SArc(new_name));
+                    let f_ctx = action_ctx.derive(name).expect(UNIQUE_DERIVE_PANIC_MSG);
+                    (f_ctx, x)
+                })
+                // flat_map will discard any
+                // skippable / never branches here
+                .flat_map(|(mut f_ctx, func)| {
+                    let mut this_ctx = f_ctx
+                        // this should always be Ok(_)
+                        .derive(PathFragment::CondCompIf)
+                        .expect(UNIQUE_DERIVE_PANIC_MSG);
+                    match CCILWrapper(func.get_conditional_compile_if())
+                        .assemble(self_ref, &mut this_ctx)
+                    {
+                        // Throw errors
+                        ConditionalCompileType::Fail(errors) => {
+                            Some(Err(CompilationError::ConditionalCompilationFailed(errors)))
+                        }
+                        // Non nullable
+                        ConditionalCompileType::Required | ConditionalCompileType::NoConstraint => {
+                            Some(Ok((f_ctx, func, Nullable::No)))
+                        }
+                        // Nullable
+                        ConditionalCompileType::Nullable => Some(Ok((f_ctx, func, Nullable::Yes))),
+                        // Drop these
+                        ConditionalCompileType::Skippable | ConditionalCompileType::Never => None,
                     }
-                    // Nullable
-                    ConditionalCompileType::Nullable => Some(Ok((f_ctx, func, Nullable::Yes))),
-                    // Drop these
-                    ConditionalCompileType::Skippable | ConditionalCompileType::Never => None,
-                }
-            })
-            .map(|r| {
-                let (mut f_ctx, func, nullability) = r?;
-                let gctx = f_ctx.derive(PathFragment::Guard)?;
-                // TODO: Suggested path frag?
-                let guards = create_guards(self_ref, gctx, func.get_guard(), &mut guard_clauses);
-                let effect_ctx = f_ctx.derive(if func.get_returned_txtmpls_modify_guards() {
-                    PathFragment::Next
-                } else {
-                    PathFragment::Suggested
-                })?;
-                let effect_path = effect_ctx.path().clone();
-                let transactions = compute_all_effects(effect_ctx, self_ref, func.as_ref());
-                // If no guards and not CTV, then nothing gets added (not
-                // interpreted as Trivial True)
-                //   - If CTV and no guards, just CTV added.
-                //   - If CTV and guards, CTV & guards added.
-                // it would be an error if any of r_txtmpls is an error
-                // instead of just an empty iterator.
-                let txtmpl_clauses = transactions?
-                    .map(|r_txtmpl| {
-                        let txtmpl = r_txtmpl?;
-                        let h = txtmpl.hash();
-                        amount_range.update_range(txtmpl.max);
-                        // Add the addition guards to these clauses
-                        let txtmpl = if func.get_returned_txtmpls_modify_guards() {
-                            &mut comitted_txns
+                })
+                .map(|r| {
+                    let (mut f_ctx, func, nullability) = r?;
+                    let gctx = f_ctx.derive(PathFragment::Guard)?;
+                    // TODO: Suggested path frag?
+                    let guards =
+                        create_guards(self_ref, gctx, func.get_guard(), &mut guard_clauses);
+                    let effect_ctx =
+                        f_ctx.derive(if func.get_returned_txtmpls_modify_guards() {
+                            PathFragment::Next
                         } else {
-                            &mut other_txns
-                        }
-                        .entry(h)
-                        .or_insert(txtmpl);
-                        let extractor = func.get_extract_clause_from_txtmpl();
-                        (extractor)(&txtmpl, &ctx)
-                    })
-                    // Drop None values
-                    .filter_map(|s| s.transpose())
-                    // Forces any error to abort the whole thing
-                    .collect::<Result<Vec<Clause>, CompilationError>>()?;
+                            PathFragment::Suggested
+                        })?;
+                    let effect_path = effect_ctx.path().clone();
+                    let transactions = compute_all_effects(effect_ctx, self_ref, func.as_ref());
+                    // If no guards and not CTV, then nothing gets added (not
+                    // interpreted as Trivial True)
+                    //   - If CTV and no guards, just CTV added.
+                    //   - If CTV and guards, CTV & guards added.
+                    // it would be an error if any of r_txtmpls is an error
+                    // instead of just an empty iterator.
+                    let txtmpl_clauses = transactions?
+                        .map(|r_txtmpl| {
+                            let txtmpl = r_txtmpl?;
+                            let h = txtmpl.hash();
+                            amount_range.update_range(txtmpl.max);
+                            // Add the addition guards to these clauses
+                            let txtmpl = if func.get_returned_txtmpls_modify_guards() {
+                                &mut comitted_txns
+                            } else {
+                                &mut other_txns
+                            }
+                            .entry(h)
+                            .or_insert(txtmpl);
+                            let extractor = func.get_extract_clause_from_txtmpl();
+                            (extractor)(&txtmpl, &ctx)
+                        })
+                        // Drop None values
+                        .filter_map(|s| s.transpose())
+                        // Forces any error to abort the whole thing
+                        .collect::<Result<Vec<Clause>, CompilationError>>()?;
 
-                // N.B. the order of the matches below is significant
-                if func.get_returned_txtmpls_modify_guards() {
-                    Ok((None, combine_txtmpls(nullability, txtmpl_clauses, guards)?))
-                } else {
-                    Ok((
-                        Some((
-                            SArc(effect_path.clone()),
-                            ContinuationPoint::at(func.get_schema().clone(), effect_path),
-                        )),
-                        vec![guards.compile().map_err(Into::<CompilationError>::into)?],
-                    ))
-                }
-            })
-            .collect::<Result<Vec<(_, Vec<Miniscript<XOnlyPublicKey, Tap>>)>, CompilationError>>()?
-            .into_iter()
-            .unzip();
+                    let weight = func.get_weight();
+                    // N.B. the order of the matches below is significant
+                    if func.get_returned_txtmpls_modify_guards() {
+                        // the leaves generated by this function split its weight
+                        let leaves = combine_txtmpls(nullability, txtmpl_clauses, guards)?;
+                        let weights = split_weight(weight, leaves.len());
+                        Ok((None, weights.zip(leaves).collect()))
+                    } else {
+                        Ok((
+                            Some((
+                                SArc(effect_path.clone()),
+                                ContinuationPoint::at(func.get_schema().clone(), effect_path),
+                            )),
+                            vec![(
+                                leaf_weight(weight),
+                                guards.compile().map_err(Into::<CompilationError>::into)?,
+                            )],
+                        ))
+                    }
+                })
+                .collect::<Result<
+                    Vec<(_, Vec<(LeafWeight, Miniscript<XOnlyPublicKey, Tap>)>)>,
+                    CompilationError,
+                >>()?
+                .into_iter()
+                .unzip();
 
-        let branches: Vec<Miniscript<XOnlyPublicKey, Tap>> = {
+        let branches: Vec<(LeafWeight, Miniscript<XOnlyPublicKey, Tap>)> = {
             let mut finish_fns_ctx = ctx.derive(PathFragment::FinishFn)?;
             // Compute all finish_functions at this level, caching if requested.
+            let weights = self.finish_weights();
             self.finish_fns()
                 .iter()
                 // note that this zip with would loop forever if there were to be a bug here
@@ -290,33 +305,71 @@ where
                     (0..)
                         .filter_map(|i| finish_fns_ctx.derive(PathFragment::Branch(i as u64)).ok()),
                 )
-                .filter_map(|(func, c)| guard_clauses.get(self_ref, *func, c))
-                .map(|policy| policy.compile().map_err(Into::<CompilationError>::into))
+                .enumerate()
+                .filter_map(|(i, (func, c))| {
+                    let weight = weights.get(i).copied().unwrap_or(DEFAULT_BRANCH_WEIGHT);
+                    guard_clauses
+                        .get(self_ref, *func, c)
+                        .map(|policy| (leaf_weight(weight), policy))
+                })
+                .map(|(weight, policy)| {
+                    policy
+                        .compile()
+                        .map(|ms| (weight, ms))
+                        .map_err(Into::<CompilationError>::into)
+                })
                 .chain(clause_accumulator.into_iter().flatten().map(Ok))
                 .collect::<Result<Vec<_>, _>>()?
         };
-        // TODO: Pick a better branch that is guaranteed to work!
-        let some_key = pick_key_from_miniscripts(branches.iter());
+        let internal_key_ctx = ctx.derive(PathFragment::InternalKey)?;
+        let internal_key = select_internal_key(
+            self.internal_key(internal_key_ctx)?,
+            branches.iter().map(|(_, ms)| ms),
+            ctx.path(),
+        )?;
         // Don't remove the key from the scripts in case it was bogus
         let tree = branches_to_tree(branches);
-        let descriptor = Descriptor::Tr(descriptor::Tr::new(some_key, tree)?);
-        let estimated_max_size = descriptor.max_satisfaction_weight()?;
+        let tr = descriptor::Tr::new(internal_key.key(), tree)?;
+        // each template is only spent through its own leaf, so an unrelated
+        // heavier leaf should not count against its fee budget
+        let satisfaction_weight =
+            |h: &bitcoin::hashes::sha256::Hash| -> Result<u64, CompilationError> {
+                match ctv_leaf_satisfaction_weight(&tr, h) {
+                    Some(w) => Ok(w),
+                    None => Ok(Descriptor::Tr(tr.clone()).max_satisfaction_weight()? as u64),
+                }
+            };
+        let weights = comitted_txns
+            .iter()
+            .filter(|(_, a)| a.min_feerate_sats_vbyte.is_some())
+            .map(|(h, _)| Ok((*h, satisfaction_weight(h)?)))
+            .collect::<Result<BTreeMap<_, _>, CompilationError>>()?;
+        let descriptor = Descriptor::Tr(tr);
         let address = descriptor.address(ctx.network)?.into();
         let descriptor = Some(descriptor.into());
         let root_path = SArc(ctx.path().clone());
 
-        let failed_estimate = comitted_txns.values().any(|a| {
-            // witness space not scaled
-            let tx_size = a.tx.get_weight() + estimated_max_size;
-            let fees = amount_range.max() - a.total_amount();
-            a.min_feerate_sats_vbyte
-                .map(|m| fees.as_sat() < (m.as_sat() * tx_size as u64))
-                == Some(false)
+        let failed_estimate = comitted_txns.iter().any(|(h, a)| {
+            a.min_feerate_sats_vbyte.map_or(false, |m| {
+                let vsize = a.estimate_vsize(weights[h]);
+                // fees are whatever of the funds for this contract the
+                // template's outputs do not spend
+                match a.fees_from(amount_range.max()) {
+                    Some(fees) => fees.as_sat() < m.as_sat() * vsize,
+                    None => true,
+                }
+            })
         });
         if failed_estimate {
             Err(CompilationError::MinFeerateError)
         } else {
             let metadata_ctx = ctx.derive(PathFragment::Metadata)?;
+            let mut metadata = self.metadata(metadata_ctx)?;
+            // the default strategy has nothing to prove, and recording it
+            // would change the metadata of every existing contract
+            if !matches!(internal_key, InternalKeyProof::Heuristic { .. }) {
+                metadata.internal_key = Some(internal_key);
+            }
             Ok(Compiled {
                 ctv_to_tx: comitted_txns,
                 suggested_txs: other_txns,
@@ -325,7 +378,7 @@ where
                 address,
                 descriptor,
                 amount_range,
-                metadata: self.metadata(metadata_ctx)?,
+                metadata,
             })
         }
     }
@@ -6,11 +6,16 @@
 
 //! utility functions for compiler
 
+use crate::contract::internal_key::*;
+use crate::contract::CompilationError;
+use crate::util::musig;
 use ::miniscript::descriptor::TapTree;
 use ::miniscript::*;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
 use bitcoin::XOnlyPublicKey;
+use sapio_base::effects::EffectPath;
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 use std::sync::Arc;
@@ -34,13 +39,105 @@ pub fn pick_key_from_miniscripts<'a, I: Iterator<Item = &'a Miniscript<XOnlyPubl
         )
 }
 
-/// Convert the branches into a heap for taproot tree consumption
+/// selects the internal key for a contract according to `strategy`, returning
+/// the key along with the data needed to check how it was picked.
+///
+/// If an unspendable key is requested without a tweak, the tweak is derived
+/// from the contract's path and the scripts of its branches.
+pub fn select_internal_key<'a, I: Iterator<Item = &'a Miniscript<XOnlyPublicKey, Tap>>>(
+    strategy: InternalKeyStrategy,
+    branches: I,
+    path: &EffectPath,
+) -> Result<InternalKeyProof, CompilationError> {
+    let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+    Ok(match strategy {
+        InternalKeyStrategy::Heuristic => InternalKeyProof::Heuristic {
+            key: pick_key_from_miniscripts(branches),
+        },
+        InternalKeyStrategy::Cooperative(key) => InternalKeyProof::Cooperative { key },
+        InternalKeyStrategy::MuSig(participants) => InternalKeyProof::MuSig {
+            key: musig::key_agg(&secp, &participants).map_err(CompilationError::custom)?,
+            participants,
+        },
+        InternalKeyStrategy::Unspendable(tweak) => {
+            let tweak = tweak.unwrap_or_else(|| {
+                let mut engine = Sha256::engine();
+                engine.input(String::from(path.clone()).as_bytes());
+                for ms in branches {
+                    engine.input(&ms.encode()[..]);
+                }
+                Sha256::from_engine(engine)
+            });
+            InternalKeyProof::Unspendable {
+                key: tweaked_nums_point(&secp, &tweak).map_err(CompilationError::custom)?,
+                tweak,
+            }
+        }
+    })
+}
+
+/// The witness weight of a script path spend through the leaf committing to
+/// the CTV template `h`, the heaviest if several do, or `None` if none does.
+pub fn ctv_leaf_satisfaction_weight(
+    tr: &descriptor::Tr<XOnlyPublicKey>,
+    h: &Sha256,
+) -> Option<u64> {
+    tr.iter_scripts()
+        .filter(|(_, ms)| {
+            ms.iter()
+                .any(|node| matches!(&node.node, Terminal::TxTemplate(t) if t == h))
+        })
+        .filter_map(|(depth, ms)| {
+            let script_size = ms.script_size();
+            let elements = ms.max_satisfaction_witness_elements().ok()?;
+            let satisfaction = ms.max_satisfaction_size().ok()?;
+            let varint = |n: usize| bitcoin::VarInt(n as u64).len();
+            // as in `Tr::max_satisfaction_weight`, but for one leaf
+            Some(
+                (4 + 33
+                    + 32 * depth as usize
+                    + varint(script_size)
+                    + script_size
+                    + varint(elements)
+                    + satisfaction) as u64,
+            )
+        })
+        .max()
+}
+
+/// The weight of a single Taproot leaf.
+///
+/// Branch weights are scaled up by [`WEIGHT_SCALE`] before being split between
+/// the leaves of a branch, so that leaves of equal weight stay equal to within
+/// one unit and are placed in a balanced subtree.
+pub type LeafWeight = u128;
+
+/// The factor a branch's weight is scaled by, see [`LeafWeight`].
+pub const WEIGHT_SCALE: LeafWeight = 1 << 64;
+
+/// The leaf weight of a branch with a single leaf.
+pub fn leaf_weight(weight: u64) -> LeafWeight {
+    LeafWeight::from(weight) * WEIGHT_SCALE
+}
+
+/// Splits a branch's `weight` between its `leaves`, the earlier leaves
+/// taking any remainder, so that the leaves' weights sum to the branch's.
+pub fn split_weight(weight: u64, leaves: usize) -> impl Iterator<Item = LeafWeight> {
+    let total = leaf_weight(weight);
+    let n = leaves.max(1) as LeafWeight;
+    (0..leaves as LeafWeight).map(move |i| total / n + LeafWeight::from(i < total % n))
+}
+
+/// Convert the weighted branches into a heap for taproot tree consumption.
+///
+/// The tree is built as a Huffman tree over the branch weights, so that the
+/// most likely branches end up with the shortest control blocks.
 pub fn branches_to_tree(
-    branches: Vec<Miniscript<XOnlyPublicKey, Tap>>,
+    branches: Vec<(LeafWeight, Miniscript<XOnlyPublicKey, Tap>)>,
 ) -> Option<TapTree<XOnlyPublicKey>> {
-    let mut scripts: BinaryHeap<(Reverse<u64>, TapTree<XOnlyPublicKey>)> = branches
+    let mut scripts: BinaryHeap<(Reverse<LeafWeight>, TapTree<XOnlyPublicKey>)> = branches
         .into_iter()
-        .map(|b| (Reverse(1), TapTree::Leaf(Arc::new(b))))
+        .map(|(w, b)| (Reverse(w), TapTree::Leaf(Arc::new(b))))
         .collect();
     while scripts.len() > 1 {
         let (w1, v1) = scripts.pop().unwrap();
@@ -52,3 +149,117 @@ pub fn branches_to_tree(
     }
     scripts.pop().map(|v| v.1)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::DEFAULT_BRANCH_WEIGHT;
+    fn key_leaves(n: u8) -> Vec<Miniscript<XOnlyPublicKey, Tap>> {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        (1..=n)
+            .map(|i| {
+                let sk = bitcoin::secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+                let pk = XOnlyPublicKey::from(bitcoin::secp256k1::PublicKey::from_secret_key(
+                    &secp, &sk,
+                ));
+                Miniscript::from_ast(Terminal::Check(Arc::new(
+                    Miniscript::from_ast(Terminal::PkK(pk)).unwrap(),
+                )))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_branches_to_tree() {
+        let leaves = key_leaves(5);
+        let heavy = leaves[4].clone();
+        let branches = leaves
+            .into_iter()
+            .enumerate()
+            .map(|(i, ms)| (leaf_weight(if i == 4 { 100 } else { 1 }), ms))
+            .collect();
+        let tree = branches_to_tree(branches).unwrap();
+        for (depth, ms) in tree.iter() {
+            if *ms == heavy {
+                assert_eq!(depth, 1);
+            } else {
+                assert_eq!(depth, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_weight() {
+        let scale = WEIGHT_SCALE;
+        assert_eq!(
+            split_weight(10, 3).collect::<Vec<_>>(),
+            vec![10 * scale / 3 + 1, 10 * scale / 3, 10 * scale / 3]
+        );
+        assert_eq!(
+            split_weight(1, 2).collect::<Vec<_>>(),
+            vec![scale / 2, scale / 2]
+        );
+        assert_eq!(split_weight(5, 0).count(), 0);
+        assert_eq!(split_weight(7, 5).sum::<LeafWeight>(), leaf_weight(7));
+        // the leaves of one branch of the default weight stay balanced
+        let leaves = key_leaves(8);
+        let tree = branches_to_tree(
+            split_weight(DEFAULT_BRANCH_WEIGHT, 8)
+                .zip(leaves.clone())
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(tree.iter().count(), 8);
+        assert!(tree.iter().all(|(depth, _)| depth == 3));
+        // and next to another branch, are as deep as its leaf allows
+        let mut branches: Vec<_> = split_weight(DEFAULT_BRANCH_WEIGHT, 4)
+            .zip(leaves.iter().cloned())
+            .collect();
+        branches.push((leaf_weight(DEFAULT_BRANCH_WEIGHT), leaves[7].clone()));
+        let tree = branches_to_tree(branches).unwrap();
+        for (depth, ms) in tree.iter() {
+            if *ms == leaves[7] {
+                assert_eq!(depth, 1);
+            } else {
+                assert_eq!(depth, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ctv_leaf_satisfaction_weight() {
+        let keys: Vec<XOnlyPublicKey> = key_leaves(5)
+            .iter()
+            .map(|ms| match &ms.node {
+                Terminal::Check(c) => match &c.node {
+                    Terminal::PkK(k) => *k,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            })
+            .collect();
+        let ctv = Sha256::hash(b"template");
+        let ctv_leaf: Miniscript<XOnlyPublicKey, Tap> =
+            Miniscript::from_ast(Terminal::TxTemplate(ctv)).unwrap();
+        let heavy_leaf: Miniscript<XOnlyPublicKey, Tap> =
+            Miniscript::from_ast(Terminal::MultiA(5, keys)).unwrap();
+        let tr = descriptor::Tr::new(
+            pick_key_from_miniscripts(std::iter::empty()),
+            branches_to_tree(vec![
+                (leaf_weight(1), ctv_leaf),
+                (leaf_weight(1), heavy_leaf),
+            ]),
+        )
+        .unwrap();
+        let ctv_weight = ctv_leaf_satisfaction_weight(&tr, &ctv).unwrap();
+        let max_weight = Descriptor::Tr(tr.clone())
+            .max_satisfaction_weight()
+            .unwrap() as u64;
+        assert!(ctv_weight < max_weight);
+        assert_eq!(
+            ctv_leaf_satisfaction_weight(&tr, &Sha256::hash(b"other")),
+            None
+        );
+    }
+}
@@ -18,6 +18,18 @@ use std::collections::LinkedList;
 use std::error::Error;
 use std::fmt;
 type ErrT = Box<dyn std::error::Error>;
+
+/// Which resource limit a plugin module exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleLimit {
+    /// Executed more instructions than the fuel allowed
+    Fuel(u64),
+    /// Attempted to grow its memory past this many pages
+    Memory(u32),
+    /// Ran for longer than this many milliseconds
+    Timeout(u64),
+}
+
 /// Sapio's core error type.
 #[derive(Debug)]
 pub enum CompilationError {
@@ -53,6 +65,11 @@ pub enum CompilationError {
     IncompatibleLockTime,
     /// Error if a sequence at index j >= inputs.len() is attempted to be set
     NoSuchSequence,
+    /// Error if a second anchor output is added to a template
+    DuplicateAnchor,
+    /// Error if a template with an ephemeral anchor would pay a fee, which
+    /// relay policy for ephemeral dust does not allow
+    EphemeralAnchorFee,
     /// Error if parsing an Amount failed
     ParseAmountError(bitcoin::util::amount::ParseAmountError),
     /// Error from the Policy Compiler
@@ -83,6 +100,10 @@ pub enum CompilationError {
     ModuleCouldNotAllocateError(i32, ErrT),
     /// Module failed to find function
     ModuleCouldNotFindFunction(String),
+    /// Module referred to memory (offset, length) outside of its memory
+    ModuleMemoryOutOfBounds(i32, i32),
+    /// Module returned something which breaks the plugin ABI
+    ModuleABIViolation(String),
     /// Module Failed to Deallocate
     ModuleCouldNotDeallocate(i32, ErrT),
     /// Module failed to create
@@ -95,6 +116,10 @@ pub enum CompilationError {
     ModuleCouldNotGetName(ErrT),
     /// Module hit an error at runtime
     ModuleRuntimeError(ErrT),
+    /// Module was stopped for exceeding a resource limit set by the host
+    ModuleExceededLimit(ModuleLimit),
+    /// Module was stopped because the host cancelled the call
+    ModuleCancelled,
     /// API Check Failed, module didn't satisfy examples.
     /// Used in Plugin interface (TODO: Wrap these types)
     ModuleFailedAPICheck(String),
@@ -0,0 +1,146 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Strategies for selecting the Taproot internal key of a contract
+use crate::util::musig;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{Scalar, Secp256k1, Verification};
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The x coordinate of the BIP-341 suggested NUMS point, `lift_x(sha256(G))`.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Get the BIP-341 NUMS point `H`, for which nobody knows the discrete log.
+pub fn nums_point() -> XOnlyPublicKey {
+    XOnlyPublicKey::from_slice(&NUMS_H[..]).expect("constant")
+}
+
+/// Computes `H + tG`, which is unspendable so long as `H` is.
+pub fn tweaked_nums_point<C: Verification>(
+    secp: &Secp256k1<C>,
+    tweak: &sha256::Hash,
+) -> Result<XOnlyPublicKey, bitcoin::secp256k1::Error> {
+    let t = Scalar::from_be_bytes(tweak.into_inner())
+        .map_err(|_| bitcoin::secp256k1::Error::InvalidTweak)?;
+    Ok(nums_point().add_tweak(secp, &t)?.0)
+}
+
+/// # Internal Key Strategy
+/// How the Taproot internal key (the key path) of a contract is chosen.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum InternalKeyStrategy {
+    /// # Heuristic
+    /// Use the first `pk(K)` leaf in the script tree, or a fixed fallback key
+    /// if there is none. Note that this makes the key path spendable by
+    /// whoever holds `K`.
+    Heuristic,
+    /// # Cooperative Key
+    /// A key declared by the contract, e.g. one the parties control jointly.
+    Cooperative(#[schemars(with = "bitcoin::hashes::sha256::Hash")] XOnlyPublicKey),
+    /// # MuSig
+    /// The MuSig2 aggregate of all of the listed keys.
+    MuSig(#[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")] Vec<XOnlyPublicKey>),
+    /// # Unspendable
+    /// A provably unspendable key `H + tG`. If no tweak `t` is provided, one is
+    /// derived from the contract's path and leaf scripts.
+    Unspendable(Option<sha256::Hash>),
+}
+
+impl Default for InternalKeyStrategy {
+    fn default() -> Self {
+        InternalKeyStrategy::Heuristic
+    }
+}
+
+/// # Internal Key Proof
+/// Records which strategy selected a contract's internal key, along with the
+/// data needed to check it.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum InternalKeyProof {
+    /// # Heuristic
+    Heuristic {
+        /// # Internal Key
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+    /// # Cooperative Key
+    Cooperative {
+        /// # Internal Key
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+    /// # MuSig
+    MuSig {
+        /// # Internal Key
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+        /// # Participants
+        /// The keys aggregated into `key`
+        #[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")]
+        participants: Vec<XOnlyPublicKey>,
+    },
+    /// # Unspendable
+    Unspendable {
+        /// # Internal Key
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+        /// # Tweak
+        /// `key == H + tweak*G` for the BIP-341 NUMS point `H`
+        tweak: sha256::Hash,
+    },
+}
+
+impl InternalKeyProof {
+    /// The internal key selected
+    pub fn key(&self) -> XOnlyPublicKey {
+        match self {
+            InternalKeyProof::Heuristic { key }
+            | InternalKeyProof::Cooperative { key }
+            | InternalKeyProof::MuSig { key, .. }
+            | InternalKeyProof::Unspendable { key, .. } => *key,
+        }
+    }
+
+    /// Checks that the recorded key matches the recorded data.
+    ///
+    /// Heuristic and Cooperative keys carry no proof and are always valid.
+    pub fn verify<C: Verification>(&self, secp: &Secp256k1<C>) -> bool {
+        match self {
+            InternalKeyProof::Heuristic { .. } | InternalKeyProof::Cooperative { .. } => true,
+            InternalKeyProof::MuSig { key, participants } => {
+                musig::key_agg(secp, participants).ok() == Some(*key)
+            }
+            InternalKeyProof::Unspendable { key, tweak } => {
+                tweaked_nums_point(secp, tweak).ok() == Some(*key)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_unspendable_proof() {
+        let secp = Secp256k1::verification_only();
+        let tweak = sha256::Hash::hash(b"contract");
+        let key = tweaked_nums_point(&secp, &tweak).unwrap();
+        assert_ne!(key, nums_point());
+        let proof = InternalKeyProof::Unspendable { key, tweak };
+        assert!(proof.verify(&secp));
+        let bad = InternalKeyProof::Unspendable {
+            key: nums_point(),
+            tweak,
+        };
+        assert!(!bad.verify(&secp));
+    }
+}
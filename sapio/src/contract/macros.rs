@@ -18,6 +18,8 @@ use std::sync::Mutex;
 /// ```ignore
 /// declare!{then, a,...}
 /// declare!{finish, a,...}
+/// /// finish branches may be given a weight, see `DEFAULT_BRANCH_WEIGHT`
+/// declare!{finish, a => 10, b,...}
 /// declare!{updatable<X>, a,...}
 /// /// because of a quirk in stable rust, non updatable
 /// /// is required if no updatable<X> declaration is made
@@ -56,13 +58,21 @@ macro_rules! declare {
         #[cfg(not(feature = "nightly"))]
         declare![state ()];
     };
-    {finish $(,$a:expr)*} => {
+    {finish $(,$a:expr $(=> $w:expr)?)*} => {
         /// binds the list of `Gurard`'s to this impl as unlocking conditions.
         /// `Guard`s only need to be bound if it is desired that they are
         /// sufficient to unlock funds, a `Guard` should not be bound if it is
         /// intended to be used with a `ThenFunc`.
         /// Any fn() which returns None is ignored (useful for type-level state machines)
         const FINISH_FNS: &'static [fn() -> Option<$crate::contract::actions::Guard<Self>>] = &[$($a,)*];
+        /// the weights of the `FINISH_FNS`, in the same order
+        const FINISH_WEIGHTS: &'static [u64] = &[$($crate::declare!(@weight $($w)?),)*];
+    };
+    {@weight} => {
+        $crate::contract::actions::DEFAULT_BRANCH_WEIGHT
+    };
+    {@weight $w:expr} => {
+        $w
     };
 
 
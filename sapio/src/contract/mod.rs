@@ -16,6 +16,8 @@ pub mod actions;
 pub mod compiler;
 pub mod error;
 pub use error::CompilationError;
+pub mod internal_key;
+use internal_key::InternalKeyStrategy;
 pub mod context;
 use bitcoin::util::amount::Amount;
 pub use compiler::Compilable;
@@ -56,6 +58,11 @@ where
     fn ensure_amount(&self, _ctx: Context) -> Result<Amount, CompilationError> {
         Ok(Amount::from_sat(0))
     }
+
+    /// how to pick the Taproot internal key for this contract
+    fn internal_key(&self, _ctx: Context) -> Result<InternalKeyStrategy, CompilationError> {
+        Ok(InternalKeyStrategy::default())
+    }
 }
 
 /// DynamicContract wraps a struct S with a set of methods (that can be constructed dynamically)
@@ -67,10 +74,14 @@ pub struct DynamicContract<'a, T, S> {
     pub finish_or: Vec<fn() -> Option<Box<dyn actions::CallableAsFoF<S, T>>>>,
     /// the list of `Guard` for this contract to finish.
     pub finish: Vec<fn() -> Option<actions::Guard<S>>>,
+    /// the weights of the `finish` branches, in the same order
+    pub finish_weights: Vec<u64>,
     /// A metadata generator function
     pub metadata_f: Box<dyn (Fn(&S, Context) -> Result<ObjectMetadata, CompilationError>)>,
     /// A min amount generator function
     pub ensure_amount_f: Box<dyn (Fn(&S, Context) -> Result<Amount, CompilationError>)>,
+    /// An internal key strategy generator function
+    pub internal_key_f: Box<dyn (Fn(&S, Context) -> Result<InternalKeyStrategy, CompilationError>)>,
 
     /// The contract data argument to pass to functions
     pub data: S,
@@ -98,6 +109,9 @@ where
     fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<S>>] {
         &self.finish[..]
     }
+    fn finish_weights<'a>(&'a self) -> &'a [u64] {
+        &self.finish_weights[..]
+    }
     fn get_inner_ref<'a>(&self) -> &Self::Ref {
         &self.data
     }
@@ -109,6 +123,10 @@ where
     fn ensure_amount<'a>(&'a self, ctx: Context) -> Result<Amount, CompilationError> {
         (self.ensure_amount_f)(self.get_inner_ref(), ctx)
     }
+
+    fn internal_key<'a>(&'a self, ctx: Context) -> Result<InternalKeyStrategy, CompilationError> {
+        (self.internal_key_f)(self.get_inner_ref(), ctx)
+    }
 }
 
 /// Catch all trait for things `StatefulArguments` must be required to do.
@@ -146,12 +164,19 @@ where
     ) -> &'a [fn() -> Option<Box<dyn actions::CallableAsFoF<Self::Ref, Self::StatefulArguments>>>];
     /// obtain a reference to the `Guard` list.
     fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self::Ref>>];
+    /// obtain a reference to the weights of the `Guard` list, any `Guard`
+    /// without one has weight [`actions::DEFAULT_BRANCH_WEIGHT`].
+    fn finish_weights<'a>(&'a self) -> &'a [u64] {
+        &[]
+    }
     /// obtain a reference to `Self::Ref` type.
     fn get_inner_ref<'a>(&'a self) -> &'a Self::Ref;
     /// Generate the metadata
     fn metadata<'a>(&'a self, ctx: Context) -> Result<ObjectMetadata, CompilationError>;
     /// Minimum Amount
     fn ensure_amount<'a>(&'a self, ctx: Context) -> Result<Amount, CompilationError>;
+    /// Internal Key Selection
+    fn internal_key<'a>(&'a self, ctx: Context) -> Result<InternalKeyStrategy, CompilationError>;
 }
 
 impl<C> AnyContract for C
@@ -180,6 +205,9 @@ where
     fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self::Ref>>] {
         Self::FINISH_FNS
     }
+    fn finish_weights<'a>(&'a self) -> &'a [u64] {
+        Self::FINISH_WEIGHTS
+    }
     fn get_inner_ref<'a>(&'a self) -> &Self::Ref {
         self
     }
@@ -190,4 +218,7 @@ where
     fn ensure_amount<'a>(&'a self, ctx: Context) -> Result<Amount, CompilationError> {
         Self::Ref::ensure_amount(self, ctx)
     }
+    fn internal_key<'a>(&'a self, ctx: Context) -> Result<InternalKeyStrategy, CompilationError> {
+        Self::Ref::internal_key(self, ctx)
+    }
 }
@@ -0,0 +1,61 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Anchor outputs for bumping the fees of a Template with a CPFP child
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The Pay-to-Anchor (P2A) script, `OP_1 <0x4e73>`, spendable by anyone with
+/// an empty witness.
+pub const P2A_SCRIPT: [u8; 4] = [0x51, 0x02, 0x4e, 0x73];
+
+/// The value (in sats) given to a keyed anchor, which must be above the dust
+/// limit.
+pub const KEYED_ANCHOR_SATS: u64 = 330;
+
+/// The transaction version required to relay a zero value ephemeral anchor
+/// (TRUC, BIP-431).
+pub const EPHEMERAL_ANCHOR_TX_VERSION: i32 = 3;
+
+/// # Anchor
+/// The kind of anchor output attached to a Template
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    /// # Ephemeral Anchor
+    /// A zero value P2A output anyone can spend. Requires the template to be a
+    /// v3 transaction relayed as a package with its child.
+    Ephemeral,
+    /// # Keyed Anchor
+    /// A small taproot output spendable only with the given key.
+    Keyed(#[schemars(with = "bitcoin::hashes::sha256::Hash")] XOnlyPublicKey),
+}
+
+impl Anchor {
+    /// the amount the anchor output holds
+    pub fn amount(&self) -> Amount {
+        match self {
+            Anchor::Ephemeral => Amount::from_sat(0),
+            Anchor::Keyed(_) => Amount::from_sat(KEYED_ANCHOR_SATS),
+        }
+    }
+    /// the script_pubkey for an ephemeral anchor
+    pub fn p2a_script() -> Script {
+        Script::from(P2A_SCRIPT.to_vec())
+    }
+}
+
+/// # Anchor Output
+/// Which output of a Template is its anchor
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnchorOutput {
+    /// # Output Index
+    pub vout: u32,
+    /// # Anchor Type
+    pub anchor: Anchor,
+}
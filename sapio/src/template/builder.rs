@@ -5,13 +5,17 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Interactive Transaction Template Builder
+use super::anchor::EPHEMERAL_ANCHOR_TX_VERSION;
+use super::{
+    weight_to_vsize, witness_weight, Anchor, AnchorOutput, Template, TemplateMetadata,
+    WITNESS_SCALE_FACTOR,
+};
 pub use super::{Output, OutputMeta};
-use super::{Template, TemplateMetadata};
-use crate::contract::{CompilationError, Context};
+use crate::contract::{CompilationError, Compiled, Context};
+use crate::util::amountrange::AmountRange;
 use bitcoin::util::amount::Amount;
 use bitcoin::VarInt;
 use bitcoin::Witness;
-use miniscript::DescriptorTrait;
 use sapio_base::effects::PathFragment;
 use sapio_base::timelocks::*;
 use sapio_base::CTVHash;
@@ -27,6 +31,8 @@ pub struct Builder {
     outputs: Vec<Output>,
     version: i32,
     lock_time: Option<AnyAbsTimeLock>,
+    ctv_index: u32,
+    anchor: Option<AnchorOutput>,
     ctx: Context,
     fees: Amount,
     min_feerate: Option<Amount>,
@@ -43,6 +49,8 @@ impl Builder {
             outputs: vec![],
             version: 2,
             lock_time: None,
+            ctv_index: 0,
+            anchor: None,
             metadata: TemplateMetadata::new(),
             fees: Amount::from_sat(0),
             min_feerate: None,
@@ -62,7 +70,12 @@ impl Builder {
     }
 
     /// reduce the amount availble in the builder's context, and add to the fees
+    ///
+    /// A template with an `Anchor::Ephemeral` anchor may not pay fees.
     pub fn add_fees(self, amount: Amount) -> Result<Self, CompilationError> {
+        if amount.as_sat() > 0 && self.has_ephemeral_anchor() {
+            return Err(CompilationError::EphemeralAnchorFee);
+        }
         let mut c = self.spend_amount(amount)?;
         c.fees += amount;
         Ok(c)
@@ -152,6 +165,62 @@ impl Builder {
         Ok(self)
     }
 
+    /// set_ctv_index sets which input the contract's coin is spent from, which
+    /// is committed to by the template hash. Defaults to 0.
+    ///
+    /// The input must already exist, see `add_sequence`.
+    pub fn set_ctv_index(mut self, idx: u32) -> Result<Self, CompilationError> {
+        if idx as usize >= self.sequences.len() {
+            return Err(CompilationError::NoSuchSequence);
+        }
+        self.ctv_index = idx;
+        Ok(self)
+    }
+
+    /// add_anchor_output adds an output which a CPFP child can spend to bump
+    /// the fees of this template. Only one anchor may be added.
+    ///
+    /// An `Anchor::Ephemeral` anchor is a zero value P2A output and makes the
+    /// transaction v3 so that it may be relayed as a package. Relay policy
+    /// only allows such dust on a transaction which pays no fee, so it may not
+    /// be added to a builder with fees. An `Anchor::Keyed` anchor spends
+    /// `KEYED_ANCHOR_SATS` from the builder's funds to a key path only taproot
+    /// output.
+    pub fn add_anchor_output(self, anchor: Anchor) -> Result<Self, CompilationError> {
+        if self.anchor.is_some() {
+            return Err(CompilationError::DuplicateAnchor);
+        }
+        if anchor == Anchor::Ephemeral && self.fees.as_sat() > 0 {
+            return Err(CompilationError::EphemeralAnchorFee);
+        }
+        let vout = self.outputs.len() as u32;
+        let mut ret = match anchor {
+            Anchor::Ephemeral => {
+                let contract = Compiled::from_script(
+                    Anchor::p2a_script(),
+                    Some(AmountRange::new()),
+                    self.ctx.network,
+                )?;
+                let mut ret = self.add_output(anchor.amount(), &contract, None)?;
+                ret.version = EPHEMERAL_ANCHOR_TX_VERSION;
+                ret
+            }
+            Anchor::Keyed(key) => self.add_output(anchor.amount(), &key, None)?,
+        };
+        ret.anchor = Some(AnchorOutput { vout, anchor });
+        Ok(ret)
+    }
+
+    fn has_ephemeral_anchor(&self) -> bool {
+        matches!(
+            self.anchor,
+            Some(AnchorOutput {
+                anchor: Anchor::Ephemeral,
+                ..
+            })
+        )
+    }
+
     /// overwrite any existing label with the provided string,
     /// or set a label if none provided thus far.
     pub fn set_label(mut self, label: String) -> Self {
@@ -218,8 +287,9 @@ impl Builder {
     /// For example, s.set_min_feerate(100.into()).set_min_feerate(1000.into())
     /// results in feerate Some(100).
     ///
-    /// During compilation, templates should be checked to ensure that at least
-    /// that feerate is paid.
+    /// During compilation, templates are checked to ensure that at least
+    /// that feerate (in sats/vbyte) is paid, using [`Template::estimate_vsize`]
+    /// with the parent contract's max satisfaction weight.
     pub fn set_min_feerate(mut self, a: Amount) -> Self {
         let v: &mut Amount = self.min_feerate.get_or_insert(a);
         *v = std::cmp::min(*v, a);
@@ -228,48 +298,43 @@ impl Builder {
 
     /// more efficient that get_tx() to estimate a tx size, not including witness
     pub fn estimate_tx_size(&self) -> u64 {
-        let mut input_weight: u64 = 0;
-        let inputs_with_witnesses: u64 = self.sequences.len() as u64;
-        let scale_factor = 1u64;
-        for _seq in &self.sequences {
-            input_weight += scale_factor
-                * (32 + 4 + 4 + // outpoint (32+4) + nSequence
-                VarInt(0u64).len() as u64 + 0);
-            //if !input.witness.is_empty() {
-            //    inputs_with_witnesses += 1;
-            //    input_weight += VarInt(input.witness.len() as u64).len();
-            //    for elem in &input.witness {
-            //        input_weight += VarInt(elem.len() as u64).len() + elem.len();
-            //    }
-            //}
-        }
-        let mut output_size: u64 = 0;
-        for output in &self.outputs {
-            let spk = output
-                .contract
-                .descriptor
-                .as_ref()
-                .map(|d| d.script_pubkey().len() as u64);
-            output_size += 8 + // value
-                (VarInt(spk.unwrap_or(0)).len() as u64) +
-                spk.unwrap_or(0);
-        }
-        let non_input_size : u64=
+        let input_size: u64 = self.sequences.len() as u64
+            * (32 + 4 + 4 + // outpoint (32+4) + nSequence
+                VarInt(0u64).len() as u64); // empty scriptSig
+        let output_size: u64 = self
+            .outputs
+            .iter()
+            .map(|output| {
+                let spk = bitcoin::Script::from(output.contract.address.clone()).len() as u64;
+                8 + // value
+                    VarInt(spk).len() as u64 + spk
+            })
+            .sum();
         // version:
         4 +
         // count varints:
-        (VarInt(self.sequences.len() as u64).len() as u64 +
-        VarInt(self.outputs.len() as u64).len() as u64)+
+        VarInt(self.sequences.len() as u64).len() as u64 +
+        VarInt(self.outputs.len() as u64).len() as u64 +
+        input_size +
         output_size +
         // lock_time
-        4;
-        if inputs_with_witnesses == 0 {
-            non_input_size * scale_factor + input_weight
-        } else {
-            non_input_size * scale_factor + input_weight + (self.sequences.len() as u64)
-                - inputs_with_witnesses
-                + 2
-        }
+        4
+    }
+
+    /// estimate the weight of the transaction once the input at the CTV index
+    /// is satisfied with a witness of at most `satisfaction_weight` (e.g., as
+    /// reported by `max_satisfaction_weight` on the parent's descriptor).
+    ///
+    /// Other inputs are counted as having empty witnesses.
+    pub fn estimate_weight(&self, satisfaction_weight: u64) -> u64 {
+        self.estimate_tx_size() * WITNESS_SCALE_FACTOR
+            + witness_weight(self.sequences.len() as u64, satisfaction_weight)
+    }
+
+    /// estimate the virtual size of the transaction, see
+    /// [`Builder::estimate_weight`].
+    pub fn estimate_vsize(&self, satisfaction_weight: u64) -> u64 {
+        weight_to_vsize(self.estimate_weight(satisfaction_weight))
     }
 }
 impl From<Builder> for Template {
@@ -278,12 +343,13 @@ impl From<Builder> for Template {
         Template {
             guards: t.guards,
             outputs: t.outputs,
-            ctv: tx.get_ctv_hash(0),
-            ctv_index: 0,
+            ctv: tx.get_ctv_hash(t.ctv_index),
+            ctv_index: t.ctv_index,
             max: tx.total_amount() + t.fees,
             min_feerate_sats_vbyte: t.min_feerate,
             tx,
             metadata_map_s2s: t.metadata,
+            anchor: t.anchor,
         }
     }
 }
@@ -294,3 +360,171 @@ impl From<Builder> for crate::contract::TxTmplIt {
         Ok(Box::new(std::iter::once(Ok(t.into()))))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+    #[test]
+    fn test_ctv_index() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        assert!(ctx.template().set_ctv_index(1).is_err());
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let tmpl: Template = ctx
+            .template()
+            .add_sequence()
+            .set_ctv_index(1)
+            .unwrap()
+            .into();
+        assert_eq!(tmpl.ctv_index, 1);
+        assert_eq!(tmpl.ctv, tmpl.tx.get_ctv_hash(1));
+        assert_ne!(tmpl.ctv, tmpl.tx.get_ctv_hash(0));
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let key = crate::contract::internal_key::nums_point();
+        let bld = ctx
+            .template()
+            .add_sequence()
+            .add_output(Amount::from_sat(1000), &key, None)
+            .unwrap()
+            .add_output(Amount::from_sat(1000), &key, None)
+            .unwrap();
+        let mut tx = bld.get_tx();
+        assert_eq!(
+            bld.estimate_tx_size(),
+            bitcoin::consensus::serialize(&tx).len() as u64
+        );
+        // a single 64 byte signature on the first input
+        tx.input[0].witness = Witness::from_vec(vec![vec![0u8; 64]]);
+        // scriptSig len + stack count + element len + signature
+        let satisfaction_weight = 4 + 1 + 1 + 64;
+        assert_eq!(
+            bld.estimate_weight(satisfaction_weight),
+            tx.get_weight() as u64
+        );
+        assert_eq!(
+            bld.estimate_vsize(satisfaction_weight),
+            tx.get_vsize() as u64
+        );
+    }
+
+    #[test]
+    fn test_anchor_output() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let key = crate::contract::internal_key::nums_point();
+        let bld = ctx
+            .template()
+            .add_output(Amount::from_sat(1000), &key, None)
+            .unwrap()
+            .add_anchor_output(Anchor::Ephemeral)
+            .unwrap();
+        let tmpl: Template = bld.into();
+        assert_eq!(tmpl.tx.version, 3);
+        assert_eq!(tmpl.tx.output[1].script_pubkey, Anchor::p2a_script());
+        assert_eq!(tmpl.tx.output[1].value, 0);
+        assert_eq!(
+            tmpl.anchor,
+            Some(AnchorOutput {
+                vout: 1,
+                anchor: Anchor::Ephemeral
+            })
+        );
+        assert!(tmpl.needs_child(Amount::from_sat(100_000), 0));
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let keyed: Template = ctx
+            .template()
+            .add_output(Amount::from_sat(1000), &key, None)
+            .unwrap()
+            .add_anchor_output(Anchor::Keyed(key))
+            .unwrap()
+            .set_min_feerate(Amount::from_sat(2))
+            .into();
+        let weight = 4 + 1 + 1 + 64;
+        let required = 2 * keyed.estimate_vsize(weight);
+        let total = keyed.total_amount();
+        // implicit fees, from funding beyond the outputs, count
+        assert!(!keyed.needs_child(total + Amount::from_sat(required), weight));
+        assert!(keyed.needs_child(total + Amount::from_sat(required - 1), weight));
+        assert!(keyed.needs_child(total, weight));
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("test").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let res = ctx
+            .template()
+            .add_anchor_output(Anchor::Keyed(key))
+            .unwrap()
+            .add_anchor_output(Anchor::Ephemeral);
+        assert!(matches!(res, Err(CompilationError::DuplicateAnchor)));
+    }
+
+    #[test]
+    fn test_ephemeral_anchor_fee() {
+        let ctx = || {
+            Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("test").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let res = ctx()
+            .template()
+            .add_fees(Amount::from_sat(100))
+            .unwrap()
+            .add_anchor_output(Anchor::Ephemeral);
+        assert!(matches!(res, Err(CompilationError::EphemeralAnchorFee)));
+        let res = ctx()
+            .template()
+            .add_anchor_output(Anchor::Ephemeral)
+            .unwrap()
+            .add_fees(Amount::from_sat(100));
+        assert!(matches!(res, Err(CompilationError::EphemeralAnchorFee)));
+        let key = crate::contract::internal_key::nums_point();
+        assert!(ctx()
+            .template()
+            .add_anchor_output(Anchor::Keyed(key))
+            .unwrap()
+            .add_fees(Amount::from_sat(100))
+            .is_ok());
+    }
+}
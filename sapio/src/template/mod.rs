@@ -16,6 +16,8 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 pub mod output;
 pub use output::{Output, OutputMeta};
+pub mod anchor;
+pub use anchor::{Anchor, AnchorOutput};
 pub mod builder;
 pub use builder::Builder;
 /// Metadata Struct which has some standard defined fields
@@ -116,8 +118,8 @@ pub struct Template {
     /// the precomputed template hash for this Template
     #[serde(rename = "precomputed_template_hash")]
     pub ctv: sha256::Hash,
-    /// the index used for the template hash. (TODO: currently always 0, although
-    /// future version may support other indexes)
+    /// the input index used for the template hash, i.e., the position at which
+    /// the contract's coin is spent in `tx`.
     #[serde(rename = "precomputed_template_hash_idx")]
     pub ctv_index: u32,
     /// the amount being sent to this Template (TODO: currently computed via tx.total_amount())
@@ -146,6 +148,9 @@ pub struct Template {
     /// sapio specific information about all the outputs in the `tx`.
     #[serde(rename = "outputs_info")]
     pub outputs: Vec<Output>,
+    /// the output, if any, which a CPFP child may spend to bump fees
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub anchor: Option<AnchorOutput>,
 }
 
 impl Template {
@@ -163,4 +168,71 @@ impl Template {
             .map(|o| o.amount)
             .fold(Amount::from_sat(0), |b, a| b + a)
     }
+
+    /// estimate the virtual size of this template's transaction once the
+    /// input at `ctv_index` is satisfied with a witness of at most
+    /// `satisfaction_weight`. Other inputs are counted as having empty
+    /// witnesses.
+    pub fn estimate_vsize(&self, satisfaction_weight: u64) -> u64 {
+        // the stored tx has no witnesses, so this is the stripped size * 4
+        let base_weight = self.tx.get_weight() as u64;
+        weight_to_vsize(
+            base_weight + witness_weight(self.tx.input.len() as u64, satisfaction_weight),
+        )
+    }
+
+    /// whether this template must be confirmed with a CPFP child spending its
+    /// anchor, i.e., it has an ephemeral anchor, or a keyed one and the fees it
+    /// pays when funded with `funding` fall short of its minimum feerate (or
+    /// the minimum relay feerate, if it has none) once its input is satisfied
+    /// with `satisfaction_weight`.
+    pub fn needs_child(&self, funding: Amount, satisfaction_weight: u64) -> bool {
+        match self.anchor {
+            Some(AnchorOutput {
+                anchor: Anchor::Ephemeral,
+                ..
+            }) => true,
+            Some(_) => {
+                let feerate = self
+                    .min_feerate_sats_vbyte
+                    .map_or(MIN_RELAY_FEERATE, |f| f.as_sat());
+                let required = feerate.saturating_mul(self.estimate_vsize(satisfaction_weight));
+                self.fees_from(funding)
+                    .map_or(true, |fees| fees.as_sat() < required)
+            }
+            None => false,
+        }
+    }
+
+    /// the fee paid by this template if it is funded with `funding`, or
+    /// `None` if `funding` does not cover the outputs.
+    pub fn fees_from(&self, funding: Amount) -> Option<Amount> {
+        funding.checked_sub(self.total_amount())
+    }
+}
+
+/// The lowest feerate, in sats/vbyte, that nodes relay by default
+pub const MIN_RELAY_FEERATE: u64 = 1;
+
+/// The weight multiplier for non-witness data
+pub(crate) const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// The weight added to a transaction with `n_inputs` inputs (and empty
+/// witnesses) by satisfying one of them with `satisfaction_weight`.
+///
+/// Descriptor satisfaction weights (from miniscript) include the scriptSig
+/// length byte, which the stripped size already counts, so it is removed
+/// here. The segwit marker and flag are added, as is the empty witness stack
+/// count for every other input.
+pub(crate) fn witness_weight(n_inputs: u64, satisfaction_weight: u64) -> u64 {
+    if n_inputs == 0 {
+        return 0;
+    }
+    // marker + flag
+    2 + (n_inputs - 1) + satisfaction_weight.saturating_sub(WITNESS_SCALE_FACTOR)
+}
+
+/// rounds weight up to virtual bytes
+pub(crate) fn weight_to_vsize(weight: u64) -> u64 {
+    (weight + WITNESS_SCALE_FACTOR - 1) / WITNESS_SCALE_FACTOR
 }
@@ -0,0 +1,83 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! MuSig2 (BIP-327) key aggregation for x-only keys
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::secp256k1::{Error, PublicKey, Scalar, Secp256k1, Verification};
+use bitcoin::secp256k1::{Parity, XOnlyPublicKey};
+
+/// BIP-340 style tagged hash
+fn tagged_hash(tag: &str, msg: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for m in msg {
+        engine.input(m);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// Aggregates a set of x-only keys into a single MuSig2 key.
+///
+/// Keys are lifted to their even-y point and sorted (as in BIP-327 `KeySort`)
+/// before running `KeyAgg`, so the result does not depend on the order the
+/// keys are passed in. Returns `Error::InvalidPublicKeySum` if `keys` is
+/// empty or the aggregate is the point at infinity.
+pub fn key_agg<C: Verification>(
+    secp: &Secp256k1<C>,
+    keys: &[XOnlyPublicKey],
+) -> Result<XOnlyPublicKey, Error> {
+    let mut keys: Vec<PublicKey> = keys
+        .iter()
+        .map(|k| PublicKey::from_x_only_public_key(*k, Parity::Even))
+        .collect();
+    keys.sort_by_key(|k| k.serialize());
+    let serialized: Vec<[u8; 33]> = keys.iter().map(PublicKey::serialize).collect();
+    let list: Vec<&[u8]> = serialized.iter().map(|k| &k[..]).collect();
+    let l = tagged_hash("KeyAgg list", &list[..]);
+    // the first key which differs from the first key gets a coefficient of 1
+    let second = serialized.iter().find(|k| **k != serialized[0]);
+    let tweaked = keys
+        .iter()
+        .zip(serialized.iter())
+        .map(|(k, ser)| {
+            if Some(ser) == second {
+                Ok(*k)
+            } else {
+                let a =
+                    Scalar::from_be_bytes(tagged_hash("KeyAgg coefficient", &[&l[..], &ser[..]]))
+                        .map_err(|_| Error::InvalidTweak)?;
+                k.mul_tweak(secp, &a)
+            }
+        })
+        .collect::<Result<Vec<PublicKey>, Error>>()?;
+    let refs: Vec<&PublicKey> = tweaked.iter().collect();
+    Ok(PublicKey::combine_keys(&refs[..])?.x_only_public_key().0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    #[test]
+    fn test_key_agg_vector() {
+        // BIP-327 key_agg_vectors.json, valid test case (X1, X1, X1)
+        let secp = Secp256k1::verification_only();
+        let x1 = XOnlyPublicKey::from_str(
+            "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        )
+        .unwrap();
+        let expected = XOnlyPublicKey::from_str(
+            "b436e3bad62b8cd409969a224731c193d051162d8c5ae8b109306127da3aa935",
+        )
+        .unwrap();
+        assert_eq!(key_agg(&secp, &[x1, x1, x1]).unwrap(), expected);
+        assert!(key_agg(&secp, &[]).is_err());
+    }
+}
@@ -79,6 +79,22 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
     })
 }
 
+fn get_weight(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("weight") => match &v.lit {
+                Lit::Int(l) => {
+                    let w: u64 = l.base10_parse().expect("Weight must be a u64");
+                    return quote! {#w};
+                }
+                _ => panic!("Improperly Formatted {:?}", v),
+            },
+            _ => continue,
+        }
+    }
+    quote! {sapio::contract::actions::DEFAULT_BRANCH_WEIGHT}
+}
+
 fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
     let mut compile_if_array = None;
     let mut guarded_by_array = None;
@@ -123,7 +139,9 @@ fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2:
 ///     /// optional: only compile these branches if these compile_if statements permit
 ///     compile_if= "[compile_if_1, ... compile_if_n]",
 ///     /// optional: protect these branches with the conjunction (and) of these clauses
-///     guarded_by= "[guard_1, ... guard_n]"
+///     guarded_by= "[guard_1, ... guard_n]",
+///     /// optional: relative likelihood of these branches being used
+///     weight = 10
 /// )]
 /// fn name(self, ctx) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -141,6 +159,7 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
     let then_fn_name = format_ident!("then_{}", name);
     let block = input.block;
     let (cia, gba) = get_arrays(&args);
+    let weight = get_weight(&args);
     proc_macro::TokenStream::from(quote! {
             /// (missing docs fix)
             fn #name<'a>() -> Option<sapio::contract::actions::ThenFuncAsFinishOrFunc<'a, Self, <Self as sapio::contract::Contract>::StatefulArguments>>{
@@ -149,6 +168,7 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
                     conditional_compile_if: &#cia,
                     func: Self::#then_fn_name,
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    weight: #weight,
                 }.into())
             }
             /// (missing docs fix)
@@ -233,7 +253,9 @@ fn web_api_schema(
 ///     ///  optional: Enables compiling this for a json callable continuation
 ///     web_api,
 ///     /// helper for coercing args for json api, could be arbitrary
-///     coerce_args = "default_coerce"
+///     coerce_args = "default_coerce",
+///     /// optional: relative likelihood of this branch being used
+///     weight = 10
 /// )]
 /// fn name(self, ctx:Context, o:UpdateType) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -263,6 +285,7 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
         format_ident!("CONTINUE_SCHEMA_FOR_{}", name.to_string().to_uppercase());
     let web_api_schema_s = web_api_schema(&args, &continue_schema_for_name, &arg_type);
     let coerce_args_f = coerce_args(&args);
+    let weight = get_weight(&args);
     proc_macro::TokenStream::from(quote! {
             #web_api_schema_s
             /// (missing docs fix)
@@ -279,6 +302,7 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
                     func: Self::#continue_name,
                     schema: Self::#continue_schema_for_name.map(|f|f()),
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    weight: #weight,
                     f: std::default::Default::default(),
                     returned_txtmpls_modify_guards: false,
                     extract_clause_from_txtmpl: sapio::contract::actions::default_extract_clause_from_txtmpl
@@ -0,0 +1,750 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Broadcasts the transactions of a bound Program once their timelocks mature
+use crate::chain::{ChainWindow, MTP_WINDOW};
+use crate::monitor::{decode_psbt, BlockSource, MonitorError};
+use bitcoin::hash_types::*;
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Block, BlockHeader, OutPoint, SchnorrSighashType, Transaction};
+use miniscript::psbt::PsbtExt;
+use sapio::contract::abi::studio::Program;
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use sapio_psbt::{PSBTSigningError, SigningKey};
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Errors which can arise while broadcasting
+#[derive(Debug)]
+pub enum BroadcastError {
+    /// Error reading blocks or decoding a Program
+    Monitor(MonitorError),
+    /// Error from the TxIndex
+    TxIndex(TxIndexError),
+    /// Error signing a PSBT
+    Signing(PSBTSigningError),
+    /// The Error was for an unknown/unhandled reason
+    Custom(Box<dyn std::error::Error>),
+}
+impl std::error::Error for BroadcastError {}
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<MonitorError> for BroadcastError {
+    fn from(e: MonitorError) -> Self {
+        BroadcastError::Monitor(e)
+    }
+}
+impl From<TxIndexError> for BroadcastError {
+    fn from(e: TxIndexError) -> Self {
+        BroadcastError::TxIndex(e)
+    }
+}
+impl From<PSBTSigningError> for BroadcastError {
+    fn from(e: PSBTSigningError) -> Self {
+        BroadcastError::Signing(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, BroadcastError>;
+
+/// The chain tip, against which locks are evaluated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    /// height of the tip
+    pub height: u32,
+    /// median time past of the tip
+    pub mtp: u32,
+}
+
+/// When a parent transaction confirmed, for evaluating relative locks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    /// height of the block containing the parent
+    pub height: u32,
+    /// median time past of the block before the one containing the parent
+    /// (as in BIP-68)
+    pub mtp: u32,
+}
+
+/// Whether a transaction may be included in the block after the tip
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Readiness {
+    /// The transaction is valid in the next block
+    Ready,
+    /// The parent of an input has not confirmed
+    WaitingForParent(Txid),
+    /// The transaction is valid once the tip reaches `height` and `mtp`
+    Locked {
+        /// the tip height required
+        height: u32,
+        /// the tip median time past required
+        mtp: u32,
+    },
+}
+
+const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// Computes whether `tx` is valid in the block after `tip`, given the
+/// confirmations of its parents, following BIP-65 and BIP-68/BIP-113.
+pub fn readiness(
+    tx: &Transaction,
+    parents: &BTreeMap<Txid, Confirmation>,
+    tip: ChainTip,
+) -> Readiness {
+    // the tip height needed for the tx to be valid in the next block
+    let mut height = 0u32;
+    let mut mtp = 0u32;
+    let all_final = tx.input.iter().all(|i| i.sequence == SEQUENCE_FINAL);
+    if tx.lock_time != 0 && !all_final {
+        if tx.lock_time < LOCKTIME_THRESHOLD {
+            // nLockTime < next height
+            height = tx.lock_time;
+        } else {
+            // nLockTime < MTP of the tip
+            mtp = tx.lock_time + 1;
+        }
+    }
+    for input in &tx.input {
+        let conf = match parents.get(&input.previous_output.txid) {
+            Some(conf) => conf,
+            None => return Readiness::WaitingForParent(input.previous_output.txid),
+        };
+        if tx.version < 2 || input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            continue;
+        }
+        let value = input.sequence & SEQUENCE_LOCKTIME_MASK;
+        if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            mtp = std::cmp::max(mtp, conf.mtp + (value << SEQUENCE_LOCKTIME_GRANULARITY));
+        } else {
+            // next height >= conf.height + value
+            height = std::cmp::max(height, (conf.height + value).saturating_sub(1));
+        }
+    }
+    if tip.height >= height && tip.mtp >= mtp {
+        Readiness::Ready
+    } else {
+        Readiness::Locked { height, mtp }
+    }
+}
+
+/// Chooses which of several scheduled transactions to broadcast when they
+/// are valid at once but spend the same outputs, i.e., are alternative
+/// branches of a contract.
+pub trait BranchPolicy {
+    /// pick one of `conflicting` to broadcast, or `None` to leave all of
+    /// them scheduled for now.
+    fn choose(&self, conflicting: &[&Transaction]) -> Option<Txid>;
+}
+
+impl<F> BranchPolicy for F
+where
+    F: Fn(&[&Transaction]) -> Option<Txid>,
+{
+    fn choose(&self, conflicting: &[&Transaction]) -> Option<Txid> {
+        self(conflicting)
+    }
+}
+
+/// A `BranchPolicy` taking the first of a list of preferred transactions
+/// which is among the conflicting ones, and otherwise none of them.
+pub struct Preferred(pub Vec<Txid>);
+
+impl BranchPolicy for Preferred {
+    fn choose(&self, conflicting: &[&Transaction]) -> Option<Txid> {
+        self.0
+            .iter()
+            .find(|txid| conflicting.iter().any(|tx| tx.txid() == **txid))
+            .cloned()
+    }
+}
+
+/// The outcome of attempting to broadcast at a new tip
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    /// transactions finalized and added to the TxIndex
+    pub broadcast: Vec<Txid>,
+    /// transactions broadcast before which have not confirmed, e.g. as the
+    /// block confirming them was reorged out, added to the TxIndex again
+    pub rebroadcast: Vec<Txid>,
+    /// transactions which were ready but could not be signed, finalized or
+    /// broadcast, with the reasons why. These remain scheduled.
+    pub failed: Vec<(Txid, Vec<String>)>,
+    /// sets of ready transactions spending the same outputs, of which the
+    /// `BranchPolicy` chose none. These remain scheduled.
+    pub undecided: Vec<Vec<Txid>>,
+}
+
+impl BroadcastReport {
+    fn extend(&mut self, other: BroadcastReport) {
+        self.broadcast.extend(other.broadcast);
+        self.rebroadcast.extend(other.rebroadcast);
+        self.failed.extend(other.failed);
+        self.undecided.extend(other.undecided);
+    }
+}
+
+/// whether `a` and `b` spend any of the same outputs
+fn spend_same(a: &Transaction, b: &Transaction) -> bool {
+    a.input.iter().any(|i| {
+        b.input
+            .iter()
+            .any(|j| i.previous_output == j.previous_output)
+    })
+}
+
+/// Schedules the PSBTs of bound Programs and broadcasts each through a
+/// `TxIndex` (e.g., a `BitcoinNodeIndex` with `can_add`) once it is valid.
+///
+/// A transaction is only considered once all of its inputs' parents have
+/// confirmed, and where several conflicting transactions are valid at once
+/// the `BranchPolicy` decides which, if any, is broadcast. A transaction
+/// broadcast is sent again at each tip until it confirms, and the others
+/// spending the same outputs are held back until one of them confirms.
+/// Reorgs up to [`crate::chain::MAX_REORG_DEPTH`] blocks deep are followed by
+/// [`Broadcaster::run`], rescheduling whatever the disconnected blocks had
+/// settled.
+pub struct Broadcaster {
+    key: SigningKey,
+    secp: Secp256k1<All>,
+    index: Rc<dyn TxIndex>,
+    policy: Box<dyn BranchPolicy>,
+    /// every transaction scheduled, including those broadcast or settled
+    scheduled: BTreeMap<Txid, PartiallySignedTransaction>,
+    /// the finalized transactions broadcast
+    sent: BTreeMap<Txid, Transaction>,
+    /// transactions spent by scheduled transactions
+    parents: BTreeSet<Txid>,
+    /// outputs spent by scheduled transactions
+    outpoints: BTreeSet<OutPoint>,
+    confirmed: BTreeMap<Txid, Confirmation>,
+    /// the confirmed spends of `outpoints`, and their heights
+    spent: BTreeMap<OutPoint, (Txid, u32)>,
+    chain: ChainWindow,
+}
+
+impl Broadcaster {
+    /// create a broadcaster which signs with `key`, broadcasts to `index`,
+    /// and picks between conflicting branches with `policy`
+    pub fn new(key: SigningKey, index: Rc<dyn TxIndex>, policy: Box<dyn BranchPolicy>) -> Self {
+        Broadcaster {
+            key,
+            secp: Secp256k1::new(),
+            index,
+            policy,
+            scheduled: BTreeMap::new(),
+            sent: BTreeMap::new(),
+            parents: BTreeSet::new(),
+            outpoints: BTreeSet::new(),
+            confirmed: BTreeMap::new(),
+            spent: BTreeMap::new(),
+            chain: ChainWindow::new(),
+        }
+    }
+
+    /// schedule every transaction of a program, returning how many were added
+    pub fn schedule(&mut self, program: &Program) -> Result<usize> {
+        let mut added = 0;
+        for obj in program.program.values() {
+            for f in &obj.txs {
+                let psbt = decode_psbt(f)?;
+                let txid = psbt.unsigned_tx.txid();
+                for input in &psbt.unsigned_tx.input {
+                    self.parents.insert(input.previous_output.txid);
+                    self.outpoints.insert(input.previous_output);
+                }
+                if self.scheduled.insert(txid, psbt).is_none() {
+                    added += 1;
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    /// whether `tx`, or another transaction spending one of its inputs, has
+    /// confirmed
+    fn settled(&self, tx: &Transaction) -> bool {
+        self.confirmed.contains_key(&tx.txid())
+            || tx
+                .input
+                .iter()
+                .any(|i| self.spent.contains_key(&i.previous_output))
+    }
+
+    /// the transactions broadcast which have not settled
+    fn unconfirmed_sent(&self) -> impl Iterator<Item = &Transaction> {
+        self.sent.values().filter(move |tx| !self.settled(tx))
+    }
+
+    /// the scheduled transactions which are neither broadcast, settled, nor
+    /// in conflict with one broadcast
+    fn pending_psbts(&self) -> impl Iterator<Item = (&Txid, &PartiallySignedTransaction)> {
+        self.scheduled.iter().filter(move |(txid, psbt)| {
+            let tx = &psbt.unsigned_tx;
+            !self.sent.contains_key(*txid)
+                && !self.settled(tx)
+                && !self.unconfirmed_sent().any(|sent| spend_same(sent, tx))
+        })
+    }
+
+    /// the transactions still waiting to be broadcast
+    pub fn pending(&self) -> impl Iterator<Item = &Txid> {
+        self.pending_psbts().map(|(txid, _)| txid)
+    }
+
+    /// the transactions spent by scheduled transactions which have not been
+    /// seen confirmed
+    pub fn unconfirmed_parents(&self) -> impl Iterator<Item = &Txid> {
+        self.parents
+            .iter()
+            .filter(move |txid| !self.confirmed.contains_key(*txid))
+    }
+
+    /// record that a transaction confirmed, e.g., a program's funding tx
+    /// which confirmed before the broadcaster started.
+    pub fn set_confirmed(&mut self, txid: Txid, conf: Confirmation) {
+        let inputs: Vec<OutPoint> = self
+            .sent
+            .get(&txid)
+            .or_else(|| self.scheduled.get(&txid).map(|psbt| &psbt.unsigned_tx))
+            .map(|tx| tx.input.iter().map(|i| i.previous_output).collect())
+            .unwrap_or_default();
+        for out in inputs {
+            self.spent.insert(out, (txid, conf.height));
+        }
+        self.confirmed.insert(txid, conf);
+    }
+
+    /// the readiness of a scheduled transaction at `tip`
+    pub fn readiness(&self, txid: &Txid, tip: ChainTip) -> Option<Readiness> {
+        self.scheduled
+            .get(txid)
+            .map(|psbt| readiness(&psbt.unsigned_tx, &self.confirmed, tip))
+    }
+
+    /// groups the transactions valid at `tip` into sets spending the same
+    /// outputs
+    fn ready_branches(&self, tip: ChainTip) -> Vec<Vec<Txid>> {
+        let mut groups: Vec<Vec<&Transaction>> = vec![];
+        for (_, psbt) in self.pending_psbts() {
+            let tx = &psbt.unsigned_tx;
+            if readiness(tx, &self.confirmed, tip) != Readiness::Ready {
+                continue;
+            }
+            let conflicts = |group: &Vec<&Transaction>| group.iter().any(|o| spend_same(o, tx));
+            let (mut joined, rest): (Vec<_>, Vec<_>) = groups.into_iter().partition(conflicts);
+            let mut group: Vec<&Transaction> = joined.drain(..).flatten().collect();
+            group.push(tx);
+            groups = rest;
+            groups.push(group);
+        }
+        groups
+            .into_iter()
+            .map(|group| {
+                if group.len() == 1 {
+                    vec![group[0].txid()]
+                } else {
+                    match self.policy.choose(&group) {
+                        Some(txid) => vec![txid],
+                        None => group.iter().map(|tx| tx.txid()).collect(),
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// send again every transaction broadcast which has not confirmed and
+    /// is valid at `tip`, then sign, finalize and broadcast every scheduled
+    /// transaction valid at `tip`.
+    ///
+    /// Once a transaction is broadcast, any other scheduled transactions
+    /// spending the same outputs are held back while it is unconfirmed.
+    pub fn on_tip(&mut self, tip: ChainTip) -> Result<BroadcastReport> {
+        let mut report = BroadcastReport::default();
+        let unconfirmed: Vec<Transaction> = self.unconfirmed_sent().cloned().collect();
+        for tx in unconfirmed {
+            if readiness(&tx, &self.confirmed, tip) != Readiness::Ready {
+                continue;
+            }
+            let txid = tx.txid();
+            match self.index.add_tx(Arc::new(tx)) {
+                Ok(_) => report.rebroadcast.push(txid),
+                Err(e) => report.failed.push((txid, vec![e.to_string()])),
+            }
+        }
+        for branch in self.ready_branches(tip) {
+            let txid = match branch[..] {
+                [txid] => txid,
+                _ => {
+                    report.undecided.push(branch);
+                    continue;
+                }
+            };
+            let mut psbt = match self.scheduled.get(&txid) {
+                Some(psbt) => psbt.clone(),
+                // the policy chose something which is not scheduled
+                None => continue,
+            };
+            if let Err(e) =
+                self.key
+                    .sign_psbt_mut(&mut psbt, &self.secp, SchnorrSighashType::Default)
+            {
+                report.failed.push((txid, vec![format!("{:?}", e)]));
+                continue;
+            }
+            let tx = match psbt.finalize(&self.secp) {
+                Ok(psbt) => psbt.extract_tx(),
+                Err((_, errors)) => {
+                    report
+                        .failed
+                        .push((txid, errors.iter().map(|e| format!("{:?}", e)).collect()));
+                    continue;
+                }
+            };
+            if let Err(e) = self.index.add_tx(Arc::new(tx.clone())) {
+                report.failed.push((txid, vec![e.to_string()]));
+                continue;
+            }
+            self.sent.insert(txid, tx);
+            report.broadcast.push(txid);
+        }
+        Ok(report)
+    }
+
+    /// remember the headers of blocks before the first to be ingested, so
+    /// that the median time past is correct from the start.
+    pub fn seed(&mut self, headers: &[(u32, BlockHeader)]) {
+        for (height, header) in headers {
+            self.chain.push(*height, header);
+        }
+    }
+
+    /// the height and median time past of the last block ingested
+    pub fn tip(&self) -> Option<ChainTip> {
+        self.chain.tip().map(|(height, _)| ChainTip {
+            height,
+            mtp: self.chain.mtp(),
+        })
+    }
+
+    /// undo the last block ingested, e.g. as it was reorged out, so that
+    /// what it confirmed is scheduled (or sent again) as before
+    pub fn disconnect(&mut self) -> Result<()> {
+        let height = self
+            .chain
+            .pop()
+            .ok_or(MonitorError::ReorgTooDeep)
+            .map_err(BroadcastError::Monitor)?;
+        self.confirmed.retain(|_, conf| conf.height < height);
+        self.spent.retain(|_, (_, h)| *h < height);
+        Ok(())
+    }
+
+    /// record the confirmations in a block of scheduled transactions, their
+    /// parents, and whatever spends their inputs, and broadcast whatever
+    /// became valid. Blocks must be ingested in order, after seeding with the
+    /// blocks before the first, for the median time past to be correct.
+    ///
+    /// Any blocks ingested at or above `height` are disconnected first, and
+    /// the block must build on the one before it.
+    pub fn ingest_block(&mut self, height: u32, block: &Block) -> Result<BroadcastReport> {
+        while matches!(self.chain.tip(), Some((h, _)) if h >= height) {
+            self.disconnect()?;
+        }
+        if !self.chain.connects(height, &block.header) {
+            return Err(MonitorError::DoesNotConnect(height).into());
+        }
+        let conf = Confirmation {
+            height,
+            mtp: self.chain.mtp(),
+        };
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            if self.parents.contains(&txid) || self.scheduled.contains_key(&txid) {
+                self.confirmed.insert(txid, conf);
+            }
+            for input in &tx.input {
+                if self.outpoints.contains(&input.previous_output) {
+                    self.spent.insert(input.previous_output, (txid, height));
+                }
+            }
+        }
+        self.chain.push(height, &block.header);
+        let mtp = self.chain.mtp();
+        self.on_tip(ChainTip { height, mtp })
+    }
+
+    /// ingest all available blocks from `source`, seeding the median time
+    /// past from the blocks before the first if nothing was ingested yet, and
+    /// following it back to the fork point when a block does not build on
+    /// the last one ingested.
+    pub fn run(&mut self, source: &mut dyn BlockSource) -> Result<BroadcastReport> {
+        if self.chain.is_empty() {
+            let headers = source.prior_headers(MTP_WINDOW)?;
+            self.seed(&headers);
+        }
+        let mut report = BroadcastReport::default();
+        while let Some((height, block)) = source.next_block()? {
+            if self.chain.connects(height, &block.header) {
+                report.extend(self.ingest_block(height, &block)?);
+            } else {
+                // the block before this one was reorged out
+                let (stale, _) = self
+                    .chain
+                    .tip()
+                    .ok_or(MonitorError::ReorgTooDeep)
+                    .map_err(BroadcastError::Monitor)?;
+                self.disconnect()?;
+                source.rewind(stale)?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_readiness() {
+        let parent = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![Default::default()],
+        };
+        let mut tx = parent.clone();
+        tx.input[0].previous_output = OutPoint::new(parent.txid(), 0);
+        tx.input[0].sequence = 10;
+        let mut parents = BTreeMap::new();
+        let tip = ChainTip {
+            height: 100,
+            mtp: 1_000_000,
+        };
+        assert_eq!(
+            readiness(&tx, &parents, tip),
+            Readiness::WaitingForParent(parent.txid())
+        );
+        parents.insert(
+            parent.txid(),
+            Confirmation {
+                height: 100,
+                mtp: 1_000_000,
+            },
+        );
+        // spendable in block 110, so once the tip is 109
+        assert_eq!(
+            readiness(&tx, &parents, tip),
+            Readiness::Locked {
+                height: 109,
+                mtp: 0
+            }
+        );
+        assert_eq!(
+            readiness(&tx, &parents, ChainTip { height: 109, ..tip }),
+            Readiness::Ready
+        );
+        // 2 * 512 seconds relative, and an absolute height
+        tx.input[0].sequence = SEQUENCE_LOCKTIME_TYPE_FLAG | 2;
+        tx.lock_time = 120;
+        assert_eq!(
+            readiness(&tx, &parents, tip),
+            Readiness::Locked {
+                height: 120,
+                mtp: 1_001_024
+            }
+        );
+        assert_eq!(
+            readiness(
+                &tx,
+                &parents,
+                ChainTip {
+                    height: 120,
+                    mtp: 1_001_024
+                }
+            ),
+            Readiness::Ready
+        );
+    }
+    #[test]
+    fn test_branch_policy() {
+        use sapio_base::txindex::TxIndexLogger;
+        let parent = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![Default::default()],
+        };
+        let mut a = parent.clone();
+        a.input[0].previous_output = OutPoint::new(parent.txid(), 0);
+        let mut b = a.clone();
+        b.output[0].value = 1;
+        let unrelated = Transaction {
+            lock_time: 1,
+            ..parent.clone()
+        };
+        let key = SigningKey::new_key(bitcoin::Network::Regtest).unwrap();
+        let new = |policy: Box<dyn BranchPolicy>| {
+            let mut broadcaster = Broadcaster::new(
+                SigningKey(key.0.clone()),
+                Rc::new(TxIndexLogger::new()),
+                policy,
+            );
+            for tx in [&a, &b] {
+                broadcaster.parents.insert(parent.txid());
+                broadcaster.scheduled.insert(
+                    tx.txid(),
+                    PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap(),
+                );
+            }
+            broadcaster
+        };
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![parent.clone(), unrelated.clone()],
+        };
+
+        // without a choice, neither branch is broadcast
+        let mut broadcaster = new(Box::new(|_: &[&Transaction]| None));
+        let report = broadcaster.ingest_block(1, &block).unwrap();
+        assert!(report.broadcast.is_empty() && report.failed.is_empty());
+        let mut expected = vec![a.txid(), b.txid()];
+        expected.sort();
+        assert_eq!(report.undecided, vec![expected]);
+        // only the parent of the scheduled transactions is recorded
+        assert_eq!(
+            broadcaster.confirmed.keys().collect::<Vec<_>>(),
+            vec![&parent.txid()]
+        );
+
+        // the preferred branch is the one attempted (and fails to sign,
+        // lacking the output it spends)
+        let mut broadcaster = new(Box::new(Preferred(vec![b.txid()])));
+        let report = broadcaster.ingest_block(1, &block).unwrap();
+        assert!(report.undecided.is_empty());
+        assert_eq!(
+            report.failed.iter().map(|f| f.0).collect::<Vec<_>>(),
+            vec![b.txid()]
+        );
+    }
+
+    #[test]
+    fn test_reorg_rebroadcast() {
+        use sapio_base::txindex::TxIndexLogger;
+        let parent = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![Default::default()],
+        };
+        let mut a = parent.clone();
+        a.input[0].previous_output = OutPoint::new(parent.txid(), 0);
+        let mut b = a.clone();
+        b.output[0].value = 1;
+        // spendable by anyone, so that the PSBTs finalize without signatures
+        let script = bitcoin::Script::from(vec![0x51]);
+        let psbt = |tx: &Transaction| {
+            let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+            psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+                value: 1000,
+                script_pubkey: bitcoin::Script::new_v0_p2wsh(&script.wscript_hash()),
+            });
+            psbt.inputs[0].witness_script = Some(script.clone());
+            psbt
+        };
+        let index = Rc::new(TxIndexLogger::new());
+        let mut broadcaster = Broadcaster::new(
+            SigningKey::new_key(bitcoin::Network::Regtest).unwrap(),
+            index.clone(),
+            Box::new(Preferred(vec![a.txid()])),
+        );
+        for tx in [&a, &b] {
+            broadcaster.scheduled.insert(tx.txid(), psbt(tx));
+        }
+        broadcaster.parents.insert(parent.txid());
+        broadcaster.outpoints.insert(a.input[0].previous_output);
+        let block = |prev: BlockHash, time, txdata| Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev,
+                merkle_root: Default::default(),
+                time,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        };
+        let b1 = block(Default::default(), 1, vec![parent]);
+        let report = broadcaster.ingest_block(1, &b1).unwrap();
+        assert_eq!(report.broadcast, vec![a.txid()]);
+        assert!(index.lookup_tx(&a.txid()).is_ok());
+        // the alternative is held back while a is unconfirmed
+        assert_eq!(broadcaster.pending().count(), 0);
+        let b2 = block(b1.block_hash(), 2, vec![a.clone()]);
+        let report = broadcaster.ingest_block(2, &b2).unwrap();
+        assert!(report.broadcast.is_empty() && report.rebroadcast.is_empty());
+
+        // b2 is reorged out, so a is sent again rather than forgotten
+        let b2b = block(b1.block_hash(), 3, vec![]);
+        let b3b = block(b2b.block_hash(), 4, vec![]);
+        struct Blocks(Vec<(u32, Block)>, usize);
+        impl BlockSource for Blocks {
+            fn next_block(&mut self) -> std::result::Result<Option<(u32, Block)>, MonitorError> {
+                self.1 += 1;
+                Ok(self.0.get(self.1 - 1).cloned())
+            }
+            fn rewind(&mut self, height: u32) -> std::result::Result<(), MonitorError> {
+                self.1 = self.0.iter().position(|(h, _)| *h == height).unwrap();
+                Ok(())
+            }
+        }
+        let mut source = Blocks(vec![(2, b2b.clone()), (3, b3b.clone())], 1);
+        let report = broadcaster.run(&mut source).unwrap();
+        assert!(report.broadcast.is_empty());
+        // at each new tip
+        assert_eq!(report.rebroadcast, vec![a.txid(), a.txid()]);
+        assert_eq!(broadcaster.tip().unwrap().height, 3);
+        assert_eq!(broadcaster.pending().count(), 0);
+
+        // the other branch confirming settles both
+        let b4b = block(b3b.block_hash(), 5, vec![b.clone()]);
+        let report = broadcaster.ingest_block(4, &b4b).unwrap();
+        assert!(report.broadcast.is_empty() && report.rebroadcast.is_empty());
+        // and disconnecting it reschedules neither, as a is still sent
+        broadcaster.disconnect().unwrap();
+        assert_eq!(broadcaster.pending().count(), 0);
+        assert_eq!(
+            broadcaster
+                .on_tip(broadcaster.tip().unwrap())
+                .unwrap()
+                .rebroadcast,
+            vec![a.txid()]
+        );
+        broadcaster.ingest_block(4, &b4b).unwrap();
+        // a block which does not build on the tip is refused
+        assert!(matches!(
+            broadcaster.ingest_block(5, &block(b2b.block_hash(), 6, vec![])),
+            Err(BroadcastError::Monitor(MonitorError::DoesNotConnect(5)))
+        ));
+    }
+}
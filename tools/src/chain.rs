@@ -0,0 +1,90 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Follows the most recent blocks of the chain, for median time past and for
+//! noticing reorgs
+use bitcoin::{BlockHash, BlockHeader};
+use std::collections::VecDeque;
+
+/// number of blocks used to compute the median time past
+pub const MTP_WINDOW: usize = 11;
+/// how many blocks are remembered, and so how deep a reorg may be followed
+pub const MAX_REORG_DEPTH: usize = 100;
+
+/// The median of a set of block times
+pub fn median_time_past(times: impl Iterator<Item = u32>) -> u32 {
+    let mut times: Vec<u32> = times.collect();
+    times.sort_unstable();
+    times.get(times.len() / 2).cloned().unwrap_or(0)
+}
+
+/// The most recent blocks of one branch of the chain, oldest first
+#[derive(Default)]
+pub struct ChainWindow {
+    /// height, hash and time of each block
+    blocks: VecDeque<(u32, BlockHash, u32)>,
+}
+
+impl ChainWindow {
+    /// an empty window
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// whether no blocks are known
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// the height and hash of the last block
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.blocks.back().map(|(h, hash, _)| (*h, *hash))
+    }
+
+    /// the hash of the block at `height`, if it is remembered
+    pub fn hash_at(&self, height: u32) -> Option<BlockHash> {
+        self.blocks
+            .iter()
+            .find(|(h, _, _)| *h == height)
+            .map(|(_, hash, _)| *hash)
+    }
+
+    /// whether a block at `height` with `header` builds on the block before
+    /// it, or that block is not known.
+    pub fn connects(&self, height: u32, header: &BlockHeader) -> bool {
+        match height.checked_sub(1).and_then(|h| self.hash_at(h)) {
+            Some(hash) => hash == header.prev_blockhash,
+            None => true,
+        }
+    }
+
+    /// the height of the oldest block remembered
+    pub fn first_height(&self) -> Option<u32> {
+        self.blocks.front().map(|(h, _, _)| *h)
+    }
+
+    /// add the block at `height`, replacing any at or above it
+    pub fn push(&mut self, height: u32, header: &BlockHeader) {
+        while matches!(self.blocks.back(), Some((h, _, _)) if *h >= height) {
+            self.blocks.pop_back();
+        }
+        self.blocks
+            .push_back((height, header.block_hash(), header.time));
+        if self.blocks.len() > MAX_REORG_DEPTH {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// forget the last block, returning its height
+    pub fn pop(&mut self) -> Option<u32> {
+        self.blocks.pop_back().map(|(h, _, _)| h)
+    }
+
+    /// the median time past of the last block
+    pub fn mtp(&self) -> u32 {
+        median_time_past(self.blocks.iter().rev().take(MTP_WINDOW).map(|b| b.2))
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A persistent TxIndex backed by sled
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hash_types::*;
+use bitcoin::hashes::Hash;
+use bitcoin::OutPoint;
+use sapio_base::effects::EffectPath;
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use sled::transaction::ConflictableTransactionError;
+use sled::Transactional;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, TxIndexError>;
+
+fn db_err<E: std::error::Error + 'static>(e: E) -> TxIndexError {
+    TxIndexError::DatabaseError(Box::new(e))
+}
+
+/// separates a path from the txid in keys of the `by_path` tree. Path
+/// fragments may not contain it.
+const PATH_SEP: u8 = 0;
+
+/// A `TxIndex` stored on disk, which survives restarts.
+///
+/// Along with the transactions, it records:
+/// - every transaction added which spends each outpoint, e.g., each of the
+///   alternative templates of a bound contract
+/// - which transactions were seen confirmed, when, and which confirmed
+///   transaction spends each outpoint
+/// - which contract path (see `TxIndex::add_tx_from`) created a transaction
+///
+/// Inputs spending the null txid, those of coinbases and the placeholders
+/// `bind_psbt` uses for inputs it does not know, are not recorded as spends.
+///
+/// Clones share the same database.
+#[derive(Clone)]
+pub struct DiskTxIndex {
+    db: sled::Db,
+    /// txid -> transaction
+    txs: sled::Tree,
+    /// outpoint || spending txid -> ()
+    spends: sled::Tree,
+    /// txid -> height || mtp, big endian
+    confirmed: sled::Tree,
+    /// outpoint -> confirmed spending txid
+    confirmed_spends: sled::Tree,
+    /// txid -> (), for transactions only added as confirmed
+    chain_only: sled::Tree,
+    /// txid -> path which created it
+    origins: sled::Tree,
+    /// path || PATH_SEP || txid -> ()
+    by_path: sled::Tree,
+}
+
+/// how a transaction is being added
+#[derive(Clone, Copy)]
+enum Added<'a> {
+    /// by a contract, perhaps at a path
+    Planned(Option<&'a EffectPath>),
+    /// as seen confirmed at a height and mtp
+    Confirmed(u32, u32),
+}
+
+/// the outpoints `tx` spends, other than the null txid
+fn spent_by_tx(tx: &bitcoin::Transaction) -> impl Iterator<Item = Vec<u8>> + '_ {
+    tx.input
+        .iter()
+        .filter(|i| i.previous_output.txid != Txid::default())
+        .map(|i| serialize(&i.previous_output))
+}
+
+impl DiskTxIndex {
+    /// open (or create) an index at the given directory
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_db(sled::open(path).map_err(db_err)?)
+    }
+
+    /// create an index which is deleted when dropped, e.g. for tests
+    pub fn temporary() -> Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open().map_err(db_err)?)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self> {
+        Ok(DiskTxIndex {
+            txs: db.open_tree("txs").map_err(db_err)?,
+            spends: db.open_tree("planned_spends").map_err(db_err)?,
+            confirmed: db.open_tree("confirmed").map_err(db_err)?,
+            confirmed_spends: db.open_tree("confirmed_spends").map_err(db_err)?,
+            chain_only: db.open_tree("chain_only").map_err(db_err)?,
+            origins: db.open_tree("origins").map_err(db_err)?,
+            by_path: db.open_tree("by_path").map_err(db_err)?,
+            db,
+        })
+    }
+
+    /// write all pending changes to disk
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush().map(|_| ()).map_err(db_err)
+    }
+
+    /// every transaction added which spends `out`, confirmed or not
+    pub fn spenders(&self, out: &OutPoint) -> Result<Vec<Txid>> {
+        let prefix = serialize(out);
+        self.spends
+            .scan_prefix(&prefix)
+            .map(|entry| {
+                let (k, _) = entry.map_err(db_err)?;
+                Txid::from_slice(&k[prefix.len()..]).map_err(db_err)
+            })
+            .collect()
+    }
+
+    /// the confirmed transaction which spends `out`, if one is known
+    pub fn spent_by(&self, out: &OutPoint) -> Result<Option<Txid>> {
+        self.confirmed_spends
+            .get(serialize(out))
+            .map_err(db_err)?
+            .map(|v| Txid::from_slice(&v).map_err(db_err))
+            .transpose()
+    }
+
+    /// whether a confirmed transaction is known to spend `out`
+    pub fn is_spent(&self, out: &OutPoint) -> Result<bool> {
+        self.confirmed_spends
+            .contains_key(serialize(out))
+            .map_err(db_err)
+    }
+
+    /// the contract path recorded as creating `txid`, if any
+    pub fn created_by(&self, txid: &Txid) -> Result<Option<EffectPath>> {
+        self.origins
+            .get(txid)
+            .map_err(db_err)?
+            .map(|v| {
+                let s = String::from_utf8(v.to_vec()).map_err(db_err)?;
+                EffectPath::try_from(s)
+                    .map_err(|e| TxIndexError::DatabaseError(format!("{:?}", e).into()))
+            })
+            .transpose()
+    }
+
+    /// all transactions created by `root` or any path beneath it
+    pub fn txs_under(&self, root: &EffectPath) -> Result<Vec<Txid>> {
+        let prefix = String::from(root.clone());
+        let mut txids = vec![];
+        for entry in self.by_path.scan_prefix(prefix.as_bytes()) {
+            let (k, _) = entry.map_err(db_err)?;
+            let rest = &k[prefix.len()..];
+            // skip sibling paths that merely share a prefix, e.g., a/bc for a/b
+            match rest.first() {
+                Some(&PATH_SEP) => txids.push(Txid::from_slice(&rest[1..]).map_err(db_err)?),
+                Some(b'/') => {
+                    let sep = rest.iter().position(|b| *b == PATH_SEP).ok_or_else(|| {
+                        TxIndexError::DatabaseError("Malformed path index key".into())
+                    })?;
+                    txids.push(Txid::from_slice(&rest[sep + 1..]).map_err(db_err)?)
+                }
+                _ => (),
+            }
+        }
+        Ok(txids)
+    }
+
+    /// inserts `tx` and what it spends, as planned or confirmed, and moves it
+    /// to the path it was created by if given, all in one transaction so a
+    /// crash can't leave the trees disagreeing
+    fn insert(&self, tx: &bitcoin::Transaction, added: Added) -> Result<Txid> {
+        let txid = tx.txid();
+        let raw = serialize(tx);
+        let path = match added {
+            Added::Planned(p) => p.map(|p| String::from(p.clone())),
+            Added::Confirmed(..) => None,
+        };
+        (
+            &self.txs,
+            &self.spends,
+            &self.confirmed,
+            &self.confirmed_spends,
+            &self.chain_only,
+            &self.origins,
+            &self.by_path,
+        )
+            .transaction(
+                |(txs, spends, confirmed, confirmed_spends, chain_only, origins, by_path)| {
+                    let known = txs.insert(&txid[..], raw.clone())?.is_some();
+                    match added {
+                        Added::Planned(_) => {
+                            chain_only.remove(&txid[..])?;
+                            for out in spent_by_tx(tx) {
+                                spends.insert([&out[..], &txid[..]].concat(), &[])?;
+                            }
+                        }
+                        Added::Confirmed(height, mtp) => {
+                            if !known {
+                                chain_only.insert(&txid[..], &[])?;
+                            }
+                            let conf = [height.to_be_bytes(), mtp.to_be_bytes()].concat();
+                            confirmed.insert(&txid[..], conf)?;
+                            for out in spent_by_tx(tx) {
+                                confirmed_spends.insert(out, &txid[..])?;
+                            }
+                        }
+                    }
+                    if let Some(path) = &path {
+                        // a tx re-added under another path is only listed there
+                        if let Some(old) = origins.insert(&txid[..], path.as_bytes())? {
+                            by_path.remove(by_path_key(&old[..], &txid))?;
+                        }
+                        by_path.insert(by_path_key(path.as_bytes(), &txid), &[])?;
+                    }
+                    Ok::<_, ConflictableTransactionError<()>>(())
+                },
+            )
+            .map_err(|e| TxIndexError::DatabaseError(format!("{:?}", e).into()))?;
+        Ok(txid)
+    }
+}
+
+fn by_path_key(path: &[u8], txid: &Txid) -> Vec<u8> {
+    let mut key = path.to_vec();
+    key.push(PATH_SEP);
+    key.extend_from_slice(&txid[..]);
+    key
+}
+
+impl TxIndex for DiskTxIndex {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
+        let v = self
+            .txs
+            .get(b)
+            .map_err(db_err)?
+            .ok_or_else(|| TxIndexError::UnknownTxid(*b))?;
+        deserialize(&v).map(Arc::new).map_err(db_err)
+    }
+    fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid> {
+        self.insert(&tx, Added::Planned(None))
+    }
+    fn add_tx_from(&self, tx: Arc<bitcoin::Transaction>, created_by: &EffectPath) -> Result<Txid> {
+        self.insert(&tx, Added::Planned(Some(created_by)))
+    }
+    fn add_confirmed_tx(
+        &self,
+        tx: Arc<bitcoin::Transaction>,
+        height: u32,
+        mtp: u32,
+    ) -> Result<Txid> {
+        self.insert(&tx, Added::Confirmed(height, mtp))
+    }
+    fn remove_confirmed_tx(&self, txid: &Txid) -> Result<()> {
+        let tx = match self.lookup_tx(txid) {
+            Ok(tx) => tx,
+            Err(TxIndexError::UnknownTxid(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        (
+            &self.txs,
+            &self.confirmed,
+            &self.confirmed_spends,
+            &self.chain_only,
+        )
+            .transaction(|(txs, confirmed, confirmed_spends, chain_only)| {
+                confirmed.remove(&txid[..])?;
+                for out in spent_by_tx(&tx) {
+                    if confirmed_spends.get(&out)?.as_deref() == Some(&txid[..]) {
+                        confirmed_spends.remove(out)?;
+                    }
+                }
+                if chain_only.remove(&txid[..])?.is_some() {
+                    txs.remove(&txid[..])?;
+                }
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| TxIndexError::DatabaseError(format!("{:?}", e).into()))
+    }
+    fn confirmation(&self, txid: &Txid) -> Result<Option<(u32, u32)>> {
+        Ok(self.confirmed.get(txid).map_err(db_err)?.and_then(|v| {
+            let height = u32::from_be_bytes(<[u8; 4]>::try_from(&v[..4]).ok()?);
+            let mtp = u32::from_be_bytes(<[u8; 4]>::try_from(&v[4..]).ok()?);
+            Some((height, mtp))
+        }))
+    }
+    fn confirmed_spend(&self, out: &OutPoint) -> Result<Option<Txid>> {
+        self.spent_by(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_disk_index() {
+        let index = DiskTxIndex::temporary().unwrap();
+        let parent = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![Default::default()],
+        };
+        let mut child = parent.clone();
+        child.input[0].previous_output = OutPoint::new(parent.txid(), 0);
+        let a = EffectPath::try_from("a").unwrap();
+        let a_b = EffectPath::try_from("a/b").unwrap();
+        let ab = EffectPath::try_from("ab").unwrap();
+        let parent_id = index.add_tx_from(Arc::new(parent.clone()), &a).unwrap();
+        let child_id = index.add_tx_from(Arc::new(child), &a_b).unwrap();
+        let other = index
+            .add_tx_from(
+                Arc::new(bitcoin::Transaction {
+                    lock_time: 1,
+                    ..parent.clone()
+                }),
+                &ab,
+            )
+            .unwrap();
+        assert_eq!(index.lookup_tx(&parent_id).unwrap().txid(), parent_id);
+        assert_eq!(
+            index.spenders(&OutPoint::new(parent_id, 0)).unwrap(),
+            vec![child_id]
+        );
+        // planned spends are not confirmed ones
+        assert_eq!(index.spent_by(&OutPoint::new(parent_id, 0)).unwrap(), None);
+        // placeholder outpoints are never recorded
+        assert!(index.spenders(&OutPoint::null()).unwrap().is_empty());
+        assert!(index
+            .spenders(&OutPoint::new(Txid::default(), 0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(index.created_by(&child_id).unwrap(), Some(a_b.clone()));
+        let mut under_a = index.txs_under(&a).unwrap();
+        under_a.sort();
+        let mut expected = vec![parent_id, child_id];
+        expected.sort();
+        assert_eq!(under_a, expected);
+        assert_eq!(index.txs_under(&a_b).unwrap(), vec![child_id]);
+        assert_eq!(index.txs_under(&ab).unwrap(), vec![other]);
+        // moving a tx to another path removes it from the old one
+        index.add_tx_from(Arc::new(parent), &ab).unwrap();
+        assert_eq!(index.txs_under(&a).unwrap(), vec![child_id]);
+        assert_eq!(index.txs_under(&ab).unwrap().len(), 2);
+        assert_eq!(index.created_by(&parent_id).unwrap(), Some(ab));
+    }
+
+    #[test]
+    fn test_confirmed_spends() {
+        let index = DiskTxIndex::temporary().unwrap();
+        let funding = OutPoint::new(Txid::from_inner([1; 32]), 0);
+        let spend = |lock_time| bitcoin::Transaction {
+            version: 2,
+            lock_time,
+            input: vec![bitcoin::TxIn {
+                previous_output: funding,
+                ..Default::default()
+            }],
+            output: vec![Default::default()],
+        };
+        // two alternative templates spending the same coin are both kept
+        let a = index.add_tx(Arc::new(spend(0))).unwrap();
+        let b = index.add_tx(Arc::new(spend(1))).unwrap();
+        let mut spenders = index.spenders(&funding).unwrap();
+        spenders.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(spenders, expected);
+        assert!(!index.is_spent(&funding).unwrap());
+
+        index.add_confirmed_tx(Arc::new(spend(1)), 10, 100).unwrap();
+        assert_eq!(index.spent_by(&funding).unwrap(), Some(b));
+        assert_eq!(index.confirmation(&b).unwrap(), Some((10, 100)));
+        assert_eq!(index.confirmation(&a).unwrap(), None);
+        // unconfirming keeps a planned tx, but drops one only seen on chain
+        index.remove_confirmed_tx(&b).unwrap();
+        assert!(!index.is_spent(&funding).unwrap());
+        assert_eq!(index.confirmation(&b).unwrap(), None);
+        assert!(index.lookup_tx(&b).is_ok());
+        let c = index.add_confirmed_tx(Arc::new(spend(2)), 11, 110).unwrap();
+        assert_eq!(index.spent_by(&funding).unwrap(), Some(c));
+        index.remove_confirmed_tx(&c).unwrap();
+        assert!(index.lookup_tx(&c).is_err());
+        assert_eq!(index.spenders(&funding).unwrap().len(), 2);
+    }
+}
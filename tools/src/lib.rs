@@ -10,6 +10,11 @@ use bitcoincore_rpc_async as rpc;
 use rpc::RpcApi;
 use sapio_base::txindex::{TxIndex, TxIndexError};
 use std::sync::Arc;
+pub mod broadcaster;
+pub mod chain;
+pub mod disk_index;
+pub mod monitor;
+pub mod simulator;
 /// A TxIndex based on a Bitcoin RPC Client
 pub struct BitcoinNodeIndex {
     /// RPC Client
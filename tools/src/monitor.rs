@@ -0,0 +1,885 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watches the chain to follow bound Programs through their lifecycle
+use crate::broadcaster::{readiness, ChainTip, Confirmation, Readiness};
+use crate::chain::{ChainWindow, MTP_WINDOW};
+use bitcoin::consensus::deserialize;
+use bitcoin::hash_types::*;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Block, BlockHeader, OutPoint, Transaction};
+use bitcoincore_rpc_async as rpc;
+use rpc::RpcApi;
+use sapio::contract::abi::studio::{Program, SapioStudioFormat};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Errors which can arise while monitoring
+#[derive(Debug)]
+pub enum MonitorError {
+    /// Error from the TxIndex
+    TxIndex(TxIndexError),
+    /// Error reading blocks
+    Io(std::io::Error),
+    /// Error decoding a block or PSBT
+    Decode(bitcoin::consensus::encode::Error),
+    /// Error decoding a hex block
+    Hex(bitcoin::hashes::hex::Error),
+    /// Error decoding a base64 PSBT
+    Base64(base64::DecodeError),
+    /// Error from the RPC block source
+    Rpc(Box<dyn std::error::Error>),
+    /// No program is being watched under this name
+    UnknownProgram(String),
+    /// The block at this height does not build on the last block ingested
+    DoesNotConnect(u32),
+    /// A reorg went deeper than the blocks remembered
+    ReorgTooDeep,
+    /// The block source cannot go back to read a new branch from this height
+    CannotRewind(u32),
+    /// The Error was for an unknown/unhandled reason
+    Custom(Box<dyn std::error::Error>),
+}
+impl std::error::Error for MonitorError {}
+impl std::fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<TxIndexError> for MonitorError {
+    fn from(e: TxIndexError) -> Self {
+        MonitorError::TxIndex(e)
+    }
+}
+impl From<std::io::Error> for MonitorError {
+    fn from(e: std::io::Error) -> Self {
+        MonitorError::Io(e)
+    }
+}
+impl From<bitcoin::consensus::encode::Error> for MonitorError {
+    fn from(e: bitcoin::consensus::encode::Error) -> Self {
+        MonitorError::Decode(e)
+    }
+}
+impl From<bitcoin::hashes::hex::Error> for MonitorError {
+    fn from(e: bitcoin::hashes::hex::Error) -> Self {
+        MonitorError::Hex(e)
+    }
+}
+impl From<base64::DecodeError> for MonitorError {
+    fn from(e: base64::DecodeError) -> Self {
+        MonitorError::Base64(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, MonitorError>;
+
+/// decode the PSBT held in a `SapioStudioFormat`
+pub(crate) fn decode_psbt(f: &SapioStudioFormat) -> Result<PartiallySignedTransaction> {
+    match f {
+        SapioStudioFormat::LinkedPSBT { psbt, .. } => Ok(deserialize(&base64::decode(psbt)?[..])?),
+    }
+}
+
+/// A source of blocks, in chain order
+pub trait BlockSource {
+    /// get the next block and its height, or `None` if there are no more
+    /// blocks (yet).
+    fn next_block(&mut self) -> Result<Option<(u32, Block)>>;
+
+    /// get the headers (and heights) of up to `count` blocks before the next
+    /// block, oldest first, e.g. to compute the median time past on start.
+    fn prior_headers(&mut self, _count: usize) -> Result<Vec<(u32, BlockHeader)>> {
+        Ok(vec![])
+    }
+
+    /// read blocks from `height` again, as the block previously read there
+    /// is no longer in the best chain.
+    fn rewind(&mut self, height: u32) -> Result<()> {
+        Err(MonitorError::CannotRewind(height))
+    }
+}
+
+/// Reads hex encoded raw blocks, one per line
+pub struct RawBlockFile<R: BufRead> {
+    lines: std::io::Lines<R>,
+    height: u32,
+}
+
+impl<R: BufRead> RawBlockFile<R> {
+    /// create a block source from a reader, where the first block is at
+    /// `start_height`.
+    pub fn new(reader: R, start_height: u32) -> Self {
+        RawBlockFile {
+            lines: reader.lines(),
+            height: start_height,
+        }
+    }
+}
+
+impl<R: BufRead> BlockSource for RawBlockFile<R> {
+    fn next_block(&mut self) -> Result<Option<(u32, Block)>> {
+        use bitcoin::hashes::hex::FromHex;
+        loop {
+            match self.lines.next() {
+                None => return Ok(None),
+                Some(line) => {
+                    let line = line?;
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let block: Block = deserialize(&Vec::<u8>::from_hex(line)?)?;
+                    let height = self.height;
+                    self.height += 1;
+                    return Ok(Some((height, block)));
+                }
+            }
+        }
+    }
+}
+
+/// Pulls blocks from a node (e.g., a local regtest) over RPC
+pub struct RpcBlockSource {
+    /// RPC Client
+    pub client: rpc::Client,
+    /// tokio runtime
+    pub runtime: Arc<tokio::runtime::Runtime>,
+    /// the height of the next block to fetch
+    pub next_height: u32,
+}
+
+impl BlockSource for RpcBlockSource {
+    fn next_block(&mut self) -> Result<Option<(u32, Block)>> {
+        let rpc_err = |e: rpc::Error| MonitorError::Rpc(Box::new(e));
+        let height = self.next_height;
+        let client = &self.client;
+        let block = tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let count = client.get_block_count().await.map_err(rpc_err)?;
+                if count < height as u64 {
+                    return Ok(None);
+                }
+                let hash = client
+                    .get_block_hash(height as u64)
+                    .await
+                    .map_err(rpc_err)?;
+                client.get_block(&hash).await.map(Some).map_err(rpc_err)
+            })
+        })?;
+        if block.is_some() {
+            self.next_height += 1;
+        }
+        Ok(block.map(|b| (height, b)))
+    }
+
+    fn prior_headers(&mut self, count: usize) -> Result<Vec<(u32, BlockHeader)>> {
+        let rpc_err = |e: rpc::Error| MonitorError::Rpc(Box::new(e));
+        let client = &self.client;
+        let start = self.next_height.saturating_sub(count as u32);
+        let end = self.next_height;
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let mut headers = vec![];
+                for height in start..end {
+                    let hash = client
+                        .get_block_hash(height as u64)
+                        .await
+                        .map_err(rpc_err)?;
+                    headers.push((
+                        height,
+                        client.get_block_header(&hash).await.map_err(rpc_err)?,
+                    ));
+                }
+                Ok(headers)
+            })
+        })
+    }
+
+    fn rewind(&mut self, height: u32) -> Result<()> {
+        self.next_height = height;
+        Ok(())
+    }
+}
+
+/// Where an object's output is in its lifecycle
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectState {
+    /// The output has not been seen in a block
+    Unconfirmed,
+    /// The output was confirmed, and is not spent
+    Unspent {
+        /// height of the block creating the output
+        height: u32,
+        /// median time past of the block before the one creating the output
+        mtp: u32,
+    },
+    /// The output has been spent
+    Spent {
+        /// the spending transaction
+        txid: Txid,
+        /// height of the block spending the output
+        height: u32,
+        /// whether the spend was one of the object's own transactions
+        known_template: bool,
+    },
+}
+
+/// The lifecycle of a whole Program
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramPhase {
+    /// None of the program's outputs have been confirmed
+    Unfunded,
+    /// Some outputs are confirmed and unspent
+    Active,
+    /// Every confirmed output has been spent
+    Complete,
+}
+
+struct WatchedObject {
+    out: OutPoint,
+    txs: Vec<Transaction>,
+    continue_apis: Vec<SArc<EffectPath>>,
+    state: ObjectState,
+}
+
+/// The state of every object in a Program
+pub struct ProgramState {
+    objects: BTreeMap<SArc<EffectPath>, WatchedObject>,
+}
+
+impl ProgramState {
+    /// the state of every object in the program
+    pub fn objects(&self) -> impl Iterator<Item = (&SArc<EffectPath>, &ObjectState)> {
+        self.objects.iter().map(|(k, v)| (k, &v.state))
+    }
+
+    /// the phase the program as a whole is in
+    pub fn phase(&self) -> ProgramPhase {
+        let mut any_confirmed = false;
+        for o in self.objects.values() {
+            match o.state {
+                ObjectState::Unspent { .. } => return ProgramPhase::Active,
+                ObjectState::Spent { .. } => any_confirmed = true,
+                ObjectState::Unconfirmed => (),
+            }
+        }
+        if any_confirmed {
+            ProgramPhase::Complete
+        } else {
+            ProgramPhase::Unfunded
+        }
+    }
+}
+
+/// Something which changed while ingesting a block
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MonitorEvent {
+    /// An object's output was confirmed
+    Created {
+        /// the program name
+        program: String,
+        /// the object's path
+        path: SArc<EffectPath>,
+        /// the output
+        out: OutPoint,
+        /// block height
+        height: u32,
+    },
+    /// An object's output was spent
+    Spent {
+        /// the program name
+        program: String,
+        /// the object's path
+        path: SArc<EffectPath>,
+        /// the output
+        out: OutPoint,
+        /// the spending transaction
+        txid: Txid,
+        /// whether the spend was one of the object's own transactions
+        known_template: bool,
+        /// block height
+        height: u32,
+    },
+    /// The block which created or spent an object's output was disconnected
+    /// by a reorg, returning the object to its prior state
+    Reverted {
+        /// the program name
+        program: String,
+        /// the object's path
+        path: SArc<EffectPath>,
+        /// the output
+        out: OutPoint,
+        /// height of the disconnected block
+        height: u32,
+    },
+}
+
+/// What may be done next with a Program, given the current tip
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LiveSet {
+    /// continuation points of unspent objects, which may be resumed
+    pub continuations: Vec<SArc<EffectPath>>,
+    /// transactions which may be broadcast now
+    pub ready: Vec<Txid>,
+    /// transactions spending an unspent object which are still timelocked
+    pub timelocked: Vec<Txid>,
+    /// transactions spending an unspent object which also spend an output
+    /// not yet seen confirmed
+    pub waiting: Vec<Txid>,
+}
+
+/// The changes a block made, for undoing it in a reorg
+#[derive(Default)]
+struct Undo {
+    /// objects whose state changed, and their state before the block
+    states: Vec<(String, SArc<EffectPath>, ObjectState)>,
+    /// transactions confirmed in the block
+    confirmed: Vec<Txid>,
+    /// transactions added to the index as confirmed in the block
+    indexed: Vec<Txid>,
+}
+
+/// Tracks bound Programs against blocks, recording the transactions which
+/// create or spend their outputs, or are spent by them, in a TxIndex
+///
+/// Timelocks are evaluated with [`readiness`], against the median time past
+/// of the blocks ingested (BIP-113). Reorgs up to
+/// [`crate::chain::MAX_REORG_DEPTH`] blocks deep are followed by
+/// [`Monitor::run`].
+pub struct Monitor {
+    index: Rc<dyn TxIndex>,
+    programs: BTreeMap<String, ProgramState>,
+    chain: ChainWindow,
+    /// transactions spent by the programs' transactions
+    parents: BTreeSet<Txid>,
+    /// when each of `parents` confirmed
+    confirmed: BTreeMap<Txid, Confirmation>,
+    /// the changes made by each block still in `chain`
+    undo: BTreeMap<u32, Undo>,
+}
+
+impl Monitor {
+    /// create a new monitor writing to `index`
+    pub fn new(index: Rc<dyn TxIndex>) -> Self {
+        Monitor {
+            index,
+            programs: BTreeMap::new(),
+            chain: ChainWindow::new(),
+            parents: BTreeSet::new(),
+            confirmed: BTreeMap::new(),
+            undo: BTreeMap::new(),
+        }
+    }
+
+    /// start watching a Program under the given name.
+    ///
+    /// Objects start in the state the index has seen confirmed, e.g. by an
+    /// earlier run, and outputs confirmed elsewhere may be given with
+    /// [`Monitor::set_confirmed`].
+    pub fn watch(&mut self, name: String, program: &Program) -> Result<()> {
+        let mut objects = BTreeMap::new();
+        for (path, obj) in program.program.iter() {
+            let txs = obj
+                .txs
+                .iter()
+                .map(|f| decode_psbt(f).map(PartiallySignedTransaction::extract_tx))
+                .collect::<Result<Vec<_>>>()?;
+            self.parents.extend(
+                txs.iter()
+                    .flat_map(|tx| tx.input.iter().map(|i| i.previous_output.txid)),
+            );
+            let state = self.indexed_state(&obj.out, &txs)?;
+            objects.insert(
+                path.clone(),
+                WatchedObject {
+                    out: obj.out,
+                    txs,
+                    continue_apis: obj.continue_apis.keys().cloned().collect(),
+                    state,
+                },
+            );
+        }
+        for txid in &self.parents {
+            if let Some((height, mtp)) = self.index.confirmation(txid)? {
+                self.confirmed.insert(*txid, Confirmation { height, mtp });
+            }
+        }
+        self.programs.insert(name, ProgramState { objects });
+        Ok(())
+    }
+
+    /// the state of `out` as recorded in the index
+    fn indexed_state(&self, out: &OutPoint, txs: &[Transaction]) -> Result<ObjectState> {
+        if let Some(txid) = self.index.confirmed_spend(out)? {
+            if let Some((height, _)) = self.index.confirmation(&txid)? {
+                return Ok(ObjectState::Spent {
+                    txid,
+                    height,
+                    known_template: txs.iter().any(|t| t.txid() == txid),
+                });
+            }
+        }
+        Ok(match self.index.confirmation(&out.txid)? {
+            Some((height, mtp)) => ObjectState::Unspent { height, mtp },
+            None => ObjectState::Unconfirmed,
+        })
+    }
+
+    /// the transactions whose confirmation would change what is live: those
+    /// creating unconfirmed objects, the templates of unspent ones, and
+    /// parents not yet seen confirmed
+    pub fn unconfirmed_txids(&self) -> BTreeSet<Txid> {
+        let mut txids: BTreeSet<Txid> = self
+            .parents
+            .iter()
+            .filter(|t| !self.confirmed.contains_key(*t))
+            .cloned()
+            .collect();
+        for obj in self.programs.values().flat_map(|p| p.objects.values()) {
+            match obj.state {
+                ObjectState::Unconfirmed => {
+                    txids.insert(obj.out.txid);
+                }
+                ObjectState::Unspent { .. } => txids.extend(obj.txs.iter().map(|t| t.txid())),
+                ObjectState::Spent { .. } => (),
+            }
+        }
+        txids
+    }
+
+    /// record that `txid` confirmed before the blocks to be ingested, e.g., as
+    /// reported by a node.
+    pub fn set_confirmed(&mut self, txid: Txid, conf: Confirmation) {
+        if self.parents.contains(&txid) {
+            self.confirmed.insert(txid, conf);
+        }
+        for obj in self
+            .programs
+            .values_mut()
+            .flat_map(|p| p.objects.values_mut())
+        {
+            if obj.out.txid == txid && obj.state == ObjectState::Unconfirmed {
+                obj.state = ObjectState::Unspent {
+                    height: conf.height,
+                    mtp: conf.mtp,
+                };
+            }
+            if obj.txs.iter().any(|t| t.txid() == txid) {
+                obj.state = ObjectState::Spent {
+                    txid,
+                    height: conf.height,
+                    known_template: true,
+                };
+            }
+        }
+    }
+
+    /// get the state of a watched program
+    pub fn state(&self, name: &str) -> Option<&ProgramState> {
+        self.programs.get(name)
+    }
+
+    /// the height and median time past of the last block ingested
+    pub fn tip(&self) -> Option<ChainTip> {
+        self.chain.tip().map(|(height, _)| ChainTip {
+            height,
+            mtp: self.chain.mtp(),
+        })
+    }
+
+    /// remember the headers of blocks before the first to be ingested, so
+    /// that the median time past is correct from the start.
+    pub fn seed(&mut self, headers: &[(u32, BlockHeader)]) {
+        for (height, header) in headers {
+            self.chain.push(*height, header);
+        }
+    }
+
+    /// ingest all available blocks from `source`, following it back to the
+    /// fork point when a block does not build on the last one ingested.
+    pub fn run(&mut self, source: &mut dyn BlockSource) -> Result<Vec<MonitorEvent>> {
+        if self.chain.is_empty() {
+            let headers = source.prior_headers(MTP_WINDOW)?;
+            self.seed(&headers);
+        }
+        let mut events = vec![];
+        while let Some((height, block)) = source.next_block()? {
+            if self.chain.connects(height, &block.header) {
+                events.extend(self.ingest_block(height, &block)?);
+            } else {
+                // the block before this one was reorged out
+                let (stale, _) = self.chain.tip().ok_or(MonitorError::ReorgTooDeep)?;
+                events.extend(self.disconnect()?);
+                source.rewind(stale)?;
+            }
+        }
+        Ok(events)
+    }
+
+    /// undo the last block ingested, e.g. as it was reorged out
+    pub fn disconnect(&mut self) -> Result<Vec<MonitorEvent>> {
+        let height = self.chain.pop().ok_or(MonitorError::ReorgTooDeep)?;
+        let undo = self.undo.remove(&height).unwrap_or_default();
+        for txid in undo.confirmed {
+            self.confirmed.remove(&txid);
+        }
+        for txid in undo.indexed {
+            self.index.remove_confirmed_tx(&txid)?;
+        }
+        let mut events = vec![];
+        for (name, path, state) in undo.states.into_iter().rev() {
+            if let Some(obj) = self
+                .programs
+                .get_mut(&name)
+                .and_then(|p| p.objects.get_mut(&path))
+            {
+                obj.state = state;
+                events.push(MonitorEvent::Reverted {
+                    program: name,
+                    path,
+                    out: obj.out,
+                    height,
+                });
+            }
+        }
+        Ok(events)
+    }
+
+    /// record the block's transactions which touch a watched program and
+    /// advance the state of every program.
+    ///
+    /// Any blocks ingested at or above `height` are disconnected first, and
+    /// the block must build on the one before it.
+    pub fn ingest_block(&mut self, height: u32, block: &Block) -> Result<Vec<MonitorEvent>> {
+        let mut events = vec![];
+        while matches!(self.chain.tip(), Some((h, _)) if h >= height) {
+            events.extend(self.disconnect()?);
+        }
+        if !self.chain.connects(height, &block.header) {
+            return Err(MonitorError::DoesNotConnect(height));
+        }
+        let conf = Confirmation {
+            height,
+            mtp: self.chain.mtp(),
+        };
+        let mut undo = Undo::default();
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            let mut relevant = false;
+            if self.parents.contains(&txid) {
+                relevant = true;
+                self.confirmed.insert(txid, conf);
+                undo.confirmed.push(txid);
+            }
+            for (name, program) in self.programs.iter_mut() {
+                for (path, obj) in program.objects.iter_mut() {
+                    if obj.out.txid == txid && (obj.out.vout as usize) < tx.output.len() {
+                        relevant = true;
+                        undo.states.push((name.clone(), path.clone(), obj.state));
+                        obj.state = ObjectState::Unspent {
+                            height,
+                            mtp: conf.mtp,
+                        };
+                        events.push(MonitorEvent::Created {
+                            program: name.clone(),
+                            path: path.clone(),
+                            out: obj.out,
+                            height,
+                        });
+                    }
+                    if tx.input.iter().any(|i| i.previous_output == obj.out) {
+                        relevant = true;
+                        let known_template = obj.txs.iter().any(|t| t.txid() == txid);
+                        undo.states.push((name.clone(), path.clone(), obj.state));
+                        obj.state = ObjectState::Spent {
+                            txid,
+                            height,
+                            known_template,
+                        };
+                        events.push(MonitorEvent::Spent {
+                            program: name.clone(),
+                            path: path.clone(),
+                            out: obj.out,
+                            txid,
+                            known_template,
+                            height,
+                        });
+                    }
+                }
+            }
+            if relevant {
+                self.index
+                    .add_confirmed_tx(Arc::new(tx.clone()), height, conf.mtp)?;
+                undo.indexed.push(txid);
+            }
+        }
+        self.chain.push(height, &block.header);
+        self.undo.insert(height, undo);
+        if let Some(first) = self.chain.first_height() {
+            self.undo = self.undo.split_off(&first);
+        }
+        Ok(events)
+    }
+
+    /// which continuation points and transactions of a program are live as
+    /// of the last block ingested.
+    pub fn live(&self, name: &str) -> Result<LiveSet> {
+        let program = self
+            .programs
+            .get(name)
+            .ok_or_else(|| MonitorError::UnknownProgram(name.into()))?;
+        let mut live = LiveSet::default();
+        let tip = match self.tip() {
+            Some(tip) => tip,
+            None => return Ok(live),
+        };
+        for obj in program.objects.values() {
+            if let ObjectState::Unspent { .. } = obj.state {
+                live.continuations.extend(obj.continue_apis.iter().cloned());
+                for tx in &obj.txs {
+                    match readiness(tx, &self.confirmed, tip) {
+                        Readiness::Ready => live.ready.push(tx.txid()),
+                        Readiness::Locked { .. } => live.timelocked.push(tx.txid()),
+                        Readiness::WaitingForParent(_) => live.waiting.push(tx.txid()),
+                    }
+                }
+            }
+        }
+        Ok(live)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio::contract::abi::studio::{LinkedPSBT, SapioStudioObject};
+    use sapio_base::txindex::TxIndexLogger;
+    use std::convert::TryFrom;
+
+    fn block(prev: &Block, time: u32, txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: bitcoin::BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Default::default(),
+                time,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        }
+    }
+
+    fn genesis() -> Block {
+        Block {
+            header: bitcoin::BlockHeader {
+                version: 1,
+                prev_blockhash: Default::default(),
+                merkle_root: Default::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![],
+        }
+    }
+
+    /// a program with one object, the output of `funding`, spent by `spend`
+    fn program(out: OutPoint, spend: &Transaction) -> Program {
+        let path = SArc(Arc::new(EffectPath::try_from("root").unwrap()));
+        let mut program = Program {
+            program: BTreeMap::new(),
+        };
+        program.program.insert(
+            path,
+            SapioStudioObject {
+                metadata: Default::default(),
+                out,
+                txs: vec![LinkedPSBT {
+                    psbt: PartiallySignedTransaction::from_unsigned_tx(spend.clone()).unwrap(),
+                    metadata: sapio::template::TemplateMetadata::new(),
+                    output_metadata: vec![],
+                    added_output_metadata: vec![],
+                    cpfp: None,
+                    needs_child: false,
+                }
+                .into()],
+                continue_apis: Default::default(),
+            },
+        );
+        program
+    }
+
+    fn funding() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![Default::default()],
+        }
+    }
+
+    #[test]
+    fn test_monitor_lifecycle() {
+        let funding = funding();
+        let out = OutPoint::new(funding.txid(), 0);
+        let mut spend = funding.clone();
+        spend.input[0].previous_output = out;
+        // relative lock of 2 blocks
+        spend.input[0].sequence = 2;
+        let path = SArc(Arc::new(EffectPath::try_from("root").unwrap()));
+        let mut monitor = Monitor::new(Rc::new(TxIndexLogger::new()));
+        monitor.watch("p".into(), &program(out, &spend)).unwrap();
+        assert_eq!(monitor.state("p").unwrap().phase(), ProgramPhase::Unfunded);
+
+        let b0 = genesis();
+        monitor.ingest_block(0, &b0).unwrap();
+        let b1 = block(&b0, 10, vec![funding]);
+        let events = monitor.ingest_block(1, &b1).unwrap();
+        assert!(matches!(
+            events[..],
+            [MonitorEvent::Created { height: 1, .. }]
+        ));
+        assert_eq!(monitor.state("p").unwrap().phase(), ProgramPhase::Active);
+        assert_eq!(monitor.live("p").unwrap().timelocked, vec![spend.txid()]);
+
+        let b2 = block(&b1, 20, vec![]);
+        monitor.ingest_block(2, &b2).unwrap();
+        assert_eq!(monitor.live("p").unwrap().ready, vec![spend.txid()]);
+
+        let b3 = block(&b2, 30, vec![spend.clone()]);
+        let events = monitor.ingest_block(3, &b3).unwrap();
+        assert_eq!(
+            events,
+            vec![MonitorEvent::Spent {
+                program: "p".into(),
+                path,
+                out,
+                txid: spend.txid(),
+                known_template: true,
+                height: 3,
+            }]
+        );
+        assert_eq!(monitor.state("p").unwrap().phase(), ProgramPhase::Complete);
+        assert_eq!(monitor.live("p").unwrap(), LiveSet::default());
+        // a block which does not build on the tip is refused
+        assert!(matches!(
+            monitor.ingest_block(4, &block(&b2, 40, vec![])),
+            Err(MonitorError::DoesNotConnect(4))
+        ));
+    }
+
+    #[test]
+    fn test_monitor_mtp_and_reorg() {
+        let funding = funding();
+        let out = OutPoint::new(funding.txid(), 0);
+        let mut spend = funding.clone();
+        spend.input[0].previous_output = out;
+        spend.input[0].sequence = 0;
+        spend.lock_time = 500_000_100;
+        let mut monitor = Monitor::new(Rc::new(TxIndexLogger::new()));
+        monitor.watch("p".into(), &program(out, &spend)).unwrap();
+
+        let mut chain = vec![genesis()];
+        let headers: Vec<_> = (1..11u32)
+            .map(|h| {
+                let b = block(chain.last().unwrap(), 500_000_000 + h * 10, vec![]);
+                chain.push(b.clone());
+                (h, b.header)
+            })
+            .collect();
+        monitor.seed(&headers);
+        let b11 = block(&chain[10], 500_000_110, vec![funding.clone()]);
+        monitor.ingest_block(11, &b11).unwrap();
+        // the tip's time is past the lock time, but its MTP is not
+        assert_eq!(monitor.tip().unwrap().mtp, 500_000_060);
+        assert_eq!(monitor.live("p").unwrap().timelocked, vec![spend.txid()]);
+
+        // a competing block 11 without the funding tx, and a block 12
+        let b11b = block(&chain[10], 500_000_111, vec![]);
+        let b12 = block(&b11b, 500_000_120, vec![]);
+        struct Blocks(Vec<(u32, Block)>, usize);
+        impl BlockSource for Blocks {
+            fn next_block(&mut self) -> Result<Option<(u32, Block)>> {
+                self.1 += 1;
+                Ok(self.0.get(self.1 - 1).cloned())
+            }
+            fn rewind(&mut self, height: u32) -> Result<()> {
+                self.1 = self.0.iter().position(|(h, _)| *h == height).unwrap();
+                Ok(())
+            }
+        }
+        let mut source = Blocks(vec![(11, b11b.clone()), (12, b12)], 1);
+        let events = monitor.run(&mut source).unwrap();
+        assert!(matches!(
+            events[..],
+            [MonitorEvent::Reverted { height: 11, .. }]
+        ));
+        assert_eq!(monitor.state("p").unwrap().phase(), ProgramPhase::Unfunded);
+        assert_eq!(monitor.tip().unwrap().height, 12);
+        assert_eq!(monitor.chain.hash_at(11), Some(b11b.block_hash()));
+    }
+
+    #[test]
+    fn test_monitor_indexes_and_seeds() {
+        let funding = funding();
+        let out = OutPoint::new(funding.txid(), 0);
+        let mut spend = funding.clone();
+        spend.input[0].previous_output = out;
+        let unrelated = Transaction {
+            lock_time: 7,
+            ..funding.clone()
+        };
+        let index = crate::disk_index::DiskTxIndex::temporary().unwrap();
+        let mut monitor = Monitor::new(Rc::new(index.clone()));
+        monitor.watch("p".into(), &program(out, &spend)).unwrap();
+        let b0 = genesis();
+        monitor.ingest_block(0, &b0).unwrap();
+        let b1 = block(&b0, 10, vec![funding.clone(), unrelated.clone()]);
+        monitor.ingest_block(1, &b1).unwrap();
+        // only the transaction touching the program is indexed
+        assert_eq!(index.confirmation(&funding.txid()).unwrap(), Some((1, 0)));
+        assert!(index.lookup_tx(&unrelated.txid()).is_err());
+
+        // a later monitor picks up where the index left off
+        let mut later = Monitor::new(Rc::new(index.clone()));
+        later.watch("p".into(), &program(out, &spend)).unwrap();
+        assert_eq!(later.state("p").unwrap().phase(), ProgramPhase::Active);
+        assert_eq!(
+            later.unconfirmed_txids(),
+            vec![spend.txid()].into_iter().collect()
+        );
+
+        let b2 = block(&b1, 20, vec![spend.clone()]);
+        monitor.ingest_block(2, &b2).unwrap();
+        assert_eq!(index.spent_by(&out).unwrap(), Some(spend.txid()));
+        let mut later = Monitor::new(Rc::new(index.clone()));
+        later.watch("p".into(), &program(out, &spend)).unwrap();
+        assert_eq!(later.state("p").unwrap().phase(), ProgramPhase::Complete);
+
+        // disconnecting blocks removes what they confirmed from the index
+        monitor.disconnect().unwrap();
+        monitor.disconnect().unwrap();
+        assert_eq!(index.spent_by(&out).unwrap(), None);
+        assert_eq!(index.confirmation(&funding.txid()).unwrap(), None);
+        let mut later = Monitor::new(Rc::new(index));
+        later.watch("p".into(), &program(out, &spend)).unwrap();
+        assert_eq!(later.state("p").unwrap().phase(), ProgramPhase::Unfunded);
+
+        // confirmations reported from elsewhere
+        later.set_confirmed(funding.txid(), Confirmation { height: 1, mtp: 0 });
+        assert_eq!(later.state("p").unwrap().phase(), ProgramPhase::Active);
+        later.set_confirmed(spend.txid(), Confirmation { height: 2, mtp: 0 });
+        assert_eq!(later.state("p").unwrap().phase(), ProgramPhase::Complete);
+    }
+}
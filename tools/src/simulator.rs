@@ -0,0 +1,864 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Deterministic simulation of a compiled contract over a mock chain
+use crate::broadcaster::{readiness, ChainTip, Confirmation, Readiness};
+use crate::monitor::{decode_psbt, MonitorError};
+use bitcoin::hash_types::*;
+use bitcoin::secp256k1::{schnorr, Secp256k1};
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
+use bitcoin::{
+    OutPoint, SchnorrSig, SchnorrSighashType, Script, Transaction, TxIn, TxOut, XOnlyPublicKey,
+};
+use miniscript::descriptor::Tr;
+use miniscript::miniscript::satisfy::{After, Older};
+use miniscript::psbt::PsbtExt;
+use miniscript::{Descriptor, Miniscript, Tap, Terminal};
+use sapio::contract::abi::studio::Program;
+use sapio::contract::object::{ObjectError, SupportedDescriptors};
+use sapio::contract::Compiled;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::txindex::{TxIndex, TxIndexLogger};
+use sapio_ctv_emulator_trait::CTVAvailable;
+use sapio_psbt::{PSBTSigningError, SigningKey};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Errors (and failed checks) from simulating a contract
+#[derive(Debug)]
+pub enum SimulationError {
+    /// Error binding the contract
+    Object(ObjectError),
+    /// Error decoding the bound Program
+    Monitor(MonitorError),
+    /// Error signing a transaction
+    Signing(PSBTSigningError),
+    /// No object exists at this path
+    UnknownPath(SArc<EffectPath>),
+    /// The object has no transaction with this txid
+    UnknownTemplate(Txid),
+    /// The object's output has not been created on the mock chain
+    NotCreated(SArc<EffectPath>),
+    /// The object's output has already been spent
+    AlreadySpent(SArc<EffectPath>),
+    /// The transaction spends an output which is not on the mock chain
+    UnknownInput(OutPoint),
+    /// The transaction is not yet valid at the current tip
+    Timelocked(Readiness),
+    /// A transaction spends more than its inputs hold
+    AmountNotConserved {
+        /// the total of the inputs
+        input: Amount,
+        /// the total of the outputs
+        output: Amount,
+    },
+    /// The transaction could not be finalized (e.g., missing signatures)
+    Unsatisfiable(Vec<String>),
+    /// The Error was for an unknown/unhandled reason
+    Custom(Box<dyn std::error::Error>),
+}
+impl std::error::Error for SimulationError {}
+impl std::fmt::Display for SimulationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<ObjectError> for SimulationError {
+    fn from(e: ObjectError) -> Self {
+        SimulationError::Object(e)
+    }
+}
+impl From<MonitorError> for SimulationError {
+    fn from(e: MonitorError) -> Self {
+        SimulationError::Monitor(e)
+    }
+}
+impl From<PSBTSigningError> for SimulationError {
+    fn from(e: PSBTSigningError) -> Self {
+        SimulationError::Signing(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, SimulationError>;
+
+/// Seconds the median time past advances by with every block mined
+pub const BLOCK_INTERVAL: u32 = 600;
+/// The median time past of the mock chain's genesis
+pub const GENESIS_MTP: u32 = 1_600_000_000;
+
+/// A chain holding only the UTXOs of a simulated contract
+#[derive(Clone, Debug)]
+pub struct MockChain {
+    /// the current tip
+    pub tip: ChainTip,
+    utxos: BTreeMap<OutPoint, (TxOut, Confirmation)>,
+    history: Vec<(u32, Txid)>,
+}
+
+impl MockChain {
+    fn new() -> Self {
+        MockChain {
+            tip: ChainTip {
+                height: 0,
+                mtp: GENESIS_MTP,
+            },
+            utxos: BTreeMap::new(),
+            history: vec![],
+        }
+    }
+
+    /// mine `tx` in a new block, without any validation
+    fn mine(&mut self, tx: &Transaction) {
+        let conf = Confirmation {
+            height: self.tip.height + 1,
+            mtp: self.tip.mtp,
+        };
+        self.step_blocks(1);
+        for input in &tx.input {
+            self.utxos.remove(&input.previous_output);
+        }
+        let txid = tx.txid();
+        for (vout, out) in tx.output.iter().enumerate() {
+            self.utxos
+                .insert(OutPoint::new(txid, vout as u32), (out.clone(), conf));
+        }
+        self.history.push((conf.height, txid));
+    }
+
+    /// mine a transaction from outside the contract, e.g., a wallet's, so
+    /// that its outputs may be spent alongside the contract's
+    pub fn mine_external(&mut self, tx: &Transaction) {
+        self.mine(tx)
+    }
+
+    /// mine `n` empty blocks
+    pub fn step_blocks(&mut self, n: u32) {
+        self.tip.height += n;
+        self.tip.mtp += n * BLOCK_INTERVAL;
+    }
+
+    /// advance the median time past without mining
+    pub fn advance_time(&mut self, secs: u32) {
+        self.tip.mtp += secs;
+    }
+
+    /// the unspent output at `out`, if any
+    pub fn utxo(&self, out: &OutPoint) -> Option<&TxOut> {
+        self.utxos.get(out).map(|(o, _)| o)
+    }
+
+    /// the (height, txid) of every transaction mined so far
+    pub fn history(&self) -> &[(u32, Txid)] {
+        &self.history
+    }
+}
+
+/// Steps a compiled contract through its transactions on a `MockChain`,
+/// checking each transaction fired for amount conservation, timelock
+/// validity, and script satisfiability.
+#[derive(Clone)]
+pub struct Simulator {
+    /// the mock chain
+    pub chain: MockChain,
+    objects: BTreeMap<SArc<EffectPath>, (OutPoint, Vec<PartiallySignedTransaction>)>,
+    /// the Taproot descriptors of the contract's objects, by their scripts
+    descriptors: Rc<BTreeMap<Script, Tr<XOnlyPublicKey>>>,
+    keys: Rc<SigningKey>,
+}
+
+/// collects the Taproot descriptor of `contract` and of every object its
+/// transactions create
+fn collect_descriptors(contract: &Compiled, into: &mut BTreeMap<Script, Tr<XOnlyPublicKey>>) {
+    if let Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) = &contract.descriptor {
+        into.insert(contract.address.clone().into(), tr.clone());
+    }
+    for template in contract
+        .ctv_to_tx
+        .values()
+        .chain(contract.suggested_txs.values())
+    {
+        for output in &template.outputs {
+            collect_descriptors(&output.contract, into);
+        }
+    }
+}
+
+/// whether `ms` spends through a CTV template, rather than being a finish
+/// branch
+fn is_template_leaf(ms: &Miniscript<XOnlyPublicKey, Tap>) -> bool {
+    ms.iter()
+        .any(|node| matches!(node.node, Terminal::TxTemplate(_)))
+}
+
+/// the sequence and lock time with which `ms` can be satisfied by signatures
+/// of `ours` alone, preferring the least locked path through it.
+///
+/// Each combination of the relative and absolute locks in `ms` is tried with
+/// miniscript's satisfier, so that the locks are those of the path it
+/// satisfies: the greatest within a conjunction, and only one branch's of a
+/// disjunction. If none is satisfiable, every lock is used, and the spend is
+/// expected to fail.
+fn finish_locks(ms: &Miniscript<XOnlyPublicKey, Tap>, ours: &[XOnlyPublicKey]) -> (u32, u32) {
+    // nothing checks the signatures, only whether there are any
+    let sig = SchnorrSig {
+        sig: schnorr::Signature::from_slice(&[1; 64]).expect("64 bytes"),
+        hash_ty: SchnorrSighashType::Default,
+    };
+    let leaf_hash = TapLeafHash::from_script(&ms.encode(), LeafVersion::TapScript);
+    let sigs: HashMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig> =
+        ours.iter().map(|pk| ((*pk, leaf_hash), sig)).collect();
+    let mut olders = BTreeSet::new();
+    let mut afters = BTreeSet::new();
+    for node in ms.iter() {
+        match node.node {
+            Terminal::Older(n) => {
+                olders.insert(n);
+            }
+            Terminal::After(n) => {
+                afters.insert(n);
+            }
+            _ => (),
+        }
+    }
+    for older in std::iter::once(0).chain(olders.iter().cloned()) {
+        for after in std::iter::once(0).chain(afters.iter().cloned()) {
+            if ms.satisfy((&sigs, Older(older), After(after))).is_ok() {
+                return (older, after);
+            }
+        }
+    }
+    (
+        olders.iter().cloned().max().unwrap_or(0),
+        afters.iter().cloned().max().unwrap_or(0),
+    )
+}
+
+/// a transaction spending `out` through `ms`, with the sequence and lock
+/// time its satisfying path's relative and absolute locks require (see
+/// [`finish_locks`]), sweeping `value` (less a nominal fee) to an
+/// anyone-can-spend output
+fn finish_sweep(
+    out: OutPoint,
+    value: u64,
+    ms: &Miniscript<XOnlyPublicKey, Tap>,
+    ours: &[XOnlyPublicKey],
+) -> Transaction {
+    let (older, lock_time) = finish_locks(ms, ours);
+    // without a relative lock, disable it but still enable the lock time
+    let sequence = if older == 0 { 0xffff_fffe } else { older };
+    Transaction {
+        version: 2,
+        lock_time,
+        input: vec![TxIn {
+            previous_output: out,
+            sequence,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: value.saturating_sub(1_000),
+            script_pubkey: Script::new(),
+        }],
+    }
+}
+
+impl Simulator {
+    /// fund `contract` with `amount` in the first block of a new mock chain.
+    /// `keys` are used to sign transactions needing signatures.
+    pub fn new(contract: &Compiled, amount: Amount, keys: SigningKey) -> Result<Self> {
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![Default::default()],
+            output: vec![TxOut {
+                value: amount.as_sat(),
+                script_pubkey: contract.address.clone().into(),
+            }],
+        };
+        let index = Rc::new(TxIndexLogger::new());
+        index
+            .add_tx(Arc::new(funding.clone()))
+            .map_err(|e| SimulationError::Custom(Box::new(e)))?;
+        let program: Program = contract.bind_psbt(
+            OutPoint::new(funding.txid(), 0),
+            BTreeMap::new(),
+            index,
+            &CTVAvailable,
+        )?;
+        let mut objects = BTreeMap::new();
+        for (path, obj) in program.program.iter() {
+            let psbts = obj
+                .txs
+                .iter()
+                .map(decode_psbt)
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            objects.insert(path.clone(), (obj.out, psbts));
+        }
+        let mut descriptors = BTreeMap::new();
+        collect_descriptors(contract, &mut descriptors);
+        let mut chain = MockChain::new();
+        chain.mine(&funding);
+        Ok(Simulator {
+            chain,
+            objects,
+            descriptors: Rc::new(descriptors),
+            keys: Rc::new(keys),
+        })
+    }
+
+    /// paths of objects whose outputs are currently unspent
+    pub fn live_objects(&self) -> Vec<SArc<EffectPath>> {
+        self.objects
+            .iter()
+            .filter(|(_, (out, _))| self.chain.utxo(out).is_some())
+            .map(|(p, _)| p.clone())
+            .collect()
+    }
+
+    /// the txids of the transactions which may spend the object at `path`
+    pub fn options(&self, path: &SArc<EffectPath>) -> Result<Vec<Txid>> {
+        Ok(self
+            .object(path)?
+            .1
+            .iter()
+            .map(|p| p.unsigned_tx.txid())
+            .collect())
+    }
+
+    /// when the transaction `txid` spending `path` becomes valid
+    pub fn readiness(&self, path: &SArc<EffectPath>, txid: &Txid) -> Result<Readiness> {
+        let psbt = self.template(path, txid)?;
+        Ok(readiness(
+            &psbt.unsigned_tx,
+            &self.confirmations(&psbt.unsigned_tx)?,
+            self.chain.tip,
+        ))
+    }
+
+    /// check and mine the transaction `txid` spending the object at `path`,
+    /// returning the paths of the objects it creates.
+    pub fn fire(&mut self, path: &SArc<EffectPath>, txid: &Txid) -> Result<Vec<SArc<EffectPath>>> {
+        self.check_spendable(path)?;
+        let mut psbt = self.template(path, txid)?.clone();
+        let tx = psbt.unsigned_tx.clone();
+        self.check_ready(&tx)?;
+        self.check_amounts(&tx)?;
+        let (out, _) = self.object(path)?;
+        if let Some(idx) = tx.input.iter().position(|i| i.previous_output == *out) {
+            self.fill_inputs(&mut psbt, idx, None);
+        }
+        let secp = Secp256k1::new();
+        self.keys
+            .sign_psbt_mut(&mut psbt, &secp, SchnorrSighashType::Default)?;
+        if let Err(errors) = psbt.finalize_mut(&secp) {
+            return Err(SimulationError::Unsatisfiable(
+                errors.iter().map(|e| format!("{:?}", e)).collect(),
+            ));
+        }
+        self.chain.mine(&tx);
+        let created = self
+            .objects
+            .iter()
+            .filter(|(_, (out, _))| out.txid == tx.txid())
+            .map(|(p, _)| p.clone())
+            .collect();
+        Ok(created)
+    }
+
+    /// spend the object at `path` with a transaction which is not one of its
+    /// templates, e.g., through a finish branch.
+    ///
+    /// The transaction's locks are checked against the mock chain, and the
+    /// object's input must be satisfiable by one of its leaves, with the
+    /// signatures and preimages in `psbt` and any signatures the simulator's
+    /// keys can add.
+    pub fn fire_finish(
+        &mut self,
+        path: &SArc<EffectPath>,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<Vec<SArc<EffectPath>>> {
+        self.fire_leaf(path, psbt, None)
+    }
+
+    /// as `fire_finish`, but only the leaf `leaf` may satisfy the object's
+    /// input
+    fn fire_leaf(
+        &mut self,
+        path: &SArc<EffectPath>,
+        mut psbt: PartiallySignedTransaction,
+        leaf: Option<&Script>,
+    ) -> Result<Vec<SArc<EffectPath>>> {
+        let (out, _) = self.object(path)?;
+        let idx = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .position(|i| i.previous_output == *out)
+            .ok_or_else(|| {
+                SimulationError::Custom("Transaction does not spend the object".into())
+            })?;
+        self.check_spendable(path)?;
+        let tx = psbt.unsigned_tx.clone();
+        self.check_ready(&tx)?;
+        self.check_amounts(&tx)?;
+        self.fill_inputs(&mut psbt, idx, leaf);
+        let secp = Secp256k1::new();
+        self.keys
+            .sign_psbt_mut(&mut psbt, &secp, SchnorrSighashType::Default)?;
+        if let Err(errors) = psbt.finalize_inp_mut(&secp, idx) {
+            return Err(SimulationError::Unsatisfiable(vec![format!(
+                "{:?}",
+                errors
+            )]));
+        }
+        self.chain.mine(&tx);
+        Ok(vec![])
+    }
+
+    /// add the previous outputs of `psbt`'s inputs, and the Taproot script
+    /// information for the input at `idx`, restricted to `leaf` if given,
+    /// marking the simulator's keys as able to sign for each leaf.
+    fn fill_inputs(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        idx: usize,
+        leaf: Option<&Script>,
+    ) {
+        for (input, txin) in psbt.inputs.iter_mut().zip(psbt.unsigned_tx.input.iter()) {
+            if input.witness_utxo.is_none() {
+                input.witness_utxo = self.chain.utxo(&txin.previous_output).cloned();
+            }
+        }
+        let tr = match psbt.inputs[idx]
+            .witness_utxo
+            .as_ref()
+            .and_then(|o| self.descriptors.get(&o.script_pubkey))
+        {
+            Some(tr) => tr,
+            None => return,
+        };
+        let secp = Secp256k1::new();
+        let ours: BTreeMap<XOnlyPublicKey, _> = self
+            .keys
+            .0
+            .iter()
+            .map(|k| {
+                (
+                    k.to_keypair(&secp).x_only_public_key().0,
+                    (k.fingerprint(&secp), Default::default()),
+                )
+            })
+            .collect();
+        let info = tr.spend_info();
+        let input = &mut psbt.inputs[idx];
+        for (_, ms) in tr.iter_scripts() {
+            let script = ms.encode();
+            if leaf.map_or(false, |l| *l != script) {
+                continue;
+            }
+            let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+            for pk in ms.iter_pk() {
+                if let Some(origin) = ours.get(&pk) {
+                    input
+                        .tap_key_origins
+                        .entry(pk)
+                        .or_insert_with(|| (vec![], origin.clone()))
+                        .0
+                        .push(leaf_hash);
+                }
+            }
+            let ver_script = (script, LeafVersion::TapScript);
+            if let Some(cb) = info.control_block(&ver_script) {
+                input.tap_scripts.insert(cb, ver_script);
+            }
+        }
+        if leaf.is_none() {
+            input.tap_internal_key = Some(*tr.internal_key());
+            input.tap_merkle_root = info.merkle_root();
+        }
+    }
+
+    /// fire every possible sequence of transactions from the current state,
+    /// mining empty blocks and advancing time as needed for timelocks.
+    ///
+    /// Besides an object's templates, each of its finish branches (leaves
+    /// without a CTV template) is tried with a transaction sweeping the
+    /// object, signed by the simulator's keys.
+    ///
+    /// Returns each branch taken (as the txids fired) along with the outcome.
+    /// A branch ends when a transaction fails a check, a finish branch is
+    /// taken, or no objects remain.
+    pub fn explore(&self) -> Vec<(Vec<Txid>, Result<()>)> {
+        let mut results = vec![];
+        self.explore_from(vec![], &mut results);
+        results
+    }
+
+    /// the finish leaves of the object at `path`, each with a transaction
+    /// sweeping it through that leaf
+    fn finish_options(&self, path: &SArc<EffectPath>) -> Vec<(Script, Transaction)> {
+        let out = match self.object(path) {
+            Ok((out, _)) => *out,
+            Err(_) => return vec![],
+        };
+        let utxo = match self.chain.utxo(&out) {
+            Some(utxo) => utxo,
+            None => return vec![],
+        };
+        let tr = match self.descriptors.get(&utxo.script_pubkey) {
+            Some(tr) => tr,
+            None => return vec![],
+        };
+        let secp = Secp256k1::new();
+        let ours: Vec<XOnlyPublicKey> = self
+            .keys
+            .0
+            .iter()
+            .map(|k| k.to_keypair(&secp).x_only_public_key().0)
+            .collect();
+        tr.iter_scripts()
+            .filter(|(_, ms)| !is_template_leaf(ms))
+            .map(|(_, ms)| (ms.encode(), finish_sweep(out, utxo.value, ms, &ours)))
+            .collect()
+    }
+
+    /// mine empty blocks and advance time until `tx` is no longer timelocked
+    fn wait_for(&mut self, tx: &Transaction) {
+        if let Ok(Readiness::Locked { height, mtp }) = self
+            .confirmations(tx)
+            .map(|c| readiness(tx, &c, self.chain.tip))
+        {
+            let blocks = height.saturating_sub(self.chain.tip.height);
+            self.chain.step_blocks(blocks);
+            self.chain
+                .advance_time(mtp.saturating_sub(self.chain.tip.mtp));
+        }
+    }
+
+    fn explore_from(&self, branch: Vec<Txid>, results: &mut Vec<(Vec<Txid>, Result<()>)>) {
+        // only objects with transactions or finish branches can be stepped
+        let next = self.live_objects().into_iter().find(|p| {
+            self.object(p)
+                .map(|(_, txs)| !txs.is_empty())
+                .unwrap_or(false)
+                || !self.finish_options(p).is_empty()
+        });
+        let path = match next {
+            Some(path) => path,
+            None => {
+                results.push((branch, Ok(())));
+                return;
+            }
+        };
+        for txid in self.options(&path).unwrap_or_default() {
+            let mut sim = self.clone();
+            let mut branch = branch.clone();
+            branch.push(txid);
+            if let Ok(psbt) = sim.template(&path, &txid) {
+                let tx = psbt.unsigned_tx.clone();
+                sim.wait_for(&tx);
+            }
+            match sim.fire(&path, &txid) {
+                Ok(_) => sim.explore_from(branch, results),
+                Err(e) => results.push((branch, Err(e))),
+            }
+        }
+        for (leaf, tx) in self.finish_options(&path) {
+            let mut sim = self.clone();
+            let mut branch = branch.clone();
+            branch.push(tx.txid());
+            sim.wait_for(&tx);
+            let result = PartiallySignedTransaction::from_unsigned_tx(tx)
+                .map_err(|e| SimulationError::Custom(Box::new(e)))
+                .and_then(|psbt| sim.fire_leaf(&path, psbt, Some(&leaf)));
+            results.push((branch, result.map(|_| ())));
+        }
+    }
+
+    fn check_spendable(&self, path: &SArc<EffectPath>) -> Result<()> {
+        let (out, _) = self.object(path)?;
+        if self.chain.utxo(out).is_some() {
+            Ok(())
+        } else if self.chain.history.iter().any(|(_, t)| *t == out.txid) {
+            Err(SimulationError::AlreadySpent(path.clone()))
+        } else {
+            Err(SimulationError::NotCreated(path.clone()))
+        }
+    }
+
+    fn check_ready(&self, tx: &Transaction) -> Result<()> {
+        match readiness(tx, &self.confirmations(tx)?, self.chain.tip) {
+            Readiness::Ready => Ok(()),
+            r => Err(SimulationError::Timelocked(r)),
+        }
+    }
+
+    fn check_amounts(&self, tx: &Transaction) -> Result<()> {
+        let input = tx
+            .input
+            .iter()
+            .map(|i| {
+                self.chain
+                    .utxo(&i.previous_output)
+                    .map(|o| o.value)
+                    .ok_or(SimulationError::UnknownInput(i.previous_output))
+            })
+            .sum::<Result<u64>>()?;
+        let output: u64 = tx.output.iter().map(|o| o.value).sum();
+        if output > input {
+            return Err(SimulationError::AmountNotConserved {
+                input: Amount::from_sat(input),
+                output: Amount::from_sat(output),
+            });
+        }
+        Ok(())
+    }
+
+    fn object(
+        &self,
+        path: &SArc<EffectPath>,
+    ) -> Result<&(OutPoint, Vec<PartiallySignedTransaction>)> {
+        self.objects
+            .get(path)
+            .ok_or_else(|| SimulationError::UnknownPath(path.clone()))
+    }
+
+    fn template(
+        &self,
+        path: &SArc<EffectPath>,
+        txid: &Txid,
+    ) -> Result<&PartiallySignedTransaction> {
+        self.object(path)?
+            .1
+            .iter()
+            .find(|p| p.unsigned_tx.txid() == *txid)
+            .ok_or(SimulationError::UnknownTemplate(*txid))
+    }
+
+    /// confirmations of `tx`'s inputs, which must all be unspent on the
+    /// mock chain (see `MockChain::mine_external` for outside inputs).
+    fn confirmations(&self, tx: &Transaction) -> Result<BTreeMap<Txid, Confirmation>> {
+        tx.input
+            .iter()
+            .map(|i| {
+                self.chain
+                    .utxos
+                    .get(&i.previous_output)
+                    .map(|(_, c)| (i.previous_output.txid, *c))
+                    .ok_or(SimulationError::UnknownInput(i.previous_output))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::XOnlyPublicKey;
+    use sapio::contract::*;
+    use sapio::*;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_base::Clause;
+    use schemars::*;
+    use serde::*;
+    use std::convert::TryFrom;
+
+    #[derive(JsonSchema, Serialize, Deserialize)]
+    struct Delayed {
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        to: XOnlyPublicKey,
+    }
+    impl Delayed {
+        #[then]
+        fn pay(self, ctx: sapio::Context) {
+            let amt = ctx.funds();
+            ctx.template()
+                .add_output(amt, &self.to, None)?
+                .set_sequence(0, RelHeight::from(10).into())?
+                .into()
+        }
+        #[then]
+        fn overspend(self, ctx: sapio::Context) {
+            let amt = ctx.funds();
+            ctx.template()
+                .add_amount(amt)
+                .add_output(amt + amt, &self.to, None)?
+                .into()
+        }
+    }
+    impl Contract for Delayed {
+        declare! {then, Self::pay, Self::overspend}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_simulate() {
+        let amount = Amount::from_sat(100_000);
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("sim").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let to = sapio::contract::internal_key::nums_point();
+        let compiled = Delayed { to }.compile(ctx).unwrap();
+        let sim = Simulator::new(
+            &compiled,
+            amount,
+            SigningKey::new_key(bitcoin::Network::Regtest).unwrap(),
+        )
+        .unwrap();
+        let root = sim.live_objects();
+        assert_eq!(root.len(), 1);
+
+        let results = sim.explore();
+        assert_eq!(results.len(), 2);
+        let ok = results.iter().filter(|(_, r)| r.is_ok()).count();
+        assert_eq!(ok, 1);
+        assert!(results
+            .iter()
+            .any(|(_, r)| matches!(r, Err(SimulationError::AmountNotConserved { .. }))));
+
+        // stepping by hand, the delayed payment must wait 10 blocks
+        let mut sim = sim;
+        let pay = sim
+            .options(&root[0])
+            .unwrap()
+            .into_iter()
+            .find(|t| matches!(sim.readiness(&root[0], t), Ok(Readiness::Locked { .. })))
+            .unwrap();
+        assert!(matches!(
+            sim.fire(&root[0], &pay),
+            Err(SimulationError::Timelocked(_))
+        ));
+        sim.chain.step_blocks(9);
+        sim.fire(&root[0], &pay).unwrap();
+        assert!(matches!(
+            sim.fire(&root[0], &pay),
+            Err(SimulationError::AlreadySpent(_))
+        ));
+    }
+    #[derive(JsonSchema, Serialize, Deserialize)]
+    struct Escrow {
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        ours: XOnlyPublicKey,
+    }
+    impl Escrow {
+        #[guard]
+        fn mine(self, _ctx: sapio::Context) {
+            Clause::And(vec![Clause::Key(self.ours), Clause::Older(5)])
+        }
+        #[guard]
+        fn theirs(self, _ctx: sapio::Context) {
+            Clause::Key(sapio::contract::internal_key::nums_point())
+        }
+    }
+    impl Contract for Escrow {
+        declare! {finish, Self::mine, Self::theirs}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn test_simulate_finish() {
+        let amount = Amount::from_sat(100_000);
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("sim").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let keys = SigningKey::new_key(bitcoin::Network::Regtest).unwrap();
+        let ours = keys.0[0]
+            .to_keypair(&Secp256k1::new())
+            .x_only_public_key()
+            .0;
+        let compiled = Escrow { ours }.compile(ctx).unwrap();
+        let sim = Simulator::new(&compiled, amount, keys).unwrap();
+        let root = sim.live_objects();
+
+        // our leaf succeeds once the lock matures, theirs cannot be signed
+        let results = sim.explore();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results.iter().filter(|(_, r)| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|(_, r)| matches!(r, Err(SimulationError::Unsatisfiable(_)))));
+
+        let (leaf, tx) = sim
+            .finish_options(&root[0])
+            .into_iter()
+            .find(|(leaf, _)| leaf.len() > 34)
+            .unwrap();
+        assert_eq!(tx.input[0].sequence, 5);
+        let psbt =
+            |tx: &Transaction| PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+        let mut sim = sim;
+        assert!(matches!(
+            sim.fire_finish(&root[0], psbt(&tx)),
+            Err(SimulationError::Timelocked(_))
+        ));
+        // an input which is not on the mock chain is an error
+        let mut unknown = tx.clone();
+        unknown.input.push(Default::default());
+        assert!(matches!(
+            sim.clone().fire_finish(&root[0], psbt(&unknown)),
+            Err(SimulationError::UnknownInput(_))
+        ));
+        sim.chain.step_blocks(4);
+        // the leaf's lock is checked against the transaction's sequence
+        let mut early = tx.clone();
+        early.input[0].sequence = 1;
+        assert!(matches!(
+            sim.clone().fire_leaf(&root[0], psbt(&early), Some(&leaf)),
+            Err(SimulationError::Unsatisfiable(_))
+        ));
+        sim.fire_finish(&root[0], psbt(&tx)).unwrap();
+        assert!(sim.live_objects().is_empty());
+    }
+
+    #[test]
+    fn test_finish_locks() {
+        let ours = sapio::contract::internal_key::nums_point();
+        let key = |i| {
+            XOnlyPublicKey::from_keypair(
+                &bitcoin::secp256k1::Keypair::from_seckey_slice(&Secp256k1::new(), &[i; 32])
+                    .unwrap(),
+            )
+            .0
+        };
+        let (also_ours, theirs) = (key(1), key(2));
+        let locks = |c: Clause| finish_locks(&c.compile::<Tap>().unwrap(), &[ours, also_ours]);
+        let and = |k, n| Clause::And(vec![Clause::Key(k), Clause::Older(n)]);
+        // every lock of a conjunction must be met
+        assert_eq!(
+            locks(Clause::And(vec![and(ours, 5), Clause::Older(100)])),
+            (100, 0)
+        );
+        assert_eq!(
+            locks(Clause::And(vec![and(ours, 5), Clause::After(50)])),
+            (5, 50)
+        );
+        // only the branch which can be satisfied counts
+        assert_eq!(
+            locks(Clause::Or(vec![(1, and(theirs, 5)), (1, and(ours, 100))])),
+            (100, 0)
+        );
+        // and of those, the least locked
+        assert_eq!(
+            locks(Clause::Or(vec![
+                (1, and(ours, 100)),
+                (1, and(also_ours, 5))
+            ])),
+            (5, 0)
+        );
+    }
+}